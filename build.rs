@@ -0,0 +1,59 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The commit and the time of the build, for `kass --version --verbose`,
+// and the target
+fn version_info() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .map(|x| String::from_utf8_lossy(&x.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=KASS_GIT_COMMIT={}", commit);
+    // SOURCE_DATE_EPOCH for reproducible builds
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |x| x.as_secs())
+        });
+    println!("cargo:rustc-env=KASS_BUILD_TIMESTAMP={}", timestamp);
+    // The binary of the target in the releases, for `kass self-update`
+    println!(
+        "cargo:rustc-env=KASS_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in ["src", "build.rs", ".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn main() {
+    version_info();
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(
+                &["proto/kass.proto"],
+                &[
+                    "proto",
+                    protoc_bin_vendored::include_path()
+                        .unwrap()
+                        .to_str()
+                        .unwrap(),
+                ],
+            )
+            .unwrap();
+    }
+}
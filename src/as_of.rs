@@ -0,0 +1,163 @@
+// `--as-of`: approximate a point in time export by fetching the write
+// time of every selected column and dropping the values written after
+// the cutoff. Rows whose values were all written after the cutoff
+// didn't exist yet and are skipped altogether.
+use cdrs::frame::frame_result::RowsMetadata;
+use chrono::{DateTime, NaiveDateTime};
+use regex::Regex;
+use serde_json::Value as JsonValue;
+
+use crate::core::{Config, CurrentSession};
+use crate::date_range::DATE_TIME_FORMAT;
+use crate::errors::{AppError, AppResult};
+use crate::schema::{self, quote, unquote, Column};
+
+const WRITETIME_PREFIX: &str = "kass_writetime_";
+
+lazy_static! {
+    static ref SELECT: Regex = Regex::new(r"(?is)^\s*select\s+(.+?)\s+from\s+(\S+)(.*)$").unwrap();
+    static ref IDENTIFIER: Regex =
+        Regex::new(r#"^(?:[A-Za-z_][A-Za-z0-9_]*|"(?:[^"]|"")+")$"#).unwrap();
+}
+
+/// The cutoff in microseconds since the epoch, as used by `WRITETIME`.
+pub fn parse_timestamp(s: &str) -> AppResult<i64> {
+    let t = match DateTime::parse_from_rfc3339(s) {
+        Ok(t) => t.naive_utc(),
+        Err(_) => NaiveDateTime::parse_from_str(s, DATE_TIME_FORMAT)?,
+    };
+    t.timestamp()
+        .checked_mul(1_000_000)
+        .and_then(|x| x.checked_add(t.timestamp_subsec_micros() as i64))
+        .ok_or_else(|| AppError::new(format!("--as-of {} is out of range", s)))
+}
+
+/// Add the write time of the selected columns to `cql`, which must be
+/// a `SELECT` of plain columns (or `*`) from a keyspace qualified table.
+pub fn rewrite(session: &CurrentSession, cql: &str, config: &Config) -> AppResult<String> {
+    let unsupported =
+        || AppError::new("--as-of requires a query like SELECT a, b FROM keyspace.table ...");
+    let caps = SELECT.captures(cql).ok_or_else(unsupported)?;
+    let (selection, table, rest) = (caps[1].trim(), &caps[2], &caps[3]);
+    let (keyspace, name) = table.split_once('.').ok_or_else(unsupported)?;
+    let columns = schema::table_columns(session, &unquote(keyspace), &unquote(name), config)?;
+
+    let selected: Vec<&Column> = if selection == "*" {
+        columns.iter().collect()
+    } else {
+        selection
+            .split(',')
+            .map(|x| {
+                let x = x.trim();
+                if !IDENTIFIER.is_match(x) {
+                    return Err(unsupported());
+                }
+                let name = unquote(x);
+                columns
+                    .iter()
+                    .find(|c| c.name == name)
+                    .ok_or_else(|| AppError::new(format!("Unknown column {} in {}", x, table)))
+            })
+            .collect::<AppResult<_>>()?
+    };
+
+    let selectors: Vec<String> = selected
+        .iter()
+        .map(|c| quote(&c.name))
+        .chain(selected.iter().filter(|c| c.has_writetime).map(|c| {
+            format!(
+                "WRITETIME({}) AS {}",
+                quote(&c.name),
+                quote(&format!("{}{}", WRITETIME_PREFIX, c.name))
+            )
+        }))
+        .collect();
+    Ok(format!(
+        "SELECT {} FROM {}{}",
+        selectors.join(", "),
+        table,
+        rest
+    ))
+}
+
+/// Drop the values of `row` written after `as_of`, along with the
+/// write time columns added by `rewrite`. `None` if the row didn't
+/// exist at that time.
+pub fn filter_row(row: JsonValue, as_of: i64, sparse: bool) -> Option<JsonValue> {
+    let obj = match row {
+        JsonValue::Object(obj) => obj,
+        x => return Some(x),
+    };
+    let mut written_after = vec![];
+    let mut before = false;
+    for (k, v) in obj.iter() {
+        match (k.strip_prefix(WRITETIME_PREFIX), v.as_i64()) {
+            (Some(col), Some(t)) if t > as_of => written_after.push(col.to_string()),
+            (Some(_), Some(_)) => before = true,
+            _ => {}
+        }
+    }
+    if !written_after.is_empty() && !before {
+        return None;
+    }
+
+    // Rebuilt rather than removing entries to keep the column order
+    let filtered = obj
+        .into_iter()
+        .filter(|(k, _)| !k.starts_with(WRITETIME_PREFIX))
+        .filter_map(|(k, v)| {
+            if !written_after.contains(&k) {
+                Some((k, v))
+            } else if sparse {
+                None
+            } else {
+                Some((k, JsonValue::Null))
+            }
+        })
+        .collect();
+    Some(JsonValue::Object(filtered))
+}
+
+/// The metadata of the rows once filtered, without the write times.
+pub fn output_metadata(meta: &RowsMetadata) -> RowsMetadata {
+    let mut meta = meta.clone();
+    meta.col_specs
+        .retain(|c| !c.name.as_str().starts_with(WRITETIME_PREFIX));
+    meta.columns_count = meta.col_specs.len() as i32;
+    meta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(1_000_000, parse_timestamp("1970-01-01T00:00:01Z").unwrap());
+        assert_eq!(1_000_000, parse_timestamp("1970-01-01T00:00:01").unwrap());
+        assert_eq!(
+            10_413_792_000_000_000,
+            parse_timestamp("2300-01-01T00:00:00Z").unwrap()
+        );
+        assert_eq!(-1_500, parse_timestamp("1969-12-31T23:59:59.9985Z").unwrap());
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_filter_row() {
+        let row = json!({
+            "id": 1, "a": "old", "b": "new",
+            "kass_writetime_a": 10, "kass_writetime_b": 30,
+        });
+        assert_eq!(
+            Some(json!({"id": 1, "a": "old", "b": null})),
+            filter_row(row.clone(), 20, false)
+        );
+        assert_eq!(
+            Some(json!({"id": 1, "a": "old"})),
+            filter_row(row.clone(), 20, true)
+        );
+        assert_eq!(None, filter_row(row, 5, false));
+    }
+}
@@ -0,0 +1,229 @@
+// `kass assert`: run a query for every parameter set and check the
+// number of rows returned (`--expect-rows '>0'`) and the values of the
+// rows (`--expect 'col == 5'`), e.g. for health checks run from CI or
+// cron. The failures are reported as JSON and the run fails when there
+// are any.
+use std::cmp::Ordering as CmpOrdering;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+use crate::iterator_consumer::IteratorConsumer;
+use crate::params::ParamSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// Two character operators first, so that `<=` isn't read as `<`
+const OPS: &[(&str, Op)] = &[
+    ("==", Op::Eq),
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+    ("=", Op::Eq),
+];
+
+impl Op {
+    fn holds(self, ordering: Option<CmpOrdering>) -> bool {
+        match (self, ordering) {
+            (Op::Ne, None) => true,
+            (_, None) => false,
+            (Op::Eq, Some(x)) => x == CmpOrdering::Equal,
+            (Op::Ne, Some(x)) => x != CmpOrdering::Equal,
+            (Op::Lt, Some(x)) => x == CmpOrdering::Less,
+            (Op::Le, Some(x)) => x != CmpOrdering::Greater,
+            (Op::Gt, Some(x)) => x == CmpOrdering::Greater,
+            (Op::Ge, Some(x)) => x != CmpOrdering::Less,
+        }
+    }
+}
+
+// Split `s` at its first operator
+fn split_op(s: &str) -> Option<(&str, Op, &str)> {
+    (0..s.len())
+        .filter(|&i| s.is_char_boundary(i))
+        .find_map(|i| {
+            OPS.iter()
+                .find(|(op, _)| s[i..].starts_with(op))
+                .map(|(op, x)| (s[..i].trim(), *x, s[i + op.len()..].trim()))
+        })
+}
+
+// Numbers compare as numbers and strings as strings, values of other
+// types can only be equal or not
+fn compare(a: &JsonValue, b: &JsonValue) -> Option<CmpOrdering> {
+    match (a, b) {
+        (JsonValue::Number(a), JsonValue::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (JsonValue::String(a), JsonValue::String(b)) => Some(a.cmp(b)),
+        (a, b) if a == b => Some(CmpOrdering::Equal),
+        _ => None,
+    }
+}
+
+/// `--expect-rows`: the number of rows of each parameter set, e.g.
+/// `>0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowCount {
+    op: Op,
+    count: u64,
+    text: String,
+}
+
+impl RowCount {
+    pub fn parse(s: &str) -> AppResult<RowCount> {
+        let invalid = || AppError::new(format!("Invalid row count {}, expected e.g. >0", s));
+        let (_, op, count) = split_op(s).filter(|x| x.0.is_empty()).ok_or_else(invalid)?;
+        Ok(RowCount {
+            op,
+            count: count.parse().map_err(|_| invalid())?,
+            text: format!("rows {}", s.trim()),
+        })
+    }
+
+    fn check(&self, rows: usize) -> bool {
+        self.op.holds(Some((rows as u64).cmp(&self.count)))
+    }
+}
+
+/// `--expect`: a condition on a column of every row, e.g. `col == 5`.
+/// The value is JSON, strings may also be quoted with single quotes or
+/// not at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    column: String,
+    op: Op,
+    value: JsonValue,
+    text: String,
+}
+
+impl Expectation {
+    pub fn parse(s: &str) -> AppResult<Expectation> {
+        let (column, op, value) = split_op(s)
+            .filter(|x| !x.0.is_empty() && !x.2.is_empty())
+            .ok_or_else(|| {
+                AppError::new(format!("Invalid expectation {}, expected e.g. col == 5", s))
+            })?;
+        let value = match serde_json::from_str(value) {
+            Ok(x) => x,
+            Err(_) => {
+                let unquoted = value.strip_prefix('\'').and_then(|x| x.strip_suffix('\''));
+                JsonValue::String(unquoted.unwrap_or(value).to_string())
+            }
+        };
+        Ok(Expectation {
+            column: column.to_string(),
+            op,
+            value,
+            text: s.trim().to_string(),
+        })
+    }
+
+    fn check(&self, row: &JsonValue) -> bool {
+        let actual = row.get(&self.column).unwrap_or(&JsonValue::Null);
+        self.op.holds(compare(actual, &self.value))
+    }
+}
+
+/// Run `cql` with every parameter set of `vals`, reporting the row
+/// counts and rows that don't meet the expectations.
+pub fn check(
+    config: Config,
+    cql: &str,
+    vals: Vec<ParamSet>,
+    rows: Option<RowCount>,
+    expectations: Vec<Expectation>,
+) -> AppResult<()> {
+    let session = config.connect()?;
+    let prepared = session.prepare(cql)?;
+    let redaction = config.redaction(cql)?;
+    let total = vals.len();
+    let failed = Arc::new(AtomicUsize::new(0));
+
+    let counter = failed.clone();
+    vals.into_iter()
+        .consume(config.parallelism(), move |vs| -> AppResult<()> {
+            let params = config.params(vs.values);
+            let resp = session.execute(&prepared, params)?;
+            let results = core::collect_rows(&resp, &config)?;
+            let labels = redaction.labels(&vs.labels);
+
+            let mut reports = vec![];
+            if let Some(rows) = rows.as_ref().filter(|x| !x.check(results.len())) {
+                reports
+                    .push(json!({ "params": labels, "failed": rows.text, "rows": results.len() }));
+            }
+            for row in results.iter() {
+                for expectation in expectations.iter().filter(|x| !x.check(row)) {
+                    reports
+                        .push(json!({ "params": labels, "failed": expectation.text, "row": row }));
+                }
+            }
+            if !reports.is_empty() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            for report in reports {
+                core::write_report(report, &config)?;
+            }
+            Ok(())
+        })?;
+
+    match failed.load(Ordering::Relaxed) {
+        0 => {
+            diag!("All assertions passed for {} parameter sets", total);
+            Ok(())
+        }
+        n => Err(AppError::new(format!(
+            "{} of {} parameter sets failed assertions",
+            n, total
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_count() {
+        let rows = RowCount::parse(">0").unwrap();
+        assert!(rows.check(1));
+        assert!(!rows.check(0));
+        let rows = RowCount::parse("<= 10").unwrap();
+        assert!(rows.check(10));
+        assert!(!rows.check(11));
+        assert!(RowCount::parse("0").is_err());
+        assert!(RowCount::parse("x > 0").is_err());
+        assert!(RowCount::parse(">-1").is_err());
+    }
+
+    #[test]
+    fn test_expectation() {
+        let row = json!({ "id": 5, "name": "alice", "deleted": false, "email": null });
+        let holds = |s: &str| Expectation::parse(s).unwrap().check(&row);
+        assert!(holds("id == 5"));
+        assert!(holds("id >= 5.0"));
+        assert!(!holds("id > 5"));
+        assert!(holds("name == 'alice'"));
+        assert!(holds("name == \"alice\""));
+        assert!(holds("name < bob"));
+        assert!(holds("deleted != true"));
+        assert!(holds("email == null"));
+        assert!(!holds("email > 1"));
+        assert!(holds("missing == null"));
+        assert!(!holds("name > 1"));
+        assert!(Expectation::parse("== 5").is_err());
+        assert!(Expectation::parse("id").is_err());
+    }
+}
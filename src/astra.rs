@@ -0,0 +1,204 @@
+// `--secure-connect-bundle bundle.zip`: connect to DataStax Astra. The
+// bundle holds the address of the metadata service of the database and
+// the certificates to reach it. The service tells the address of the SNI
+// proxy in front of the nodes and the host ids of the nodes, which are
+// the server names to ask the proxy for.
+//
+// The driver takes the server name from the address it connects to, so
+// each node gets a local forwarder of `proxy` instead, relaying the
+// traffic over TLS to the proxy with the host id of the node.
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use cdrs::compression::Compression;
+use openssl::pkey::PKey;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use openssl::x509::X509;
+use serde::Deserialize;
+use zip::ZipArchive;
+
+use crate::errors::{AppError, AppResult};
+use crate::proxy::{self, Remote};
+
+#[derive(Deserialize)]
+struct BundleConfig {
+    host: String,
+    port: u16,
+}
+
+pub struct Bundle {
+    config: BundleConfig,
+    connector: SslConnector,
+    compression: Compression,
+    // The addresses of the forwarders, once started
+    forwarders: Mutex<Option<Vec<String>>>,
+}
+
+#[derive(Deserialize)]
+struct ContactInfo {
+    sni_proxy_address: String,
+    contact_points: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    contact_info: ContactInfo,
+}
+
+fn bundle_error<E: std::fmt::Display>(err: E) -> AppError {
+    AppError::new(format!("Invalid secure connect bundle: {}", err))
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> AppResult<Vec<u8>> {
+    let mut entry = archive.by_name(name).map_err(bundle_error)?;
+    let mut bytes = vec![];
+    entry.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl Bundle {
+    pub fn open(path: &str, compression: Compression) -> AppResult<Bundle> {
+        let mut archive = ZipArchive::new(File::open(path)?).map_err(bundle_error)?;
+        let config = serde_json::from_slice(&read_entry(&mut archive, "config.json")?)?;
+
+        let ca = X509::from_pem(&read_entry(&mut archive, "ca.crt")?)?;
+        let cert = X509::from_pem(&read_entry(&mut archive, "cert")?)?;
+        let key = PKey::private_key_from_pem(&read_entry(&mut archive, "key")?)?;
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.cert_store_mut().add_cert(ca)?;
+        builder.set_certificate(&cert)?;
+        builder.set_private_key(&key)?;
+        Ok(Bundle {
+            config,
+            connector: builder.build(),
+            compression,
+            forwarders: Mutex::new(None),
+        })
+    }
+
+    fn tls_connect(&self, address: &str, server_name: &str) -> AppResult<SslStream<TcpStream>> {
+        let tcp = TcpStream::connect(address)?;
+        let mut config = self.connector.configure()?;
+        // The certificates are issued for the proxy rather than for
+        // the server names of the nodes
+        config.set_verify_hostname(false);
+        config
+            .connect(server_name, tcp)
+            .map_err(|err| AppError::new(format!("TLS error connecting to {}: {}", address, err)))
+    }
+
+    // Connect to the node `host_id` behind `proxy` on behalf of the
+    // driver, up to its `STARTUP`
+    fn connect(&self, local: &mut TcpStream, proxy: &str, host_id: &str) -> AppResult<Remote> {
+        let mut remote = Remote::Tls(self.tls_connect(proxy, host_id)?);
+        proxy::relay_startup(local, &mut remote, self.compression)?;
+        Ok(remote)
+    }
+
+    fn metadata(&self) -> AppResult<Metadata> {
+        let host = &self.config.host;
+        let mut stream = self.tls_connect(&format!("{}:{}", host, self.config.port), host)?;
+        write!(
+            stream,
+            "GET /metadata HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            host
+        )?;
+        let mut response = vec![];
+        // Servers may close the connection without a TLS close_notify
+        if let Err(err) = stream.read_to_end(&mut response) {
+            if response.is_empty() {
+                return Err(err.into());
+            }
+        }
+        Ok(serde_json::from_slice(&http_body(&response)?)?)
+    }
+
+    /// The addresses of the forwarders to the nodes, started on first
+    /// use.
+    pub fn nodes(self: &Arc<Self>) -> AppResult<Vec<String>> {
+        let mut forwarders = self.forwarders.lock().unwrap();
+        if forwarders.is_none() {
+            *forwarders = Some(self.start_forwarders()?);
+        }
+        Ok(forwarders.clone().unwrap_or_default())
+    }
+
+    fn start_forwarders(self: &Arc<Self>) -> AppResult<Vec<String>> {
+        let metadata = self.metadata()?;
+        let proxy = metadata.contact_info.sni_proxy_address;
+        let mut addresses = vec![];
+        for host_id in metadata.contact_info.contact_points {
+            let (bundle, proxy) = (self.clone(), proxy.clone());
+            addresses.push(proxy::listen(move |mut local| {
+                match bundle.connect(&mut local, &proxy, &host_id) {
+                    Ok(remote) => {
+                        let _ = proxy::forward(local, remote);
+                    }
+                    Err(err) => diag!("Unable to connect to node {}: {}", host_id, err),
+                }
+            })?);
+        }
+        if addresses.is_empty() {
+            return Err(AppError::new("The metadata service returned no nodes"));
+        }
+        Ok(addresses)
+    }
+}
+
+// The body of an HTTP response, with the chunked transfer encoding
+// decoded
+fn http_body(response: &[u8]) -> AppResult<Vec<u8>> {
+    let invalid = || AppError::new("Invalid response from the metadata service");
+    let split = response
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&response[..split]).to_lowercase();
+    let mut body = &response[split + 4..];
+    if !head.starts_with("http/1.1 200") && !head.starts_with("http/1.0 200") {
+        return Err(AppError::new(format!(
+            "The metadata service returned {}",
+            head.lines().next().unwrap_or_default()
+        )));
+    }
+    if !head.contains("transfer-encoding: chunked") {
+        return Ok(body.to_vec());
+    }
+    let mut decoded = vec![];
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|x| x == b"\r\n")
+            .ok_or_else(invalid)?;
+        let size = String::from_utf8_lossy(&body[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or_default().trim(), 16)
+            .map_err(|_| invalid())?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = body
+            .get(line_end + 2..line_end + 2 + size)
+            .ok_or_else(invalid)?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(line_end + 4 + size..).ok_or_else(invalid)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_body() {
+        let body = http_body(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+        assert_eq!(b"{}".to_vec(), body);
+        let body = http_body(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\": 1\r\n1\r\n}\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(b"{\"a\": 1}".to_vec(), body);
+        assert!(http_body(b"HTTP/1.1 401 Unauthorized\r\n\r\n").is_err());
+    }
+}
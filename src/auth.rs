@@ -0,0 +1,185 @@
+use cdrs::authenticators::Authenticator;
+use cdrs::types::CBytes;
+
+use crate::errors::{AppError, AppResult};
+
+const PASSWORD_AUTHENTICATOR: &str = "org.apache.cassandra.auth.PasswordAuthenticator";
+
+/// The authenticator handed to the driver. Every provider boils down
+/// to the name of the server side authenticator class and the token
+/// sent in the `AUTH_RESPONSE` frame, so a single concrete type is
+/// enough to keep the session of `backend::Cdrs` monomorphic.
+#[derive(Debug, Clone)]
+pub struct KassAuthenticator {
+    class_name: Option<String>,
+    token: Vec<u8>,
+}
+
+impl KassAuthenticator {
+    pub fn none() -> Self {
+        KassAuthenticator {
+            class_name: None,
+            token: vec![0],
+        }
+    }
+}
+
+impl Authenticator for KassAuthenticator {
+    fn get_auth_token(&self) -> CBytes {
+        CBytes::new(self.token.clone())
+    }
+
+    fn get_cassandra_name(&self) -> Option<&str> {
+        self.class_name.as_deref()
+    }
+}
+
+/// Authentication related options collected from the command line.
+#[derive(Debug, Default)]
+pub struct AuthOptions {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub class_name: Option<String>,
+}
+
+pub trait AuthProvider: Sync {
+    /// The name used to select this provider with `--auth`.
+    fn name(&self) -> &'static str;
+
+    fn authenticator(&self, opts: &AuthOptions) -> AppResult<KassAuthenticator>;
+}
+
+struct NoneAuth;
+
+impl AuthProvider for NoneAuth {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn authenticator(&self, _opts: &AuthOptions) -> AppResult<KassAuthenticator> {
+        Ok(KassAuthenticator::none())
+    }
+}
+
+// SASL PLAIN, as used by `PasswordAuthenticator` and most LDAP
+// authenticators (which only differ in the class name).
+struct PasswordAuth;
+
+impl AuthProvider for PasswordAuth {
+    fn name(&self) -> &'static str {
+        "password"
+    }
+
+    fn authenticator(&self, opts: &AuthOptions) -> AppResult<KassAuthenticator> {
+        let username = required(&opts.username, "--username", self.name())?;
+        let password = required(&opts.password, "--password", self.name())?;
+
+        let mut token = vec![0];
+        token.extend_from_slice(username.as_bytes());
+        token.push(0);
+        token.extend_from_slice(password.as_bytes());
+
+        Ok(KassAuthenticator {
+            class_name: Some(
+                opts.class_name
+                    .clone()
+                    .unwrap_or_else(|| PASSWORD_AUTHENTICATOR.to_string()),
+            ),
+            token,
+        })
+    }
+}
+
+// Sends an opaque, pre-computed token to a custom server side
+// authenticator.
+struct TokenAuth;
+
+impl AuthProvider for TokenAuth {
+    fn name(&self) -> &'static str {
+        "token"
+    }
+
+    fn authenticator(&self, opts: &AuthOptions) -> AppResult<KassAuthenticator> {
+        let token = required(&opts.token, "--auth-token", self.name())?;
+        let class_name = required(&opts.class_name, "--auth-class", self.name())?;
+        Ok(KassAuthenticator {
+            class_name: Some(class_name.to_string()),
+            token: token.as_bytes().to_vec(),
+        })
+    }
+}
+
+// AWS SigV4 needs a challenge/response round trip while the driver only
+// ever sends a single `AUTH_RESPONSE`. It is done by the forwarders of
+// `proxy`, which answer the driver's `STARTUP` once authenticated.
+struct SigV4Auth;
+
+impl AuthProvider for SigV4Auth {
+    fn name(&self) -> &'static str {
+        "sigv4"
+    }
+
+    fn authenticator(&self, _opts: &AuthOptions) -> AppResult<KassAuthenticator> {
+        Ok(KassAuthenticator::none())
+    }
+}
+
+// Site specific authenticators should be registered here. Kerberos
+// isn't one of them: GSSAPI would need the system library to get the
+// service tickets, which kass doesn't link against.
+static PROVIDERS: &[&dyn AuthProvider] = &[&NoneAuth, &PasswordAuth, &TokenAuth, &SigV4Auth];
+
+fn required<'a>(value: &'a Option<String>, flag: &str, provider: &str) -> AppResult<&'a str> {
+    value.as_deref().ok_or_else(|| {
+        AppError::new(format!(
+            "{} is required by the {} authenticator",
+            flag, provider
+        ))
+    })
+}
+
+pub fn authenticator(name: &str, opts: &AuthOptions) -> AppResult<KassAuthenticator> {
+    PROVIDERS
+        .iter()
+        .find(|p| p.name() == name)
+        .ok_or_else(|| {
+            AppError::new(format!(
+                "Unknown authenticator '{}', expected one of: {}",
+                name,
+                PROVIDERS
+                    .iter()
+                    .map(|p| p.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })
+        .and_then(|p| p.authenticator(opts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_authenticator() {
+        let opts = AuthOptions {
+            username: Some("foo".into()),
+            password: Some("bar".into()),
+            ..Default::default()
+        };
+        let auth = authenticator("password", &opts).unwrap();
+        assert_eq!(Some(PASSWORD_AUTHENTICATOR), auth.get_cassandra_name());
+        assert_eq!(
+            Some(b"\0foo\0bar".to_vec()),
+            auth.get_auth_token().into_plain()
+        );
+    }
+
+    #[test]
+    fn test_missing_options() {
+        assert!(authenticator("password", &AuthOptions::default()).is_err());
+        assert!(authenticator("token", &AuthOptions::default()).is_err());
+        assert!(authenticator("nope", &AuthOptions::default()).is_err());
+    }
+}
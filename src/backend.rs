@@ -0,0 +1,452 @@
+// The interaction with Cassandra goes through the `Backend` trait so
+// that the driver can be replaced. Statements and results are described
+// with the types of the native protocol, as decoded by cdrs, which any
+// driver giving access to the raw values can produce.
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use cdrs::cluster::session::{
+    new as new_session, new_lz4, new_snappy, new_ssl as new_ssl_session, Session,
+};
+use cdrs::cluster::{
+    ClusterSslConfig, ClusterTcpConfig, NodeSslConfigBuilder, NodeTcpConfigBuilder,
+    SslConnectionPool, TcpConnectionPool,
+};
+use cdrs::compression::Compression;
+use cdrs::consistency::Consistency;
+use cdrs::error::{Error, Result};
+use cdrs::frame::frame_error::AdditionalErrorInfo;
+use cdrs::frame::frame_response::ResponseBody;
+use cdrs::frame::frame_result::{BodyResResultRows, ResResultBody};
+use cdrs::frame::{Frame, Opcode};
+use cdrs::load_balancing::RoundRobinSync;
+use cdrs::query::*;
+use cdrs::types::value::Value;
+use cdrs::types::CBytes;
+use openssl::ssl::SslConnector;
+
+use crate::auth::KassAuthenticator;
+use crate::params::Values;
+use crate::record::Recorder;
+use crate::warnings::Warnings;
+
+/// The id of a prepared statement.
+pub type Prepared = PreparedQuery;
+
+/// The parameters of a statement.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    pub values: Values,
+    pub consistency: Consistency,
+    // The serial consistency of the conditional statements, SERIAL
+    // unless set
+    pub serial_consistency: Option<Consistency>,
+    // Results are fetched in one page unless set
+    pub page_size: Option<i32>,
+    pub paging_state: Option<CBytes>,
+}
+
+impl Params {
+    pub fn new(values: Values, consistency: Consistency) -> Self {
+        Params {
+            values,
+            consistency,
+            ..Default::default()
+        }
+    }
+}
+
+/// The result of a statement.
+#[derive(Debug)]
+pub enum Response {
+    Rows(BodyResResultRows),
+    SchemaChange,
+    Void,
+}
+
+pub trait Backend: Send + Sync {
+    fn prepare(&self, cql: &str) -> Result<Prepared>;
+
+    /// Run an unprepared statement.
+    fn query(&self, cql: &str, params: Params) -> Result<Response>;
+
+    fn execute(&self, prepared: &Prepared, params: Params) -> Result<Response>;
+
+    /// Run a prepared statement again on another node than the
+    /// execution it speculates on, which those sending the statements to
+    /// the nodes in turn do anyway.
+    fn speculate(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        self.execute(prepared, params)
+    }
+
+    /// The rows of `system_schema.columns` describing a table, read at
+    /// `consistency`. Drivers keeping track of the schema can answer
+    /// without a query.
+    fn metadata(&self, keyspace: &str, table: &str, consistency: Consistency) -> Result<Response> {
+        self.query(
+            "SELECT column_name, kind, position, type, clustering_order FROM system_schema.columns \
+             WHERE keyspace_name = ? AND table_name = ?",
+            Params::new(vec![keyspace.into(), table.into()], consistency),
+        )
+    }
+}
+
+enum CdrsSession {
+    Tcp(Session<RoundRobinSync<TcpConnectionPool<KassAuthenticator>>>),
+    Ssl(Session<RoundRobinSync<SslConnectionPool<KassAuthenticator>>>),
+}
+
+/// The number of connections to each node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolSize {
+    // The connections kept open while idle, all of them when not set
+    pub min_idle: Option<u32>,
+    pub max: u32,
+}
+
+/// The statements prepared through a session, for the nodes that don't
+/// know them yet to prepare them again: each node has its own cache of
+/// prepared statements, and a statement is only prepared on one node.
+#[derive(Debug, Default)]
+pub struct Statements(RwLock<HashMap<Vec<u8>, String>>);
+
+// The times a statement unknown to a node is prepared again
+const MAX_PREPARE_ATTEMPTS: usize = 3;
+
+fn is_unprepared(err: &Error) -> bool {
+    matches!(err, Error::Server(err) if matches!(err.additional_info, AdditionalErrorInfo::Unprepared(_)))
+}
+
+impl Statements {
+    pub fn insert(&self, prepared: &Prepared, cql: &str) {
+        let id = prepared.clone().into_plain().unwrap_or_default();
+        self.0.write().unwrap().insert(id, cql.to_string());
+    }
+
+    /// Run `execute`, and again while the node it goes to doesn't know
+    /// its statement, once `prepare` has prepared it `nodes` times, each
+    /// preparation going to the next of the `nodes` taking the
+    /// statements in turn.
+    pub fn execute<T>(
+        &self,
+        prepared: &Prepared,
+        nodes: usize,
+        mut execute: impl FnMut() -> Result<T>,
+        mut prepare: impl FnMut(&str) -> Result<()>,
+    ) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match execute() {
+                // The statements of the other workers interleave with
+                // the preparations
+                Err(err) if is_unprepared(&err) && attempt < MAX_PREPARE_ATTEMPTS => {
+                    let id = prepared.clone().into_plain().unwrap_or_default();
+                    let cql = match self.0.read().unwrap().get(&id).cloned() {
+                        Some(x) => x,
+                        None => return Err(err),
+                    };
+                    for _ in 0..nodes {
+                        prepare(&cql)?;
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The default backend, using cdrs.
+pub struct Cdrs {
+    session: CdrsSession,
+    // The number of nodes the statements go to in turn
+    nodes: usize,
+    statements: Statements,
+    recorder: Option<Recorder>,
+    warnings: Option<Arc<Warnings>>,
+}
+
+impl Cdrs {
+    /// Connect to `hosts`, the queries going to each of them in turn.
+    /// The responses are decoded with `compression`, which the `STARTUP`
+    /// must have asked for.
+    pub fn connect(
+        hosts: &[String],
+        auth: KassAuthenticator,
+        pool: PoolSize,
+        compression: Compression,
+    ) -> Result<Cdrs> {
+        let nodes = hosts
+            .iter()
+            .map(|host| {
+                NodeTcpConfigBuilder::new(host, auth.clone())
+                    .max_size(pool.max)
+                    .min_idle(pool.min_idle)
+                    .connection_timeout(Duration::from_secs(10)) //TODO CLI option for timeout
+                    .build()
+            })
+            .collect();
+        let cluster_config = ClusterTcpConfig(nodes);
+        let balancing = RoundRobinSync::new();
+        let session = match compression {
+            Compression::Lz4 => new_lz4(&cluster_config, balancing)?,
+            Compression::Snappy => new_snappy(&cluster_config, balancing)?,
+            Compression::None => new_session(&cluster_config, balancing)?,
+        };
+        Ok(Cdrs {
+            session: CdrsSession::Tcp(session),
+            nodes: hosts.len(),
+            statements: Statements::default(),
+            recorder: None,
+            warnings: None,
+        })
+    }
+
+    pub fn connect_ssl(
+        hosts: &[String],
+        auth: KassAuthenticator,
+        pool: PoolSize,
+        connector: SslConnector,
+    ) -> Result<Cdrs> {
+        let nodes = hosts
+            .iter()
+            .map(|host| {
+                NodeSslConfigBuilder::new(host, auth.clone(), connector.clone())
+                    .max_size(pool.max)
+                    .min_idle(pool.min_idle)
+                    .connection_timeout(Duration::from_secs(10))
+                    .build()
+            })
+            .collect();
+        let cluster_config = ClusterSslConfig(nodes);
+        Ok(Cdrs {
+            session: CdrsSession::Ssl(new_ssl_session(&cluster_config, RoundRobinSync::new())?),
+            nodes: hosts.len(),
+            statements: Statements::default(),
+            recorder: None,
+            warnings: None,
+        })
+    }
+
+    /// Record the results received to replay them with `--replay`.
+    pub fn with_recorder(self, recorder: Recorder) -> Cdrs {
+        Cdrs {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    /// Count the warnings sent along with the responses.
+    pub fn with_warnings(self, warnings: Arc<Warnings>) -> Cdrs {
+        Cdrs {
+            warnings: Some(warnings),
+            ..self
+        }
+    }
+
+    fn respond(&self, cql: &str, values: &[Value], frame: Frame) -> Result<Response> {
+        count_bytes_read(take_wire_size(frame.stream).unwrap_or_else(|| wire_size(&frame)));
+        if let Some(warnings) = &self.warnings {
+            warnings.record(&frame.warnings);
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(cql, values, &frame.body)?;
+        }
+        decode(&frame.body)
+    }
+}
+
+// The size of the header of a frame of protocol v4
+const FRAME_HEADER_SIZE: u64 = 9;
+
+// The size of an uncompressed `frame` as read from the network, its
+// tracing id and warnings included
+fn wire_size(frame: &Frame) -> u64 {
+    let tracing = frame.tracing_id.map_or(0, |_| 16);
+    let warnings = match frame.warnings.len() {
+        0 => 0,
+        _ => 2 + frame.warnings.iter().map(|x| 2 + x.len()).sum::<usize>(),
+    };
+    FRAME_HEADER_SIZE + (tracing + warnings + frame.body.len()) as u64
+}
+
+// The sizes of the frames relayed by the forwarders, compressed or not,
+// by stream, until the workers reading them take them
+static WIRE_SIZES: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    // The bytes of the frames received by the thread, as read from the
+    // network
+    static BYTES_READ: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Record the size of a frame on `stream` relayed by a forwarder from
+/// a node, which may have compressed it, for the thread receiving it to
+/// count.
+pub fn record_wire_size(stream: u16, bytes: u64) {
+    WIRE_SIZES.lock().unwrap().insert(stream, bytes);
+}
+
+pub(crate) fn take_wire_size(stream: u16) -> Option<u64> {
+    WIRE_SIZES.lock().unwrap().remove(&stream)
+}
+
+/// Add `bytes` to those read by the current thread, e.g. by a thread
+/// it ran a query on.
+pub fn count_bytes_read(bytes: u64) {
+    BYTES_READ.with(|x| x.set(x.get() + bytes));
+}
+
+/// The bytes read by the current thread since the last call.
+pub fn take_bytes_read() -> u64 {
+    BYTES_READ.with(|x| x.replace(0))
+}
+
+fn query_params(params: Params) -> QueryParams {
+    let mut builder = QueryParamsBuilder::new()
+        .consistency(params.consistency)
+        .values(QueryValues::SimpleValues(params.values));
+    if let Some(serial) = params.serial_consistency {
+        builder = builder.serial_consistency(serial);
+    }
+    if let Some(size) = params.page_size {
+        builder = builder.page_size(size);
+    }
+    if let Some(state) = params.paging_state {
+        builder = builder.paging_state(state);
+    }
+    builder.finalize()
+}
+
+/// Decode the body of a `RESULT` frame.
+pub fn decode(body: &[u8]) -> Result<Response> {
+    Ok(match ResponseBody::from(body, &Opcode::Result)? {
+        ResponseBody::Result(ResResultBody::Rows(rows)) => Response::Rows(rows),
+        ResponseBody::Result(ResResultBody::SchemaChange(_)) => Response::SchemaChange,
+        _ => Response::Void,
+    })
+}
+
+impl Backend for Cdrs {
+    fn prepare(&self, cql: &str) -> Result<Prepared> {
+        let prepared = match &self.session {
+            CdrsSession::Tcp(s) => s.prepare(cql)?,
+            CdrsSession::Ssl(s) => s.prepare(cql)?,
+        };
+        self.statements.insert(&prepared, cql);
+        if let Some(recorder) = &self.recorder {
+            recorder.prepared(&prepared, cql);
+        }
+        Ok(prepared)
+    }
+
+    fn query(&self, cql: &str, params: Params) -> Result<Response> {
+        let values = params.values.clone();
+        let params = query_params(params);
+        let frame = match &self.session {
+            CdrsSession::Tcp(s) => s.query_with_params(cql, params)?,
+            CdrsSession::Ssl(s) => s.query_with_params(cql, params)?,
+        };
+        self.respond(cql, &values, frame)
+    }
+
+    fn execute(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        let values = params.values.clone();
+        let frame = self.statements.execute(
+            prepared,
+            self.nodes,
+            || match &self.session {
+                CdrsSession::Tcp(s) => s.exec_with_params(prepared, query_params(params.clone())),
+                CdrsSession::Ssl(s) => s.exec_with_params(prepared, query_params(params.clone())),
+            },
+            |cql| match &self.session {
+                CdrsSession::Tcp(s) => s.prepare(cql).map(drop),
+                CdrsSession::Ssl(s) => s.prepare(cql).map(drop),
+            },
+        )?;
+        let cql = match &self.recorder {
+            Some(recorder) => recorder.query_of(prepared).unwrap_or_default(),
+            None => String::new(),
+        };
+        self.respond(&cql, &values, frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdrs::frame::frame_error::{CDRSError, UnpreparedError};
+    use cdrs::types::{CBytesShort, CString};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    // Two nodes taking the statements in turn, the second not knowing
+    // the statement prepared on the first
+    struct TwoNodes {
+        prepared: Mutex<[bool; 2]>,
+        next: AtomicUsize,
+    }
+
+    impl TwoNodes {
+        fn node(&self) -> usize {
+            self.next.fetch_add(1, Ordering::Relaxed) % 2
+        }
+
+        fn prepare(&self) {
+            let node = self.node();
+            self.prepared.lock().unwrap()[node] = true;
+        }
+
+        fn execute(&self) -> Result<usize> {
+            let node = self.node();
+            match self.prepared.lock().unwrap()[node] {
+                true => Ok(node),
+                false => Err(Error::Server(CDRSError {
+                    error_code: 0x2500,
+                    message: CString::new("Prepared query not found".to_string()),
+                    additional_info: AdditionalErrorInfo::Unprepared(UnpreparedError {
+                        id: CBytesShort::new(vec![1]),
+                    }),
+                })),
+            }
+        }
+    }
+
+    #[test]
+    fn test_prepare_again() {
+        let nodes = TwoNodes {
+            prepared: Mutex::new([false, false]),
+            next: AtomicUsize::new(0),
+        };
+        let statements = Statements::default();
+        let prepared = Prepared::new(vec![1]);
+        // Prepared on the first node, sent to the second
+        nodes.prepare();
+        statements.insert(&prepared, "SELECT * FROM ks.t WHERE id = ?");
+        let execute = || {
+            statements.execute(
+                &prepared,
+                2,
+                || nodes.execute(),
+                |_| {
+                    nodes.prepare();
+                    Ok(())
+                },
+            )
+        };
+        assert_eq!(0, execute().unwrap());
+        assert_eq!([true, true], *nodes.prepared.lock().unwrap());
+        assert_eq!(1, execute().unwrap());
+        assert_eq!(0, execute().unwrap());
+
+        // Not prepared through the session
+        let unknown = Prepared::new(vec![2]);
+        nodes.prepared.lock().unwrap()[1] = false;
+        nodes.next.store(1, Ordering::Relaxed);
+        assert!(statements
+            .execute(&unknown, 2, || nodes.execute(), |_| Ok(()))
+            .is_err());
+    }
+}
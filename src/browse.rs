@@ -0,0 +1,189 @@
+// `kass browse ks.table`: build the parameters of a query on some
+// partitions of a table interactively, for those who don't know the
+// parameter grammar by heart. The values of each partition key column
+// are prompted for, in the same format as query parameters, and the rows
+// of the partitions written out once the number of partitions is
+// confirmed.
+//
+// With `--fzf`, the partitions are picked with fzf among the first
+// partitions of the table instead.
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use cdrs::types::value::{Bytes, Value};
+use serde_json::Value as JsonValue;
+
+use crate::backend::Response;
+use crate::core::{self, Config, CurrentSession};
+use crate::credentials::prompt_line;
+use crate::errors::{AppError, AppResult};
+use crate::params::{self, ParamSet};
+use crate::schema::{self, quote, unquote};
+
+struct KeyColumn {
+    name: String,
+    col_type: String,
+}
+
+fn select_cql(table: &str, keys: &[KeyColumn]) -> String {
+    let key: Vec<String> = keys
+        .iter()
+        .map(|c| format!("{} = ?", quote(&c.name)))
+        .collect();
+    format!("SELECT * FROM {} WHERE {}", table, key.join(" AND "))
+}
+
+// The text of a value in the list of partitions
+fn label(value: &JsonValue) -> String {
+    match value {
+        JsonValue::String(s) => s.clone(),
+        x => x.to_string(),
+    }
+}
+
+// Prompt for the values of each key column until they parse
+fn prompt_params(keys: &[KeyColumn]) -> AppResult<Vec<ParamSet>> {
+    eprintln!(
+        "Values of the partition key, e.g. 1..100, 1..100/10, a,b,c or 2020-01-01..2020-01-31"
+    );
+    let mut specs = vec![];
+    for key in keys {
+        loop {
+            let spec = prompt_line(&format!("{} ({}): ", key.name, key.col_type))?;
+            if spec.is_empty() {
+                return Err(AppError::new(format!("No values given for {}", key.name)));
+            }
+            match params::parse_args(std::iter::once(spec.as_str())) {
+                Ok(values) => {
+                    eprintln!("{} values", values.len());
+                    specs.push(spec);
+                    break;
+                }
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+    }
+    params::parse_args(specs.iter().map(String::as_str))
+}
+
+// The first `limit` partitions of `table`, bound with the bytes stored
+fn partitions(
+    session: &CurrentSession,
+    table: &str,
+    keys: &[KeyColumn],
+    limit: usize,
+    config: &Config,
+) -> AppResult<Vec<ParamSet>> {
+    let names: Vec<String> = keys.iter().map(|c| quote(&c.name)).collect();
+    let resp = session.query(
+        &format!(
+            "SELECT DISTINCT {} FROM {} LIMIT {}",
+            names.join(", "),
+            table,
+            limit
+        ),
+        config.params(vec![]),
+    )?;
+    let rows = match &resp {
+        Response::Rows(rows) => &rows.rows_content,
+        _ => return Ok(vec![]),
+    };
+    Ok(core::collect_rows(&resp, config)?
+        .iter()
+        .zip(rows)
+        .map(|(json, row)| ParamSet {
+            values: row
+                .iter()
+                .map(|x| Value::new_normal(Bytes::new(x.as_plain().unwrap_or_default())))
+                .collect(),
+            labels: keys.iter().map(|c| label(&json[&c.name])).collect(),
+        })
+        .collect())
+}
+
+// Pick some of `partitions` with fzf
+fn pick(keys: &[KeyColumn], partitions: Vec<ParamSet>) -> AppResult<Vec<ParamSet>> {
+    let mut fzf = Command::new("fzf")
+        .args(["--multi", "--delimiter", "\t", "--with-nth", "2.."])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| AppError::new(format!("Unable to run fzf: {}", err)))?;
+    if let Some(mut stdin) = fzf.stdin.take() {
+        for (i, partition) in partitions.iter().enumerate() {
+            let values = keys
+                .iter()
+                .zip(&partition.labels)
+                .map(|(c, x)| format!("{}={}", c.name, x));
+            writeln!(stdin, "{}\t{}", i, values.collect::<Vec<_>>().join("  "))?;
+        }
+    }
+    // Nothing is written out when fzf is cancelled
+    let output = fzf.wait_with_output()?;
+    let picked: Vec<usize> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split('\t').next()?.parse().ok())
+        .collect();
+    Ok(partitions
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picked.contains(i))
+        .map(|(_, x)| x)
+        .collect())
+}
+
+/// Prompt for the partitions of `table` (`keyspace.table`), or pick
+/// them with fzf among the first `fzf_limit` ones, and write their rows.
+pub fn browse(config: Config, table: &str, fzf_limit: Option<usize>) -> AppResult<()> {
+    let session = config.connect()?;
+    let (keyspace, name) = table
+        .split_once('.')
+        .ok_or_else(|| AppError::new("The table must be given as keyspace.table"))?;
+    let columns = schema::table_columns(&session, &unquote(keyspace), &unquote(name), &config)?;
+    let keys: Vec<KeyColumn> = columns
+        .into_iter()
+        .filter(|c| c.kind == "partition_key")
+        .map(|c| KeyColumn {
+            name: c.name,
+            col_type: c.col_type,
+        })
+        .collect();
+
+    let vals = match fzf_limit {
+        Some(limit) => pick(&keys, partitions(&session, table, &keys, limit, &config)?)?,
+        None => prompt_params(&keys)?,
+    };
+    if vals.is_empty() {
+        return Err(AppError::new("No partitions to query"));
+    }
+    let cql = select_cql(table, &keys);
+    let answer = prompt_line(&format!(
+        "Query {} partitions with {}? [y/N] ",
+        vals.len(),
+        cql
+    ))?;
+    if !answer.eq_ignore_ascii_case("y") {
+        return Ok(());
+    }
+    core::run_query(config, &cql, Some(vals))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select_cql() {
+        let key = |name: &str| KeyColumn {
+            name: name.to_string(),
+            col_type: "int".to_string(),
+        };
+        assert_eq!(
+            "SELECT * FROM ks.events WHERE \"day\" = ? AND \"Region\" = ?",
+            select_cql("ks.events", &[key("day"), key("Region")])
+        );
+        assert_eq!("nz", label(&json!("nz")));
+        assert_eq!("42", label(&json!(42)));
+    }
+}
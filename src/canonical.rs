@@ -0,0 +1,132 @@
+// Canonical JSON serialization following RFC 8785 (JCS): object keys
+// sorted by their UTF-16 code units, numbers formatted the way
+// ECMAScript does and no insignificant whitespace, so the same data
+// is always serialized to the same bytes.
+use serde_json::{Number, Value as JsonValue};
+use std::fmt::Write;
+
+pub fn to_canonical_string(json: &JsonValue) -> String {
+    let mut out = String::new();
+    write_value(&mut out, json);
+    out
+}
+
+fn write_value(out: &mut String, json: &JsonValue) {
+    match json {
+        JsonValue::Null => out.push_str("null"),
+        JsonValue::Bool(x) => out.push_str(if *x { "true" } else { "false" }),
+        JsonValue::Number(n) => write_number(out, n),
+        JsonValue::String(s) => write_string(out, s),
+        JsonValue::Array(xs) => {
+            out.push('[');
+            for (i, x) in xs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, x);
+            }
+            out.push(']');
+        }
+        JsonValue::Object(obj) => {
+            let mut entries: Vec<(&String, &JsonValue)> = obj.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, k);
+                out.push(':');
+                write_value(out, v);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0C}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < '\u{20}' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_number(out: &mut String, n: &Number) {
+    if let Some(x) = n.as_i64() {
+        write!(out, "{}", x).unwrap();
+    } else if let Some(x) = n.as_u64() {
+        write!(out, "{}", x).unwrap();
+    } else {
+        out.push_str(&format_f64(n.as_f64().unwrap_or(0.0)));
+    }
+}
+
+// Number::toString from ECMAScript, see
+// https://tc39.es/ecma262/#sec-numeric-types-number-tostring
+fn format_f64(x: f64) -> String {
+    if x == 0.0 || !x.is_finite() {
+        return "0".to_string();
+    }
+    let sign = if x < 0.0 { "-" } else { "" };
+    // Shortest round-trip digits, e.g. "1.2345e-7"
+    let sci = format!("{:e}", x.abs());
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    // x = 0.digits * 10^n
+    let n = exp[1..].parse::<i32>().unwrap() + 1;
+
+    let body = if k <= n && n <= 21 {
+        format!("{}{}", digits, "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
+    } else {
+        let exp_sign = if n - 1 < 0 { "-" } else { "+" };
+        let rest = if k > 1 {
+            format!(".{}", &digits[1..])
+        } else {
+            String::new()
+        };
+        format!("{}{}e{}{}", &digits[..1], rest, exp_sign, (n - 1).abs())
+    };
+    format!("{}{}", sign, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_format_f64() {
+        assert_eq!("1.5", format_f64(1.5));
+        assert_eq!("-100", format_f64(-100.0));
+        assert_eq!("0.000001", format_f64(0.000001));
+        assert_eq!("1e-7", format_f64(0.0000001));
+        assert_eq!("1e+21", format_f64(1e21));
+        assert_eq!("123456789012345680000", format_f64(1.2345678901234568e20));
+        assert_eq!("0", format_f64(-0.0));
+    }
+
+    #[test]
+    fn test_canonical_string() {
+        let json = json!({"b": [1, 2.5, "x\ny"], "a": null, "\u{e9}": true, "A": {}});
+        assert_eq!(
+            r#"{"A":{},"a":null,"b":[1,2.5,"x\ny"],"é":true}"#,
+            to_canonical_string(&json).as_str()
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// `--compare-to golden.jsonl`: diff the rows of a run against those of a
+// previous export, e.g. to regression test a data pipeline. The
+// differences are written out instead of the rows, and the run fails
+// when there are any.
+//
+// With `--key`, rows are matched by the values of the key columns and
+// reported as added, removed or changed. Otherwise they are compared in
+// order, which requires the rows to come out in a deterministic order,
+// e.g. a single query or `--parallelism 1`.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+
+enum Golden {
+    // The golden rows by key, along with whether the run returned them
+    Keyed(HashMap<String, (JsonValue, bool)>),
+    // The golden rows and the position of the next row of the run
+    Ordered(Vec<JsonValue>, usize),
+}
+
+pub struct Comparison {
+    path: String,
+    key: Vec<String>,
+    golden: Mutex<Golden>,
+    added: AtomicUsize,
+    changed: AtomicUsize,
+}
+
+fn row_key(row: &JsonValue, key: &[String]) -> AppResult<String> {
+    let values = key
+        .iter()
+        .map(|column| {
+            row.get(column)
+                .cloned()
+                .ok_or_else(|| AppError::new(format!("The key column {} is missing", column)))
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+    Ok(JsonValue::Array(values).to_string())
+}
+
+impl Comparison {
+    pub fn load(path: &str, key: Vec<String>) -> AppResult<Comparison> {
+        let rows = serde_json::Deserializer::from_reader(BufReader::new(File::open(path)?))
+            .into_iter::<JsonValue>()
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(path, key, rows)
+    }
+
+    fn new(path: &str, key: Vec<String>, rows: Vec<JsonValue>) -> AppResult<Comparison> {
+        let golden = if key.is_empty() {
+            Golden::Ordered(rows, 0)
+        } else {
+            let mut by_key = HashMap::with_capacity(rows.len());
+            for row in rows {
+                by_key.insert(row_key(&row, &key)?, (row, false));
+            }
+            Golden::Keyed(by_key)
+        };
+        Ok(Comparison {
+            path: path.to_string(),
+            key,
+            golden: Mutex::new(golden),
+            added: AtomicUsize::new(0),
+            changed: AtomicUsize::new(0),
+        })
+    }
+
+    /// The difference between `row` of the run and the golden rows, if
+    /// any.
+    pub fn check(&self, row: &JsonValue) -> AppResult<Option<JsonValue>> {
+        let mut golden = self.golden.lock().unwrap();
+        let expected = match &mut *golden {
+            Golden::Keyed(by_key) => match by_key.get_mut(&row_key(row, &self.key)?) {
+                Some((expected, seen)) => {
+                    *seen = true;
+                    Some(&*expected)
+                }
+                None => None,
+            },
+            Golden::Ordered(rows, next) => {
+                *next += 1;
+                rows.get(*next - 1)
+            }
+        };
+        Ok(match expected {
+            Some(expected) if expected == row => None,
+            Some(expected) => {
+                self.changed.fetch_add(1, Ordering::Relaxed);
+                Some(json!({ "changed": { "from": expected, "to": row } }))
+            }
+            None => {
+                self.added.fetch_add(1, Ordering::Relaxed);
+                Some(json!({ "added": row }))
+            }
+        })
+    }
+
+    // The golden rows not returned by the run, once done
+    fn removed(&self) -> Vec<JsonValue> {
+        let golden = self.golden.lock().unwrap();
+        let rows: Vec<&JsonValue> = match &*golden {
+            Golden::Keyed(by_key) => by_key
+                .values()
+                .filter(|(_, seen)| !seen)
+                .map(|(row, _)| row)
+                .collect(),
+            Golden::Ordered(rows, next) => rows.iter().skip(*next).collect(),
+        };
+        rows.into_iter()
+            .map(|row| json!({ "removed": row }))
+            .collect()
+    }
+
+    /// Write out the golden rows not returned by the run, failing when
+    /// there were differences.
+    pub fn finish(&self, config: &Config) -> AppResult<()> {
+        let removed = self.removed();
+        let summary = self.summary(removed.len());
+        for report in removed {
+            core::write_report(report, config)?;
+        }
+        match summary {
+            Some(summary) => Err(AppError::new(summary)),
+            None => {
+                diag!("No differences with {}", self.path);
+                Ok(())
+            }
+        }
+    }
+
+    fn summary(&self, removed: usize) -> Option<String> {
+        let added = self.added.load(Ordering::Relaxed);
+        let changed = self.changed.load(Ordering::Relaxed);
+        if added + removed + changed == 0 {
+            return None;
+        }
+        Some(format!(
+            "{} rows added, {} removed and {} changed compared to {}",
+            added, removed, changed, self.path
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyed() {
+        let golden = vec![
+            json!({ "id": 1, "name": "a" }),
+            json!({ "id": 2, "name": "b" }),
+            json!({ "id": 3, "name": "c" }),
+        ];
+        let comparison = Comparison::new("golden.jsonl", vec!["id".into()], golden).unwrap();
+        assert_eq!(
+            None,
+            comparison.check(&json!({ "id": 3, "name": "c" })).unwrap()
+        );
+        assert_eq!(
+            Some(
+                json!({ "changed": { "from": { "id": 1, "name": "a" }, "to": { "id": 1, "name": "x" } } })
+            ),
+            comparison.check(&json!({ "id": 1, "name": "x" })).unwrap()
+        );
+        assert_eq!(
+            Some(json!({ "added": { "id": 4, "name": "d" } })),
+            comparison.check(&json!({ "id": 4, "name": "d" })).unwrap()
+        );
+        assert!(comparison.check(&json!({ "name": "e" })).is_err());
+        let removed = comparison.removed();
+        assert_eq!(
+            vec![json!({ "removed": { "id": 2, "name": "b" } })],
+            removed
+        );
+        assert_eq!(
+            Some("1 rows added, 1 removed and 1 changed compared to golden.jsonl".to_string()),
+            comparison.summary(removed.len())
+        );
+    }
+
+    #[test]
+    fn test_ordered() {
+        let golden = vec![json!({ "id": 1 }), json!({ "id": 2 })];
+        let comparison = Comparison::new("golden.jsonl", vec![], golden).unwrap();
+        assert_eq!(None, comparison.check(&json!({ "id": 1 })).unwrap());
+        assert_eq!(None, comparison.check(&json!({ "id": 2 })).unwrap());
+        assert!(comparison.removed().is_empty());
+        assert_eq!(None, comparison.summary(0));
+        assert_eq!(
+            Some(json!({ "added": { "id": 3 } })),
+            comparison.check(&json!({ "id": 3 })).unwrap()
+        );
+    }
+}
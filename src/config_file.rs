@@ -0,0 +1,321 @@
+// Named connection profiles in `~/.config/kass/config.toml` (or
+// `$XDG_CONFIG_HOME/kass/config.toml`, or the file of `KASS_CONFIG`),
+// selected with `--profile`:
+//
+//     [profiles.prod-eu]
+//     host = "cassandra.eu.example.com:9142"
+//     ssl = true
+//     username = "reporting"
+//     parallelism = 16
+//
+// The keys are the long names of the command line options, and the
+// values those of the options not given on the command line, `true`
+// for flags. Only the subset of TOML needed for that is read: tables,
+// strings, numbers, booleans and arrays on one line.
+//
+// Execution profiles bundle the options of how the queries run, like
+// those of the Java driver, selected with `--execution-profile` or the
+// `execution-profile` of a profile, and taking precedence over the
+// options of the profile:
+//
+//     [execution-profiles.bulk-read]
+//     consistency = "LOCAL_ONE"
+//     parallelism = 32
+//     request-timeout = 60
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use itertools::Itertools;
+
+use crate::errors::{AppError, AppResult};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+type Profiles = HashMap<String, Vec<(String, Value)>>;
+
+/// The options execution profiles can hold.
+pub const EXECUTION_OPTIONS: &[&str] = &[
+    "consistency",
+    "serial-consistency",
+    "fallback-consistency",
+    "parallelism",
+    "page-size",
+    "request-timeout",
+    "speculative-execution",
+    "reconnect-timeout",
+    "force-counter-retry",
+    "retries",
+    "retry-budget",
+    "max-pending-reads",
+    "rate-schedule",
+];
+
+#[derive(Debug, Default)]
+struct File {
+    profiles: Profiles,
+    execution_profiles: Profiles,
+}
+
+fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("KASS_CONFIG") {
+        return Some(path.into());
+    }
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("kass").join("config.toml"))
+}
+
+// A quoted string at the start of `s` and the rest of `s`
+fn string(s: &str) -> Option<(String, &str)> {
+    let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let mut out = String::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Some((out, &s[i + 2..])),
+            // Literal strings, in single quotes, have no escapes
+            '\\' if quote == '"' => match chars.next()?.1 {
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+// A scalar at the start of `s`, as a string, and the rest of `s`
+fn scalar(s: &str) -> Option<(String, &str)> {
+    if let Some(x) = string(s) {
+        return Some(x);
+    }
+    let end = s.find([',', ']', ' ', '#']).unwrap_or(s.len());
+    let value = &s[..end];
+    if value.is_empty()
+        || !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-._:".contains(c))
+    {
+        return None;
+    }
+    Some((value.to_string(), &s[end..]))
+}
+
+fn value(s: &str) -> Option<Value> {
+    let s = s.trim();
+    let (value, rest) = match s.strip_prefix('[') {
+        Some(mut rest) => {
+            let mut items = vec![];
+            loop {
+                rest = rest.trim_start();
+                if let Some(after) = rest.strip_prefix(']') {
+                    break (Value::List(items), after);
+                }
+                let (item, after) = scalar(rest)?;
+                items.push(item);
+                rest = after.trim_start();
+                rest = rest.strip_prefix(',').unwrap_or(rest);
+            }
+        }
+        None => match scalar(s)? {
+            (x, rest) if x == "true" && string(s).is_none() => (Value::Bool(true), rest),
+            (x, rest) if x == "false" && string(s).is_none() => (Value::Bool(false), rest),
+            (x, rest) => (Value::Str(x), rest),
+        },
+    };
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn parse(text: &str) -> Result<File, String> {
+    let mut file = File::default();
+    // The profiles of the current table and its name, if any
+    let mut current: Option<(bool, String)> = None;
+    for (i, line) in text.lines().enumerate() {
+        let invalid = || format!("invalid line {}: {}", i + 1, line.trim());
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let (header, _) = header.split_once(']').ok_or_else(invalid)?;
+            let header = header.trim();
+            let table = match header.strip_prefix("profiles.") {
+                Some(name) => Some((false, name)),
+                None => header.strip_prefix("execution-profiles.").map(|x| (true, x)),
+            };
+            current = table.map(|(execution, name)| {
+                let name = string(name).map_or(name.to_string(), |(x, _)| x);
+                let profiles = match execution {
+                    true => &mut file.execution_profiles,
+                    false => &mut file.profiles,
+                };
+                profiles.entry(name.clone()).or_default();
+                (execution, name)
+            });
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+        let value = self::value(value).ok_or_else(invalid)?;
+        if let Some((execution, name)) = &current {
+            let key = key.trim().trim_matches('"').to_string();
+            if *execution && !EXECUTION_OPTIONS.contains(&key.as_str()) {
+                return Err(format!(
+                    "{} is not an option of execution profiles, which hold: {}",
+                    key,
+                    EXECUTION_OPTIONS.join(", ")
+                ));
+            }
+            let profiles = match execution {
+                true => &mut file.execution_profiles,
+                false => &mut file.profiles,
+            };
+            profiles.entry(name.clone()).or_default().push((key, value));
+        }
+    }
+    Ok(file)
+}
+
+// The command line arguments of the options of `values` not `given`
+fn args(values: &[(String, Value)], given: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut args = vec![];
+    for (key, value) in values {
+        if given(key) || key == "profile" {
+            continue;
+        }
+        match value {
+            Value::Bool(true) => args.push(format!("--{}", key)),
+            Value::Bool(false) => {}
+            Value::Str(x) => args.push(format!("--{}={}", key, x)),
+            Value::List(xs) => args.extend(xs.iter().map(|x| format!("--{}={}", key, x))),
+        }
+    }
+    args
+}
+
+fn file() -> AppResult<File> {
+    let path = match path() {
+        Some(x) if x.exists() => x,
+        _ => return Ok(File::default()),
+    };
+    parse(&fs::read_to_string(&path)?)
+        .map_err(|err| AppError::new(format!("{}: {}", path.display(), err)))
+}
+
+/// Whether `profile` is in the configuration file.
+pub fn has_profile(profile: &str) -> bool {
+    file().is_ok_and(|x| x.profiles.contains_key(profile))
+}
+
+/// The command line arguments for the options of `profile` in the
+/// configuration file not `given` on the command line. There are none
+/// when the file or the profile doesn't exist, as the profile may only
+/// hold credentials saved with `kass login`.
+pub fn profile_args(profile: &str, given: impl Fn(&str) -> bool) -> AppResult<Vec<String>> {
+    let file = file()?;
+    let values = match file.profiles.get(profile) {
+        Some(x) => x,
+        None => return Ok(vec![]),
+    };
+    if values.iter().any(|(key, _)| key == "password") {
+        diag!(
+            "Warning: the profile {} holds a password in plain text, \
+             consider `kass config set-password {}` instead",
+            profile, profile
+        );
+    }
+    Ok(args(values, given))
+}
+
+/// The command line arguments for the options of the execution profile
+/// `name` not `given`.
+pub fn execution_profile_args(name: &str, given: impl Fn(&str) -> bool) -> AppResult<Vec<String>> {
+    let file = file()?;
+    match file.execution_profiles.get(name) {
+        Some(values) => Ok(args(values, given)),
+        None => Err(AppError::new(format!(
+            "Unknown execution profile {}, the configuration file has: {}",
+            name,
+            file.execution_profiles.keys().sorted().join(", ")
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+            # Connection profiles
+            [profiles.prod-eu]
+            host = "cassandra.eu:9142"  # behind the load balancer
+            ssl = true
+            parallelism = 16
+            mask = ["email", 'phone']
+
+            [profiles."dev"]
+            no-verify-hostname = false
+        "#;
+        let profiles = parse(text).unwrap().profiles;
+        let prod = &profiles["prod-eu"];
+        assert_eq!(
+            ("host".to_string(), Value::Str("cassandra.eu:9142".into())),
+            prod[0]
+        );
+        assert_eq!(
+            vec![
+                "--host=cassandra.eu:9142",
+                "--ssl",
+                "--mask=email",
+                "--mask=phone"
+            ],
+            args(prod, |key| key == "parallelism")
+        );
+        assert!(args(&profiles["dev"], |_| false).is_empty());
+
+        assert_eq!(
+            Err("invalid line 2: host = \"unterminated".to_string()),
+            parse("[profiles.x]\nhost = \"unterminated").map(|_| ())
+        );
+        assert!(parse("[profiles.x]\nhost = a b").is_err());
+    }
+
+    #[test]
+    fn test_execution_profiles() {
+        let text = r#"
+            [profiles.prod]
+            execution-profile = "bulk-read"
+            parallelism = 4
+
+            [execution-profiles.bulk-read]
+            consistency = "LOCAL_ONE"
+            parallelism = 32
+        "#;
+        let file = parse(text).unwrap();
+        assert_eq!(
+            vec!["--consistency=LOCAL_ONE", "--parallelism=32"],
+            args(&file.execution_profiles["bulk-read"], |_| false)
+        );
+        assert_eq!(
+            vec!["--execution-profile=bulk-read", "--parallelism=4"],
+            args(&file.profiles["prod"], |_| false)
+        );
+        assert!(parse("[execution-profiles.x]\nhost = \"h\"").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use cdrs::consistency::Consistency;
+
+use crate::errors::{AppError, AppResult};
+
+const LEVELS: &[Consistency] = &[
+    Consistency::Any,
+    Consistency::One,
+    Consistency::Two,
+    Consistency::Three,
+    Consistency::Quorum,
+    Consistency::All,
+    Consistency::LocalQuorum,
+    Consistency::EachQuorum,
+    Consistency::Serial,
+    Consistency::LocalSerial,
+    Consistency::LocalOne,
+];
+
+/// The CQL name of a consistency level, e.g. `LOCAL_QUORUM`.
+pub fn name(consistency: Consistency) -> &'static str {
+    match consistency {
+        Consistency::Any => "ANY",
+        Consistency::One => "ONE",
+        Consistency::Two => "TWO",
+        Consistency::Three => "THREE",
+        Consistency::Quorum => "QUORUM",
+        Consistency::All => "ALL",
+        Consistency::LocalQuorum => "LOCAL_QUORUM",
+        Consistency::EachQuorum => "EACH_QUORUM",
+        Consistency::Serial => "SERIAL",
+        Consistency::LocalSerial => "LOCAL_SERIAL",
+        Consistency::LocalOne => "LOCAL_ONE",
+        Consistency::Unknown => "UNKNOWN",
+    }
+}
+
+/// Parse a consistency level by its CQL name, case insensitive.
+pub fn parse(s: &str) -> AppResult<Consistency> {
+    LEVELS
+        .iter()
+        .find(|x| name(**x).eq_ignore_ascii_case(s))
+        .cloned()
+        .ok_or_else(|| AppError::new(format!("Invalid consistency level: {}", s)))
+}
+
+/// Parse a serial consistency level, `SERIAL` or `LOCAL_SERIAL`.
+pub fn parse_serial(s: &str) -> AppResult<Consistency> {
+    match parse(s) {
+        Ok(x @ (Consistency::Serial | Consistency::LocalSerial)) => Ok(x),
+        _ => Err(AppError::new(format!(
+            "Invalid serial consistency level: {}, expected SERIAL or LOCAL_SERIAL",
+            s
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_consistency() {
+        assert_eq!(Consistency::LocalQuorum, parse("LOCAL_QUORUM").unwrap());
+        assert_eq!(Consistency::One, parse("one").unwrap());
+        assert!(parse("UNKNOWN").is_err());
+        assert!(parse("TWENTY").is_err());
+    }
+
+    #[test]
+    fn test_parse_serial() {
+        assert_eq!(Consistency::LocalSerial, parse_serial("local_serial").unwrap());
+        assert_eq!(Consistency::Serial, parse_serial("SERIAL").unwrap());
+        assert!(parse_serial("QUORUM").is_err());
+    }
+}
@@ -0,0 +1,201 @@
+// The signals, the control socket and `kass ctl` are Unix only, the
+// runs of `kass serve` being paused and cancelled over its API.
+#[cfg(unix)]
+use std::env;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::Arc;
+use std::sync::{Condvar, Mutex};
+#[cfg(unix)]
+use std::thread;
+
+#[cfg(unix)]
+use signal_hook::consts::{SIGUSR1, SIGUSR2};
+#[cfg(unix)]
+use signal_hook::iterator::Signals;
+
+use crate::errors::{AppError, AppResult};
+
+/// Lets operators pause a run: while paused, workers finish their
+/// in-flight queries but don't start new ones. A cancelled run doesn't
+/// start any new query either and fails instead.
+#[derive(Debug, Default)]
+pub struct PauseControl {
+    paused: Mutex<bool>,
+    cancelled: AtomicBool,
+    resumed: Condvar,
+}
+
+impl PauseControl {
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    pub fn cancel(&self) {
+        let _paused = self.paused.lock().unwrap();
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.resumed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn wait_while_paused(&self) -> AppResult<()> {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused && !self.is_cancelled() {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+        if self.is_cancelled() {
+            return Err(AppError::new("Run cancelled"));
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn apply(&self, command: &str) -> AppResult<()> {
+        match command {
+            "pause" => self.pause(),
+            "resume" => self.resume(),
+            _ => return Err(AppError::new(format!("Unknown command: {}", command))),
+        }
+        diag!("Run {}d", command);
+        Ok(())
+    }
+}
+
+/// Pause on `SIGUSR1` and resume on `SIGUSR2`.
+#[cfg(unix)]
+pub fn listen_signals(control: Arc<PauseControl>) -> AppResult<()> {
+    let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let command = if signal == SIGUSR1 { "pause" } else { "resume" };
+            control.apply(command).unwrap();
+        }
+    });
+    Ok(())
+}
+
+/// Control socket of the current process, removed when dropped.
+#[cfg(unix)]
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn socket_path(pid: u32) -> PathBuf {
+    env::temp_dir().join(format!("kass-{}.sock", pid))
+}
+
+/// Accept `pause`/`resume` commands sent by `kass ctl` on a Unix
+/// socket named after the pid of the current process.
+#[cfg(unix)]
+pub fn listen_socket(control: Arc<PauseControl>) -> AppResult<ControlSocket> {
+    let path = socket_path(process::id());
+    let listener = UnixListener::bind(&path)?;
+    diag!("Listening for control commands on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            if reader.read_line(&mut line).is_ok() {
+                let reply = match control.apply(line.trim()) {
+                    Ok(_) => "ok".to_string(),
+                    Err(err) => err.to_string(),
+                };
+                let _ = writeln!(reader.get_mut(), "{}", reply);
+            }
+        }
+    });
+    Ok(ControlSocket { path })
+}
+
+/// `kass ctl <command> <pid>`: send a command to a running kass.
+#[cfg(unix)]
+pub fn send(command: &str, pid: u32) -> AppResult<()> {
+    let mut stream = UnixStream::connect(socket_path(pid)).map_err(|err| {
+        AppError::new(format!(
+            "Unable to connect to kass process {}, was it started with --control-socket? ({})",
+            pid, err
+        ))
+    })?;
+    writeln!(stream, "{}", command)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    match reply.trim() {
+        "ok" => Ok(()),
+        err => Err(AppError::new(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pause_resume() {
+        let control = Arc::new(PauseControl::default());
+        control.pause();
+
+        let worker = control.clone();
+        let handle = thread::spawn(move || worker.wait_while_paused());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+
+        control.resume();
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_cancel_while_paused() {
+        let control = Arc::new(PauseControl::default());
+        control.pause();
+
+        let worker = control.clone();
+        let handle = thread::spawn(move || worker.wait_while_paused());
+        control.cancel();
+        assert!(handle.join().unwrap().is_err());
+        assert!(control.wait_while_paused().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_control_socket() {
+        let control = Arc::new(PauseControl::default());
+        let _socket = listen_socket(control.clone()).unwrap();
+
+        send("pause", process::id()).unwrap();
+        assert!(*control.paused.lock().unwrap());
+        send("resume", process::id()).unwrap();
+        assert!(!*control.paused.lock().unwrap());
+        assert!(send("stop", process::id()).is_err());
+    }
+}
@@ -1,129 +1,1521 @@
-use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use ansi_term::{Colour, Style};
-use cdrs::authenticators::NoneAuthenticator;
-use cdrs::cluster::session::{new as new_session, Session};
-use cdrs::cluster::{ClusterTcpConfig, NodeTcpConfigBuilder, TcpConnectionPool};
-use cdrs::frame::frame_response::ResponseBody;
-use cdrs::frame::frame_result::ResResultBody;
-use cdrs::frame::frame_result::RowsMetadata;
-use cdrs::frame::Frame;
-use cdrs::load_balancing::RoundRobinSync;
-use cdrs::query::*;
+use cdrs::compression::Compression;
+use cdrs::consistency::Consistency;
+use cdrs::frame::frame_result::{ColTypeOption, RowsMetadata};
 use cdrs::types::CBytes;
 use clap::ArgMatches;
 use colored_json::{ColorMode, ColoredFormatter, Output, Styler};
 use serde_json::ser::{CompactFormatter, Formatter, PrettyFormatter};
-use serde_json::{Map, Value as JsonValue};
+use serde_json::{json, Map, Value as JsonValue};
 
-use crate::errors::AppResult;
+use crate::as_of;
+use crate::astra::Bundle;
+use crate::auth::{self, AuthOptions, KassAuthenticator};
+use crate::backend::{self, Backend, Cdrs, Params, PoolSize, Prepared, Response};
+use crate::canonical;
+use crate::compare::Comparison;
+use crate::config_file;
+use crate::consistency;
+use crate::control::PauseControl;
+use crate::counter;
+use crate::credentials;
+use crate::csv;
+use crate::dc::{self, HostFilter};
+use crate::ddl;
+use crate::dead_letter::DeadLetter;
+use crate::errors::{AppError, AppResult};
+use crate::envelope::{Envelope, ResultEnvelope};
+use crate::escape;
+use crate::impact::{self, ImpactGuard};
+use crate::incremental::{self, Incremental};
+use crate::index;
 use crate::iterator_consumer::IteratorConsumer;
-use crate::params;
-use crate::types::ColValue;
+use crate::latest::Latest;
+use crate::limit::RowLimit;
+use crate::mask::Mask;
+use crate::memory::MemoryBudget;
+use crate::metrics::Metrics;
+use crate::mock::Mock;
+use crate::params::{self, ParamSet, Redaction, Values};
+use crate::plugin::{self, Plugins, Transform};
+use crate::proxy::Forwarders;
+use crate::random::RandomRows;
+use crate::rate::{RateLimiter, RateSchedule};
+use crate::reconnect::Reconnecting;
+use crate::record::Recorder;
+use crate::retry::{self, RetryBudget};
+use crate::sample::Sample;
+use crate::schema;
+use crate::scylla::{self, Ring, Sharded};
+use crate::sigv4::SigV4;
+use crate::sink::{FileSink, PartitionedSink, Sink, Stdout};
+use crate::socks::Socks5;
+use crate::statics::{Partitions, Statics, STATIC_RECORD_KEY};
+use crate::sticky::Sticky;
+use crate::stats::RunStats;
+use crate::tls::Tls;
+use crate::types::{ColValue, DecodeOptions, InvalidDates, MapMode, OnDecodeError, TimePrecision};
+use crate::validate::Validator;
+use crate::usage::Usage;
+use crate::warnings::Warnings;
 
-pub type CurrentSession = Session<RoundRobinSync<TcpConnectionPool<NoneAuthenticator>>>;
+// The key of the position of the query of the rows of a run of several
+const QUERY_TAG_KEY: &str = "_query";
 
+// The number of rows of the pages of the results unless --page-size,
+// that of the drivers
+const PAGE_SIZE: i32 = 5000;
+
+pub type CurrentSession = Box<dyn Backend>;
+
+// State shared by the workers of a parallel run
+struct RunState {
+    stats: RunStats,
+    control: Arc<PauseControl>,
+    redaction: Redaction,
+    // The number of queries that hit --request-timeout
+    timeouts: AtomicUsize,
+    // The first error of a worker other than reaching --limit, which
+    // that of the workers stopped by it would hide
+    failure: Mutex<Option<AppError>>,
+    // Lowers the queries in flight with --max-pending-reads
+    impact: Option<Arc<ImpactGuard>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Clone)]
+enum BackendOption {
+    Cdrs { record: Option<String> },
+    // The fixture file of --backend mock
+    Mock(String),
+    Replay(String),
+}
+
+#[derive(Clone)]
 pub struct Config {
     host: String,
+    auth: KassAuthenticator,
+    tls: Option<Tls>,
+    bundle: Option<Arc<Bundle>>,
+    // The compression asked for in the `STARTUP`, by the forwarders
+    compression: Compression,
+    forwarders: Option<Arc<Forwarders>>,
+    // Only the nodes of this data center are queried when set
+    local_dc: Option<String>,
+    // Whether the other nodes of the cluster are queried as well as `host`
+    discover_peers: bool,
+    // Whether the shards of ScyllaDB nodes get their own connections
+    shard_aware: bool,
+    // Whether the statements of a parameter set all go to the same node
+    sticky_coordinator: bool,
+    host_filter: HostFilter,
+    backend: BackendOption,
+    consistency: Consistency,
+    serial_consistency: Option<Consistency>,
+    // Of the parameter sets that are unavailable at `consistency`
+    fallback_consistency: Option<Consistency>,
     color: ColorMode,
     parallelism: usize,
+    // Max parallelism of the queries going through a secondary index
+    index_parallelism: usize,
+    page_size: i32,
+    pool: PoolSize,
+    // Parameters that don't parse as the range they look like are
+    // literal strings
+    lenient_params: bool,
     pretty: bool,
+    max_result_bytes: Option<usize>,
+    truncate_oversized_results: bool,
+    // The results received but not written out yet, within
+    // --max-memory-mb
+    memory: Arc<MemoryBudget>,
+    rate: Option<Arc<RateLimiter>>,
+    sparse: bool,
+    decode_opts: DecodeOptions,
+    sort_keys: bool,
+    canonical: bool,
+    format: OutputFormat,
+    typed_header: bool,
+    // Keeps the rows on one line whatever the text columns hold
+    escape_control_chars: bool,
+    emit_ddl: Option<ddl::Target>,
+    ddl_file: Option<String>,
+    // Makes sure the CSV header and DDL are written once per run
+    first_result: Arc<Once>,
+    control_socket: bool,
+    schema_agreement_timeout: Duration,
+    request_timeout: Option<Duration>,
+    // After which a prepared statement is sent to another node as well
+    speculative_execution: Option<Duration>,
+    // How long statements are retried for when the cluster can't be
+    // reached
+    reconnect_timeout: Duration,
+    // Whether counter updates are retried like the other statements
+    force_counter_retry: bool,
+    // The retries of the statements failing with a transient error
+    retries: u32,
+    retry_budget: Option<Arc<RetryBudget>>,
+    // The pending reads above which fewer queries are run at once
+    max_pending_reads: Option<u64>,
+    impact_metrics_url: Option<String>,
+    // The warnings sent by the cluster, up to --max-warnings
+    warnings: Arc<Warnings>,
+    // The resources used, reported with --stats
+    usage: Option<Arc<Usage>>,
+    stats_interval: Option<Duration>,
+    // Cutoff of --as-of, in microseconds
+    as_of: Option<i64>,
+    latest: Option<Latest>,
+    incremental: Option<Arc<Incremental>>,
+    sample: Option<Arc<Sample>>,
+    // Static columns written once per partition with --static-once
+    statics: Option<Arc<Statics>>,
+    // The position of the query among those of a run of several, added
+    // to its rows
+    query_tag: Option<usize>,
+    envelope: Option<Arc<Envelope>>,
+    limit: Option<Arc<RowLimit>>,
+    // The sample of --random, written once the run is done
+    random: Option<Arc<RandomRows>>,
+    mask: Option<Arc<Mask>>,
+    validator: Option<Arc<Validator>>,
+    comparison: Option<Arc<Comparison>>,
+    dead_letter: Option<Arc<DeadLetter>>,
+    control: Arc<PauseControl>,
+    redact_params: Vec<String>,
+    metrics: Arc<Metrics>,
+    // The --transform plugins and --transform-wasm modules, applied in
+    // turn
+    transforms: Vec<Arc<Transform>>,
+    sink: Arc<dyn Sink>,
 }
 
 impl Config {
     pub fn from_matches(matches: &ArgMatches) -> AppResult<Self> {
-        let mut host = matches
-            .value_of("host")
-            .unwrap_or("localhost:9042")
-            .to_string();
+        let host = dc::host_port(
+            matches.value_of("host").unwrap_or("localhost"),
+            matches.value_of("port"),
+        )?;
+
+        let mut auth_opts = AuthOptions {
+            username: matches.value_of("username").map(str::to_string),
+            password: matches.value_of("password").map(str::to_string),
+            token: matches.value_of("auth-token").map(str::to_string),
+            class_name: matches.value_of("auth-class").map(str::to_string),
+        };
+        let mut auth_name = matches.value_of("auth").unwrap_or("none");
 
-        if host.find(':').is_none() {
-            host.push_str(":9042");
+        // Credentials of a credentials file or saved with `kass login`
+        // are used unless they are given explicitly on the command line
+        if let Some(path) = matches.value_of("credentials-file") {
+            if auth_opts.username.is_none() {
+                if let Some((username, password)) = credentials::from_file(path, &host)? {
+                    auth_opts.username = Some(username);
+                    auth_opts.password = auth_opts.password.or(password);
+                }
+            }
         }
+        if let Some(profile) = matches.value_of("profile") {
+            if auth_opts.username.is_none() || auth_opts.password.is_none() {
+                // Profiles of the configuration file may not have any
+                // credentials, nor the OS a keychain
+                let saved = match credentials::load(profile) {
+                    Err(_) if config_file::has_profile(profile) => None,
+                    x => x?,
+                };
+                let login =
+                    saved.and_then(|x| credentials::saved_login(x, auth_opts.username.as_deref()));
+                if let Some((username, password)) = login {
+                    auth_opts.username = Some(username);
+                    auth_opts.password = auth_opts.password.or(Some(password));
+                    if matches.occurrences_of("auth") == 0 {
+                        auth_name = "password";
+                    }
+                }
+            }
+        }
+        // `--username` alone is enough for password authentication, the
+        // password is prompted for when omitted
+        if auth_opts.username.is_some() && matches.occurrences_of("auth") == 0 {
+            auth_name = "password";
+        }
+        if auth_name == "password" && auth_opts.username.is_some() && auth_opts.password.is_none() {
+            auth_opts.password = Some(credentials::prompt_password("Password: ")?);
+        }
+        let auth = auth::authenticator(auth_name, &auth_opts)?;
+        let tls = if matches.is_present("ssl") {
+            Some(Tls {
+                ca_cert: matches.value_of("ca-cert").map(String::from),
+                verify_hostname: !matches.is_present("no-verify-hostname"),
+                client_cert: matches.value_of("client-cert").map(String::from),
+                client_key: matches.value_of("client-key").map(String::from),
+            })
+        } else {
+            None
+        };
+        let compression = match matches.value_of("compression") {
+            Some("lz4") => Compression::Lz4,
+            Some("snappy") => Compression::Snappy,
+            _ => Compression::None,
+        };
+        let sigv4 = if auth_name == "sigv4" {
+            Some(SigV4::new(&host)?)
+        } else {
+            None
+        };
+        let socks = match (matches.value_of("proxy"), matches.value_of("ssh-tunnel")) {
+            (Some(url), _) => Some(Socks5::parse(url)?),
+            (None, Some(destination)) => Some(Socks5::ssh_tunnel(destination)?),
+            (None, None) => None,
+        };
+        let forwarders = if sigv4.is_some() || compression != Compression::None || socks.is_some() {
+            // Amazon Keyspaces is only reachable over TLS
+            let tls = match (&tls, &sigv4) {
+                (None, Some(_)) => Some(Tls {
+                    ca_cert: None,
+                    verify_hostname: true,
+                    client_cert: None,
+                    client_key: None,
+                }),
+                _ => tls.clone(),
+            };
+            let connector = tls.map(|x| x.connector()).transpose()?;
+            Some(Arc::new(Forwarders::new(
+                connector,
+                compression,
+                sigv4,
+                socks,
+            )))
+        } else {
+            None
+        };
 
+        let output = matches.value_of("output");
+        let plugins = Plugins::parse(matches.values_of("plugin").into_iter().flatten())?;
         let color = match matches.value_of("color") {
             Some("off") => ColorMode::Off,
             Some("on") => ColorMode::On,
+            _ if output.is_some()
+                || matches.is_present("output-template")
+                || matches.is_present("sink") =>
+            {
+                ColorMode::Off
+            }
             _ => ColorMode::Auto(Output::StdOut),
         };
         let parallelism = match matches.value_of("parallelism") {
             Some(x) => x.parse().unwrap_or(5),
             None => 5,
         };
+        if parallelism == 0 {
+            return Err(AppError::new("--parallelism must be at least 1"));
+        }
+        // Enough connections for every worker to have its own, as
+        // they would otherwise wait for each other
+        let pool = PoolSize {
+            min_idle: matches.value_of("pool-min").map(str::parse).transpose()?,
+            max: match matches.value_of("pool-max") {
+                Some(x) => x.parse()?,
+                None => (parallelism as u32).max(10),
+            },
+        };
+        if pool.max == 0 || pool.min_idle.is_some_and(|x| x > pool.max) {
+            return Err(AppError::new(
+                "--pool-max must be at least 1 and at least --pool-min",
+            ));
+        }
+        let index_parallelism = match matches.value_of("index-parallelism") {
+            Some(x) => x.parse()?,
+            None => 2,
+        };
+        if index_parallelism == 0 {
+            return Err(AppError::new("--index-parallelism must be at least 1"));
+        }
+        let page_size = match matches.value_of("page-size") {
+            Some(x) => x.parse()?,
+            None => PAGE_SIZE,
+        };
+        if page_size <= 0 {
+            return Err(AppError::new("--page-size must be at least 1"));
+        }
         let pretty = matches.is_present("pretty");
+        let stats_interval = matches
+            .value_of("stats-interval")
+            .map(str::parse::<u64>)
+            .transpose()?;
+        if stats_interval == Some(0) {
+            return Err(AppError::new("--stats-interval must be at least 1"));
+        }
+        let stats_interval = stats_interval.map(Duration::from_secs);
+        let max_result_bytes = matches
+            .value_of("max-result-bytes-per-query")
+            .map(str::parse)
+            .transpose()?;
+        let truncate_oversized_results = matches.is_present("truncate-oversized-results");
+        let memory = Arc::new(MemoryBudget::new(
+            matches
+                .value_of("max-memory-mb")
+                .map(str::parse::<usize>)
+                .transpose()?
+                .map(|x| x * 1024 * 1024),
+        ));
+        let sparse = matches.is_present("sparse");
+        let map_mode = match matches.value_of("map-mode") {
+            Some("entries") => MapMode::Entries,
+            _ => MapMode::Object,
+        };
+        let invalid_dates = match matches.value_of("invalid-dates") {
+            Some("error") => InvalidDates::Error,
+            _ => InvalidDates::Raw,
+        };
+        let decode_opts = DecodeOptions {
+            map_mode,
+            invalid_dates,
+            time_precision: matches
+                .value_of("time-precision")
+                .and_then(TimePrecision::parse),
+            on_decode_error: match matches.value_of("on-decode-error") {
+                Some("null") => OnDecodeError::Null,
+                Some("raw-hex") => OnDecodeError::RawHex,
+                _ => OnDecodeError::Fail,
+            },
+            empty_as_null: sparse,
+        };
+        let sort_keys = matches.is_present("sort-keys");
+        let canonical = matches.is_present("canonical");
+        let format = match matches.value_of("format") {
+            Some("csv") => OutputFormat::Csv,
+            _ => OutputFormat::Json,
+        };
+        if matches.values_of("queries").map_or(0, |xs| xs.len()) > 1
+            && format != OutputFormat::Json
+        {
+            return Err(AppError::new("Several --query require JSON output"));
+        }
+        let limit: Option<usize> = matches.value_of("row-limit").map(str::parse).transpose()?;
+        if limit == Some(0) {
+            return Err(AppError::new("--limit must be at least 1"));
+        }
+        let random: Option<usize> = matches.value_of("random").map(str::parse).transpose()?;
+        if random == Some(0) {
+            return Err(AppError::new("--random must be at least 1"));
+        }
+        if matches.is_present("envelope") && format != OutputFormat::Json {
+            return Err(AppError::new("--envelope requires JSON output"));
+        }
+        let static_record = matches.is_present("static-record");
+        if static_record && format != OutputFormat::Json {
+            return Err(AppError::new("--static-record requires JSON output"));
+        }
+        let typed_header = matches.is_present("typed-header");
+        if typed_header && format != OutputFormat::Csv {
+            return Err(AppError::new("--typed-header requires --format csv"));
+        }
+        let control_socket = matches.is_present("control-socket");
+        let schema_agreement_timeout = match matches.value_of("schema-agreement-timeout") {
+            Some(x) => Duration::from_secs(x.parse()?),
+            None => Duration::from_secs(30),
+        };
+
+        let backend = match (matches.value_of("backend"), matches.value_of("replay")) {
+            (_, Some(path)) => BackendOption::Replay(path.to_string()),
+            (Some("mock"), None) => BackendOption::Mock(
+                matches
+                    .value_of("fixture")
+                    .ok_or_else(|| AppError::new("--backend mock requires --fixture"))?
+                    .to_string(),
+            ),
+            _ => BackendOption::Cdrs {
+                record: matches.value_of("record").map(String::from),
+            },
+        };
 
         Ok(Self {
             host,
+            auth,
+            tls,
+            bundle: matches
+                .value_of("secure-connect-bundle")
+                .map(|path| Bundle::open(path, compression))
+                .transpose()?
+                .map(Arc::new),
+            compression,
+            forwarders,
+            local_dc: matches.value_of("local-dc").map(String::from),
+            discover_peers: !matches.is_present("no-peer-discovery"),
+            shard_aware: !matches.is_present("no-shard-awareness"),
+            sticky_coordinator: matches.is_present("sticky-coordinator"),
+            host_filter: HostFilter {
+                only: matches
+                    .values_of("only-host")
+                    .map(|xs| xs.map(String::from).collect())
+                    .unwrap_or_default(),
+                exclude: matches
+                    .values_of("exclude-host")
+                    .map(|xs| xs.map(String::from).collect())
+                    .unwrap_or_default(),
+            },
+            backend,
+            consistency: matches
+                .value_of("consistency")
+                .map(consistency::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            serial_consistency: matches
+                .value_of("serial-consistency")
+                .map(consistency::parse_serial)
+                .transpose()?,
+            fallback_consistency: matches
+                .value_of("fallback-consistency")
+                .map(consistency::parse)
+                .transpose()?,
             color,
             parallelism,
+            index_parallelism,
+            page_size,
+            pool,
+            lenient_params: matches.is_present("lenient-params"),
             pretty,
+            max_result_bytes,
+            truncate_oversized_results,
+            memory: memory.clone(),
+            rate: matches
+                .value_of("rate-schedule")
+                .map(RateSchedule::parse)
+                .transpose()?
+                .map(|x| Arc::new(RateLimiter::new(x))),
+            sparse,
+            decode_opts,
+            sort_keys,
+            canonical,
+            format,
+            typed_header,
+            escape_control_chars: matches.is_present("escape-control-chars"),
+            emit_ddl: matches
+                .value_of("emit-ddl")
+                .map(ddl::Target::parse)
+                .transpose()?,
+            ddl_file: matches.value_of("ddl-file").map(String::from),
+            first_result: Arc::new(Once::new()),
+            control_socket,
+            schema_agreement_timeout,
+            request_timeout: matches
+                .value_of("request-timeout")
+                .map(|x| x.parse().map(Duration::from_secs))
+                .transpose()?,
+            speculative_execution: matches
+                .value_of("speculative-execution")
+                .map(|x| x.parse().map(Duration::from_millis))
+                .transpose()?,
+            max_pending_reads: matches
+                .value_of("max-pending-reads")
+                .map(str::parse)
+                .transpose()?,
+            impact_metrics_url: matches.value_of("impact-metrics-url").map(String::from),
+            warnings: Arc::new(Warnings::new(
+                matches
+                    .value_of("max-warnings")
+                    .map(str::parse)
+                    .transpose()?,
+            )),
+            usage: (matches.is_present("stats") || stats_interval.is_some())
+                .then(|| Arc::new(Usage::new(memory))),
+            stats_interval,
+            reconnect_timeout: Duration::from_secs(
+                matches
+                    .value_of("reconnect-timeout")
+                    .unwrap_or("60")
+                    .parse()?,
+            ),
+            force_counter_retry: matches.is_present("force-counter-retry"),
+            retries: matches
+                .value_of("retries")
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or(0),
+            retry_budget: matches
+                .value_of("retry-budget")
+                .map(RetryBudget::parse)
+                .transpose()?
+                .map(Arc::new),
+            as_of: matches
+                .value_of("as-of")
+                .map(as_of::parse_timestamp)
+                .transpose()?,
+            latest: match (
+                matches.value_of("latest-per-partition"),
+                matches.value_of("order-col"),
+            ) {
+                (Some(rows), Some(column)) => match rows.parse()? {
+                    0 => return Err(AppError::new("--latest-per-partition must be at least 1")),
+                    rows => Some(Latest {
+                        rows,
+                        column: column.to_string(),
+                    }),
+                },
+                _ => None,
+            },
+            incremental: match (
+                matches.is_present("incremental"),
+                matches.value_of("cursor-column"),
+                matches.value_of("state-file"),
+            ) {
+                (true, Some(column), Some(path)) => {
+                    Some(Arc::new(Incremental::load(column, path)?))
+                }
+                _ => None,
+            },
+            sample: matches
+                .value_of("row-sample")
+                .map(Sample::parse)
+                .transpose()?
+                .map(Arc::new),
+            statics: (matches.is_present("static-once") || static_record)
+                .then(|| Arc::new(Statics::new(static_record))),
+            query_tag: None,
+            envelope: matches
+                .is_present("envelope")
+                .then(|| Arc::new(Envelope::default())),
+            limit: limit.map(|x| Arc::new(RowLimit::new(x))),
+            random: random.map(|x| Arc::new(RandomRows::new(x, parallelism))),
+            mask: matches
+                .values_of("mask")
+                .map(|xs| Mask::parse(xs, matches.value_of("mask-key")))
+                .transpose()?
+                .map(Arc::new),
+            validator: matches
+                .value_of("validate-schema")
+                .map(|path| Validator::open(path, matches.value_of("validation-errors")))
+                .transpose()?
+                .map(Arc::new),
+            comparison: matches
+                .value_of("compare-to")
+                .map(|path| {
+                    let key = matches
+                        .values_of("key")
+                        .map_or(vec![], |xs| xs.map(String::from).collect());
+                    Comparison::load(path, key)
+                })
+                .transpose()?
+                .map(Arc::new),
+            dead_letter: matches
+                .value_of("dead-letter")
+                .map(DeadLetter::create)
+                .transpose()?
+                .map(Arc::new),
+            control: Arc::new(PauseControl::default()),
+            redact_params: matches
+                .values_of("redact-params")
+                .map(|xs| xs.map(String::from).collect())
+                .unwrap_or_default(),
+            metrics: Arc::new(Metrics::default()),
+            transforms: matches
+                .values_of("transform")
+                .into_iter()
+                .flatten()
+                .map(|spec| plugins.transform(spec))
+                .chain(
+                    matches
+                        .values_of("transform-wasm")
+                        .into_iter()
+                        .flatten()
+                        .map(|module| {
+                            plugin::wasm_transform(
+                                module,
+                                matches.value_of("wasm-runtime").unwrap_or("wasmtime"),
+                            )
+                        }),
+                )
+                .map(|x| x.map(Arc::new))
+                .collect::<AppResult<_>>()?,
+            sink: match (output, matches.value_of("output-template")) {
+                (Some(path), _) => {
+                    Arc::new(FileSink::create(path, matches.value_of("encrypt-to"))?)
+                }
+                (None, Some(template)) => Arc::new(PartitionedSink::new(
+                    template,
+                    matches
+                        .values_of("partition-output-by")
+                        .map_or(vec![], |xs| xs.map(String::from).collect()),
+                    matches
+                        .value_of("max-open-files")
+                        .unwrap_or_default()
+                        .parse()?,
+                )?),
+                (None, None) => match matches.value_of("sink") {
+                    Some(spec) => Arc::new(plugins.sink(spec)?),
+                    None => Arc::new(Stdout),
+                },
+            },
         })
     }
+
+    // Copy of this config tagging the rows with the position of their
+    // query, when there are several queries
+    fn tagged(&self, position: usize, queries: usize) -> Self {
+        Config {
+            query_tag: (queries > 1).then_some(position),
+            ..self.clone()
+        }
+    }
+
+    /// Copy of this config for a run of its own, e.g. of `kass serve`,
+    /// writing uncolored JSON rows to `sink`. The run starts from its
+    /// own --limit, --random sample, warnings and --stats.
+    pub fn with_sink(&self, sink: Arc<dyn Sink>) -> Self {
+        Config {
+            color: ColorMode::Off,
+            format: OutputFormat::Json,
+            first_result: Arc::new(Once::new()),
+            sink,
+            warnings: Arc::new(self.warnings.fresh()),
+            usage: self
+                .usage
+                .as_ref()
+                .map(|_| Arc::new(Usage::new(self.memory.clone()))),
+            limit: self.limit.as_ref().map(|x| Arc::new(x.fresh())),
+            random: self.random.as_ref().map(|x| Arc::new(x.fresh())),
+            ..self.clone()
+        }
+    }
+
+    pub fn connect(&self) -> AppResult<CurrentSession> {
+        let cdrs = |hosts: &[String]| -> AppResult<Cdrs> {
+            Ok(match (&self.forwarders, &self.tls) {
+                // The forwarders take care of TLS, compression,
+                // authentication and proxies
+                (Some(forwarders), _) => Cdrs::connect(
+                    &forwarders.nodes(hosts)?,
+                    self.auth.clone(),
+                    self.pool,
+                    self.compression,
+                )?,
+                (None, Some(tls)) => {
+                    Cdrs::connect_ssl(hosts, self.auth.clone(), self.pool, tls.connector()?)?
+                }
+                (None, None) => {
+                    Cdrs::connect(hosts, self.auth.clone(), self.pool, Compression::None)?
+                }
+            })
+        };
+        Ok(match &self.backend {
+            BackendOption::Cdrs { record } => {
+                let mut session = match &self.bundle {
+                    // The forwarders to the nodes take care of TLS
+                    Some(bundle) => Cdrs::connect(
+                        &bundle.nodes()?,
+                        self.auth.clone(),
+                        self.pool,
+                        self.compression,
+                    )?,
+                    None => cdrs(std::slice::from_ref(&self.host))?,
+                };
+                let mut nodes = vec![self.host.clone()];
+                if self.local_dc.is_some() || !self.host_filter.is_empty() {
+                    nodes = dc::nodes(&session, &self.host, self.local_dc.as_deref(), self)?;
+                    nodes = self.host_filter.apply(nodes)?;
+                    session = cdrs(&nodes)?;
+                } else if self.discover_peers && self.bundle.is_none() {
+                    (session, nodes) = self.with_peers(session, &cdrs);
+                }
+                // The shard-aware port is plain text, and the recording
+                // of a single session. The statements of a parameter set
+                // stick to a node rather than going to the shards.
+                let ring = if self.shard_aware
+                    && !self.sticky_coordinator
+                    && self.forwarders.is_none()
+                    && self.tls.is_none()
+                    && self.bundle.is_none()
+                    && record.is_none()
+                {
+                    self.shards(&session, &nodes)
+                } else {
+                    None
+                };
+                if let Some(path) = record {
+                    session = session.with_recorder(Recorder::open(path)?);
+                }
+                // A session to each node, the recording being that of a
+                // single session
+                if self.sticky_coordinator && record.is_none() && nodes.len() > 1 {
+                    if let Some(sticky) = self.sticky(&nodes, &cdrs) {
+                        return Ok(self.reconnecting(sticky));
+                    }
+                }
+                session = session.with_warnings(self.warnings.clone());
+                match ring {
+                    Some(ring) => {
+                        self.reconnecting(Sharded::new(ring, Box::new(session), self.clone()))
+                    }
+                    None => self.reconnecting(session),
+                }
+            }
+            BackendOption::Mock(path) => Box::new(Mock::load(path)?),
+            BackendOption::Replay(path) => Box::new(Mock::replay(path)?),
+        })
+    }
+
+    fn reconnecting<B: Backend + 'static>(&self, session: B) -> CurrentSession {
+        if self.reconnect_timeout.is_zero() {
+            Box::new(session)
+        } else {
+            Box::new(Reconnecting::new(session, self.reconnect_timeout))
+        }
+    }
+
+    // A session to all the nodes discovered through `session` to `host`,
+    // along with them, or `session` itself when there are no others or
+    // they can't be reached, e.g. their addresses being those of a
+    // private network
+    fn with_peers(
+        &self,
+        session: Cdrs,
+        cdrs: &dyn Fn(&[String]) -> AppResult<Cdrs>,
+    ) -> (Cdrs, Vec<String>) {
+        let peers = dc::nodes(&session, &self.host, None, self).and_then(|nodes| {
+            if nodes.len() > 1 {
+                cdrs(&nodes).map(|x| Some((x, nodes)))
+            } else {
+                Ok(None)
+            }
+        });
+        match peers {
+            Ok(Some(x)) => x,
+            Ok(None) => (session, vec![self.host.clone()]),
+            Err(err) => {
+                diag!(
+                    "Unable to connect to the other nodes of the cluster, querying {} only: {}",
+                    self.host, err
+                );
+                (session, vec![self.host.clone()])
+            }
+        }
+    }
+
+    // Sessions to each of `nodes`, for the statements of a parameter set
+    // to go to the same one
+    fn sticky(
+        &self,
+        nodes: &[String],
+        cdrs: &dyn Fn(&[String]) -> AppResult<Cdrs>,
+    ) -> Option<Sticky<Cdrs>> {
+        let sessions = nodes
+            .iter()
+            .map(|node| {
+                cdrs(std::slice::from_ref(node)).map(|x| x.with_warnings(self.warnings.clone()))
+            })
+            .collect::<AppResult<Vec<_>>>();
+        match sessions {
+            Ok(sessions) => Some(Sticky::new(sessions)),
+            Err(err) => {
+                diag!(
+                    "Unable to connect to each node of the cluster, ignoring --sticky-coordinator: {}",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    // Sessions to each shard of `nodes` when the cluster is ScyllaDB,
+    // sharing the connections of the pool
+    fn shards(&self, session: &Cdrs, nodes: &[String]) -> Option<Ring<Cdrs>> {
+        let ring = scylla::shards(session, &self.host, nodes, self).and_then(|ring| {
+            let ring = match ring {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+            let max = (self.pool.max / ring.sessions().count() as u32).max(1);
+            let pool = PoolSize {
+                min_idle: self.pool.min_idle.map(|x| x.min(max)),
+                max,
+            };
+            let ring = ring.try_map(|address| {
+                Cdrs::connect(&[address], self.auth.clone(), pool, Compression::None)
+                    .map(|x| x.with_warnings(self.warnings.clone()))
+            })?;
+            Ok(Some(ring))
+        });
+        ring.unwrap_or_else(|err| {
+            diag!(
+                "Unable to connect to the shards of the nodes, ignoring the sharding of ScyllaDB: {}",
+                err
+            );
+            None
+        })
+    }
+
+    pub fn schema_agreement_timeout(&self) -> Duration {
+        self.schema_agreement_timeout
+    }
+
+    /// The consistency level of the run.
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+
+    /// The parameters of a statement bound to `values`, at the
+    /// consistency levels of the run.
+    pub fn params(&self, values: Values) -> Params {
+        Params {
+            serial_consistency: self.serial_consistency,
+            ..Params::new(values, self.consistency)
+        }
+    }
+
+    /// Wait for the `--rate-schedule` to allow another query.
+    pub fn throttle(&self) {
+        if let Some(rate) = &self.rate {
+            rate.acquire();
+        }
+    }
+
+    // Count the bytes read by the query just run for --stats
+    fn record_read(&self) {
+        if let Some(usage) = &self.usage {
+            usage.record_read();
+        }
+    }
+
+    pub fn control(&self) -> Arc<PauseControl> {
+        self.control.clone()
+    }
+
+    pub fn redaction(&self, cql: &str) -> AppResult<Redaction> {
+        Redaction::parse(self.redact_params.iter().map(String::as_str), cql)
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn control_socket(&self) -> bool {
+        self.control_socket
+    }
+
+    /// Parse query parameters, leniently with `--lenient-params`.
+    pub fn parse_params<'a>(
+        &self,
+        args: impl Iterator<Item = &'a str>,
+    ) -> AppResult<Vec<ParamSet>> {
+        params::parse_args_with(args, self.lenient_params)
+    }
+
+    pub fn parallelism(&self) -> usize {
+        self.parallelism
+    }
+
+    pub fn set_parallelism(&mut self, parallelism: usize) {
+        self.parallelism = parallelism;
+    }
+
+    pub fn set_control(&mut self, control: Arc<PauseControl>) {
+        self.control = control;
+    }
 }
 
-pub fn run_query(
-    config: Config,
-    query: &str,
-    params: Option<Vec<params::Values>>,
+pub fn run_query(config: Config, query: &str, params: Option<Vec<ParamSet>>) -> AppResult<()> {
+    run_queries(config, &[query], params)
+}
+
+/// Run each of `queries` on every parameter set, their rows tagged with
+/// the position of their query under `_query` when there are several.
+pub fn run_queries(
+    mut config: Config,
+    queries: &[&str],
+    params: Option<Vec<ParamSet>>,
 ) -> AppResult<()> {
-    let session = connect(config.host.as_str())?;
-    match params {
-        Some(params) => parallel_query(session, query, params, config),
-        None => simple_query(&session, query, &config),
+    let mut counter = false;
+    for query in queries {
+        counter |= counter::check(query)?;
+    }
+    if counter && !config.force_counter_retry {
+        config.reconnect_timeout = Duration::ZERO;
+        config.retries = 0;
+        config.speculative_execution = None;
+        config.fallback_consistency = None;
+    }
+    let session = config.connect()?;
+    for query in queries {
+        if params.is_some() && config.parallelism > config.index_parallelism {
+            if let Some(column) = index::indexed_column(&session, query, &config)? {
+                diag!(
+                    "Warning: the query uses the secondary index on {}, lowering the parallelism from {} to {} (see --index-parallelism)",
+                    column, config.parallelism, config.index_parallelism
+                );
+                config.parallelism = config.index_parallelism;
+            }
+        }
+    }
+    let mut rewritten = Vec::with_capacity(queries.len());
+    for query in queries {
+        let query = match config.as_of {
+            Some(_) => as_of::rewrite(&session, query, &config)?,
+            None => query.to_string(),
+        };
+        let query = match &config.latest {
+            Some(latest) => latest.rewrite(&session, &query, &config)?,
+            None => query,
+        };
+        rewritten.push(query);
+    }
+    let incremental = config.incremental.clone();
+    let validator = config.validator.clone();
+    let dead_letter = config.dead_letter.clone();
+    let warnings = config.warnings.clone();
+    let usage = config.usage.clone();
+    let _ticker = usage
+        .as_ref()
+        .zip(config.stats_interval)
+        .map(|(usage, interval)| usage.tick(interval));
+    // The golden rows not returned are written out once done
+    let comparison = config.comparison.clone().map(|x| (x, config.clone()));
+    let limit = config.limit.clone();
+    let random = config.random.clone().map(|x| (x, config.clone()));
+    let result = match params {
+        Some(params) => parallel_query(session, &rewritten, params, config),
+        None => rewritten.iter().enumerate().try_for_each(|(i, query)| {
+            simple_query(&session, query, &config.tagged(i, rewritten.len()))
+        }),
+    };
+    // Reaching --limit stops the workers with an error
+    let result = match (limit, result) {
+        (Some(limit), Err(err)) if limit.is_limit_error(&err) => Ok(()),
+        (_, result) => result,
+    };
+    // The cursors of the partitions written out completely are saved
+    // even if the run failed, as their rows won't have to be returned
+    // again
+    if let Some(inc) = incremental {
+        inc.save()?;
+    }
+    if let Some(dead_letter) = dead_letter {
+        dead_letter.finish();
+    }
+    if let Some(summary) = warnings.summary() {
+        diag!("{}", summary);
+    }
+    if let Some(usage) = usage {
+        diag!("{}", usage.report());
+    }
+    if counter && result.is_err() {
+        diag!("The counter updates running when the run failed may or may not have been applied");
+    }
+    result?;
+    if let Some((random, config)) = random {
+        for (out, json) in random.take() {
+            config.sink.write_result(&out, &json)?;
+            config.metrics.row_emitted();
+        }
+    }
+    if let Some((comparison, config)) = comparison {
+        comparison.finish(&config)?;
+    }
+    match validator {
+        Some(validator) => validator.finish(),
+        None => Ok(()),
     }
 }
 
-fn connect(host: &str) -> AppResult<CurrentSession> {
-    let node = NodeTcpConfigBuilder::new(host, NoneAuthenticator {})
-        .connection_timeout(Duration::from_secs(10)) //TODO CLI option for timeout
-        .build();
-    let cluster_config = ClusterTcpConfig(vec![node]);
-    let session = new_session(&cluster_config, RoundRobinSync::new())?;
-    Ok(session)
+// The driver can't cancel a query, so with --request-timeout queries
+// run on their own thread, which is left behind when they time out.
+// With --speculative-execution, a query that hasn't returned after that
+// delay is sent to another node as well, the first response winning
+// and the other query being left behind the same way. `None` when timed
+// out.
+fn execute(
+    session: &Arc<CurrentSession>,
+    query: &Prepared,
+    params: Params,
+    timeout: Option<Duration>,
+    speculative: Option<Duration>,
+) -> cdrs::error::Result<Option<Response>> {
+    if timeout.is_none() && speculative.is_none() {
+        return session.execute(query, params).map(Some);
+    }
+    let start = Instant::now();
+    let deadline = timeout.map(|x| start + x);
+    let mut speculate_at = speculative.map(|x| start + x);
+    let (tx, rx) = mpsc::channel();
+    let spawn = |speculate: bool| {
+        let (session, query, params, tx) =
+            (session.clone(), query.clone(), params.clone(), tx.clone());
+        thread::spawn(move || {
+            let resp = match speculate {
+                true => session.speculate(&query, params),
+                false => session.execute(&query, params),
+            };
+            tx.send((resp, backend::take_bytes_read()))
+        });
+    };
+    spawn(false);
+    let mut pending = 1;
+    loop {
+        let wait = speculate_at.into_iter().chain(deadline).min();
+        let received = match wait {
+            Some(until) => rx.recv_timeout(until.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok((resp, read)) => {
+                backend::count_bytes_read(read);
+                pending -= 1;
+                match resp {
+                    // The other query may still succeed
+                    Err(_) if pending > 0 => {}
+                    resp => return resp.map(Some),
+                }
+            }
+            Err(_) if speculate_at.is_some_and(|x| x <= Instant::now()) => {
+                speculate_at = None;
+                if pending > 0 {
+                    spawn(true);
+                    pending += 1;
+                }
+            }
+            Err(_) => return Ok(None),
+        }
+    }
 }
 
 fn prepared_query(
-    session: &CurrentSession,
-    query: &PreparedQuery,
-    vals: params::Values,
+    session: &Arc<CurrentSession>,
+    query: &Prepared,
+    vals: ParamSet,
     config: &Config,
+    state: &RunState,
 ) -> AppResult<()> {
-    let query_vals = QueryValues::SimpleValues(vals);
-    let params = QueryParamsBuilder::new().values(query_vals).finalize();
-    let resp = session.exec_with_params(query, params)?;
-    write_results(&resp, config)
+    let ParamSet { values, labels } = vals;
+    let params = Params {
+        page_size: Some(config.page_size),
+        ..config.params(values)
+    };
+    // The consistency level of the parameter set, lowered for it and
+    // its next pages once its replicas can't meet the requested one
+    let served = Cell::new(config.consistency);
+    let fetch = |paging_state| -> AppResult<Option<Response>> {
+        let params = Params {
+            paging_state,
+            consistency: served.get(),
+            ..params.clone()
+        };
+        state.control.wait_while_paused()?;
+        if let Some(limit) = &config.limit {
+            limit.check()?;
+        }
+        config.memory.wait_for_capacity();
+        let _slot = state.impact.as_ref().map(|x| x.acquire());
+        config.throttle();
+        let run = |params: &Params| {
+            retry::with_retries(config.retries, config.retry_budget.as_deref(), || {
+                timed(config, || {
+                    execute(
+                        session,
+                        query,
+                        params.clone(),
+                        config.request_timeout,
+                        config.speculative_execution,
+                    )
+                })
+            })
+        };
+        let resp = match (run(&params), config.fallback_consistency) {
+            (Err(err), Some(fallback))
+                if retry::is_unavailable(&err) && served.get() != fallback =>
+            {
+                diag!(
+                    "{}, falling back to {} (parameters: {})",
+                    AppError::from(err),
+                    consistency::name(fallback),
+                    state.redaction.labels(&labels).join(", ")
+                );
+                served.set(fallback);
+                run(&Params {
+                    consistency: fallback,
+                    ..params
+                })
+            }
+            (resp, _) => resp,
+        }
+        .map_err(|err| {
+            AppError::new(format!(
+                "{} (parameters: {})",
+                AppError::from(err),
+                state.redaction.labels(&labels).join(", ")
+            ))
+        })?;
+        if resp.is_none() {
+            // The other queries go on, the run fails once done
+            state.timeouts.fetch_add(1, Ordering::Relaxed);
+            diag!(
+                "Query timed out (parameters: {})",
+                state.redaction.labels(&labels).join(", ")
+            );
+        }
+        Ok(resp)
+    };
+    let resp = match fetch(None)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    // The worker running a parameter set fetches its pages one after the
+    // other and writes their rows itself, in the order the server
+    // returned them, however the parameter sets of the workers interleave
+    let redacted = state.redaction.labels(&labels);
+    let result = write_results(
+        session,
+        resp,
+        &labels,
+        &redacted,
+        config,
+        &mut |x| fetch(Some(x)),
+    );
+    state.stats.record_served(served.get());
+    config.record_read();
+    result
 }
 
 fn parallel_query(
     session: CurrentSession,
-    cql: &str,
-    vals: Vec<params::Values>,
+    cqls: &[String],
+    vals: Vec<ParamSet>,
     config: Config,
 ) -> AppResult<()> {
+    // Each query along with the config tagging its rows
+    let mut statements = Vec::with_capacity(cqls.len());
+    for (i, cql) in cqls.iter().enumerate() {
+        let _span = tracing::info_span!("prepare").entered();
+        let prepared = session.prepare(cql).map_err(|err| {
+            AppError::new(format!(
+                "Unable to prepare the query: {}",
+                AppError::from(err)
+            ))
+        })?;
+        statements.push((prepared, config.tagged(i, cqls.len())));
+    }
+    let requested = config.consistency;
+
+    let session = Arc::new(session);
+    let impact = config.max_pending_reads.map(|max| {
+        let pending_reads: Box<dyn Fn() -> AppResult<u64> + Send> = match &config.impact_metrics_url
+        {
+            Some(url) => {
+                let url = url.clone();
+                Box::new(move || impact::fetch_pending_reads(&url))
+            }
+            None => {
+                let (session, config) = (session.clone(), config.clone());
+                Box::new(move || impact::system_pending_reads(&session, &config))
+            }
+        };
+        ImpactGuard::start(max, config.parallelism, pending_reads)
+    });
+    let state = Arc::new(RunState {
+        stats: RunStats::default(),
+        control: config.control.clone(),
+        // The queries share their parameters
+        redaction: config.redaction(&cqls[0])?,
+        timeouts: AtomicUsize::new(0),
+        failure: Mutex::new(None),
+        impact,
+    });
+    // The first parameter set runs before the workers start, so that a
+    // bad host or statement fails the run once rather than from every
+    // worker, and the connections are known to work
+    let worker_state = state.clone();
+    let limit = config.limit.clone();
+    let run = move |vs: ParamSet| {
+        let result = statements.iter().try_for_each(|(prepared, config)| {
+            prepared_query(&session, prepared, vs.clone(), config, &worker_state)
+        });
+        match &result {
+            Err(err) if limit.as_ref().is_some_and(|x| x.is_limit_error(err)) => (),
+            Err(err) => {
+                let mut failure = worker_state.failure.lock().unwrap();
+                failure.get_or_insert(err.clone());
+            }
+            Ok(()) => (),
+        }
+        result
+    };
+    let mut vals = vals.into_iter();
+    if let Some(first) = vals.next() {
+        if let Some(usage) = &config.usage {
+            usage.mark_preflight();
+        }
+        match run(first) {
+            Err(err) if state.failure.lock().unwrap().is_some() => {
+                return Err(AppError::new(format!(
+                    "Pre-flight query failed, no other queries were run: {}",
+                    err
+                )))
+            }
+            result => result?,
+        }
+    }
+    let result = vals.consume(config.parallelism, run);
+
+    if let Some(summary) = state.stats.consistency_summary(requested) {
+        diag!("{}", summary);
+    }
+    if let Some(err) = state.failure.lock().unwrap().take() {
+        return Err(err);
+    }
+    result?;
+    match state.timeouts.load(Ordering::Relaxed) {
+        0 => Ok(()),
+        n => Err(AppError::new(format!(
+            "{} queries exceeded --request-timeout",
+            n
+        ))),
+    }
+}
+
+/// Run every parameter set at each of the consistency `levels` and
+/// report the rows that are not returned at all levels alike.
+pub fn consistency_check(
+    config: Config,
+    cql: &str,
+    vals: Vec<ParamSet>,
+    levels: Vec<Consistency>,
+) -> AppResult<()> {
+    let session = config.connect()?;
     let prepared = session.prepare(cql)?;
+    let redaction = config.redaction(cql)?;
+    let total = vals.len();
+    let differing = Arc::new(AtomicUsize::new(0));
+
+    let counter = differing.clone();
+    vals.into_iter()
+        .consume(config.parallelism, move |vs| -> AppResult<()> {
+            let mut results = Vec::with_capacity(levels.len());
+            for level in levels.iter() {
+                let params = Params {
+                    consistency: *level,
+                    ..config.params(vs.values.clone())
+                };
+                let resp = session.execute(&prepared, params)?;
+                results.push(collect_rows(&resp, &config)?);
+            }
+
+            let diffs = diff_rows(&levels, results);
+            if !diffs.is_empty() {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            for (row, counts) in diffs {
+                let report =
+                    json!({ "params": redaction.labels(&vs.labels), "row": row, "levels": counts });
+                write_report(report, &config)?;
+            }
+            Ok(())
+        })?;
+
+    diag!(
+        "{} of {} parameter sets differ across consistency levels",
+        differing.load(Ordering::Relaxed),
+        total
+    );
+    Ok(())
+}
 
-    vals.into_iter().consume(config.parallelism, move |vs| {
-        prepared_query(&session, &prepared, vs, &config)
-    })
+// Returns the rows whose number of occurrences differ between levels,
+// along with the number of occurrences at each level.
+fn diff_rows(
+    levels: &[Consistency],
+    results: Vec<Vec<JsonValue>>,
+) -> Vec<(JsonValue, Map<String, JsonValue>)> {
+    let mut rows: BTreeMap<String, (JsonValue, Vec<usize>)> = BTreeMap::new();
+    for (i, result) in results.into_iter().enumerate() {
+        for row in result {
+            let key = canonical::to_canonical_string(&row);
+            let entry = rows
+                .entry(key)
+                .or_insert_with(|| (row, vec![0; levels.len()]));
+            entry.1[i] += 1;
+        }
+    }
+
+    rows.into_values()
+        .filter(|(_, counts)| counts.iter().any(|n| *n != counts[0]))
+        .map(|(row, counts)| {
+            let counts = levels
+                .iter()
+                .zip(counts)
+                .map(|(level, n)| (consistency::name(*level).to_string(), n.into()))
+                .collect();
+            (row, counts)
+        })
+        .collect()
 }
 
 fn simple_query(session: &CurrentSession, cql: &str, config: &Config) -> AppResult<()> {
-    let resp = session.query(cql)?;
-    write_results(&resp, config)
+    let fetch = |paging_state| {
+        let params = Params {
+            page_size: Some(config.page_size),
+            paging_state,
+            ..config.params(vec![])
+        };
+        retry::with_retries(config.retries, config.retry_budget.as_deref(), || {
+            timed(config, || session.query(cql, params.clone()))
+        })
+    };
+    let resp = fetch(None)?;
+    let result = write_results(session, resp, &[], &[], config, &mut |x| {
+        Ok(Some(fetch(Some(x))?))
+    });
+    config.record_read();
+    result
 }
 
-fn write_results(resp: &Frame, config: &Config) -> AppResult<()> {
-    let body = resp.get_body()?;
+fn timed<T>(config: &Config, f: impl FnOnce() -> cdrs::error::Result<T>) -> cdrs::error::Result<T> {
+    let _span = tracing::info_span!("execute").entered();
+    let started = Instant::now();
+    config.metrics.query_started();
+    let result = f();
+    config.metrics.query_finished(started.elapsed(), &result);
+    result
+}
 
-    if let ResponseBody::Result(ResResultBody::Rows(rows)) = body {
+// What the rows of a result have in common
+struct ResultContext {
+    // The metadata of the output rows
+    out_meta: RowsMetadata,
+    // The partition of the result for --incremental
+    partition: String,
+    // The greatest cursor of the rows written out, the partition's once
+    // all its rows are
+    cursor: RefCell<Option<JsonValue>>,
+    sample_key: Vec<String>,
+    envelope: Option<ResultEnvelope>,
+}
+
+// Write the rows of `resp` and of the pages following it, fetched with
+// `next_page` from the paging state of the previous one until the last
+// page or a timeout. `redacted_labels` are the `labels` of the
+// parameters as shown in the output.
+fn write_results(
+    session: &CurrentSession,
+    resp: Response,
+    labels: &[String],
+    redacted_labels: &[String],
+    config: &Config,
+    next_page: &mut dyn FnMut(CBytes) -> AppResult<Option<Response>>,
+) -> AppResult<()> {
+    config.warnings.check()?;
+    // Wait for DDL to reach all the nodes before running anything else
+    if let Response::SchemaChange = resp {
+        return schema::wait_for_agreement(session, config, config.schema_agreement_timeout);
+    }
+
+    if let Response::Rows(rows) = resp {
         let meta = rows.metadata;
-        for row in rows.rows_content {
-            write_row(&meta, &row, config)
+        // The write times fetched for --as-of are not part of the output
+        let out_meta = match config.as_of {
+            Some(_) => as_of::output_metadata(&meta),
+            None => meta.clone(),
+        };
+        let mut preamble = Ok(());
+        config
+            .first_result
+            .call_once(|| preamble = write_preamble(&out_meta, config));
+        preamble?;
+        let ctx = ResultContext {
+            out_meta,
+            partition: incremental::partition_key(labels),
+            cursor: RefCell::new(None),
+            sample_key: match &config.sample {
+                Some(sample) => sample.key_columns(session, &meta, config)?,
+                None => vec![],
+            },
+            envelope: match &config.envelope {
+                Some(envelope) => Some(envelope.result(session, &meta, redacted_labels, config)?),
+                None => None,
+            },
+        };
+        let mut partitions = match &config.statics {
+            Some(statics) => Some(statics.partitions(session, &meta, config)?),
+            None => None,
+        };
+        let mut result_bytes = 0;
+        // The pages after the first may come without their metadata
+        let mut page = (rows.rows_content, meta.paging_state.clone());
+        let mut page_number = 1;
+        loop {
+            let (rows_content, paging_state) = page;
+            // Given back before fetching the next page, which waits for
+            // the results of the other workers to be written out
+            let reservation = config
+                .memory
+                .reserve(rows_content.iter().map(|x| row_size(x)).sum());
+            for row in rows_content {
+                result_bytes += row_size(&row);
+                if let Some(max) = config.max_result_bytes {
+                    if result_bytes > max {
+                        if config.truncate_oversized_results {
+                            diag!("Query result exceeds {} bytes, truncated", max);
+                            return Ok(());
+                        } else {
+                            return Err(AppError::new(format!(
+                                "Query result exceeds {} bytes, aborted",
+                                max
+                            )));
+                        }
+                    }
+                }
+                write_row(&meta, &row, &ctx, partitions.as_mut(), config)?;
+            }
+            drop(reservation);
+            let paging_state = match paging_state {
+                Some(x) => x,
+                None => break,
+            };
+            page_number += 1;
+            let next = {
+                let _span = tracing::info_span!("page", number = page_number).entered();
+                next_page(paging_state)?
+            };
+            page = match next {
+                Some(Response::Rows(rows)) => (rows.rows_content, rows.metadata.paging_state),
+                _ => break,
+            };
+        }
+        if let (Some(inc), Some(cursor)) = (&config.incremental, ctx.cursor.into_inner()) {
+            inc.commit(&ctx.partition, cursor);
         }
     }
     Ok(())
 }
 
+pub fn collect_rows(resp: &Response, config: &Config) -> AppResult<Vec<JsonValue>> {
+    match resp {
+        Response::Rows(rows) => rows
+            .rows_content
+            .iter()
+            .map(|row| row_to_json(&rows.metadata, row, config))
+            .collect(),
+        _ => Ok(vec![]),
+    }
+}
+
+fn row_size(row: &[CBytes]) -> usize {
+    row.iter()
+        .map(|x| x.as_slice().map_or(0, |bytes| bytes.len()))
+        .sum()
+}
+
 fn format_json<F: Formatter>(
     formatter: F,
     json: &JsonValue,
@@ -140,29 +1532,486 @@ fn format_json<F: Formatter>(
     Ok(fmt.to_colored_json(json, color)?)
 }
 
-fn write_row(meta: &RowsMetadata, row: &[CBytes], config: &Config) {
-    let result = row_to_json(meta, row).and_then(|x| {
-        if config.pretty {
-            format_json(PrettyFormatter::new(), &x, config.color)
-        } else {
-            format_json(CompactFormatter {}, &x, config.color)
+// Recursively sort the keys of all objects
+fn sort_keys(json: JsonValue) -> JsonValue {
+    match json {
+        JsonValue::Object(obj) => {
+            let mut entries: Vec<(String, JsonValue)> = obj.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            JsonValue::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, sort_keys(v)))
+                    .collect(),
+            )
         }
-    });
+        JsonValue::Array(xs) => JsonValue::Array(xs.into_iter().map(sort_keys).collect()),
+        x => x,
+    }
+}
+
+fn format_output(json: &JsonValue, config: &Config) -> AppResult<String> {
+    let sorted;
+    let json = if config.sort_keys {
+        sorted = sort_keys(json.clone());
+        &sorted
+    } else {
+        json
+    };
+    let out = if config.canonical {
+        canonical::to_canonical_string(json)
+    } else if config.pretty {
+        format_json(PrettyFormatter::new(), json, config.color)?
+    } else {
+        format_json(CompactFormatter {}, json, config.color)?
+    };
+    if config.escape_control_chars {
+        Ok(escape::json(&out).into_owned())
+    } else {
+        Ok(out)
+    }
+}
+
+/// Write a JSON document other than a result row, e.g. a report, to
+/// the output.
+pub fn write_report(json: JsonValue, config: &Config) -> AppResult<()> {
+    config.sink.write_row(&format_output(&json, config)?)
+}
+
+// What's derived from the metadata of the first result of a run: the
+// DDL requested with --emit-ddl and the CSV header
+fn write_preamble(meta: &RowsMetadata, config: &Config) -> AppResult<()> {
+    if let Some(target) = config.emit_ddl {
+        let ddl = ddl::create_table(meta, target);
+        match &config.ddl_file {
+            Some(path) => fs::write(path, ddl + "\n")?,
+            None => diag!("{}", ddl),
+        }
+    }
+    if config.format == OutputFormat::Csv {
+        config.sink.write_row(&csv::header(meta))?;
+        if config.typed_header {
+            config.sink.write_row(&csv::typed_header(meta))?;
+        }
+    }
+    Ok(())
+}
+
+// Rows left out by --as-of, --sample or --incremental are `None`
+fn filter_row(
+    json: JsonValue,
+    ctx: &ResultContext,
+    config: &Config,
+) -> AppResult<Option<JsonValue>> {
+    let json = match config.as_of {
+        Some(t) => match as_of::filter_row(json, t, config.sparse) {
+            Some(x) => x,
+            None => return Ok(None),
+        },
+        None => json,
+    };
+    if let Some(sample) = &config.sample {
+        if !sample.keep(&ctx.sample_key, &json)? {
+            return Ok(None);
+        }
+    }
+    match &config.incremental {
+        Some(inc) if !inc.accept(&ctx.partition, &json)? => Ok(None),
+        _ => Ok(Some(json)),
+    }
+}
+
+fn write_row(
+    meta: &RowsMetadata,
+    row: &[CBytes],
+    ctx: &ResultContext,
+    partitions: Option<&mut Partitions>,
+    config: &Config,
+) -> AppResult<()> {
+    let json = match row_to_json(meta, row, config) {
+        Ok(x) => x,
+        Err(err) => return reject_row(meta, row, err, config),
+    };
+    let json = match filter_row(json, ctx, config)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    // Taken before the transforms, which may drop the column
+    let cursor = match &config.incremental {
+        Some(inc) => Some(inc.cursor(&json)?.clone()),
+        None => None,
+    };
+    let json = match partitions {
+        Some(partitions) => {
+            let (json, record) = partitions.apply(json);
+            if let Some(record) = record {
+                write_static_record(record, ctx, config)?;
+            }
+            json
+        }
+        None => json,
+    };
+    let mut json = match (config.query_tag, json) {
+        (Some(tag), JsonValue::Object(mut row)) => {
+            row.insert(QUERY_TAG_KEY.to_string(), tag.into());
+            JsonValue::Object(row)
+        }
+        (_, json) => json,
+    };
+    for transform in &config.transforms {
+        json = match transform.apply(json)? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+    }
+    let json = match &config.mask {
+        Some(mask) => mask.apply(json),
+        None => json,
+    };
+    if let Some(validator) = &config.validator {
+        if !validator.check(&json)? {
+            return Ok(());
+        }
+    }
+    if let Some(comparison) = &config.comparison {
+        if let Some(report) = comparison.check(&json)? {
+            write_report(report, config)?;
+        }
+        return Ok(());
+    }
+    let result = {
+        let _span = tracing::info_span!("serialize").entered();
+        match (config.format, &ctx.envelope) {
+            (OutputFormat::Json, Some(envelope)) => {
+                format_output(&envelope.wrap(json.clone()), config)
+            }
+            (OutputFormat::Json, None) => format_output(&json, config),
+            (OutputFormat::Csv, _) => Ok(csv::record(
+                &ctx.out_meta,
+                &json,
+                config.escape_control_chars,
+            )),
+        }
+    };
 
     match result {
-        Ok(json) => println!("{}", json),
-        // TODO Better error reporting
-        Err(err) => eprintln!("{}", err),
+        Ok(out) => {
+            if let Some(limit) = &config.limit {
+                limit.take()?;
+            }
+            if let Some(random) = &config.random {
+                random.offer(out, json);
+                return Ok(());
+            }
+            let _span = tracing::info_span!("sink").entered();
+            config.sink.write_result(&out, &json)?;
+            config.metrics.row_emitted();
+            if let Some(cursor) = cursor {
+                incremental::advance(&mut ctx.cursor.borrow_mut(), &cursor);
+            }
+            Ok(())
+        }
+        Err(err) => reject_row(meta, row, err, config),
     }
 }
 
-fn row_to_json(meta: &RowsMetadata, row: &[CBytes]) -> AppResult<JsonValue> {
+// The static columns of a partition with --static-record, masked and
+// wrapped like the rows
+fn write_static_record(
+    record: JsonValue,
+    ctx: &ResultContext,
+    config: &Config,
+) -> AppResult<()> {
+    let record = match &config.mask {
+        Some(mask) => mask.apply(record),
+        None => record,
+    };
+    let json = json!({ STATIC_RECORD_KEY: record });
+    let out = match &ctx.envelope {
+        Some(envelope) => format_output(&envelope.wrap(json.clone()), config)?,
+        None => format_output(&json, config)?,
+    };
+    config.sink.write_result(&out, &json)
+}
+
+// A row that couldn't be decoded or formatted goes to --dead-letter,
+// or is dropped with an error message
+fn reject_row(
+    meta: &RowsMetadata,
+    row: &[CBytes],
+    err: AppError,
+    config: &Config,
+) -> AppResult<()> {
+    match &config.dead_letter {
+        Some(dead_letter) => dead_letter.write(meta, row, &err),
+        None => {
+            diag!("{}", err);
+            Ok(())
+        }
+    }
+}
+
+// A column that can't be decoded fails the row, or is written as null
+// or as its raw bytes with --on-decode-error
+fn decode_column(
+    name: &str,
+    col_type: &ColTypeOption,
+    data: &CBytes,
+    opts: &DecodeOptions,
+) -> AppResult<ColValue> {
+    let err = match ColValue::decode(col_type, data, opts) {
+        Ok(x) => return Ok(x),
+        Err(err) => AppError::from(err),
+    };
+    let value = match (opts.on_decode_error, data.as_plain()) {
+        (OnDecodeError::Fail, _) => return Err(err),
+        (OnDecodeError::RawHex, Some(bytes)) => ColValue::Blob((&bytes).into()),
+        _ => ColValue::Null,
+    };
+    diag!("Unable to decode the column {}: {}", name, err);
+    Ok(value)
+}
+
+fn row_to_json(meta: &RowsMetadata, row: &[CBytes], config: &Config) -> AppResult<JsonValue> {
+    let _span = tracing::info_span!("decode").entered();
     let mut obj = Map::with_capacity(meta.columns_count as usize);
 
     for (i, col) in meta.col_specs.iter().enumerate() {
+        // Cells that were never written (or deleted) come back as
+        // null, while explicitly written empty values are decoded as
+        // `ColValue::Null`
+        if config.sparse && row[i].as_slice().is_none() {
+            continue;
+        }
         let name = col.name.as_plain();
-        let value = ColValue::decode(&col.col_type, &row[i])?;
+        let value = decode_column(&name, &col.col_type, &row[i], &config.decode_opts)?;
         obj.insert(name, serde_json::to_value(value)?);
     }
     Ok(JsonValue::Object(obj))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_rows() {
+        let levels = [Consistency::One, Consistency::All];
+        let diffs = diff_rows(
+            &levels,
+            vec![
+                vec![json!({"id": 1}), json!({"id": 2})],
+                vec![json!({"id": 1})],
+            ],
+        );
+        assert_eq!(1, diffs.len());
+        assert_eq!(json!({"id": 2}), diffs[0].0);
+        assert_eq!(
+            json!({"ONE": 1, "ALL": 0}),
+            JsonValue::Object(diffs[0].1.clone())
+        );
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let json = json!({"b": 1, "a": [{"d": 1, "c": 2}]});
+        assert_eq!(
+            r#"{"a":[{"c":2,"d":1}],"b":1}"#,
+            sort_keys(json).to_string().as_str()
+        );
+    }
+
+    #[test]
+    fn test_decode_column() {
+        let date = ColTypeOption {
+            id: cdrs::frame::frame_result::ColType::Date,
+            value: None,
+        };
+        let data = CBytes::new(vec![0, 0, 0, 0]);
+        let decode = |on_decode_error| {
+            let opts = DecodeOptions {
+                invalid_dates: InvalidDates::Error,
+                on_decode_error,
+                ..Default::default()
+            };
+            decode_column("day", &date, &data, &opts).map(|x| serde_json::to_value(x).unwrap())
+        };
+        assert!(decode(OnDecodeError::Fail).is_err());
+        assert_eq!(JsonValue::Null, decode(OnDecodeError::Null).unwrap());
+        assert_eq!(json!("00000000"), decode(OnDecodeError::RawHex).unwrap());
+    }
+
+    // Answers queries after `0` ms
+    struct Slow(u64);
+
+    impl Backend for Slow {
+        fn prepare(&self, _cql: &str) -> cdrs::error::Result<Prepared> {
+            unimplemented!()
+        }
+
+        fn query(&self, _cql: &str, _params: Params) -> cdrs::error::Result<Response> {
+            unimplemented!()
+        }
+
+        fn execute(&self, _prepared: &Prepared, _params: Params) -> cdrs::error::Result<Response> {
+            thread::sleep(Duration::from_millis(self.0));
+            Ok(Response::Void)
+        }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let prepared = Prepared::new(vec![1]);
+        let timeout = Some(Duration::from_millis(50));
+        let session: Arc<CurrentSession> = Arc::new(Box::new(Slow(0)));
+        let resp = execute(&session, &prepared, Params::default(), timeout, None).unwrap();
+        assert!(matches!(resp, Some(Response::Void)));
+        let session: Arc<CurrentSession> = Arc::new(Box::new(Slow(1000)));
+        let resp = execute(&session, &prepared, Params::default(), timeout, None).unwrap();
+        assert!(resp.is_none());
+    }
+
+    // Slow on its first execution only
+    struct SlowOnce(AtomicUsize);
+
+    impl Backend for SlowOnce {
+        fn prepare(&self, _cql: &str) -> cdrs::error::Result<Prepared> {
+            unimplemented!()
+        }
+
+        fn query(&self, _cql: &str, _params: Params) -> cdrs::error::Result<Response> {
+            unimplemented!()
+        }
+
+        fn execute(&self, _prepared: &Prepared, _params: Params) -> cdrs::error::Result<Response> {
+            if self.0.fetch_add(1, Ordering::Relaxed) == 0 {
+                thread::sleep(Duration::from_millis(1000));
+            }
+            Ok(Response::Void)
+        }
+    }
+
+    #[test]
+    fn test_speculative_execution() {
+        let prepared = Prepared::new(vec![1]);
+        let session: Arc<CurrentSession> = Arc::new(Box::new(SlowOnce(AtomicUsize::new(0))));
+        let start = Instant::now();
+        let delay = Some(Duration::from_millis(20));
+        let timeout = Some(Duration::from_millis(500));
+        let resp = execute(&session, &prepared, Params::default(), timeout, delay).unwrap();
+        assert!(matches!(resp, Some(Response::Void)));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        // Both executions too slow
+        let session: Arc<CurrentSession> = Arc::new(Box::new(Slow(1000)));
+        let resp = execute(&session, &prepared, Params::default(), timeout, delay).unwrap();
+        assert!(resp.is_none());
+    }
+
+    // Answers each parameter `p` with the rows `(p, 0)` to `(p, 49)` in
+    // pages of 20 rows, taking longer for some parameters than others
+    struct Partitioned;
+
+    impl Backend for Partitioned {
+        fn prepare(&self, _cql: &str) -> cdrs::error::Result<Prepared> {
+            Ok(Prepared::new(vec![1]))
+        }
+
+        fn query(&self, _cql: &str, _params: Params) -> cdrs::error::Result<Response> {
+            unimplemented!()
+        }
+
+        fn execute(&self, _prepared: &Prepared, params: Params) -> cdrs::error::Result<Response> {
+            use cdrs::frame::frame_result::{BodyResResultRows, ColSpec, ColType};
+            use cdrs::types::CString;
+
+            let p = params.values[0].body.clone();
+            thread::sleep(Duration::from_millis(p.iter().map(|x| *x as u64 % 4).sum()));
+            let column = |name: &str, id| ColSpec {
+                ksname: None,
+                tablename: None,
+                name: CString::new(name.to_string()),
+                col_type: ColTypeOption { id, value: None },
+            };
+            let start = params
+                .paging_state
+                .and_then(|x| x.into_plain())
+                .map_or(0, |x| x[0] as i32);
+            let end = (start + 20).min(50);
+            let rows_content: Vec<Vec<CBytes>> = (start..end)
+                .map(|n| vec![CBytes::new(p.clone()), CBytes::new(n.to_be_bytes().to_vec())])
+                .collect();
+            Ok(Response::Rows(BodyResResultRows {
+                metadata: RowsMetadata {
+                    flags: 0,
+                    columns_count: 2,
+                    paging_state: (end < 50).then(|| CBytes::new(vec![end as u8])),
+                    global_table_space: None,
+                    col_specs: vec![column("p", ColType::Blob), column("n", ColType::Int)],
+                },
+                rows_count: rows_content.len() as i32,
+                rows_content,
+            }))
+        }
+    }
+
+    struct Collect(Mutex<Vec<JsonValue>>);
+
+    impl Sink for Collect {
+        fn write_row(&self, row: &str) -> AppResult<()> {
+            self.0.lock().unwrap().push(serde_json::from_str(row)?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rows_in_server_order() {
+        let matches = crate::app().get_matches_from(["kass", "-P", "8", "SELECT"]);
+        let sink = Arc::new(Collect(Default::default()));
+        let config = Config::from_matches(&matches).unwrap().with_sink(sink.clone());
+        let vals = params::parse_args(vec!["0..40"].into_iter()).unwrap();
+        parallel_query(Box::new(Partitioned), &["SELECT".to_string()], vals, config).unwrap();
+
+        // The rows of the parameter sets interleave, those of each one
+        // keep the order they were returned in across its pages
+        let rows = sink.0.lock().unwrap();
+        assert_eq!(40 * 50, rows.len());
+        let mut next: BTreeMap<String, i64> = BTreeMap::new();
+        for row in rows.iter() {
+            let n = next.entry(row["p"].to_string()).or_insert(0);
+            assert_eq!(Some(*n), row["n"].as_i64());
+            *n += 1;
+        }
+        assert_eq!(40, next.len());
+    }
+
+    #[test]
+    fn test_with_sink() {
+        let matches = crate::app().get_matches_from(["kass", "--limit", "2", "SELECT"]);
+        let config = Config::from_matches(&matches).unwrap();
+        let sink = Arc::new(Collect(Default::default()));
+        let (first, second) = (config.with_sink(sink.clone()), config.with_sink(sink));
+        let limit = first.limit.unwrap();
+        limit.take().unwrap();
+        limit.take().unwrap();
+        assert!(limit.is_reached());
+        assert!(!second.limit.unwrap().is_reached());
+        assert!(!Arc::ptr_eq(&first.warnings, &second.warnings));
+    }
+
+    #[test]
+    fn test_query_tags() {
+        let matches = crate::app().get_matches_from(["kass", "-P", "2", "SELECT"]);
+        let sink = Arc::new(Collect(Default::default()));
+        let config = Config::from_matches(&matches).unwrap().with_sink(sink.clone());
+        let vals = params::parse_args(vec!["0..3"].into_iter()).unwrap();
+        let cqls = ["SELECT a".to_string(), "SELECT b".to_string()];
+        parallel_query(Box::new(Partitioned), &cqls, vals, config).unwrap();
+
+        let rows = sink.0.lock().unwrap();
+        for tag in 0..2 {
+            let tagged = rows.iter().filter(|x| x[QUERY_TAG_KEY] == tag).count();
+            assert_eq!(3 * 50, tagged);
+        }
+    }
+}
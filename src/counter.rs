@@ -0,0 +1,86 @@
+// Counter updates, e.g. `UPDATE ks.counts SET c = c + ? WHERE id = ?`
+// fanned out over parameters, aren't idempotent: retrying one that
+// reached the cluster but whose response was lost counts it twice. They
+// aren't retried on connection errors unless `--force-counter-retry`,
+// and a batch can't mix them with other statements, which Cassandra
+// only rejects once the batch is sent. Updates appending to a list,
+// `l = l + ?`, look the same and are just as unsafe to retry.
+use regex::Regex;
+
+use crate::errors::{AppError, AppResult};
+use crate::schema::{self, unquote};
+
+lazy_static! {
+    static ref UPDATE: Regex =
+        Regex::new(r"(?is)^\s*update\s+\S+\s+(?:using\s+.+?\s+)?set\s+(.+?)\s+where\s").unwrap();
+    static ref INCREMENT: Regex = Regex::new(
+        r#"^([A-Za-z_][A-Za-z0-9_]*|"(?:[^"]|"")+")\s*=\s*([A-Za-z_][A-Za-z0-9_]*|"(?:[^"]|"")+")\s*[+-]"#
+    )
+    .unwrap();
+    static ref BATCH: Regex =
+        Regex::new(r"(?is)^\s*begin\s+(unlogged\s+|counter\s+)?batch\b(.*)\bapply\s+batch\s*;?\s*$")
+            .unwrap();
+}
+
+// Whether `cql` is an update adding to or subtracting from a column
+fn is_increment(cql: &str) -> bool {
+    let caps = match UPDATE.captures(cql) {
+        Some(x) => x,
+        None => return false,
+    };
+    caps[1].split(',').any(|assignment| {
+        INCREMENT
+            .captures(assignment.trim())
+            .is_some_and(|x| unquote(&x[1]) == unquote(&x[2]))
+    })
+}
+
+/// Whether `cql` updates counters, on its own or in a batch, failing
+/// for batches mixing them with other statements.
+pub fn check(cql: &str) -> AppResult<bool> {
+    let caps = match BATCH.captures(cql) {
+        Some(x) => x,
+        None => return Ok(is_increment(cql)),
+    };
+    let counter_batch = caps
+        .get(1)
+        .is_some_and(|x| x.as_str().trim().eq_ignore_ascii_case("counter"));
+    let statements = schema::split_statements(&caps[2]);
+    let increments = statements.iter().filter(|x| is_increment(x)).count();
+    match (counter_batch, increments) {
+        (true, n) if n < statements.len() => Err(AppError::new(
+            "A COUNTER BATCH can only hold counter updates",
+        )),
+        (false, n) if n > 0 => Err(AppError::new(
+            "Counter updates can't be batched with other statements, use BEGIN COUNTER BATCH",
+        )),
+        (_, n) => Ok(n > 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(check("UPDATE ks.counts SET c = c + ? WHERE id = ?").unwrap());
+        assert!(
+            check("update ks.counts using ttl 60 set a = 1, \"C\" = \"C\" - 2 where id = ?")
+                .unwrap()
+        );
+        assert!(!check("UPDATE ks.t SET a = b + ? WHERE id = ?").unwrap());
+        assert!(!check("UPDATE ks.t SET a = ? WHERE id = ?").unwrap());
+        assert!(!check("SELECT c + 1 FROM ks.t WHERE id = ?").unwrap());
+
+        let batch = |kind: &str, statements: &[&str]| {
+            format!("BEGIN {}BATCH {}; APPLY BATCH", kind, statements.join("; "))
+        };
+        let update = "UPDATE ks.counts SET c = c + 1 WHERE id = ?";
+        let insert = "INSERT INTO ks.t (id) VALUES (?)";
+        assert!(check(&batch("COUNTER ", &[update, update])).unwrap());
+        assert!(!check(&batch("", &[insert, insert])).unwrap());
+        assert!(check(&batch("COUNTER ", &[update, insert])).is_err());
+        assert!(check(&batch("UNLOGGED ", &[update])).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use clap::ArgMatches;
+use keyring::{Entry, Error as KeyringError};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{AppError, AppResult};
+
+const SERVICE: &str = "kass";
+
+/// Username and password of a connection profile, as stored in the OS
+/// keychain. The username is empty when only the password was saved,
+/// with `kass config set-password`, the username being that of the
+/// profile in the configuration file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+fn entry(profile: &str) -> AppResult<Entry> {
+    Ok(Entry::new(SERVICE, profile)?)
+}
+
+pub fn store(profile: &str, credentials: &Credentials) -> AppResult<()> {
+    let secret = serde_json::to_string(credentials)?;
+    entry(profile)?.set_password(&secret)?;
+    Ok(())
+}
+
+pub fn load(profile: &str) -> AppResult<Option<Credentials>> {
+    match entry(profile)?.get_password() {
+        Ok(secret) => Ok(Some(serde_json::from_str(&secret)?)),
+        Err(KeyringError::NoEntry) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The username and password to log in with from the `saved` ones,
+/// along with the `username` given, if any: the saved password is that
+/// of the saved username, or of any when only the password was saved.
+pub fn saved_login(saved: Credentials, username: Option<&str>) -> Option<(String, String)> {
+    match username {
+        None if saved.username.is_empty() => None,
+        None => Some((saved.username, saved.password)),
+        Some(x) if saved.username.is_empty() || saved.username == x => {
+            Some((x.to_string(), saved.password))
+        }
+        Some(_) => None,
+    }
+}
+
+/// The login and password for `host` (`host:port`) in a netrc-like
+/// credentials file, e.g. `machine prod-host login cassandra password
+/// secret`. Machines name a host with or without its port, and the
+/// `default` entry applies to the other hosts.
+pub fn from_file(path: &str, host: &str) -> AppResult<Option<(String, Option<String>)>> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| AppError::new(format!("Unable to read {}: {}", path, err)))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+            diag!(
+                "Warning: {} is readable by other users, consider chmod 600",
+                path
+            );
+        }
+    }
+    Ok(netrc_entry(&text, host))
+}
+
+fn netrc_entry(text: &str, host: &str) -> Option<(String, Option<String>)> {
+    let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    // Passwords may hold a `#`, only whole lines are comments
+    let mut tokens = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(str::split_whitespace);
+    let mut matching = false;
+    let (mut login, mut password) = (None, None);
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                if matching {
+                    break;
+                }
+                matching =
+                    token == "default" || tokens.next().is_some_and(|x| x == host || x == name);
+            }
+            "login" | "password" => {
+                let value = tokens.next().map(str::to_string);
+                if matching && token == "login" {
+                    login = value;
+                } else if matching {
+                    password = value;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some((login?, password))
+}
+
+pub fn prompt_password(prompt: &str) -> AppResult<String> {
+    Ok(rpassword::prompt_password_stderr(prompt)?)
+}
+
+pub fn prompt_line(prompt: &str) -> AppResult<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// `kass login`: prompt for the credentials of a profile and save them
+/// in the OS keychain.
+pub fn login(matches: &ArgMatches) -> AppResult<()> {
+    let profile = matches
+        .value_of("profile")
+        .ok_or_else(|| AppError::new("profile is required"))?;
+
+    let username = match matches.value_of("username") {
+        Some(x) => x.to_string(),
+        None => prompt_line("Username: ")?,
+    };
+    if username.is_empty() {
+        return Err(AppError::new("username must not be empty"));
+    }
+    let password = prompt_password("Password: ")?;
+
+    store(profile, &Credentials { username, password })?;
+    eprintln!("Credentials for profile '{}' saved", profile);
+    Ok(())
+}
+
+/// `kass config set-password`: prompt for the password of a profile and
+/// save it in the OS keychain, along with the username saved before.
+pub fn set_password(profile: &str) -> AppResult<()> {
+    let username = load(profile)?.map_or(String::new(), |x| x.username);
+    let password = prompt_password("Password: ")?;
+    if password.is_empty() {
+        return Err(AppError::new("password must not be empty"));
+    }
+    store(profile, &Credentials { username, password })?;
+    eprintln!("Password for profile '{}' saved", profile);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netrc_entry() {
+        let text = "# kass credentials\n\
+                    machine prod-host login cassandra password s3cr#t\n\
+                    machine staging:9142\n  login ops\n\
+                    default login guest password guest\n";
+        let entry = |host| netrc_entry(text, host);
+        assert_eq!(
+            Some(("cassandra".to_string(), Some("s3cr#t".to_string()))),
+            entry("prod-host:9042")
+        );
+        assert_eq!(Some(("ops".to_string(), None)), entry("staging:9142"));
+        assert_eq!(
+            Some(("guest".to_string(), Some("guest".to_string()))),
+            entry("staging:9042")
+        );
+        assert_eq!(None, netrc_entry("machine prod-host login x", "dev:9042"));
+    }
+
+    #[test]
+    fn test_saved_login() {
+        let saved = |username: &str| Credentials {
+            username: username.into(),
+            password: "s3cret".into(),
+        };
+        let login = |user: &str| Some((user.to_string(), "s3cret".to_string()));
+        assert_eq!(login("ops"), saved_login(saved("ops"), None));
+        assert_eq!(login("ops"), saved_login(saved("ops"), Some("ops")));
+        assert_eq!(None, saved_login(saved("ops"), Some("admin")));
+        assert_eq!(login("admin"), saved_login(saved(""), Some("admin")));
+        assert_eq!(None, saved_login(saved(""), None));
+    }
+}
@@ -0,0 +1,93 @@
+// CSV output: one record per row with the columns in the order of the
+// result metadata. Scalars are written as is, collections, tuples and
+// UDTs as JSON.
+use std::borrow::Cow;
+
+use cdrs::frame::frame_result::RowsMetadata;
+use serde_json::Value as JsonValue;
+
+use crate::escape;
+use crate::types::cql_type_name;
+
+fn field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn join<'a>(fields: impl Iterator<Item = Cow<'a, str>>) -> String {
+    fields.collect::<Vec<_>>().join(",")
+}
+
+/// The column names.
+pub fn header(meta: &RowsMetadata) -> String {
+    join(meta.col_specs.iter().map(|c| field(c.name.as_str())))
+}
+
+/// The CQL types of the columns, e.g. `text,bigint,list<timestamp>`.
+pub fn typed_header(meta: &RowsMetadata) -> String {
+    let types: Vec<String> = meta
+        .col_specs
+        .iter()
+        .map(|c| cql_type_name(&c.col_type))
+        .collect();
+    join(types.iter().map(|x| field(x)))
+}
+
+// `f` applied to `s`, borrowing from it when `f` does
+fn map<'a>(s: Cow<'a, str>, f: impl Fn(&str) -> Cow<'_, str>) -> Cow<'a, str> {
+    match s {
+        Cow::Borrowed(s) => f(s),
+        Cow::Owned(s) => Cow::Owned(f(&s).into_owned()),
+    }
+}
+
+fn value_field(value: Option<&JsonValue>, escape: bool) -> Cow<'_, str> {
+    let text = match value {
+        None | Some(JsonValue::Null) => return Cow::Borrowed(""),
+        Some(JsonValue::String(s)) => Cow::Borrowed(s.as_str()),
+        Some(x) => Cow::Owned(x.to_string()),
+    };
+    let text = if escape {
+        map(text, escape::text)
+    } else {
+        text
+    };
+    map(text, field)
+}
+
+/// A record with the values of `row`, a JSON object as produced by
+/// `row_to_json`. Missing columns are left empty. With `escape`, the
+/// control characters are escaped to keep the record on one line.
+pub fn record(meta: &RowsMetadata, row: &JsonValue, escape: bool) -> String {
+    join(
+        meta.col_specs
+            .iter()
+            .map(|c| value_field(row.get(c.name.as_str()), escape)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_fields() {
+        assert_eq!("abc", field("abc"));
+        assert_eq!("\"a,b\"", field("a,b"));
+        assert_eq!("\"say \"\"hi\"\"\"", field("say \"hi\""));
+        assert_eq!("", value_field(Some(&JsonValue::Null), false));
+        assert_eq!("1.5", value_field(Some(&json!(1.5)), false));
+        assert_eq!("\"[1,2]\"", value_field(Some(&json!([1, 2])), false));
+        assert_eq!("", value_field(None, false));
+        assert_eq!("\"a\nb\"", value_field(Some(&json!("a\nb")), false));
+        assert_eq!("a\\nb", value_field(Some(&json!("a\nb")), true));
+        assert_eq!(
+            "\"[\"\"a\\\\nb\"\"]\"",
+            value_field(Some(&json!(["a\nb"])), true)
+        );
+    }
+}
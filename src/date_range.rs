@@ -21,6 +21,9 @@ impl DateTimeRange {
         unit: &str,
     ) -> AppResult<DateTimeRange> {
         let step_n: u32 = step.parse()?;
+        if step_n == 0 {
+            return Err(AppError::new("the step of a range must be at least 1"));
+        }
 
         let range = if unit == "m" {
             let current_date = Some(start.date());
@@ -93,7 +96,8 @@ impl FixedInterval {
             None
         } else {
             let current = self.start;
-            self.start += self.step;
+            // The range ends past the last date chrono can represent
+            self.start = current.checked_add_signed(self.step).unwrap_or(self.end);
             Some(current)
         }
     }
@@ -123,28 +127,29 @@ impl MonthlyInterval {
     }
 }
 
-fn last_day_of_month_0(year: i32, month_0: u32) -> u32 {
+fn last_day_of_month_0(year: i32, month_0: u32) -> Option<u32> {
     last_day_of_month(year, month_0 + 1)
 }
 
-fn last_day_of_month(year: i32, month: u32) -> u32 {
-    NaiveDate::from_ymd_opt(year, month + 1, 1)
-        .unwrap_or_else(|| NaiveDate::from_ymd(year + 1, 1, 1))
-        .pred()
-        .day()
+fn last_day_of_month(year: i32, month: u32) -> Option<u32> {
+    NaiveDate::from_ymd_opt(year, month, 1)?;
+    // There is no next month after the last December chrono can represent
+    let next = NaiveDate::from_ymd_opt(year, month + 1, 1)
+        .or_else(|| NaiveDate::from_ymd_opt(year + 1, 1, 1));
+    Some(next.map_or(31, |x| x.pred().day()))
 }
 
 fn add_months_naive_date(date: NaiveDate, months: u32) -> Option<NaiveDate> {
     let next_month_0 = (date.month0() as i64).checked_add(months as i64)?;
     let additional_years = next_month_0 / 12;
     let next_month_0 = (next_month_0 % 12) as u32;
-    let additional_years = if additional_years >= (i32::max_value() as i64) {
+    let additional_years = if additional_years >= (i32::MAX as i64) {
         return None;
     } else {
         additional_years as i32
     };
     let next_year = (date.year().checked_add(additional_years))?;
-    let next_day = min(date.day(), last_day_of_month_0(next_year, next_month_0));
+    let next_day = min(date.day(), last_day_of_month_0(next_year, next_month_0)?);
     NaiveDate::from_ymd_opt(next_year, next_month_0 + 1, next_day)
 }
 
@@ -152,6 +157,8 @@ fn add_months_naive_date(date: NaiveDate, months: u32) -> Option<NaiveDate> {
 mod tests {
     use super::*;
     use chrono::NaiveDate;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     pub fn date_time(y: i32, m: u32, d: u32, hh: u32, mm: u32, ss: u32) -> NaiveDateTime {
         NaiveDate::from_ymd(y, m, d).and_hms(hh, mm, ss)
@@ -282,4 +289,42 @@ mod tests {
             .collect::<Vec<NaiveDateTime>>()
         )
     }
+
+    #[test]
+    pub fn test_add_months_clamps_to_month_end() {
+        let mut rng = StdRng::seed_from_u64(137);
+        for _ in 0..1000 {
+            let (year, month) = (rng.gen_range(1900..2100), rng.gen_range(1..=12));
+            let day = rng.gen_range(1..=last_day_of_month(year, month).unwrap());
+            let date = NaiveDate::from_ymd(year, month, day);
+            let months = rng.gen_range(0..100);
+            let next = add_months_naive_date(date, months).unwrap();
+            let month_index = |x: NaiveDate| x.year() * 12 + x.month0() as i32;
+            assert_eq!(
+                months as i32,
+                month_index(next) - month_index(date),
+                "{}",
+                date
+            );
+            let last_day = last_day_of_month(next.year(), next.month()).unwrap();
+            assert_eq!(min(day, last_day), next.day(), "{} + {}m", date, months);
+        }
+        assert_eq!(
+            Some(NaiveDate::from_ymd(2020, 2, 29)),
+            add_months_naive_date(NaiveDate::from_ymd(2019, 12, 31), 2)
+        );
+        assert_eq!(
+            None,
+            add_months_naive_date(NaiveDate::from_ymd(262_000, 1, 1), u32::MAX)
+        );
+    }
+
+    #[test]
+    pub fn test_range_past_the_last_date() {
+        let range = DateTimeRange::parse_date_strs("2019-09-01", "2019-10-01", "4000000000", "d");
+        assert_eq!(1, range.unwrap().count());
+        let range = DateTimeRange::parse_date_strs("2019-09-01", "2019-10-01", "999999999", "m");
+        assert_eq!(1, range.unwrap().count());
+        assert!(DateTimeRange::parse_date_strs("2019-09-01", "2019-10-01", "0", "d").is_err());
+    }
 }
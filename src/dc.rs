@@ -0,0 +1,191 @@
+// The nodes of the cluster, discovered through the host given with `-h`
+// so that the queries are spread over them in turn rather than all sent
+// to that host. `--local-dc` keeps those of one data center only, that
+// host possibly belonging to another one, and `--only-host` and
+// `--exclude-host` pick nodes by their address, e.g. to avoid a sick one.
+use cdrs::consistency::Consistency;
+
+use crate::backend::{Backend, Params};
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+
+/// The `host:port` addresses of the nodes of `dc`, or of all the nodes
+/// without one, `contact` being the host the cluster was reached with.
+pub fn nodes(
+    session: &dyn Backend,
+    contact: &str,
+    dc: Option<&str>,
+    config: &Config,
+) -> AppResult<Vec<String>> {
+    let port = contact.rsplit(':').next().unwrap_or("9042");
+    let mut nodes = vec![];
+    let mut dcs = vec![];
+    for table in ["local", "peers"] {
+        let cql = format!("SELECT data_center, rpc_address FROM system.{}", table);
+        let resp = session.query(&cql, Params::new(vec![], Consistency::One))?;
+        for row in core::collect_rows(&resp, config)? {
+            let row_dc = row["data_center"].as_str().unwrap_or_default();
+            if dc.is_none_or(|dc| dc == row_dc) {
+                nodes.push(match (table, row["rpc_address"].as_str()) {
+                    // The address the contact host is known by may not be
+                    // reachable, e.g. 0.0.0.0
+                    ("local", _) => contact.to_string(),
+                    (_, Some(address)) => node_address(address, port),
+                    _ => continue,
+                });
+            } else if !dcs.contains(&row_dc.to_string()) {
+                dcs.push(row_dc.to_string());
+            }
+        }
+    }
+    if let (true, Some(dc)) = (nodes.is_empty(), dc) {
+        return Err(AppError::new(format!(
+            "No nodes in data center {}, the cluster has: {}",
+            dc,
+            dcs.join(", ")
+        )));
+    }
+    Ok(nodes)
+}
+
+/// The nodes picked with `--only-host` and `--exclude-host`, by their
+/// address with or without the port.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostFilter {
+    pub only: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl HostFilter {
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty()
+    }
+
+    fn matches(hosts: &[String], node: &str) -> bool {
+        let address = match node.rsplit_once(':') {
+            Some((address, _)) => address.trim_start_matches('[').trim_end_matches(']'),
+            None => node,
+        };
+        hosts.iter().any(|x| x == node || x == address)
+    }
+
+    pub fn apply(&self, nodes: Vec<String>) -> AppResult<Vec<String>> {
+        let (picked, left): (Vec<String>, Vec<String>) = nodes.into_iter().partition(|node| {
+            (self.only.is_empty() || HostFilter::matches(&self.only, node))
+                && !HostFilter::matches(&self.exclude, node)
+        });
+        if picked.is_empty() {
+            return Err(AppError::new(format!(
+                "No nodes left with --only-host and --exclude-host, the cluster has: {}",
+                left.join(", ")
+            )));
+        }
+        Ok(picked)
+    }
+}
+
+/// The `host:port` address of `host`, given as `HOST`, `HOST:PORT`,
+/// an IPv6 address or `[IPV6]:PORT`, with `port` when it has none.
+pub fn host_port(host: &str, port: Option<&str>) -> AppResult<String> {
+    let invalid = |reason: &str| AppError::new(format!("Invalid host {}: {}", host, reason));
+    let (address, host_port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let (address, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| invalid("missing ]"))?;
+            match rest {
+                "" => (address, None),
+                _ => (
+                    address,
+                    Some(rest.strip_prefix(':').ok_or_else(|| invalid("expected :PORT after ]"))?),
+                ),
+            }
+        }
+        // IPv6 without a port
+        None if host.matches(':').count() > 1 => (host, None),
+        None => match host.split_once(':') {
+            Some((address, port)) => (address, Some(port)),
+            None => (host, None),
+        },
+    };
+    if address.is_empty() {
+        return Err(invalid("no address"));
+    }
+    let port = match (host_port, port) {
+        (Some(x), Some(y)) if x != y => {
+            return Err(invalid(&format!("the port conflicts with --port {}", y)))
+        }
+        (Some(x), _) | (None, Some(x)) => x,
+        (None, None) => "9042",
+    };
+    if port.parse::<u16>().is_err() {
+        return Err(invalid(&format!("invalid port {}", port)));
+    }
+    Ok(node_address(address, port))
+}
+
+pub fn node_address(address: &str, port: &str) -> String {
+    if address.contains(':') {
+        // IPv6
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_address() {
+        assert_eq!("10.0.0.1:9042", node_address("10.0.0.1", "9042"));
+        assert_eq!("[::1]:9142", node_address("::1", "9142"));
+    }
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!("localhost:9042", host_port("localhost", None).unwrap());
+        assert_eq!("10.0.0.1:9142", host_port("10.0.0.1:9142", None).unwrap());
+        assert_eq!("10.0.0.1:9142", host_port("10.0.0.1", Some("9142")).unwrap());
+        assert_eq!("[2001:db8::1]:9042", host_port("[2001:db8::1]", None).unwrap());
+        assert_eq!("[2001:db8::1]:9042", host_port("2001:db8::1", None).unwrap());
+        assert_eq!(
+            "[2001:db8::1]:9142",
+            host_port("[2001:db8::1]:9142", Some("9142")).unwrap()
+        );
+        assert_eq!("[::1]:19042", host_port("::1", Some("19042")).unwrap());
+        for host in ["[::1", "[::1]9042", "host:port", ":9042", "host:70000"] {
+            assert!(host_port(host, None).is_err(), "{}", host);
+        }
+        assert!(host_port("host:9042", Some("9142")).is_err());
+    }
+
+    #[test]
+    fn test_host_filter() {
+        let nodes = || {
+            ["10.0.0.1:9042", "10.0.0.2:9042", "[::1]:9042"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+        };
+        let filter = HostFilter {
+            only: vec![],
+            exclude: vec!["10.0.0.2".into(), "::1".into()],
+        };
+        assert_eq!(vec!["10.0.0.1:9042"], filter.apply(nodes()).unwrap());
+        let filter = HostFilter {
+            only: vec!["10.0.0.2:9042".into(), "[::1]:9042".into()],
+            exclude: vec![],
+        };
+        assert_eq!(
+            vec!["10.0.0.2:9042", "[::1]:9042"],
+            filter.apply(nodes()).unwrap()
+        );
+        let filter = HostFilter {
+            only: vec!["10.0.0.9".into()],
+            exclude: vec![],
+        };
+        assert!(filter.apply(nodes()).is_err());
+    }
+}
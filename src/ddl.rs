@@ -0,0 +1,164 @@
+// `CREATE TABLE` statements for loading exported rows into other
+// databases. Collections, tuples and UDTs are exported as JSON, they
+// map to the JSON type of the target when there is one.
+use cdrs::frame::frame_result::{ColType, RowsMetadata};
+
+use crate::errors::{AppError, AppResult};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Postgres,
+    Sqlite,
+    BigQuery,
+    Hive,
+}
+
+pub const TARGETS: &[&str] = &["postgres", "sqlite", "bigquery", "hive"];
+
+impl Target {
+    pub fn parse(s: &str) -> AppResult<Target> {
+        match s {
+            "postgres" => Ok(Target::Postgres),
+            "sqlite" => Ok(Target::Sqlite),
+            "bigquery" => Ok(Target::BigQuery),
+            "hive" => Ok(Target::Hive),
+            _ => Err(AppError::new(format!(
+                "Unknown DDL target '{}', expected one of: {}",
+                s,
+                TARGETS.join(", ")
+            ))),
+        }
+    }
+
+    fn quote(self, name: &str) -> String {
+        match self {
+            Target::Postgres | Target::Sqlite => format!("\"{}\"", name.replace('"', "\"\"")),
+            Target::BigQuery | Target::Hive => format!("`{}`", name.replace('`', "")),
+        }
+    }
+
+    fn column_type(self, col_type: &ColType) -> &'static str {
+        use ColType::*;
+        match self {
+            Target::Postgres => match col_type {
+                Bigint | Counter => "bigint",
+                Int => "integer",
+                Smallint | Tinyint => "smallint",
+                Varint | Decimal => "numeric",
+                Double => "double precision",
+                Float => "real",
+                Boolean => "boolean",
+                Blob => "bytea",
+                Timestamp => "timestamptz",
+                Date => "date",
+                Time => "time",
+                Uuid | Timeuuid => "uuid",
+                Inet => "inet",
+                List | Set | Map | Udt | Tuple => "jsonb",
+                Ascii | Varchar | Custom | Null => "text",
+            },
+            Target::Sqlite => match col_type {
+                Bigint | Counter | Int | Smallint | Tinyint | Varint | Boolean => "INTEGER",
+                Double | Float => "REAL",
+                Decimal => "NUMERIC",
+                Blob => "BLOB",
+                _ => "TEXT",
+            },
+            Target::BigQuery => match col_type {
+                Bigint | Counter | Int | Smallint | Tinyint => "INT64",
+                Varint | Decimal => "BIGNUMERIC",
+                Double | Float => "FLOAT64",
+                Boolean => "BOOL",
+                Blob => "BYTES",
+                Timestamp => "TIMESTAMP",
+                Date => "DATE",
+                Time => "TIME",
+                List | Set | Map | Udt | Tuple => "JSON",
+                _ => "STRING",
+            },
+            Target::Hive => match col_type {
+                Bigint | Counter => "BIGINT",
+                Int => "INT",
+                Smallint => "SMALLINT",
+                Tinyint => "TINYINT",
+                Varint => "DECIMAL(38,0)",
+                Decimal => "DECIMAL(38,18)",
+                Double => "DOUBLE",
+                Float => "FLOAT",
+                Boolean => "BOOLEAN",
+                Blob => "BINARY",
+                Timestamp => "TIMESTAMP",
+                Date => "DATE",
+                _ => "STRING",
+            },
+        }
+    }
+}
+
+fn table_name(meta: &RowsMetadata) -> String {
+    meta.global_table_space
+        .as_ref()
+        .and_then(|spec| spec.get(1))
+        .or_else(|| meta.col_specs.first().and_then(|c| c.tablename.as_ref()))
+        .map(|name| name.as_plain())
+        .unwrap_or_else(|| "kass_export".to_string())
+}
+
+/// A `CREATE TABLE` statement for rows with the given metadata.
+pub fn create_table(meta: &RowsMetadata, target: Target) -> String {
+    let columns: Vec<String> = meta
+        .col_specs
+        .iter()
+        .map(|c| {
+            format!(
+                "  {} {}",
+                target.quote(c.name.as_str()),
+                target.column_type(&c.col_type.id)
+            )
+        })
+        .collect();
+    format!(
+        "CREATE TABLE {} (\n{}\n);",
+        target.quote(&table_name(meta)),
+        columns.join(",\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdrs::frame::frame_result::{ColSpec, ColTypeOption};
+    use cdrs::types::CString;
+
+    fn metadata() -> RowsMetadata {
+        let col = |name: &str, id| ColSpec {
+            ksname: None,
+            tablename: None,
+            name: CString::new(name.into()),
+            col_type: ColTypeOption { id, value: None },
+        };
+        RowsMetadata {
+            flags: 0,
+            columns_count: 2,
+            paging_state: None,
+            global_table_space: Some(vec![
+                CString::new("ks".into()),
+                CString::new("users".into()),
+            ]),
+            col_specs: vec![col("id", ColType::Uuid), col("tags", ColType::Set)],
+        }
+    }
+
+    #[test]
+    fn test_create_table() {
+        assert_eq!(
+            "CREATE TABLE \"users\" (\n  \"id\" uuid,\n  \"tags\" jsonb\n);",
+            create_table(&metadata(), Target::Postgres)
+        );
+        assert_eq!(
+            "CREATE TABLE `users` (\n  `id` STRING,\n  `tags` JSON\n);",
+            create_table(&metadata(), Target::BigQuery)
+        );
+        assert!(Target::parse("oracle").is_err());
+    }
+}
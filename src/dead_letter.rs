@@ -0,0 +1,94 @@
+// `--dead-letter errors.jsonl`: keep the rows that can't be decoded or
+// formatted rather than dropping them with an error message. Each line
+// holds the error along with the name, CQL type and raw bytes (hex) of
+// every column, e.g. to decode the rows by other means later.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cdrs::frame::frame_result::RowsMetadata;
+use cdrs::types::CBytes;
+use itertools::Itertools;
+use serde_json::{json, Value as JsonValue};
+
+use crate::errors::{AppError, AppResult};
+use crate::sink::{FileSink, Sink};
+use crate::types::cql_type_name;
+
+pub struct DeadLetter {
+    path: String,
+    sink: FileSink,
+    rows: AtomicUsize,
+}
+
+fn record(meta: &RowsMetadata, row: &[CBytes], err: &AppError) -> JsonValue {
+    let columns: Vec<JsonValue> = meta
+        .col_specs
+        .iter()
+        .zip(row)
+        .map(|(spec, bytes)| {
+            json!({
+                "name": spec.name.as_plain(),
+                "type": cql_type_name(&spec.col_type),
+                "bytes": bytes.as_slice().map(|x| format!("{:02x}", x.iter().format(""))),
+            })
+        })
+        .collect();
+    json!({ "error": err.to_string(), "columns": columns })
+}
+
+impl DeadLetter {
+    pub fn create(path: &str) -> AppResult<DeadLetter> {
+        Ok(DeadLetter {
+            path: path.to_string(),
+            sink: FileSink::create(path, None)?,
+            rows: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn write(&self, meta: &RowsMetadata, row: &[CBytes], err: &AppError) -> AppResult<()> {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+        self.sink.write_row(&record(meta, row, err).to_string())
+    }
+
+    /// Tell how many rows were kept, if any.
+    pub fn finish(&self) {
+        match self.rows.load(Ordering::Relaxed) {
+            0 => {}
+            n => diag!("{} rows that couldn't be written are in {}", n, self.path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdrs::frame::frame_result::{ColSpec, ColType, ColTypeOption};
+    use cdrs::types::CString;
+
+    #[test]
+    fn test_record() {
+        let col = |name: &str, id| ColSpec {
+            ksname: None,
+            tablename: None,
+            name: CString::new(name.into()),
+            col_type: ColTypeOption { id, value: None },
+        };
+        let meta = RowsMetadata {
+            flags: 0,
+            columns_count: 2,
+            paging_state: None,
+            global_table_space: None,
+            col_specs: vec![col("id", ColType::Int), col("at", ColType::Timestamp)],
+        };
+        let row = vec![CBytes::new(vec![0, 0, 0, 1]), CBytes::new_empty()];
+        assert_eq!(
+            json!({
+                "error": "Invalid timestamp",
+                "columns": [
+                    { "name": "id", "type": "int", "bytes": "00000001" },
+                    { "name": "at", "type": "timestamp", "bytes": null },
+                ],
+            }),
+            record(&meta, &row, &AppError::new("Invalid timestamp"))
+        );
+    }
+}
@@ -0,0 +1,26 @@
+// The rows go to stdout, or to the files and commands of the sinks, and
+// everything else kass has to say goes to stderr through `diag!`, so
+// that piping the rows never mixes them with messages: progress,
+// summaries, warnings and the errors that don't fail the run.
+// `--quiet` silences those messages, leaving the error failing the run
+// and the exit status.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `eprintln!` unless `--quiet`.
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if !$crate::diag::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
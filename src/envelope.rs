@@ -0,0 +1,84 @@
+// `--envelope`: each row wrapped along with where it comes from, the
+// version of this layout being bumped whenever it changes so that the
+// consumers of the output don't depend on what kass adds to the rows:
+//
+//     {"v": 1, "source": {"cluster": "prod", "keyspace": "ks", "table": "users"},
+//      "params": ["1"], "row": {"id": 1, "name": "alice"}}
+//
+// The keyspace and table are null for results that aren't those of a
+// table, and the parameters are redacted with `--redact-params`.
+use std::sync::Mutex;
+
+use cdrs::frame::frame_result::RowsMetadata;
+use serde_json::{json, Value as JsonValue};
+
+use crate::core::{self, Config, CurrentSession};
+use crate::errors::AppResult;
+use crate::schema;
+
+pub const VERSION: u64 = 1;
+
+#[derive(Debug, Default)]
+pub struct Envelope {
+    // The name of the cluster, looked up once
+    cluster: Mutex<Option<JsonValue>>,
+}
+
+/// The envelope of the rows of a result.
+pub struct ResultEnvelope {
+    source: JsonValue,
+    params: JsonValue,
+}
+
+impl Envelope {
+    /// The envelope of the rows of a result of `meta`, for the parameters
+    /// labelled `params`.
+    pub fn result(
+        &self,
+        session: &CurrentSession,
+        meta: &RowsMetadata,
+        params: &[String],
+        config: &Config,
+    ) -> AppResult<ResultEnvelope> {
+        let mut cluster = self.cluster.lock().unwrap();
+        if cluster.is_none() {
+            let resp = session.query(
+                "SELECT cluster_name FROM system.local",
+                config.params(vec![]),
+            )?;
+            let rows = core::collect_rows(&resp, config)?;
+            *cluster = Some(
+                rows.first()
+                    .map_or(JsonValue::Null, |x| x["cluster_name"].clone()),
+            );
+        }
+        let (keyspace, table) = schema::result_table(meta).unzip();
+        Ok(ResultEnvelope {
+            source: json!({ "cluster": cluster.clone(), "keyspace": keyspace, "table": table }),
+            params: json!(params),
+        })
+    }
+}
+
+impl ResultEnvelope {
+    pub fn wrap(&self, row: JsonValue) -> JsonValue {
+        json!({ "v": VERSION, "source": self.source, "params": self.params, "row": row })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        let envelope = ResultEnvelope {
+            source: json!({"cluster": "prod", "keyspace": "ks", "table": null}),
+            params: json!(["1"]),
+        };
+        assert_eq!(
+            r#"{"v":1,"source":{"cluster":"prod","keyspace":"ks","table":null},"params":["1"],"row":{"id":1}}"#,
+            envelope.wrap(json!({"id": 1})).to_string()
+        );
+    }
+}
@@ -1,10 +1,13 @@
 extern crate cdrs;
 
 use cdrs::error::Error as CDRSError;
+use keyring::Error as KeyringError;
+use openssl::error::ErrorStack as SslError;
 use serde_json::Error as JsonError;
 use std::convert::From;
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
 use std::num::ParseIntError;
 use std::result::Result;
 use chrono::ParseError as DateTimeParseError;
@@ -51,3 +54,21 @@ impl From<DateTimeParseError> for AppError {
         AppError(format!("Error parsing date time: {}", err))
     }
 }
+
+impl From<IoError> for AppError {
+    fn from(err: IoError) -> Self {
+        AppError(format!("IO error: {}", err))
+    }
+}
+
+impl From<KeyringError> for AppError {
+    fn from(err: KeyringError) -> Self {
+        AppError(format!("Error accessing OS keychain: {}", err))
+    }
+}
+
+impl From<SslError> for AppError {
+    fn from(err: SslError) -> Self {
+        AppError(format!("TLS error: {}", err))
+    }
+}
@@ -0,0 +1,65 @@
+// `--escape-control-chars`: keep one row per line whatever the text
+// columns hold. JSON already escapes the ASCII control characters, so
+// only DEL, the C1 controls and the Unicode line and paragraph
+// separators, which some JSONL readers split lines on, are left to
+// escape as `\uXXXX`. CSV fields get the same escapes as JSON strings,
+// backslashes included, instead of being quoted across lines.
+use std::borrow::Cow;
+use std::fmt::Write;
+
+fn is_control(c: char) -> bool {
+    c.is_control() || c == '\u{2028}' || c == '\u{2029}'
+}
+
+/// `json` serialized with every control character escaped.
+pub fn json(json: &str) -> Cow<'_, str> {
+    // The ASCII ones outside strings are whitespace of --pretty
+    let escape = |c: char| is_control(c) && c > '\u{7e}';
+    if !json.contains(escape) {
+        return Cow::Borrowed(json);
+    }
+    let mut out = String::with_capacity(json.len());
+    for c in json.chars() {
+        if escape(c) {
+            write!(out, "\\u{:04x}", c as u32).unwrap();
+        } else {
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// The text of a CSV field with its control characters and backslashes
+/// escaped as in a JSON string.
+pub fn text(s: &str) -> Cow<'_, str> {
+    if !s.contains(|c| is_control(c) || c == '\\') {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if is_control(c) => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_escape() {
+        let row = json!({ "note": "a\nb\u{7f}\u{2028}" }).to_string();
+        assert_eq!(r#"{"note":"a\nb\u007f\u2028"}"#, json(&row));
+        assert_eq!("{\n  \"id\": 1\n}", json("{\n  \"id\": 1\n}"));
+        assert_eq!("abc", text("abc"));
+        assert_eq!(r"a\tb\r\nc\\d\u0085", text("a\tb\r\nc\\d\u{85}"));
+    }
+}
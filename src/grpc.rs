@@ -0,0 +1,172 @@
+use std::cmp::min;
+use std::sync::Arc;
+use std::thread;
+
+use prost_types::{value::Kind, ListValue, Struct, Value};
+use serde_json::Value as JsonValue;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport, Request, Response, Status};
+
+use crate::control::PauseControl;
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+use crate::server::Slots;
+use crate::sink::Sink;
+
+mod proto {
+    tonic::include_proto!("kass");
+}
+
+use proto::kass_server::{Kass, KassServer};
+use proto::{QueryRequest, Row};
+
+type RowResult = Result<Row, Status>;
+
+// The integers a double holds without losing precision
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+fn to_proto_value(json: JsonValue) -> Value {
+    let kind = match json {
+        JsonValue::Null => Kind::NullValue(0),
+        JsonValue::Bool(x) => Kind::BoolValue(x),
+        // Larger bigints, varints and counters are strings, like decimals
+        JsonValue::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(x), _, _) if x.unsigned_abs() <= MAX_SAFE_INTEGER => Kind::NumberValue(x as f64),
+            (None, Some(x), _) if x <= MAX_SAFE_INTEGER => Kind::NumberValue(x as f64),
+            (None, None, Some(x)) => Kind::NumberValue(x),
+            _ => Kind::StringValue(n.to_string()),
+        },
+        JsonValue::String(s) => Kind::StringValue(s),
+        JsonValue::Array(xs) => Kind::ListValue(ListValue {
+            values: xs.into_iter().map(to_proto_value).collect(),
+        }),
+        JsonValue::Object(obj) => Kind::StructValue(Struct {
+            fields: obj
+                .into_iter()
+                .map(|(k, v)| (k, to_proto_value(v)))
+                .collect(),
+        }),
+    };
+    Value { kind: Some(kind) }
+}
+
+fn to_row(json: JsonValue) -> Row {
+    let columns = match to_proto_value(json).kind {
+        Some(Kind::StructValue(s)) => s,
+        _ => Struct::default(),
+    };
+    Row {
+        columns: Some(columns),
+    }
+}
+
+// Sends the rows of a run to the response stream. Rows arrive already
+// formatted as JSON, so they are parsed back into a `Struct`.
+struct StreamSink(mpsc::Sender<RowResult>);
+
+impl Sink for StreamSink {
+    fn write_row(&self, row: &str) -> AppResult<()> {
+        let json: JsonValue = serde_json::from_str(row)?;
+        self.0
+            .blocking_send(Ok(to_row(json)))
+            .map_err(|_| AppError::new("Result consumer disconnected"))
+    }
+}
+
+struct KassService {
+    config: Config,
+    slots: Arc<Slots>,
+}
+
+#[tonic::async_trait]
+impl Kass for KassService {
+    type RunQueryStream = ReceiverStream<RowResult>;
+
+    async fn run_query(
+        &self,
+        request: Request<QueryRequest>,
+    ) -> Result<Response<Self::RunQueryStream>, Status> {
+        let req = request.into_inner();
+        let param_values = if req.params.is_empty() {
+            None
+        } else {
+            Some(
+                self.config
+                    .parse_params(req.params.iter().map(String::as_str))
+                    .map_err(|err| Status::invalid_argument(err.to_string()))?,
+            )
+        };
+
+        let (tx, rx) = mpsc::channel(128);
+        let mut config = self.config.with_sink(Arc::new(StreamSink(tx.clone())));
+        // --parallelism of the server is the upper bound for every run,
+        // as through HTTP
+        if req.parallelism > 0 {
+            config.set_parallelism(min(req.parallelism as usize, self.config.parallelism()));
+        }
+
+        let slots = self.slots.clone();
+        thread::spawn(move || {
+            let control = PauseControl::default();
+            let _slot = slots.acquire(&control);
+            if let Err(err) = core::run_query(config, &req.query, param_values) {
+                let _ = tx.blocking_send(Err(Status::internal(err.to_string())));
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Serve the `Kass` gRPC service defined in `proto/kass.proto`. Blocks
+/// until the server shuts down.
+pub fn serve(listen: &str, config: Config, slots: Arc<Slots>) -> AppResult<()> {
+    let addr = listen
+        .parse()
+        .map_err(|err| AppError::new(format!("Invalid gRPC address {}: {}", listen, err)))?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    diag!("Listening for gRPC on {}", listen);
+
+    runtime
+        .block_on(
+            transport::Server::builder()
+                .add_service(KassServer::new(KassService { config, slots }))
+                .serve(addr),
+        )
+        .map_err(|err| AppError::new(format!("gRPC server error: {}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_row() {
+        let row = to_row(json!({"id": 1, "tags": ["a"], "extra": null}));
+        let fields = row.columns.unwrap().fields;
+        assert_eq!(Some(Kind::NumberValue(1.0)), fields["id"].kind);
+        assert_eq!(Some(Kind::NullValue(0)), fields["extra"].kind);
+        assert_eq!(
+            Some(Kind::ListValue(ListValue {
+                values: vec![Value {
+                    kind: Some(Kind::StringValue("a".into()))
+                }]
+            })),
+            fields["tags"].kind
+        );
+
+        // Past 2^53, integers are strings
+        let row = to_row(json!({"a": 9007199254740993u64, "b": -9007199254740993i64, "c": 1.5}));
+        let fields = row.columns.unwrap().fields;
+        assert_eq!(
+            Some(Kind::StringValue("9007199254740993".into())),
+            fields["a"].kind
+        );
+        assert_eq!(
+            Some(Kind::StringValue("-9007199254740993".into())),
+            fields["b"].kind
+        );
+        assert_eq!(Some(Kind::NumberValue(1.5)), fields["c"].kind);
+    }
+}
@@ -0,0 +1,209 @@
+// `--max-pending-reads N`: keep large runs from overloading the cluster.
+// The reads pending on the nodes are polled while the run goes on, and
+// the number of queries in flight is halved while they exceed `N`, then
+// raised back one at a time up to `--parallelism` once they are below
+// half of it.
+//
+// The pending reads are those of the `ReadStage` thread pool, read from
+// `system_views.thread_pools` (Cassandra 4.0 and later) of the node
+// answering, or from the Prometheus metrics at `--impact-metrics-url`,
+// e.g. those of a JMX exporter.
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::Params;
+use crate::core::{self, Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const PENDING_READS_CQL: &str =
+    "SELECT pending_tasks FROM system_views.thread_pools WHERE name = 'ReadStage'";
+
+pub struct ImpactGuard {
+    max_pending: u64,
+    max_parallelism: usize,
+    // The queries allowed in flight and those in flight
+    slots: Mutex<(usize, usize)>,
+    freed: Condvar,
+}
+
+/// A query in flight, until dropped.
+pub struct Slot<'a>(&'a ImpactGuard);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.slots.lock().unwrap().1 -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+impl ImpactGuard {
+    /// Poll `pending_reads` until the guard is dropped, adjusting the
+    /// queries allowed in flight.
+    pub fn start<F>(max_pending: u64, max_parallelism: usize, pending_reads: F) -> Arc<ImpactGuard>
+    where
+        F: Fn() -> AppResult<u64> + Send + 'static,
+    {
+        let guard = Arc::new(ImpactGuard {
+            max_pending,
+            max_parallelism,
+            slots: Mutex::new((max_parallelism, 0)),
+            freed: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&guard);
+        thread::spawn(move || poll(weak, pending_reads));
+        guard
+    }
+
+    /// Wait for a query to be allowed in flight.
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut slots = self.slots.lock().unwrap();
+        while slots.1 >= slots.0 {
+            slots = self.freed.wait(slots).unwrap();
+        }
+        slots.1 += 1;
+        Slot(self)
+    }
+
+    // The queries allowed in flight with `pending` reads, when changed
+    fn adjust(&self, pending: u64) -> Option<usize> {
+        let mut slots = self.slots.lock().unwrap();
+        let limit = if pending > self.max_pending {
+            (slots.0 / 2).max(1)
+        } else if pending < self.max_pending / 2 {
+            (slots.0 + 1).min(self.max_parallelism)
+        } else {
+            slots.0
+        };
+        if limit == slots.0 {
+            return None;
+        }
+        slots.0 = limit;
+        self.freed.notify_all();
+        Some(limit)
+    }
+}
+
+fn poll<F: Fn() -> AppResult<u64>>(guard: Weak<ImpactGuard>, pending_reads: F) {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let guard = match guard.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+        let pending = match pending_reads() {
+            Ok(x) => x,
+            Err(err) => {
+                diag!(
+                    "Unable to read the pending reads, --max-pending-reads is ignored: {}",
+                    err
+                );
+                return;
+            }
+        };
+        match guard.adjust(pending) {
+            Some(limit) if pending > guard.max_pending => diag!(
+                "{} pending reads on the cluster, lowering the parallelism to {}",
+                pending, limit
+            ),
+            Some(limit) if limit == guard.max_parallelism => {
+                diag!("Parallelism back to {}", limit)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The pending reads of the node answering, from its virtual tables.
+pub fn system_pending_reads(session: &CurrentSession, config: &Config) -> AppResult<u64> {
+    let resp = session.query(PENDING_READS_CQL, Params::default())?;
+    core::collect_rows(&resp, config)?
+        .first()
+        .and_then(|row| row["pending_tasks"].as_u64())
+        .ok_or_else(|| AppError::new("No ReadStage pool in system_views.thread_pools"))
+}
+
+/// The pending reads of the `ReadStage` thread pool in Prometheus
+/// metrics: the sum of the samples naming the pool with `pending` in
+/// their name, e.g. `cassandra_threadpools_pendingtasks{pool="ReadStage"}`.
+fn pending_in_metrics(metrics: &str) -> Option<u64> {
+    let samples: Vec<f64> = metrics
+        .lines()
+        .filter(|line| !line.starts_with('#') && line.contains("ReadStage"))
+        .filter(|line| {
+            let name = line.split(['{', ' ']).next().unwrap_or_default();
+            name.to_lowercase().contains("pending")
+        })
+        .filter_map(|line| {
+            line.rsplit_once('}')?
+                .1
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        })
+        .collect();
+    if samples.is_empty() {
+        None
+    } else {
+        Some(samples.iter().sum::<f64>() as u64)
+    }
+}
+
+/// Fetch the pending reads from the Prometheus metrics at `url`.
+pub fn fetch_pending_reads(url: &str) -> AppResult<u64> {
+    let invalid = || {
+        AppError::new(format!(
+            "Invalid metrics URL {}, expected http://host:port/path",
+            url
+        ))
+    };
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/metrics"),
+    };
+    let mut stream = TcpStream::connect(host)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    // HTTP/1.0 for a response without chunked encoding
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(AppError::new(format!("{} returned {}", url, status)));
+    }
+    pending_in_metrics(body)
+        .ok_or_else(|| AppError::new(format!("No pending tasks of the ReadStage pool in {}", url)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjust() {
+        let guard = ImpactGuard::start(100, 8, || Ok(0));
+        assert_eq!(Some(4), guard.adjust(500));
+        assert_eq!(Some(2), guard.adjust(500));
+        assert_eq!(None, guard.adjust(80));
+        assert_eq!(Some(3), guard.adjust(10));
+        let _slots = (guard.acquire(), guard.acquire(), guard.acquire());
+        assert_eq!(3, guard.slots.lock().unwrap().1);
+    }
+
+    #[test]
+    fn test_pending_in_metrics() {
+        let metrics = "# TYPE cassandra_threadpools_pendingtasks gauge\n\
+                       cassandra_threadpools_pendingtasks{pool=\"ReadStage\"} 42.0\n\
+                       cassandra_threadpools_activetasks{pool=\"ReadStage\"} 8.0\n\
+                       cassandra_threadpools_pendingtasks{pool=\"MutationStage\"} 7.0\n";
+        assert_eq!(Some(42), pending_in_metrics(metrics));
+        assert_eq!(None, pending_in_metrics("up 1\n"));
+    }
+}
@@ -0,0 +1,563 @@
+// `kass import`: load NDJSON rows, e.g. the output of kass itself,
+// into a table with `INSERT ... JSON`. Rows are dispatched to workers
+// by partition key so that the mutations of a partition are applied in
+// the order they are read.
+//
+// With `--preserve-ttl` and `--preserve-timestamp`, the rows come with
+// the TTL and write time of their columns, e.g. as selected by
+// `SELECT id, a, TTL(a), WRITETIME(a) ...`, and the columns are written
+// with `USING TTL ? AND TIMESTAMP ?` so that they expire the same way.
+// The columns sharing a TTL and timestamp are written together. A single
+// cell column without its entries fails the import rather than being
+// written without a TTL or with the current time, which would keep it
+// forever or let it overwrite newer data.
+//
+// `--only-columns` and `--exclude-columns` write a subset of the columns
+// of the table, checked against its schema. The other columns of the
+// rows are dropped and the columns of existing rows left untouched.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+
+use cdrs::types::value::Value;
+use serde_json::{json, Map, Value as JsonValue};
+
+use crate::backend::Prepared;
+use crate::core::{self, Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+use crate::schema::{self, unquote};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Columns missing from a row are set to null
+    Insert,
+    /// Columns missing from a row are left untouched
+    Upsert,
+    /// Lightweight transaction, existing rows are reported as conflicts
+    InsertIfNotExists,
+}
+
+pub const MODES: &[&str] = &["insert", "upsert", "insert-if-not-exists"];
+
+impl Mode {
+    pub fn parse(s: &str) -> AppResult<Mode> {
+        match s {
+            "insert" => Ok(Mode::Insert),
+            "upsert" => Ok(Mode::Upsert),
+            "insert-if-not-exists" => Ok(Mode::InsertIfNotExists),
+            _ => Err(AppError::new(format!("Unknown import mode: {}", s))),
+        }
+    }
+
+    fn statement(self, table: &str) -> String {
+        match self {
+            Mode::Insert => format!("INSERT INTO {} JSON ?", table),
+            Mode::Upsert => format!("INSERT INTO {} JSON ? DEFAULT UNSET", table),
+            Mode::InsertIfNotExists => format!("INSERT INTO {} JSON ? IF NOT EXISTS", table),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub mode: Mode,
+    pub preserve_ttl: bool,
+    pub preserve_timestamp: bool,
+    pub only_columns: Vec<String>,
+    pub exclude_columns: Vec<String>,
+}
+
+impl Options {
+    fn preserve(&self) -> bool {
+        self.preserve_ttl || self.preserve_timestamp
+    }
+
+    fn partial(&self) -> bool {
+        !self.only_columns.is_empty() || !self.exclude_columns.is_empty()
+    }
+
+    /// The columns to write, from the columns of the table, or `None`
+    /// for all of them.
+    fn columns(&self, table: &[schema::Column]) -> AppResult<Option<Vec<String>>> {
+        if !self.partial() {
+            return Ok(None);
+        }
+        for col in self.only_columns.iter().chain(self.exclude_columns.iter()) {
+            if !table.iter().any(|x| x.name == *col) {
+                return Err(AppError::new(format!("Unknown column: {}", col)));
+            }
+        }
+        let mut columns = vec![];
+        for col in table {
+            let key = col.kind == "partition_key" || col.kind == "clustering";
+            let excluded = self.exclude_columns.contains(&col.name);
+            if key && excluded {
+                return Err(AppError::new(format!(
+                    "The primary key column {} can't be excluded",
+                    col.name
+                )));
+            }
+            if key
+                || (!excluded
+                    && (self.only_columns.is_empty() || self.only_columns.contains(&col.name)))
+            {
+                columns.push(col.name.clone());
+            }
+        }
+        Ok(Some(columns))
+    }
+
+    // The columns missing from a part of a row are left untouched, as
+    // they are written by the other parts
+    fn statement(&self, table: &str, timestamp: bool) -> String {
+        if !self.preserve() {
+            return match self.mode {
+                Mode::Insert if self.partial() => Mode::Upsert.statement(table),
+                mode => mode.statement(table),
+            };
+        }
+        let using = match (self.preserve_ttl, timestamp) {
+            (true, true) => " USING TTL ? AND TIMESTAMP ?",
+            (true, false) => " USING TTL ?",
+            (false, true) => " USING TIMESTAMP ?",
+            (false, false) => "",
+        };
+        format!("{}{}", Mode::Upsert.statement(table), using)
+    }
+}
+
+/// The columns of a row written with the same TTL and timestamp.
+#[derive(Debug, PartialEq)]
+struct Part {
+    columns: Map<String, JsonValue>,
+    ttl: Option<i64>,
+    timestamp: Option<i64>,
+}
+
+// Split `row` by the TTL and write time of its columns, given by the
+// `ttl(column)` and `writetime(column)` entries, which the `single_cell`
+// columns must have. Null values have neither and don't expire, and the
+// collections have none of their own.
+fn split_row(
+    row: &JsonValue,
+    key: &[String],
+    single_cell: &[String],
+    options: &Options,
+) -> AppResult<Vec<Part>> {
+    let obj = row
+        .as_object()
+        .ok_or_else(|| AppError::new(format!("Rows must be JSON objects: {}", row)))?;
+    let meta = |f: &str, col: &str| obj.get(&format!("{}({})", f, col)).and_then(|x| x.as_i64());
+    let require = |f: &str, flag: &str, col: &str| {
+        if single_cell.iter().any(|x| x == col) && !obj.contains_key(&format!("{}({})", f, col)) {
+            return Err(AppError::new(format!(
+                "{} needs the {}({}) of every column, select {}({}) along with {}: {}",
+                flag,
+                f,
+                col,
+                f.to_uppercase(),
+                col,
+                col,
+                row
+            )));
+        }
+        Ok(())
+    };
+    let key_columns: Map<String, JsonValue> = obj
+        .iter()
+        .filter(|(k, _)| key.contains(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    let mut parts: Vec<Part> = vec![];
+    for (col, value) in obj {
+        if key.contains(col) || col.starts_with("ttl(") || col.starts_with("writetime(") {
+            continue;
+        }
+        if options.preserve_ttl {
+            require("ttl", "--preserve-ttl", col)?;
+        }
+        if options.preserve_timestamp {
+            require("writetime", "--preserve-timestamp", col)?;
+        }
+        let ttl = Some(meta("ttl", col).unwrap_or(0)).filter(|_| options.preserve_ttl);
+        let timestamp = meta("writetime", col).filter(|_| options.preserve_timestamp);
+        let i = match parts
+            .iter()
+            .position(|x| x.ttl == ttl && x.timestamp == timestamp)
+        {
+            Some(i) => i,
+            None => {
+                parts.push(Part {
+                    columns: key_columns.clone(),
+                    ttl,
+                    timestamp,
+                });
+                parts.len() - 1
+            }
+        };
+        parts[i].columns.insert(col.clone(), value.clone());
+    }
+    if parts.is_empty() {
+        parts.push(Part {
+            columns: key_columns,
+            ttl: Some(0).filter(|_| options.preserve_ttl),
+            timestamp: None,
+        });
+    }
+    Ok(parts)
+}
+
+fn table_columns(
+    session: &CurrentSession,
+    table: &str,
+    config: &Config,
+) -> AppResult<Vec<schema::Column>> {
+    let (keyspace, name) = table
+        .split_once('.')
+        .ok_or_else(|| AppError::new("The table must be given as keyspace.table"))?;
+    schema::table_columns(session, &unquote(keyspace), &unquote(name), config)
+}
+
+fn of_kind(columns: &[schema::Column], kind: &str) -> Vec<String> {
+    columns
+        .iter()
+        .filter(|c| c.kind == kind)
+        .map(|c| c.name.clone())
+        .collect()
+}
+
+// Keep the entries of `row` about `columns`, along with their TTL and
+// write time
+fn select_columns(row: JsonValue, columns: &[String]) -> JsonValue {
+    let selected = |k: &str| {
+        let name = ["ttl(", "writetime("]
+            .iter()
+            .find_map(|f| k.strip_prefix(f).and_then(|x| x.strip_suffix(')')))
+            .unwrap_or(k);
+        columns.iter().any(|c| c == name)
+    };
+    match row {
+        JsonValue::Object(obj) => {
+            JsonValue::Object(obj.into_iter().filter(|(k, _)| selected(k)).collect())
+        }
+        x => x,
+    }
+}
+
+fn worker_for(row: &JsonValue, key: &[String], workers: usize) -> AppResult<usize> {
+    let mut hasher = DefaultHasher::new();
+    for col in key {
+        let value = row.get(col).ok_or_else(|| {
+            AppError::new(format!("Partition key {} missing in row {}", col, row))
+        })?;
+        value.to_string().hash(&mut hasher);
+    }
+    Ok((hasher.finish() % workers as u64) as usize)
+}
+
+struct Import<'a> {
+    session: &'a CurrentSession,
+    prepared: Prepared,
+    // The statement of the parts of rows with a write time, if preserved
+    prepared_with_timestamp: Option<Prepared>,
+    options: Options,
+    // The primary key, written along with every part of a row
+    key: Vec<String>,
+    // The columns with a TTL and write time of their own
+    single_cell: Vec<String>,
+    // The columns written, if not all of them
+    columns: Option<Vec<String>>,
+    config: &'a Config,
+    imported: AtomicUsize,
+    conflicts: AtomicUsize,
+}
+
+impl Import<'_> {
+    fn insert_parts(&self, row: JsonValue) -> AppResult<()> {
+        for part in split_row(&row, &self.key, &self.single_cell, &self.options)? {
+            let mut values = vec![Value::from(serde_json::to_string(&part.columns)?)];
+            if let Some(ttl) = part.ttl {
+                values.push(Value::from(ttl as i32));
+            }
+            let prepared = match (part.timestamp, &self.prepared_with_timestamp) {
+                (Some(timestamp), Some(prepared)) => {
+                    values.push(Value::from(timestamp));
+                    prepared
+                }
+                _ => &self.prepared,
+            };
+            let params = self.config.params(values);
+            self.config.throttle();
+            self.session.execute(prepared, params)?;
+        }
+        self.imported.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn insert(&self, row: String) -> AppResult<()> {
+        let row = match &self.columns {
+            Some(columns) => {
+                let row = select_columns(serde_json::from_str(&row)?, columns);
+                serde_json::to_string(&row)?
+            }
+            None => row,
+        };
+        if self.options.preserve() {
+            return self.insert_parts(serde_json::from_str(&row)?);
+        }
+        let params = self.config.params(vec![Value::from(row.clone())]);
+        self.config.throttle();
+        let resp = self.session.execute(&self.prepared, params)?;
+
+        if self.options.mode == Mode::InsertIfNotExists {
+            let result = core::collect_rows(&resp, self.config)?;
+            let existing = result
+                .into_iter()
+                .find(|x| x.get("[applied]") == Some(&JsonValue::Bool(false)));
+            if let Some(mut existing) = existing {
+                if let Some(obj) = existing.as_object_mut() {
+                    obj.remove("[applied]");
+                }
+                let row: JsonValue = serde_json::from_str(&row)?;
+                self.conflicts.fetch_add(1, Ordering::Relaxed);
+                return core::write_report(
+                    json!({ "conflict": row, "existing": existing }),
+                    self.config,
+                );
+            }
+        }
+        self.imported.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn run_worker(&self, rows: Receiver<String>) -> AppResult<()> {
+        for row in rows {
+            self.insert(row)?;
+        }
+        Ok(())
+    }
+}
+
+/// Insert the rows read from `input` into `table` (`keyspace.table`),
+/// using `--parallelism` workers. Conflicts of the
+/// `insert-if-not-exists` mode are written to the output.
+pub fn import(config: Config, table: &str, options: Options, input: impl BufRead) -> AppResult<()> {
+    let mode = options.mode;
+    if options.preserve() && mode == Mode::InsertIfNotExists {
+        return Err(AppError::new(
+            "--preserve-ttl and --preserve-timestamp can't be used with insert-if-not-exists",
+        ));
+    }
+    let session = config.connect()?;
+    let columns = table_columns(&session, table, &config)?;
+    let key = of_kind(&columns, "partition_key");
+    let clustering = of_kind(&columns, "clustering");
+    let import = Import {
+        session: &session,
+        prepared: session.prepare(&options.statement(table, false))?,
+        prepared_with_timestamp: if options.preserve_timestamp {
+            Some(session.prepare(&options.statement(table, true))?)
+        } else {
+            None
+        },
+        columns: options.columns(&columns)?,
+        options,
+        key: key.iter().chain(clustering.iter()).cloned().collect(),
+        single_cell: columns
+            .iter()
+            .filter(|c| c.has_writetime)
+            .map(|c| c.name.clone())
+            .collect(),
+        config: &config,
+        imported: AtomicUsize::new(0),
+        conflicts: AtomicUsize::new(0),
+    };
+    let workers = config.parallelism();
+
+    let result = thread::scope(|scope| -> AppResult<()> {
+        let (senders, handles): (Vec<_>, Vec<_>) = (0..workers)
+            .map(|_| {
+                let (tx, rx) = sync_channel::<String>(1000);
+                let import = &import;
+                (tx, scope.spawn(move || import.run_worker(rx)))
+            })
+            .unzip();
+
+        let mut read_result = Ok(());
+        for line in input.lines() {
+            let line = match line {
+                Ok(x) if x.trim().is_empty() => continue,
+                Ok(x) => x,
+                Err(err) => {
+                    read_result = Err(err.into());
+                    break;
+                }
+            };
+            let dispatched = serde_json::from_str(&line)
+                .map_err(AppError::from)
+                .and_then(|row| worker_for(&row, &key, workers));
+            match dispatched {
+                // A failed worker drops its receiver, its error is
+                // returned below
+                Ok(i) => {
+                    if senders[i].send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    read_result = Err(err);
+                    break;
+                }
+            }
+        }
+        drop(senders);
+
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        read_result
+    });
+
+    let conflicts = import.conflicts.load(Ordering::Relaxed);
+    diag!(
+        "{} rows imported{}",
+        import.imported.load(Ordering::Relaxed),
+        if mode == Mode::InsertIfNotExists {
+            format!(", {} conflicts", conflicts)
+        } else {
+            String::new()
+        }
+    );
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_for() {
+        let key = vec!["id".to_string()];
+        let a = json!({"id": 1, "x": "a"});
+        let b = json!({"id": 1, "x": "b"});
+        assert_eq!(
+            worker_for(&a, &key, 8).unwrap(),
+            worker_for(&b, &key, 8).unwrap()
+        );
+        assert!(worker_for(&json!({"x": 1}), &key, 8).is_err());
+    }
+
+    #[test]
+    fn test_statements() {
+        assert_eq!(
+            "INSERT INTO ks.t JSON ? IF NOT EXISTS",
+            Mode::parse("insert-if-not-exists")
+                .unwrap()
+                .statement("ks.t")
+        );
+        assert_eq!(
+            "INSERT INTO ks.t JSON ? DEFAULT UNSET",
+            Mode::Upsert.statement("ks.t")
+        );
+        assert!(Mode::parse("merge").is_err());
+
+        let options = Options {
+            mode: Mode::Insert,
+            preserve_ttl: true,
+            preserve_timestamp: true,
+            only_columns: vec![],
+            exclude_columns: vec![],
+        };
+        assert_eq!(
+            "INSERT INTO ks.t JSON ? DEFAULT UNSET USING TTL ? AND TIMESTAMP ?",
+            options.statement("ks.t", true)
+        );
+        assert_eq!(
+            "INSERT INTO ks.t JSON ? DEFAULT UNSET USING TTL ?",
+            options.statement("ks.t", false)
+        );
+    }
+
+    #[test]
+    fn test_columns() {
+        let column = |name: &str, kind: &str| schema::Column {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            col_type: "int".to_string(),
+            position: 0,
+            has_writetime: true,
+            descending: false,
+        };
+        let table = vec![
+            column("id", "partition_key"),
+            column("a", "regular"),
+            column("b", "regular"),
+        ];
+        let options = |only: &[&str], exclude: &[&str]| Options {
+            mode: Mode::Insert,
+            preserve_ttl: false,
+            preserve_timestamp: false,
+            only_columns: only.iter().map(|x| x.to_string()).collect(),
+            exclude_columns: exclude.iter().map(|x| x.to_string()).collect(),
+        };
+        assert_eq!(None, options(&[], &[]).columns(&table).unwrap());
+        let only_a = options(&["a"], &[]);
+        let columns = only_a.columns(&table).unwrap().unwrap();
+        assert_eq!(vec!["id", "a"], columns);
+        assert_eq!(
+            vec!["id", "b"],
+            options(&[], &["a"]).columns(&table).unwrap().unwrap()
+        );
+        assert!(options(&[], &["id"]).columns(&table).is_err());
+        assert!(options(&["c"], &[]).columns(&table).is_err());
+        assert_eq!(
+            "INSERT INTO ks.t JSON ? DEFAULT UNSET",
+            only_a.statement("ks.t", false)
+        );
+        assert_eq!(
+            json!({"id": 1, "a": 2, "ttl(a)": 60}),
+            select_columns(
+                json!({"id": 1, "a": 2, "ttl(a)": 60, "b": 3, "ttl(b)": 60}),
+                &columns
+            )
+        );
+    }
+
+    #[test]
+    fn test_split_row() {
+        let options = Options {
+            mode: Mode::Insert,
+            preserve_ttl: true,
+            preserve_timestamp: true,
+            only_columns: vec![],
+            exclude_columns: vec![],
+        };
+        let key = vec!["id".to_string()];
+        let single_cell = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let row = json!({
+            "id": 1, "a": "x", "b": "y", "c": null, "d": [1],
+            "ttl(a)": 60, "writetime(a)": 10,
+            "ttl(b)": 60, "writetime(b)": 10,
+            "ttl(c)": null, "writetime(c)": null,
+        });
+        let part = |columns: JsonValue, ttl, timestamp| Part {
+            columns: columns.as_object().unwrap().clone(),
+            ttl,
+            timestamp,
+        };
+        assert_eq!(
+            vec![
+                part(json!({"id": 1, "a": "x", "b": "y"}), Some(60), Some(10)),
+                part(json!({"id": 1, "c": null, "d": [1]}), Some(0), None),
+            ],
+            split_row(&row, &key, &single_cell, &options).unwrap()
+        );
+        let row = json!({"id": 1, "a": "x", "b": "y", "ttl(a)": 60, "writetime(a)": 10});
+        let err = split_row(&row, &key, &single_cell, &options).unwrap_err();
+        assert!(err.to_string().contains("select TTL(b) along with b"));
+    }
+}
@@ -0,0 +1,181 @@
+// `--incremental`: only return the rows whose cursor column is greater
+// than on the previous run. The greatest value written out for every
+// partition, i.e. every parameter set, is kept in a state file between
+// runs. As the rows of a partition don't come in the order of the
+// cursor, a partition only moves its cursor once all its rows are
+// written, so that a run failing or stopped by `--limit` half way
+// through a partition returns the rest of it on the next run.
+use std::cmp::Ordering;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+
+use crate::errors::{AppError, AppResult};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    column: String,
+    cursors: Map<String, JsonValue>,
+}
+
+#[derive(Debug)]
+pub struct Incremental {
+    column: String,
+    path: PathBuf,
+    // Rows are compared with the cursors of the previous run, as the
+    // rows of a partition don't come in the order of the cursor
+    previous: Map<String, JsonValue>,
+    state: Mutex<State>,
+}
+
+// Timestamps are compared as such since their textual form doesn't
+// always sort, e.g. with and without fractional seconds
+fn compare(a: &JsonValue, b: &JsonValue) -> Option<Ordering> {
+    match (a, b) {
+        (JsonValue::Number(x), JsonValue::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (JsonValue::String(x), JsonValue::String(y)) => {
+            match (
+                DateTime::parse_from_rfc3339(x),
+                DateTime::parse_from_rfc3339(y),
+            ) {
+                (Ok(x), Ok(y)) => Some(x.cmp(&y)),
+                _ => Some(x.cmp(y)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Move `latest` to `cursor`, if greater.
+pub fn advance(latest: &mut Option<JsonValue>, cursor: &JsonValue) {
+    if latest
+        .as_ref()
+        .is_none_or(|x| compare(cursor, x) == Some(Ordering::Greater))
+    {
+        *latest = Some(cursor.clone());
+    }
+}
+
+/// The key of a partition in the state file.
+pub fn partition_key(labels: &[String]) -> String {
+    labels.join(",")
+}
+
+impl Incremental {
+    pub fn load(column: &str, path: &str) -> AppResult<Incremental> {
+        let state = match fs::read_to_string(path) {
+            Ok(x) => {
+                let state: State = serde_json::from_str(&x)?;
+                if state.column != column {
+                    return Err(AppError::new(format!(
+                        "{} tracks column {}, not {}",
+                        path, state.column, column
+                    )));
+                }
+                state
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => State {
+                column: column.to_string(),
+                ..Default::default()
+            },
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Incremental {
+            column: column.to_string(),
+            path: PathBuf::from(path),
+            previous: state.cursors.clone(),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// The value of the cursor column of `row`.
+    pub fn cursor<'a>(&self, row: &'a JsonValue) -> AppResult<&'a JsonValue> {
+        match row.get(&self.column) {
+            Some(JsonValue::Null) | None => Err(AppError::new(format!(
+                "The cursor column {} is missing from the results",
+                self.column
+            ))),
+            Some(x) => Ok(x),
+        }
+    }
+
+    /// Whether `row` of `partition` is new since the previous run.
+    pub fn accept(&self, partition: &str, row: &JsonValue) -> AppResult<bool> {
+        let value = self.cursor(row)?;
+        if let Some(cursor) = self.previous.get(partition) {
+            match compare(value, cursor) {
+                Some(Ordering::Greater) => {}
+                Some(_) => return Ok(false),
+                None => {
+                    return Err(AppError::new(format!(
+                        "Unable to compare {} with the stored cursor {}",
+                        value, cursor
+                    )))
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Move the cursor of `partition`, all the rows of which have been
+    /// written, to `cursor` if greater.
+    pub fn commit(&self, partition: &str, cursor: JsonValue) {
+        let mut state = self.state.lock().unwrap();
+        let mut latest = state.cursors.get(partition).cloned();
+        advance(&mut latest, &cursor);
+        if let Some(x) = latest {
+            state.cursors.insert(partition.to_string(), x);
+        }
+    }
+
+    pub fn save(&self) -> AppResult<()> {
+        let state = self.state.lock().unwrap();
+        fs::write(&self.path, serde_json::to_string_pretty(&*state)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_accept() {
+        let path = env::temp_dir().join(format!("kass-cursor-{}.json", process::id()));
+        let path = path.to_str().unwrap();
+
+        let inc = Incremental::load("updated_at", path).unwrap();
+        let row = |t: &str| json!({ "id": 1, "updated_at": t });
+        let mut latest = None;
+        for t in ["2020-01-01T00:00:01Z", "2020-01-01T00:00:00.500Z"] {
+            assert!(inc.accept("1", &row(t)).unwrap());
+            advance(&mut latest, inc.cursor(&row(t)).unwrap());
+        }
+        inc.commit("1", latest.unwrap());
+        assert!(inc.accept("2", &row("2020-01-01T00:00:00Z")).unwrap());
+        inc.commit("2", json!("2020-01-01T00:00:00Z"));
+        // Not read completely
+        assert!(inc.accept("3", &row("2020-01-01T00:00:00Z")).unwrap());
+        inc.save().unwrap();
+
+        let inc = Incremental::load("updated_at", path).unwrap();
+        assert!(!inc.accept("1", &row("2020-01-01T00:00:01Z")).unwrap());
+        assert!(!inc.accept("2", &row("2020-01-01T00:00:00Z")).unwrap());
+        assert!(inc.accept("3", &row("2020-01-01T00:00:00Z")).unwrap());
+        assert!(inc.accept("1", &row("2020-01-01T00:00:01.5Z")).unwrap());
+        // Cursors only move forward
+        inc.commit("1", json!("2019-12-31T00:00:00Z"));
+        assert!(!inc.accept("1", &row("2020-01-01T00:00:01Z")).unwrap());
+        assert!(inc.accept("1", &json!({ "id": 1 })).is_err());
+        assert!(Incremental::load("other", path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+// Queries going through a secondary index (including SASI) are sent to
+// every node instead of the replicas of a partition, so running many of
+// them in parallel loads the whole cluster.
+use regex::Regex;
+
+use crate::core::{self, Config, CurrentSession};
+use crate::errors::AppResult;
+use crate::schema::{self, unquote};
+
+lazy_static! {
+    static ref SELECT: Regex = Regex::new(
+        r"(?is)^\s*select\s+.+?\s+from\s+(\S+)\s+where\s+(.+?)(?:\s+(?:order\s+by|group\s+by|per\s+partition\s+limit|limit|allow\s+filtering)\b.*)?$"
+    )
+    .unwrap();
+    static ref AND: Regex = Regex::new(r"(?i)\s+and\s+").unwrap();
+    static ref RESTRICTION: Regex = Regex::new(
+        r#"(?i)^([A-Za-z_][A-Za-z0-9_]*|"(?:[^"]|"")+")\s*(=|<|>|!=|\bin\b|\bcontains\b|\blike\b)"#
+    )
+    .unwrap();
+    static ref TARGET: Regex = Regex::new(r"^(?:keys|values|entries|full)\((.+)\)$").unwrap();
+}
+
+/// The table of a `SELECT` and the columns restricted by its `WHERE`
+/// clause, along with whether they are restricted by `=` or `IN`.
+fn restrictions(cql: &str) -> Option<(String, Vec<(String, bool)>)> {
+    let caps = SELECT.captures(cql)?;
+    let columns = AND
+        .split(&caps[2])
+        .filter_map(|x| {
+            let caps = RESTRICTION.captures(x.trim())?;
+            let op = caps[2].to_lowercase();
+            Some((unquote(&caps[1]), op == "=" || op == "in"))
+        })
+        .collect();
+    Some((caps[1].to_string(), columns))
+}
+
+fn indexed_columns(
+    session: &CurrentSession,
+    keyspace: &str,
+    table: &str,
+    config: &Config,
+) -> AppResult<Vec<String>> {
+    let query = "SELECT options FROM system_schema.indexes \
+                 WHERE keyspace_name = ? AND table_name = ?";
+    let params = config.params(vec![keyspace.into(), table.into()]);
+    let resp = session.query(query, params)?;
+    Ok(core::collect_rows(&resp, config)?
+        .iter()
+        .filter_map(|row| row.get("options")?.get("target")?.as_str())
+        .map(|target| match TARGET.captures(target) {
+            Some(caps) => unquote(&caps[1]),
+            None => unquote(target),
+        })
+        .collect())
+}
+
+/// The indexed column `cql` is likely to go through, i.e. an indexed
+/// column restricted by a query which doesn't restrict the whole
+/// partition key. `None` for the queries this can't tell.
+pub fn indexed_column(
+    session: &CurrentSession,
+    cql: &str,
+    config: &Config,
+) -> AppResult<Option<String>> {
+    let (table, restricted) = match restrictions(cql) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let (keyspace, name) = match table.split_once('.') {
+        Some((keyspace, name)) => (unquote(keyspace), unquote(name)),
+        None => return Ok(None),
+    };
+    let indexed = indexed_columns(session, &keyspace, &name, config)?;
+    if indexed.is_empty() {
+        return Ok(None);
+    }
+
+    let columns = schema::table_columns(session, &keyspace, &name, config)?;
+    let single_partition = columns
+        .iter()
+        .filter(|c| c.kind == "partition_key")
+        .all(|c| restricted.iter().any(|(x, eq)| *x == c.name && *eq));
+    if single_partition {
+        return Ok(None);
+    }
+    Ok(restricted
+        .into_iter()
+        .map(|(x, _)| x)
+        .find(|x| indexed.contains(x)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restrictions() {
+        let (table, columns) = restrictions(
+            "SELECT * FROM ks.users WHERE id IN ? AND \"Name\" = ? and age > 3 LIMIT 10",
+        )
+        .unwrap();
+        assert_eq!("ks.users", table);
+        assert_eq!(
+            vec![
+                ("id".to_string(), true),
+                ("Name".to_string(), true),
+                ("age".to_string(), false),
+            ],
+            columns
+        );
+        assert!(restrictions("SELECT * FROM ks.users").is_none());
+    }
+}
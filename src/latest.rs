@@ -0,0 +1,121 @@
+// `--latest-per-partition N --order-col ts`: the newest `N` rows of
+// each partition queried, by adding `ORDER BY ts DESC LIMIT N` to the
+// query. `ts` must be the first clustering column of the table, the
+// rows of a partition being sorted by it, and the `ORDER BY` is left
+// out when the table already keeps them in descending order.
+use regex::Regex;
+
+use crate::core::{Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+use crate::schema::{self, quote, unquote, Column};
+
+lazy_static! {
+    static ref SELECT: Regex =
+        Regex::new(r"(?is)^(\s*select\s+.+?\s+from\s+(\S+).*?)(\s+allow\s+filtering)?\s*;?\s*$")
+            .unwrap();
+    static ref ORDER_OR_LIMIT: Regex =
+        Regex::new(r"(?i)\b(?:order\s+by|limit|per\s+partition\s+limit)\b").unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latest {
+    pub rows: usize,
+    pub column: String,
+}
+
+impl Latest {
+    /// `cql` returning the newest rows of each partition only, which
+    /// must be a `SELECT` from a keyspace qualified table without an
+    /// `ORDER BY` or a `LIMIT`.
+    pub fn rewrite(
+        &self,
+        session: &CurrentSession,
+        cql: &str,
+        config: &Config,
+    ) -> AppResult<String> {
+        let unsupported = || {
+            AppError::new(
+                "--latest-per-partition requires a query like SELECT ... FROM keyspace.table WHERE ... without ORDER BY or LIMIT",
+            )
+        };
+        let caps = SELECT.captures(cql).ok_or_else(unsupported)?;
+        if ORDER_OR_LIMIT.is_match(&caps[1]) {
+            return Err(unsupported());
+        }
+        let (keyspace, table) = caps[2].split_once('.').ok_or_else(unsupported)?;
+        let columns = schema::table_columns(session, &unquote(keyspace), &unquote(table), config)?;
+        Ok(format!(
+            "{}{} LIMIT {}{}",
+            &caps[1],
+            self.order_by(&columns, &caps[2])?,
+            self.rows,
+            caps.get(3).map_or("", |x| x.as_str())
+        ))
+    }
+
+    fn order_by(&self, columns: &[Column], table: &str) -> AppResult<String> {
+        let name = unquote(&self.column);
+        let first = columns.iter().find(|c| c.kind == "clustering");
+        match first {
+            Some(c) if c.name == name && c.descending => Ok(String::new()),
+            Some(c) if c.name == name => Ok(format!(" ORDER BY {} DESC", quote(&c.name))),
+            _ => Err(AppError::new(format!(
+                "--order-col {} is not the first clustering column of {}",
+                self.column, table
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, kind: &str, descending: bool) -> Column {
+        Column {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            col_type: "timestamp".to_string(),
+            position: 0,
+            has_writetime: kind == "regular",
+            descending,
+        }
+    }
+
+    #[test]
+    fn test_order_by() {
+        let latest = |column: &str| Latest {
+            rows: 3,
+            column: column.to_string(),
+        };
+        let table = vec![
+            column("id", "partition_key", false),
+            column("ts", "clustering", false),
+            column("seq", "clustering", true),
+            column("v", "regular", false),
+        ];
+        assert_eq!(
+            " ORDER BY \"ts\" DESC",
+            latest("TS").order_by(&table, "ks.t").unwrap()
+        );
+        assert!(latest("seq").order_by(&table, "ks.t").is_err());
+        assert!(latest("v").order_by(&table, "ks.t").is_err());
+        let table = vec![
+            column("id", "partition_key", false),
+            column("ts", "clustering", true),
+        ];
+        assert_eq!("", latest("ts").order_by(&table, "ks.t").unwrap());
+    }
+
+    #[test]
+    fn test_select() {
+        let caps = SELECT
+            .captures("SELECT * FROM ks.t WHERE id = ? ALLOW FILTERING;")
+            .unwrap();
+        assert_eq!("SELECT * FROM ks.t WHERE id = ?", &caps[1]);
+        assert_eq!("ks.t", &caps[2]);
+        assert_eq!(" ALLOW FILTERING", &caps[3]);
+        assert!(ORDER_OR_LIMIT.is_match("SELECT * FROM ks.t WHERE id = ? LIMIT 5"));
+        assert!(!ORDER_OR_LIMIT.is_match("SELECT limits FROM ks.t WHERE id = ?"));
+    }
+}
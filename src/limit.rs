@@ -0,0 +1,74 @@
+// `--limit N`: the run stops once N rows have been written, whatever
+// the parameter sets they come from. The workers stop at their next row
+// and don't start any other query, the queries already running being
+// left to finish as the driver can't cancel them.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::errors::{AppError, AppResult};
+
+#[derive(Debug)]
+pub struct RowLimit {
+    max: usize,
+    written: AtomicUsize,
+}
+
+impl RowLimit {
+    pub fn new(max: usize) -> Self {
+        RowLimit {
+            max,
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    /// The same limit, for a run of its own.
+    pub fn fresh(&self) -> Self {
+        RowLimit::new(self.max)
+    }
+
+    pub fn is_reached(&self) -> bool {
+        self.written.load(Ordering::SeqCst) >= self.max
+    }
+
+    fn error(&self) -> AppError {
+        AppError::new(format!("--limit of {} rows reached", self.max))
+    }
+
+    /// Whether `err` is that of the workers stopped by the limit.
+    pub fn is_limit_error(&self, err: &AppError) -> bool {
+        err.to_string() == self.error().to_string()
+    }
+
+    /// Fail once the limit is reached, which stops the run.
+    pub fn check(&self) -> AppResult<()> {
+        match self.is_reached() {
+            true => Err(self.error()),
+            false => Ok(()),
+        }
+    }
+
+    /// Count a row about to be written, failing instead once the limit
+    /// is reached.
+    pub fn take(&self) -> AppResult<()> {
+        match self.written.fetch_add(1, Ordering::SeqCst) < self.max {
+            true => Ok(()),
+            false => self.check(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let limit = RowLimit::new(2);
+        assert!(limit.check().is_ok());
+        assert!(limit.take().is_ok());
+        assert!(limit.take().is_ok());
+        assert!(limit.is_reached());
+        assert!(limit.take().is_err());
+        assert!(limit.is_limit_error(&limit.check().unwrap_err()));
+        assert!(!limit.is_limit_error(&AppError::new("IO error: Broken pipe")));
+    }
+}
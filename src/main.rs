@@ -10,57 +10,667 @@ extern crate uuid;
 #[macro_use]
 extern crate lazy_static;
 
+// First, for the other modules to use its macros
+#[macro_use]
+mod diag;
+mod as_of;
+mod assertions;
+mod astra;
+mod auth;
+mod backend;
+mod browse;
+mod canonical;
+mod compare;
+mod config_file;
+mod consistency;
+mod control;
 mod core;
+mod counter;
+mod credentials;
+mod csv;
 mod date_range;
+mod dc;
+mod ddl;
+mod dead_letter;
+mod envelope;
 mod errors;
+mod escape;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod params;
+mod ping;
+mod plugin;
+mod proxy;
+mod random;
+mod rate;
+mod reconnect;
+mod retry;
+mod record;
+mod sample;
+mod schema;
+mod scylla;
+mod self_update;
+mod server;
+mod sigv4;
+mod sink;
+mod socks;
+mod import;
+mod impact;
+mod incremental;
+mod index;
 mod iterator_consumer;
+mod latest;
+mod limit;
+mod mask;
+mod memory;
+mod metrics;
+mod migrate;
+mod mock;
+mod statics;
+mod sticky;
+mod stats;
+mod tls;
+mod trace;
+mod ttl_audit;
 mod types;
+mod usage;
+mod validate;
+mod version;
+mod warnings;
+
+use std::env;
+use std::ffi::OsString;
+use std::sync::{mpsc, Arc};
+use std::thread;
 
-use self::clap::{App, AppSettings, Arg};
+use self::clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use self::errors::{AppError, AppResult};
 
+/// Options shared by all the commands running queries.
+fn query_options() -> Vec<Arg<'static, 'static>> {
+    let mut options = vec![
+        Arg::with_name("host")
+            .short("h")
+            .long("host")
+            .takes_value(true)
+            .value_name("HOST[:PORT]")
+            .help("The Cassandra host to connect to, with IPv6 addresses in brackets along with a port"),
+        Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .value_name("PORT")
+            .conflicts_with("secure-connect-bundle")
+            .help("The port of the host, when --host doesn't give one [default: 9042]"),
+        Arg::with_name("secure-connect-bundle")
+            .long("secure-connect-bundle")
+            .takes_value(true)
+            .value_name("ZIP")
+            .conflicts_with_all(&["host", "ssl", "local-dc"])
+            .help("Connect to DataStax Astra with its secure connect bundle"),
+        Arg::with_name("local-dc")
+            .long("local-dc")
+            .takes_value(true)
+            .value_name("DC")
+            .help("Only send queries to the nodes of this data center, discovered through --host"),
+        Arg::with_name("no-peer-discovery")
+            .long("no-peer-discovery")
+            .conflicts_with_all(&["local-dc", "secure-connect-bundle"])
+            .help("Only send queries to --host, rather than to all the nodes discovered through it"),
+        Arg::with_name("no-shard-awareness")
+            .long("no-shard-awareness")
+            .help("Don't connect to each shard of ScyllaDB nodes through their shard-aware port"),
+        Arg::with_name("sticky-coordinator")
+            .long("sticky-coordinator")
+            .conflicts_with("no-peer-discovery")
+            .help("Send the statements of each parameter set, their pages and retries, to the same node while it is up"),
+        Arg::with_name("only-host")
+            .long("only-host")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("HOST[:PORT]")
+            .conflicts_with_all(&["no-peer-discovery", "secure-connect-bundle"])
+            .help("Only send queries to this node of the cluster"),
+        Arg::with_name("exclude-host")
+            .long("exclude-host")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("HOST[:PORT]")
+            .conflicts_with_all(&["no-peer-discovery", "secure-connect-bundle"])
+            .help("Don't send queries to this node of the cluster, e.g. when it is struggling"),
+        Arg::with_name("proxy")
+            .long("proxy")
+            .takes_value(true)
+            .value_name("URL")
+            .conflicts_with_all(&["ssh-tunnel", "secure-connect-bundle"])
+            .help("Connect to the nodes through this SOCKS5 proxy, e.g. socks5://bastion:1080"),
+        Arg::with_name("ssh-tunnel")
+            .long("ssh-tunnel")
+            .takes_value(true)
+            .value_name("USER@HOST")
+            .conflicts_with("secure-connect-bundle")
+            .help("Connect to the nodes through an SSH tunnel to this bastion, with `ssh -D`"),
+        Arg::with_name("backend")
+            .long("backend")
+            .takes_value(true)
+            .possible_values(&["cdrs", "mock"])
+            .default_value("cdrs")
+            .help("cdrs queries the cluster, mock replays the result sets of --fixture"),
+        Arg::with_name("fixture")
+            .long("fixture")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("The JSON file of the result sets returned by --backend mock"),
+        Arg::with_name("record")
+            .long("record")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["replay", "fixture"])
+            .help("Append the results received from the cluster to this file, for --replay"),
+        Arg::with_name("replay")
+            .long("replay")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("fixture")
+            .help("Answer the queries with the results saved by --record instead of the cluster"),
+        Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .value_name("PROFILE")
+            .help("Use the credentials saved for this profile with `kass login`"),
+        Arg::with_name("execution-profile")
+            .long("execution-profile")
+            .takes_value(true)
+            .value_name("PROFILE")
+            .help("Run the queries with the options of this execution profile of the configuration file"),
+        Arg::with_name("credentials-file")
+            .long("credentials-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Read the username and password for the host from this netrc-like file"),
+        Arg::with_name("auth")
+            .long("auth")
+            .takes_value(true)
+            .value_name("AUTHENTICATOR")
+            .default_value("none")
+            .help("The authenticator to use (none, password, token, sigv4; Kerberos isn't supported)"),
+        Arg::with_name("username")
+            .short("u")
+            .long("username")
+            .takes_value(true)
+            .help("The username for password authentication"),
+        Arg::with_name("password")
+            .short("p")
+            .long("password")
+            .takes_value(true)
+            .help("The password for password authentication, prompted for when omitted"),
+        Arg::with_name("ssl")
+            .long("ssl")
+            .help("Connect over TLS"),
+        Arg::with_name("ca-cert")
+            .long("ca-cert")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("ssl")
+            .help("The PEM file of the CAs trusted to sign the certificates of the nodes, instead of the system ones"),
+        Arg::with_name("no-verify-hostname")
+            .long("no-verify-hostname")
+            .requires("ssl")
+            .help("Accept node certificates issued for another host name"),
+        Arg::with_name("client-cert")
+            .long("client-cert")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("ssl")
+            .help("The PEM file of the client certificate, for clusters requiring client authentication"),
+        Arg::with_name("client-key")
+            .long("client-key")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("client-cert")
+            .help("The PEM file of the private key of --client-cert, when not in the same file"),
+        Arg::with_name("compression")
+            .long("compression")
+            .takes_value(true)
+            .possible_values(&["lz4", "snappy", "none"])
+            .default_value("none")
+            .help("Have the nodes compress the frames they send, e.g. for large results over slow links"),
+        Arg::with_name("auth-token")
+            .long("auth-token")
+            .takes_value(true)
+            .value_name("TOKEN")
+            .help("The token sent by the token authenticator"),
+        Arg::with_name("auth-class")
+            .long("auth-class")
+            .takes_value(true)
+            .value_name("CLASS")
+            .help("The server side authenticator class, e.g. for LDAP authenticators"),
+        Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the results to this file instead of stdout"),
+        Arg::with_name("partition-output-by")
+            .long("partition-output-by")
+            .takes_value(true)
+            .use_delimiter(true)
+            .value_name("COLUMNS")
+            .requires("output-template")
+            .help("Write the rows to files named after the values of these columns"),
+        Arg::with_name("output-template")
+            .long("output-template")
+            .takes_value(true)
+            .value_name("TEMPLATE")
+            .requires("partition-output-by")
+            .conflicts_with("output")
+            .help("The files of --partition-output-by, e.g. 'out/day={day}/part-{n}.jsonl'"),
+        Arg::with_name("max-open-files")
+            .long("max-open-files")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("64")
+            .help("Max number of files of --partition-output-by open at once"),
+        Arg::with_name("encrypt-to")
+            .long("encrypt-to")
+            .takes_value(true)
+            .requires("output")
+            .value_name("RECIPIENT")
+            .help("Encrypt --output with age (for age1... recipients) or gpg (for other key ids)"),
+        Arg::with_name("plugin")
+            .long("plugin")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("[NAME=]PATH")
+            .help("A program providing sinks and transforms, named after its file name without kass- by default"),
+        Arg::with_name("sink")
+            .long("sink")
+            .takes_value(true)
+            .value_name("NAME:ARG")
+            .requires("plugin")
+            .conflicts_with_all(&["output", "output-template"])
+            .help("Write the results to this sink of a --plugin instead of stdout"),
+        Arg::with_name("transform")
+            .long("transform")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME:ARG")
+            .requires("plugin")
+            .help("Replace the result rows with those answered by this transform of a --plugin"),
+        Arg::with_name("transform-wasm")
+            .long("transform-wasm")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("MODULE")
+            .help("Replace the result rows with those answered by this WebAssembly module, sandboxed, after any --transform"),
+        Arg::with_name("wasm-runtime")
+            .long("wasm-runtime")
+            .takes_value(true)
+            .value_name("COMMAND")
+            .default_value("wasmtime")
+            .help("The WASI runtime running --transform-wasm"),
+        Arg::with_name("color")
+            .short("C")
+            .long("color")
+            .takes_value(true)
+            .possible_values(&["auto", "on", "off"])
+            .default_value("auto")
+            .help("When to use terminal colors"),
+        Arg::with_name("pretty")
+            .long("pretty")
+            .help("Pretty print JSON"),
+        Arg::with_name("quiet")
+            .long("quiet")
+            .help("Only write the rows, without the progress, summaries and warnings on stderr"),
+        Arg::with_name("canonical")
+            .long("canonical")
+            .conflicts_with("pretty")
+            .help("Print canonical JSON (RFC 8785) without colors, for hashing and diffing"),
+        Arg::with_name("sort-keys")
+            .long("sort-keys")
+            .help("Sort the keys of JSON objects"),
+        Arg::with_name("map-mode")
+            .long("map-mode")
+            .takes_value(true)
+            .possible_values(&["object", "entries"])
+            .default_value("object")
+            .help("Serialize maps as JSON objects or as arrays of [key, value] entries"),
+        Arg::with_name("time-precision")
+            .long("time-precision")
+            .takes_value(true)
+            .possible_values(&["ms", "us", "ns"])
+            .help("Write times with milli, micro or nanosecond precision rather than their significant digits"),
+        Arg::with_name("invalid-dates")
+            .long("invalid-dates")
+            .takes_value(true)
+            .possible_values(&["raw", "error"])
+            .default_value("raw")
+            .help("Write dates, times and timestamps out of range as the numbers stored, with a warning, or fail"),
+        Arg::with_name("on-decode-error")
+            .long("on-decode-error")
+            .takes_value(true)
+            .possible_values(&["fail", "null", "raw-hex"])
+            .default_value("fail")
+            .help("Reject the rows with columns that can't be decoded, or write these columns as null or as the bytes stored in hex"),
+        Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["json", "csv"])
+            .default_value("json")
+            .help("Output format of the result rows"),
+        Arg::with_name("typed-header")
+            .long("typed-header")
+            .help("With --format csv, add a second header line with the CQL type of each column"),
+        Arg::with_name("escape-control-chars")
+            .long("escape-control-chars")
+            .conflicts_with("canonical")
+            .help("Escape the newlines and other control characters of text columns, keeping each row on one line"),
+        Arg::with_name("emit-ddl")
+            .long("emit-ddl")
+            .takes_value(true)
+            .value_name("TARGET")
+            .possible_values(ddl::TARGETS)
+            .help("Write a CREATE TABLE statement for the results, to stderr unless --ddl-file is given"),
+        Arg::with_name("ddl-file")
+            .long("ddl-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("emit-ddl")
+            .help("Where to write the statement of --emit-ddl"),
+        Arg::with_name("sparse")
+            .long("sparse")
+            .help("Omit the null columns of a row, those never written or deleted, writing the empty values of non-text columns as null"),
+        Arg::with_name("consistency")
+            .long("consistency")
+            .takes_value(true)
+            .value_name("LEVEL")
+            .help("The consistency level of the queries, e.g. LOCAL_QUORUM [default: ONE]"),
+        Arg::with_name("serial-consistency")
+            .long("serial-consistency")
+            .takes_value(true)
+            .value_name("LEVEL")
+            .help("The serial consistency level of the conditional statements, SERIAL or LOCAL_SERIAL [default: SERIAL]"),
+        Arg::with_name("fallback-consistency")
+            .long("fallback-consistency")
+            .takes_value(true)
+            .value_name("LEVEL")
+            .help("The consistency level of the parameter sets whose replicas can't meet --consistency, e.g. ONE"),
+        Arg::with_name("parallelism")
+            .short("P")
+            .long("parallelism")
+            .takes_value(true)
+            .default_value("5")
+            .help("Max number of parallel queries"),
+        Arg::with_name("strict-params")
+            .long("strict-params")
+            .help("Reject the parameters that look like a range but don't parse as one (the default)"),
+        Arg::with_name("lenient-params")
+            .long("lenient-params")
+            .conflicts_with("strict-params")
+            .help("Take the parameters that look like a range but don't parse as one as literal strings"),
+        Arg::with_name("pool-max")
+            .long("pool-max")
+            .takes_value(true)
+            .value_name("N")
+            .help("Max number of connections to each node [default: the parallelism, at least 10]"),
+        Arg::with_name("pool-min")
+            .long("pool-min")
+            .takes_value(true)
+            .value_name("N")
+            .help("Number of idle connections kept open to each node, all of them when omitted"),
+        Arg::with_name("index-parallelism")
+            .long("index-parallelism")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("2")
+            .help("Max number of parallel queries when they go through a secondary index"),
+        Arg::with_name("page-size")
+            .long("page-size")
+            .takes_value(true)
+            .value_name("ROWS")
+            .default_value("5000")
+            .help("The number of rows of the pages the results are fetched in"),
+        Arg::with_name("max-result-bytes-per-query")
+            .long("max-result-bytes-per-query")
+            .takes_value(true)
+            .value_name("BYTES")
+            .help("Abort when the result of a single query exceeds this size"),
+        Arg::with_name("truncate-oversized-results")
+            .long("truncate-oversized-results")
+            .requires("max-result-bytes-per-query")
+            .help("Truncate oversized query results with a warning instead of aborting"),
+        Arg::with_name("max-memory-mb")
+            .long("max-memory-mb")
+            .takes_value(true)
+            .value_name("MB")
+            .help("Stop issuing queries while buffered results exceed this size"),
+        Arg::with_name("rate-schedule")
+            .long("rate-schedule")
+            .takes_value(true)
+            .value_name("SCHEDULE")
+            .help("Queries per second by local time of day, e.g. 00:00-07:00=2000,07:00-20:00=200"),
+        Arg::with_name("request-timeout")
+            .long("request-timeout")
+            .takes_value(true)
+            .value_name("SECS")
+            .help("Give up on queries taking longer, failing the run once the other queries are done"),
+        Arg::with_name("speculative-execution")
+            .long("speculative-execution")
+            .takes_value(true)
+            .value_name("MS")
+            .help("Send prepared statements taking longer to another node as well, taking the first response"),
+        Arg::with_name("max-pending-reads")
+            .long("max-pending-reads")
+            .takes_value(true)
+            .value_name("N")
+            .help("Lower the parallelism while more reads than N are pending on the cluster"),
+        Arg::with_name("impact-metrics-url")
+            .long("impact-metrics-url")
+            .takes_value(true)
+            .value_name("URL")
+            .requires("max-pending-reads")
+            .help("Read the pending reads from these Prometheus metrics instead of system_views"),
+        Arg::with_name("max-warnings")
+            .long("max-warnings")
+            .takes_value(true)
+            .value_name("N")
+            .help("Abort the run once the cluster has sent more than N warnings, e.g. about tombstones"),
+        Arg::with_name("stats")
+            .long("stats")
+            .help("Report the CPU time, peak RSS and bytes read per worker on stderr once done"),
+        Arg::with_name("stats-interval")
+            .long("stats-interval")
+            .takes_value(true)
+            .value_name("SECS")
+            .help("Report the resources used so far every SECS as well, implying --stats"),
+        Arg::with_name("trace-out")
+            .long("trace-out")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the spans of the stages of the run to FILE in the Chrome trace format"),
+        Arg::with_name("reconnect-timeout")
+            .long("reconnect-timeout")
+            .takes_value(true)
+            .value_name("SECS")
+            .default_value("60")
+            .help("Retry statements for so long when the cluster can't be reached, 0 to fail right away"),
+        Arg::with_name("force-counter-retry")
+            .long("force-counter-retry")
+            .help("Retry counter updates on connection errors as well, at the risk of counting them twice"),
+        Arg::with_name("retries")
+            .long("retries")
+            .takes_value(true)
+            .value_name("N")
+            .help("Retry the statements failing with a timeout or an unavailable or overloaded coordinator up to N times [default: 0]"),
+        Arg::with_name("retry-budget")
+            .long("retry-budget")
+            .takes_value(true)
+            .value_name("PERCENT")
+            .requires("retries")
+            .help("Abort the run once more than this share of the queries needed retries, e.g. 5%"),
+        Arg::with_name("schema-agreement-timeout")
+            .long("schema-agreement-timeout")
+            .takes_value(true)
+            .value_name("SECS")
+            .default_value("30")
+            .help("How long to wait for all nodes to agree on the schema after a DDL statement"),
+        Arg::with_name("as-of")
+            .long("as-of")
+            .takes_value(true)
+            .value_name("TIMESTAMP")
+            .help("Drop the values written after this time, e.g. 2020-01-31T00:00:00Z"),
+        Arg::with_name("static-once")
+            .long("static-once")
+            .help("Only write the static columns on the first row of each partition"),
+        Arg::with_name("static-record")
+            .long("static-record")
+            .help("Write the static columns of each partition to a record of their own, as {\"_static\": {...}}"),
+        Arg::with_name("envelope")
+            .long("envelope")
+            .help("Wrap each row as {\"v\": 1, \"source\": {...}, \"params\": [...], \"row\": {...}}"),
+        Arg::with_name("latest-per-partition")
+            .long("latest-per-partition")
+            .takes_value(true)
+            .value_name("N")
+            .requires("order-col")
+            .help("Only return the newest N rows of each partition, by --order-col"),
+        Arg::with_name("order-col")
+            .long("order-col")
+            .takes_value(true)
+            .value_name("COLUMN")
+            .requires("latest-per-partition")
+            .help("The clustering column the rows of --latest-per-partition are the newest by"),
+        Arg::with_name("incremental")
+            .long("incremental")
+            .requires_all(&["cursor-column", "state-file"])
+            .help("Only return the rows updated since the previous run, according to --cursor-column"),
+        Arg::with_name("cursor-column")
+            .long("cursor-column")
+            .takes_value(true)
+            .value_name("COLUMN")
+            .requires("incremental")
+            .help("The column telling when a row was updated, e.g. updated_at"),
+        Arg::with_name("state-file")
+            .long("state-file")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("incremental")
+            .help("Where the greatest --cursor-column of every partition is kept between runs"),
+        Arg::with_name("mask")
+            .long("mask")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("COLUMN=STRATEGY")
+            .help("Mask the values of a column in the output: hash, null, last4 or truncate:N"),
+        Arg::with_name("mask-key")
+            .long("mask-key")
+            .takes_value(true)
+            .env("KASS_MASK_KEY")
+            .hide_env_values(true)
+            .value_name("KEY")
+            .help("The HMAC key of --mask COLUMN=hash"),
+        Arg::with_name("compare-to")
+            .long("compare-to")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write out the differences with the rows of a previous export instead of the rows"),
+        Arg::with_name("key")
+            .long("key")
+            .takes_value(true)
+            .use_delimiter(true)
+            .value_name("COLUMNS")
+            .requires("compare-to")
+            .help("The columns matching the rows of --compare-to, which are compared in order otherwise"),
+        Arg::with_name("dead-letter")
+            .long("dead-letter")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the rows that can't be decoded, with their raw bytes, to this file"),
+        Arg::with_name("validate-schema")
+            .long("validate-schema")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Check the rows against a JSON Schema, failing the run when some don't match"),
+        Arg::with_name("validation-errors")
+            .long("validation-errors")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("validate-schema")
+            .help("Where to write the rows failing validation, stderr by default"),
+        Arg::with_name("redact-params")
+            .long("redact-params")
+            .takes_value(true)
+            .use_delimiter(true)
+            .value_name("POSITIONS|NAMES")
+            .help("Mask the values of these parameters (1-based positions or bind marker names) in logs and reports"),
+    ];
+    #[cfg(unix)]
+    options.push(
+        Arg::with_name("control-socket")
+            .long("control-socket")
+            .help("Accept pause/resume commands from `kass ctl` (SIGUSR1/SIGUSR2 always work)"),
+    );
+    options
+}
+
 fn app() -> App<'static, 'static> {
-    App::new("Kass")
+    let app = App::new("Kass")
         .version("0.1.0")
         .about("Cassandra multi-partition query runner")
         .setting(AppSettings::TrailingVarArg)
         .setting(AppSettings::UnifiedHelpMessage)
         .setting(AppSettings::ColoredHelp)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .args(&query_options())
         .arg(
-            Arg::with_name("host")
-                .short("h")
-                .long("host")
+            Arg::with_name("verbose")
+                .long("verbose")
+                .help("With --version, print the features and the build of kass as JSON"),
+        )
+        .arg(
+            // Named apart from the --sample of audit-ttl, which also
+            // takes the query options
+            Arg::with_name("row-sample")
+                .long("sample")
                 .takes_value(true)
-                .value_name("HOST:PORT")
-                .help("The Cassandra host to connect to"),
+                .value_name("FRACTION[:seed=N]")
+                .help("Only return this fraction of the rows, chosen by a hash of their primary key and the seed"),
         )
         .arg(
-            Arg::with_name("color")
-                .short("C")
-                .long("color")
+            // Named apart from the --limit of browse, which also takes
+            // the query options
+            Arg::with_name("row-limit")
+                .long("limit")
                 .takes_value(true)
-                .possible_values(&["auto", "on", "off"])
-                .default_value("auto")
-                .help("When to use terminal colors"),
+                .value_name("N")
+                .help("Stop once this number of rows have been written"),
         )
         .arg(
-            Arg::with_name("pretty")
-                .long("pretty")
-                .help("Pretty print JSON"),
+            Arg::with_name("random")
+                .long("random")
+                .takes_value(true)
+                .value_name("N")
+                .help("Only write N rows picked at random among all those of the run, once it is done"),
         )
         .arg(
-            Arg::with_name("parallelism")
-                .short("P")
-                .long("parallelism")
+            Arg::with_name("queries")
+                .short("q")
+                .long("query")
                 .takes_value(true)
-                .default_value("5")
-                .help("Max number of parallel queries"),
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("QUERY")
+                .help("A query to run on the parameters along with the other --query, the rows tagged with its position under _query"),
         )
         .arg(
             Arg::with_name("query")
                 .help("The query to run")
-                .required(true)
+                .required_unless("queries")
                 .index(1),
         )
         .arg(
@@ -69,26 +679,608 @@ fn app() -> App<'static, 'static> {
                 .value_name("param")
                 .help("Query parameters"),
         )
+        .subcommand(
+            SubCommand::with_name("consistency-check")
+                .about("Compare the results of queries run at different consistency levels")
+                .setting(AppSettings::TrailingVarArg)
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("levels")
+                        .long("levels")
+                        .takes_value(true)
+                        .required(true)
+                        .use_delimiter(true)
+                        .value_name("LEVELS")
+                        .help("Comma separated consistency levels to compare, e.g. ONE,QUORUM,ALL"),
+                )
+                .arg(
+                    Arg::with_name("query")
+                        .help("The query to run")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("param")
+                        .multiple(true)
+                        .value_name("param")
+                        .help("Query parameters"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("assert")
+                .about("Check the number and values of the rows returned by queries")
+                .setting(AppSettings::TrailingVarArg)
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("expect-rows")
+                        .long("expect-rows")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .help("The number of rows expected for each parameter set, e.g. '>0' or '==1'"),
+                )
+                .arg(
+                    Arg::with_name("expect")
+                        .long("expect")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("CONDITION")
+                        .help("A condition every row must meet, e.g. 'col == 5'"),
+                )
+                .arg(
+                    Arg::with_name("query")
+                        .help("The query to run")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("param")
+                        .multiple(true)
+                        .value_name("param")
+                        .help("Query parameters"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Insert NDJSON rows read from stdin into a table")
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .takes_value(true)
+                        .possible_values(import::MODES)
+                        .default_value("insert")
+                        .help("insert sets missing columns to null, upsert leaves them untouched, insert-if-not-exists reports existing rows as conflicts"),
+                )
+                .arg(
+                    Arg::with_name("preserve-ttl")
+                        .long("preserve-ttl")
+                        .help("Write the columns with the TTL given by the ttl(column) entries of the rows"),
+                )
+                .arg(
+                    Arg::with_name("preserve-timestamp")
+                        .long("preserve-timestamp")
+                        .help("Write the columns with the timestamp given by the writetime(column) entries of the rows"),
+                )
+                .arg(
+                    Arg::with_name("only-columns")
+                        .long("only-columns")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .value_name("COLUMNS")
+                        .conflicts_with("exclude-columns")
+                        .help("Comma separated columns to write, along with the primary key"),
+                )
+                .arg(
+                    Arg::with_name("exclude-columns")
+                        .long("exclude-columns")
+                        .takes_value(true)
+                        .use_delimiter(true)
+                        .value_name("COLUMNS")
+                        .help("Comma separated columns not to write"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .help("The table to import into, as keyspace.table")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Apply the CQL migration files of a directory in the order of their names")
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("keyspace")
+                        .long("keyspace")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("KEYSPACE")
+                        .help("The keyspace of the kass_migrations table tracking the applied files"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .help("The directory of the .cql files")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("audit-ttl")
+                .about("Report the TTL remaining on a sample of the rows of each partition")
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("pk")
+                        .long("pk")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                        .value_name("param")
+                        .help("The partition keys to audit, in the same format as query parameters"),
+                )
+                .arg(
+                    Arg::with_name("sample")
+                        .long("sample")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1000")
+                        .help("Max number of rows sampled per partition"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .help("The table to audit, as keyspace.table")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("browse")
+                .about("Prompt for the partitions of a table, or pick them with fzf, and query them")
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("fzf")
+                        .long("fzf")
+                        .help("Pick the partitions with fzf among the first ones of the table"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .takes_value(true)
+                        .value_name("N")
+                        .requires("fzf")
+                        .help("Max number of partitions listed with --fzf [default: 1000]"),
+                )
+                .arg(
+                    Arg::with_name("table")
+                        .help("The table to browse, as keyspace.table")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about("Check that the cluster can be reached, with the version, data center and rack of the node answering")
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("count")
+                        .short("c")
+                        .long("count")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("1")
+                        .help("Query the cluster N times, a second apart"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("expand")
+                .about("Print the values a query parameter expands to, one per line, without running any query")
+                .arg(
+                    Arg::with_name("param")
+                        .help("The parameter, e.g. 2019-09-01..2019-12-01/1m")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Run queries submitted over an HTTP API")
+                .args(&query_options())
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .takes_value(true)
+                        .value_name("ADDR:PORT")
+                        .default_value("127.0.0.1:8080")
+                        .help("The address to listen on"),
+                )
+                .arg(
+                    Arg::with_name("grpc-listen")
+                        .long("grpc-listen")
+                        .takes_value(true)
+                        .value_name("ADDR:PORT")
+                        .help("Also serve the gRPC API on this address (requires the grpc feature)"),
+                )
+                .arg(
+                    Arg::with_name("max-concurrent-runs")
+                        .long("max-concurrent-runs")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("2")
+                        .help("Max number of runs executed at the same time, the others are queued"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("login")
+                .about("Save the credentials of a profile in the OS keychain")
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("PROFILE")
+                        .help("The profile to save the credentials for"),
+                )
+                .arg(
+                    Arg::with_name("username")
+                        .short("u")
+                        .long("username")
+                        .takes_value(true)
+                        .help("The username, prompted for when omitted"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Manage the connection profiles")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("set-password")
+                        .about("Save the password of a profile in the OS keychain, rather than in the configuration file")
+                        .arg(
+                            Arg::with_name("profile")
+                                .help("The profile to save the password for")
+                                .required(true)
+                                .index(1),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("self-update")
+                .about("Replace kass with the binary of the latest GitHub release")
+                .arg(
+                    Arg::with_name("channel")
+                        .long("channel")
+                        .takes_value(true)
+                        .possible_values(&["stable", "nightly"])
+                        .default_value("stable")
+                        .help("The latest release, or the nightly build"),
+                ),
+        );
+    // Sent on a Unix socket
+    #[cfg(unix)]
+    let app = app.subcommand(
+        SubCommand::with_name("ctl")
+            .about("Send a command to a kass process started with --control-socket")
+            .arg(
+                Arg::with_name("command")
+                    .required(true)
+                    .possible_values(&["pause", "resume"])
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("pid")
+                    .required(true)
+                    .help("The pid of the kass process")
+                    .index(2),
+            ),
+    );
+    app
+}
+
+// The key of a command line argument like `--key=value`
+fn arg_key(arg: &str) -> &str {
+    let arg = arg.trim_start_matches("--");
+    arg.split_once('=').map_or(arg, |(key, _)| key)
+}
+
+// The command line along with the options of its `--profile` and
+// `--execution-profile` in the configuration file that it doesn't give,
+// those of the execution profile taking precedence
+fn matches() -> AppResult<ArgMatches<'static>> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let matches = app().get_matches_from(&args);
+    let (subcommand, command) = match matches.subcommand() {
+        ("login", _) | ("ctl", _) | ("config", _) => return Ok(matches),
+        (_, Some(m)) => (true, m),
+        _ => (false, &matches),
+    };
+    let given = |key: &str| command.occurrences_of(key) > 0;
+    let profile = command.value_of("profile");
+    let mut extra = match profile {
+        Some(x) => config_file::profile_args(x, given)?,
+        None => vec![],
+    };
+    let execution = command.value_of("execution-profile").map(String::from).or_else(|| {
+        extra
+            .iter()
+            .filter(|x| arg_key(x) == "execution-profile")
+            .find_map(|x| Some(x.split_once('=')?.1.to_string()))
+    });
+    if let Some(name) = &execution {
+        let execution_args = config_file::execution_profile_args(name, given)?;
+        extra.retain(|x| !execution_args.iter().any(|y| arg_key(x) == arg_key(y)));
+        extra.extend(execution_args);
+    }
+    if extra.is_empty() {
+        return Ok(matches);
+    }
+    // Before the positional arguments, which take the rest of the line
+    let at = 1 + subcommand as usize;
+    args.splice(at..at, extra.into_iter().map(OsString::from));
+    app().get_matches_from_safe(args).or_else(|err| {
+        let sources: Vec<String> = profile
+            .map(|x| format!("the profile {}", x))
+            .into_iter()
+            .chain(execution.map(|x| format!("the execution profile {}", x)))
+            .collect();
+        diag!("With the options of {}:", sources.join(" and "));
+        err.exit()
+    })
 }
 
 fn run() -> AppResult<()> {
-    let matches = app().get_matches();
+    // Before clap, which prints the version alone for --version
+    let args: Vec<String> = env::args_os()
+        .skip(1)
+        .map(|x| x.to_string_lossy().into_owned())
+        .collect();
+    if version::is_verbose(&args) {
+        println!("{}", version::info());
+        return Ok(());
+    }
+    let matches = matches()?;
+    let command = matches.subcommand().1.unwrap_or(&matches);
+    diag::set_quiet(command.is_present("quiet"));
+    let _trace = command.value_of("trace-out").map(trace::start).transpose()?;
+
+    match matches.subcommand() {
+        ("login", Some(login)) => credentials::login(login),
+        ("config", Some(config)) => match config.subcommand() {
+            ("set-password", Some(m)) => {
+                credentials::set_password(m.value_of("profile").unwrap_or_default())
+            }
+            _ => Err(AppError::new("Unknown config command")),
+        },
+        ("consistency-check", Some(check)) => consistency_check(check),
+        ("assert", Some(assert)) => assert_rows(assert),
+        ("import", Some(import_matches)) => import(import_matches),
+        ("migrate", Some(m)) => migrate::migrate(
+            core::Config::from_matches(m)?,
+            std::path::Path::new(m.value_of("dir").unwrap_or_default()),
+            m.value_of("keyspace").unwrap_or_default(),
+        ),
+        ("audit-ttl", Some(audit)) => audit_ttl(audit),
+        ("browse", Some(browse_matches)) => browse(browse_matches),
+        ("ping", Some(ping_matches)) => ping(ping_matches),
+        ("expand", Some(expand_matches)) => {
+            for value in params::expand(expand_matches.value_of("param").unwrap_or_default())? {
+                println!("{}", value);
+            }
+            Ok(())
+        }
+        ("serve", Some(serve_matches)) => serve(serve_matches),
+        ("self-update", Some(update)) => {
+            self_update::self_update(update.value_of("channel").unwrap_or("stable"))
+        }
+        #[cfg(unix)]
+        ("ctl", Some(ctl)) => control::send(
+            ctl.value_of("command").unwrap_or_default(),
+            ctl.value_of("pid").unwrap_or_default().parse()?,
+        ),
+        _ => run_query(&matches),
+    }
+}
+
+fn query_params(matches: &ArgMatches) -> AppResult<Option<Vec<params::ParamSet>>> {
+    // With --query the queries are not positional, all the arguments
+    // are parameters
+    let first = matches
+        .value_of("query")
+        .filter(|_| matches.is_present("queries"));
+    let args: Vec<&str> = first
+        .into_iter()
+        .chain(matches.values_of("param").into_iter().flatten())
+        .collect();
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let _span = tracing::info_span!("expand").entered();
+    params::parse_args_with(args.into_iter(), matches.is_present("lenient-params")).map(Some)
+}
+
+fn run_query(matches: &ArgMatches) -> AppResult<()> {
+    let queries: Vec<&str> = match matches.values_of("queries") {
+        Some(xs) => xs.collect(),
+        None => vec![matches
+            .value_of("query")
+            .ok_or_else(|| AppError::new("query is required"))?],
+    };
 
+    let param_values = query_params(matches)?;
+
+    let config = core::Config::from_matches(matches)?;
+    #[cfg(unix)]
+    let _socket = {
+        control::listen_signals(config.control())?;
+        if config.control_socket() {
+            Some(control::listen_socket(config.control())?)
+        } else {
+            None
+        }
+    };
+    core::run_queries(config, &queries, param_values)
+}
+
+fn import(matches: &ArgMatches) -> AppResult<()> {
+    let columns = |name| {
+        matches
+            .values_of(name)
+            .map_or(vec![], |xs| xs.map(String::from).collect())
+    };
+    let config = core::Config::from_matches(matches)?;
+    let options = import::Options {
+        mode: import::Mode::parse(matches.value_of("mode").unwrap_or_default())?,
+        preserve_ttl: matches.is_present("preserve-ttl"),
+        preserve_timestamp: matches.is_present("preserve-timestamp"),
+        only_columns: columns("only-columns"),
+        exclude_columns: columns("exclude-columns"),
+    };
+    let stdin = std::io::stdin();
+    import::import(
+        config,
+        matches.value_of("table").unwrap_or_default(),
+        options,
+        stdin.lock(),
+    )
+}
+
+fn audit_ttl(matches: &ArgMatches) -> AppResult<()> {
+    let partitions = matches
+        .values_of("pk")
+        .map_or(Ok(vec![]), |args| {
+            params::parse_args_with(args, matches.is_present("lenient-params"))
+        })?;
+    let sample: usize = matches.value_of("sample").unwrap_or_default().parse()?;
+    if sample == 0 {
+        return Err(AppError::new("--sample must be at least 1"));
+    }
+    let config = core::Config::from_matches(matches)?;
+    ttl_audit::audit(
+        config,
+        matches.value_of("table").unwrap_or_default(),
+        partitions,
+        sample,
+    )
+}
+
+fn browse(matches: &ArgMatches) -> AppResult<()> {
+    let fzf_limit = if matches.is_present("fzf") {
+        Some(matches.value_of("limit").map_or(Ok(1000), str::parse)?)
+    } else {
+        None
+    };
+    let config = core::Config::from_matches(matches)?;
+    browse::browse(
+        config,
+        matches.value_of("table").unwrap_or_default(),
+        fzf_limit,
+    )
+}
+
+fn ping(matches: &ArgMatches) -> AppResult<()> {
+    let count = matches.value_of("count").unwrap_or("1").parse()?;
+    if count == 0 {
+        return Err(AppError::new("--count must be at least 1"));
+    }
+    ping::ping(core::Config::from_matches(matches)?, count)
+}
+
+fn serve(matches: &ArgMatches) -> AppResult<()> {
+    // Their files hold the state of a single run
+    for option in ["incremental", "compare-to", "dead-letter", "validate-schema"] {
+        if matches.is_present(option) {
+            return Err(AppError::new(format!(
+                "--{} can't be used with kass serve",
+                option
+            )));
+        }
+    }
+    let config = core::Config::from_matches(matches)?;
+    let max_runs: usize = matches
+        .value_of("max-concurrent-runs")
+        .unwrap_or_default()
+        .parse()?;
+    if max_runs == 0 {
+        return Err(AppError::new("--max-concurrent-runs must be at least 1"));
+    }
+    // The runs of both APIs share the slots
+    let slots = Arc::new(server::Slots::new(max_runs));
+    // Either server failing, e.g. to listen, stops kass with its error
+    let (done, failed) = mpsc::channel();
+    if let Some(addr) = matches.value_of("grpc-listen") {
+        serve_grpc(addr, config.clone(), slots.clone(), done.clone())?;
+    }
+    let listen = matches.value_of("listen").unwrap_or_default().to_string();
+    thread::spawn(move || done.send(server::serve(&listen, config, slots)));
+    failed.recv().unwrap()
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(
+    addr: &str,
+    config: core::Config,
+    slots: Arc<server::Slots>,
+    done: mpsc::Sender<AppResult<()>>,
+) -> AppResult<()> {
+    let addr = addr.to_string();
+    thread::spawn(move || done.send(grpc::serve(&addr, config, slots)));
+    Ok(())
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(
+    _addr: &str,
+    _config: core::Config,
+    _slots: Arc<server::Slots>,
+    _done: mpsc::Sender<AppResult<()>>,
+) -> AppResult<()> {
+    Err(AppError::new("kass was built without gRPC support, rebuild with --features grpc"))
+}
+
+fn consistency_check(matches: &ArgMatches) -> AppResult<()> {
+    let query = matches
+        .value_of("query")
+        .ok_or_else(|| AppError::new("query is required"))?;
+
+    let param_values = query_params(matches)?.unwrap_or_else(|| vec![Default::default()]);
+
+    let levels = matches
+        .values_of("levels")
+        .map_or(Ok(vec![]), |xs| xs.map(consistency::parse).collect())?;
+    if levels.len() < 2 {
+        return Err(AppError::new("at least two consistency levels are required"));
+    }
+
+    let config = core::Config::from_matches(matches)?;
+    core::consistency_check(config, query, param_values, levels)
+}
+
+fn assert_rows(matches: &ArgMatches) -> AppResult<()> {
     let query = matches
         .value_of("query")
         .ok_or_else(|| AppError::new("query is required"))?;
 
-    let param_values = matches
-        .values_of("param")
-        .map(params::parse_args)
-        .map_or(Ok(None), |r| r.map(Some))?;
+    let param_values = query_params(matches)?.unwrap_or_else(|| vec![Default::default()]);
+
+    let rows = matches
+        .value_of("expect-rows")
+        .map(assertions::RowCount::parse)
+        .transpose()?;
+    let expectations = matches
+        .values_of("expect")
+        .map_or(Ok(vec![]), |xs| xs.map(assertions::Expectation::parse).collect())?;
+    if rows.is_none() && expectations.is_empty() {
+        return Err(AppError::new("--expect-rows or --expect is required"));
+    }
 
-    let config = core::Config::from_matches(&matches)?;
-    core::run_query(config, query, param_values)
+    let config = core::Config::from_matches(matches)?;
+    assertions::check(config, query, param_values, rows, expectations)
 }
 
 fn main() {
-    if let Err(err) = run() {
+    let result = run();
+    socks::close_tunnels();
+    if let Err(err) = result {
         eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,98 @@
+use std::sync::{Condvar, Mutex};
+
+/// Approximate accounting of the memory held by query results that
+/// have been received but not written out yet. With a limit set,
+/// workers wait for buffered results to be written before issuing
+/// new queries, so a slow consumer of stdout applies backpressure
+/// instead of letting memory grow unbounded.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// Bytes reserved from a `MemoryBudget`, given back when dropped.
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        MemoryBudget {
+            limit,
+            used: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Block until the buffered results are below the limit.
+    pub fn wait_for_capacity(&self) {
+        if let Some(limit) = self.limit {
+            let mut used = self.used.lock().unwrap();
+            while *used >= limit {
+                used = self.freed.wait(used).unwrap();
+            }
+        }
+    }
+
+    /// The bytes reserved, and the limit.
+    pub fn usage(&self) -> (usize, Option<usize>) {
+        (*self.used.lock().unwrap(), self.limit)
+    }
+
+    pub fn reserve(&self, bytes: usize) -> Reservation<'_> {
+        *self.used.lock().unwrap() += bytes;
+        Reservation {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.bytes;
+        self.budget.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn used(budget: &MemoryBudget) -> usize {
+        *budget.used.lock().unwrap()
+    }
+
+    #[test]
+    fn test_reservation_released_on_drop() {
+        let budget = MemoryBudget::new(None);
+        {
+            let _a = budget.reserve(10);
+            let _b = budget.reserve(5);
+            assert_eq!(15, used(&budget));
+        }
+        assert_eq!(0, used(&budget));
+    }
+
+    #[test]
+    fn test_wait_for_capacity() {
+        let budget = Arc::new(MemoryBudget::new(Some(10)));
+        let waiter = budget.clone();
+        let reservation = budget.reserve(20);
+
+        let handle = thread::spawn(move || {
+            waiter.wait_for_capacity();
+            used(&waiter)
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(reservation);
+
+        assert_eq!(0, handle.join().unwrap());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use cdrs::error::Error as CDRSError;
+use cdrs::frame::frame_error::AdditionalErrorInfo;
+
+// Upper bounds of the query latency buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Process wide counters, exposed in the Prometheus text format by
+/// `GET /metrics` in server mode.
+#[derive(Debug)]
+pub struct Metrics {
+    queries: AtomicU64,
+    failures: Mutex<BTreeMap<&'static str, u64>>,
+    rows: AtomicU64,
+    in_flight: AtomicI64,
+    // Not cumulative, one count per bucket plus one for +Inf
+    latency_buckets: Vec<AtomicU64>,
+    latency_sum_micros: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            queries: AtomicU64::new(0),
+            failures: Mutex::new(BTreeMap::new()),
+            rows: AtomicU64::new(0),
+            in_flight: AtomicI64::new(0),
+            latency_buckets: (0..=LATENCY_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            latency_sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+fn failure_type(err: &CDRSError) -> &'static str {
+    match err {
+        CDRSError::Io(_) => "io",
+        CDRSError::Server(err) => match err.additional_info {
+            AdditionalErrorInfo::Unavailable(_) => "unavailable",
+            AdditionalErrorInfo::Overloaded(_) => "overloaded",
+            AdditionalErrorInfo::IsBootstrapping(_) => "bootstrapping",
+            AdditionalErrorInfo::ReadTimeout(_) => "read_timeout",
+            AdditionalErrorInfo::WriteTimeout(_) => "write_timeout",
+            AdditionalErrorInfo::ReadFailure(_) => "read_failure",
+            AdditionalErrorInfo::WriteFailure(_) => "write_failure",
+            AdditionalErrorInfo::Syntax(_) | AdditionalErrorInfo::Invalid(_) => "invalid",
+            AdditionalErrorInfo::Authentication(_) | AdditionalErrorInfo::Unauthorized(_) => {
+                "unauthorized"
+            }
+            _ => "server",
+        },
+        _ => "driver",
+    }
+}
+
+impl Metrics {
+    pub fn query_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn query_finished<T>(&self, elapsed: Duration, result: &Result<T, CDRSError>) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = result {
+            *self
+                .failures
+                .lock()
+                .unwrap()
+                .entry(failure_type(err))
+                .or_insert(0) += 1;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn row_emitted(&self) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "kass_queries_total",
+            "counter",
+            "Queries executed.",
+        );
+        let queries = self.queries.load(Ordering::Relaxed);
+        writeln!(out, "kass_queries_total {}", queries).unwrap();
+
+        header(
+            &mut out,
+            "kass_query_failures_total",
+            "counter",
+            "Failed queries by type of failure.",
+        );
+        for (kind, n) in self.failures.lock().unwrap().iter() {
+            writeln!(out, "kass_query_failures_total{{type=\"{}\"}} {}", kind, n).unwrap();
+        }
+
+        header(
+            &mut out,
+            "kass_rows_emitted_total",
+            "counter",
+            "Result rows emitted.",
+        );
+        let rows = self.rows.load(Ordering::Relaxed);
+        writeln!(out, "kass_rows_emitted_total {}", rows).unwrap();
+
+        header(
+            &mut out,
+            "kass_queries_in_flight",
+            "gauge",
+            "Queries being executed.",
+        );
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        writeln!(out, "kass_queries_in_flight {}", in_flight).unwrap();
+
+        header(
+            &mut out,
+            "kass_query_duration_seconds",
+            "histogram",
+            "Latency of the queries.",
+        );
+        let mut count = 0;
+        for (i, n) in self.latency_buckets.iter().enumerate() {
+            count += n.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            writeln!(
+                out,
+                "kass_query_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, count
+            )
+            .unwrap();
+        }
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "kass_query_duration_seconds_sum {}", sum).unwrap();
+        writeln!(out, "kass_query_duration_seconds_count {}", count).unwrap();
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.query_started();
+        metrics.query_finished(Duration::from_millis(20), &Ok(()));
+        metrics.query_started();
+        let err: Result<(), _> = Err(CDRSError::General("boom".into()));
+        metrics.query_finished(Duration::from_secs(20), &err);
+        metrics.row_emitted();
+
+        let out = metrics.render();
+        for line in &[
+            "kass_queries_total 2",
+            "kass_query_failures_total{type=\"driver\"} 1",
+            "kass_rows_emitted_total 1",
+            "kass_queries_in_flight 0",
+            "kass_query_duration_seconds_bucket{le=\"0.01\"} 0",
+            "kass_query_duration_seconds_bucket{le=\"0.025\"} 1",
+            "kass_query_duration_seconds_bucket{le=\"10\"} 1",
+            "kass_query_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "kass_query_duration_seconds_sum 20.02",
+            "kass_query_duration_seconds_count 2",
+        ] {
+            assert!(out.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+}
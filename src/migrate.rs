@@ -0,0 +1,210 @@
+// `kass migrate`: apply the `.cql` files of a directory in the order of
+// their names. Applied files are recorded in `<keyspace>.kass_migrations`
+// and a lightweight transaction on `<keyspace>.kass_migrations_lock`
+// keeps concurrent runs from applying the same files twice.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::Utc;
+use serde_json::Value as JsonValue;
+
+use crate::core::{self, Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+use crate::schema;
+
+// The lock expires in case kass dies while holding it, and is refreshed
+// before every statement while migrating
+const LOCK_TTL_SECS: u32 = 600;
+
+/// The `.cql` files of `dir` sorted by name, along with their version,
+/// i.e. their name without the extension.
+fn migration_files(dir: &Path) -> AppResult<Vec<(String, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "cql") {
+            if let Some(version) = path.file_stem().and_then(|x| x.to_str()) {
+                files.push((version.to_string(), path.clone()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+struct Migrator<'a> {
+    session: &'a CurrentSession,
+    config: &'a Config,
+    keyspace: &'a str,
+    owner: String,
+}
+
+impl Migrator<'_> {
+    fn execute(&self, cql: &str, values: Vec<String>) -> AppResult<Vec<JsonValue>> {
+        let params = self
+            .config
+            .params(values.into_iter().map(Into::into).collect());
+        let resp = self.session.query(cql, params)?;
+        core::collect_rows(&resp, self.config)
+    }
+
+    fn ddl(&self, cql: &str) -> AppResult<()> {
+        self.session.query(cql, self.config.params(vec![]))?;
+        schema::wait_for_agreement(
+            self.session,
+            self.config,
+            self.config.schema_agreement_timeout(),
+        )
+    }
+
+    fn create_tables(&self) -> AppResult<()> {
+        self.ddl(&format!(
+            "CREATE TABLE IF NOT EXISTS {}.kass_migrations (version text PRIMARY KEY, applied_at timestamp)",
+            self.keyspace
+        ))?;
+        self.ddl(&format!(
+            "CREATE TABLE IF NOT EXISTS {}.kass_migrations_lock (id int PRIMARY KEY, owner text)",
+            self.keyspace
+        ))
+    }
+
+    fn lock(&self) -> AppResult<()> {
+        let rows = self.execute(
+            &format!(
+                "INSERT INTO {}.kass_migrations_lock (id, owner) VALUES (1, ?) IF NOT EXISTS USING TTL {}",
+                self.keyspace, LOCK_TTL_SECS
+            ),
+            vec![self.owner.clone()],
+        )?;
+        match rows.first() {
+            Some(row) if row.get("[applied]") == Some(&JsonValue::Bool(true)) => Ok(()),
+            Some(row) => Err(AppError::new(format!(
+                "Migrations are locked by {}",
+                row.get("owner")
+                    .and_then(|x| x.as_str())
+                    .unwrap_or("unknown")
+            ))),
+            None => Err(AppError::new("Unable to acquire the migration lock")),
+        }
+    }
+
+    // Keep the lock from expiring, failing if it was lost in the
+    // meantime, e.g. after a statement that took longer than its TTL
+    fn refresh_lock(&self) -> AppResult<()> {
+        let rows = self.execute(
+            &format!(
+                "UPDATE {}.kass_migrations_lock USING TTL {} SET owner = ? WHERE id = 1 IF owner = ?",
+                self.keyspace, LOCK_TTL_SECS
+            ),
+            vec![self.owner.clone(), self.owner.clone()],
+        )?;
+        match rows.first() {
+            Some(row) if row.get("[applied]") == Some(&JsonValue::Bool(true)) => Ok(()),
+            _ => Err(AppError::new(
+                "Lost the migration lock, stopping before the next statement",
+            )),
+        }
+    }
+
+    fn unlock(&self) -> AppResult<()> {
+        self.execute(
+            &format!(
+                "DELETE FROM {}.kass_migrations_lock WHERE id = 1 IF owner = ?",
+                self.keyspace
+            ),
+            vec![self.owner.clone()],
+        )?;
+        Ok(())
+    }
+
+    fn applied_versions(&self) -> AppResult<Vec<String>> {
+        let rows = self.execute(
+            &format!("SELECT version FROM {}.kass_migrations", self.keyspace),
+            vec![],
+        )?;
+        Ok(rows
+            .iter()
+            .filter_map(|x| x.get("version").and_then(|x| x.as_str()))
+            .map(String::from)
+            .collect())
+    }
+
+    fn apply(&self, version: &str, path: &Path) -> AppResult<()> {
+        let script = fs::read_to_string(path)?;
+        for statement in schema::split_statements(&script) {
+            self.refresh_lock()?;
+            self.ddl(&statement)
+                .map_err(|err| AppError::new(format!("Migration {} failed: {}", version, err)))?;
+        }
+        self.refresh_lock()?;
+        self.execute(
+            &format!(
+                "INSERT INTO {}.kass_migrations (version, applied_at) VALUES (?, toTimestamp(now()))",
+                self.keyspace
+            ),
+            vec![version.to_string()],
+        )?;
+        diag!("Applied {}", version);
+        Ok(())
+    }
+
+    fn apply_pending(&self, dir: &Path) -> AppResult<()> {
+        let applied = self.applied_versions()?;
+        let pending: Vec<_> = migration_files(dir)?
+            .into_iter()
+            .filter(|(version, _)| !applied.contains(version))
+            .collect();
+        if pending.is_empty() {
+            diag!("No pending migrations");
+        }
+        for (version, path) in pending {
+            self.apply(&version, &path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Apply the migrations of `dir` not applied yet, keeping track of
+/// them in `keyspace`.
+pub fn migrate(config: Config, dir: &Path, keyspace: &str) -> AppResult<()> {
+    let session = config.connect()?;
+    let migrator = Migrator {
+        session: &session,
+        config: &config,
+        keyspace,
+        owner: format!("kass-{}@{}", process::id(), Utc::now().to_rfc3339()),
+    };
+    migrator.create_tables()?;
+    migrator.lock()?;
+    let result = migrator.apply_pending(dir);
+    // The error of the migrations matters more
+    match (migrator.unlock(), &result) {
+        (Err(err), Err(_)) => diag!("Unable to release the migration lock: {}", err),
+        (Err(err), Ok(())) => return Err(err),
+        (Ok(()), _) => {}
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_migration_files() {
+        let dir = env::temp_dir().join(format!("kass-migrations-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["002_b.cql", "001_a.cql", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let versions: Vec<String> = migration_files(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|(version, _)| version)
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec!["001_a", "002_b"], versions);
+    }
+}
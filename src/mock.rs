@@ -0,0 +1,303 @@
+// `--backend mock`: answer queries with the result sets of a fixture
+// file instead of a cluster, e.g. to try the output formats or to test
+// a pipeline. A fixture file is a JSON array of:
+//
+//     {"query": "SELECT * FROM ks.users WHERE id = ?", "params": ["1..3"],
+//      "columns": {"id": "int", "name": "text"},
+//      "rows": [{"id": 1, "name": "alice"}]}
+//
+// `params` uses the syntax of query parameters and matches any value
+// when omitted. Queries are compared ignoring case and whitespace.
+//
+// `--replay` uses the same backend with the frames of a recording.
+use std::convert::TryInto;
+use std::fs;
+use std::sync::Mutex;
+
+use cdrs::error::{Error, Result};
+use cdrs::frame::frame_result::{BodyResResultRows, ColSpec, ColType, ColTypeOption, RowsMetadata};
+use cdrs::types::value::Value;
+use cdrs::types::{CBytes, CString};
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::backend::{self, Backend, Params, Prepared, Response};
+use crate::errors::{AppError, AppResult};
+use crate::params::{self, Values};
+use crate::record;
+
+#[derive(Deserialize)]
+struct Fixture {
+    query: String,
+    params: Option<Vec<String>>,
+    #[serde(default)]
+    columns: Map<String, JsonValue>,
+    #[serde(default)]
+    rows: Vec<Map<String, JsonValue>>,
+}
+
+enum Canned {
+    Rows(BodyResResultRows),
+    // The body of a recorded frame
+    Frame(Vec<u8>),
+}
+
+struct ResultSet {
+    query: String,
+    // Every combination of the parameters matched, `None` for any
+    params: Option<Vec<Values>>,
+    response: Canned,
+}
+
+pub struct Mock {
+    results: Vec<ResultSet>,
+    prepared: Mutex<Vec<String>>,
+}
+
+fn normalize(cql: &str) -> String {
+    cql.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn col_type(name: &str) -> AppResult<ColType> {
+    Ok(match name {
+        "ascii" => ColType::Ascii,
+        "text" | "varchar" => ColType::Varchar,
+        "int" => ColType::Int,
+        "bigint" => ColType::Bigint,
+        "counter" => ColType::Counter,
+        "smallint" => ColType::Smallint,
+        "tinyint" => ColType::Tinyint,
+        "boolean" => ColType::Boolean,
+        "double" => ColType::Double,
+        "float" => ColType::Float,
+        "timestamp" => ColType::Timestamp,
+        "uuid" => ColType::Uuid,
+        "timeuuid" => ColType::Timeuuid,
+        _ => return Err(AppError::new(format!("Unsupported fixture type: {}", name))),
+    })
+}
+
+fn encode(id: &ColType, value: &JsonValue) -> Option<CBytes> {
+    if value.is_null() {
+        return Some(CBytes::new_empty());
+    }
+    let value = match id {
+        ColType::Ascii | ColType::Varchar => Value::from(value.as_str()?),
+        ColType::Int => Value::from(value.as_i64()? as i32),
+        ColType::Bigint | ColType::Counter => Value::from(value.as_i64()?),
+        ColType::Smallint => Value::from(value.as_i64()? as i16),
+        ColType::Tinyint => Value::from(value.as_i64()? as i8),
+        ColType::Boolean => Value::from(value.as_bool()?),
+        ColType::Double => Value::from(value.as_f64()?),
+        ColType::Float => Value::from(value.as_f64()? as f32),
+        ColType::Timestamp => Value::from(match value {
+            JsonValue::String(x) => DateTime::parse_from_rfc3339(x).ok()?.timestamp_millis(),
+            x => x.as_i64()?,
+        }),
+        ColType::Uuid | ColType::Timeuuid => {
+            Value::from(uuid::Uuid::parse_str(value.as_str()?).ok()?)
+        }
+        _ => return None,
+    };
+    Some(CBytes::new(value.body))
+}
+
+fn result_set(fixture: Fixture) -> AppResult<ResultSet> {
+    let columns = fixture
+        .columns
+        .iter()
+        .map(|(name, t)| {
+            let t = t.as_str().ok_or_else(|| {
+                AppError::new(format!("The type of column {} must be a string", name))
+            })?;
+            Ok((name.clone(), col_type(t)?))
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+
+    let rows_content = fixture
+        .rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|(name, id)| {
+                    let value = row.get(name).unwrap_or(&JsonValue::Null);
+                    encode(id, value).ok_or_else(|| {
+                        AppError::new(format!(
+                            "Invalid value {} for column {} in the fixture of {}",
+                            value, name, fixture.query
+                        ))
+                    })
+                })
+                .collect()
+        })
+        .collect::<AppResult<Vec<Vec<CBytes>>>>()?;
+
+    let params = match &fixture.params {
+        Some(xs) => Some(
+            params::parse_args(xs.iter().map(String::as_str))?
+                .into_iter()
+                .map(|x| x.values)
+                .collect(),
+        ),
+        None => None,
+    };
+    Ok(ResultSet {
+        query: normalize(&fixture.query),
+        params,
+        response: Canned::Rows(BodyResResultRows {
+            metadata: RowsMetadata {
+                flags: 0,
+                columns_count: columns.len() as i32,
+                paging_state: None,
+                global_table_space: None,
+                col_specs: columns
+                    .into_iter()
+                    .map(|(name, id)| ColSpec {
+                        ksname: None,
+                        tablename: None,
+                        name: CString::new(name),
+                        col_type: ColTypeOption { id, value: None },
+                    })
+                    .collect(),
+            },
+            rows_count: rows_content.len() as i32,
+            rows_content,
+        }),
+    })
+}
+
+impl Mock {
+    pub fn load(path: &str) -> AppResult<Mock> {
+        let fixtures: Vec<Fixture> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(Mock {
+            results: fixtures
+                .into_iter()
+                .map(result_set)
+                .collect::<AppResult<_>>()?,
+            prepared: Mutex::new(vec![]),
+        })
+    }
+
+    /// Replay a recording of `--record`.
+    pub fn replay(path: &str) -> AppResult<Mock> {
+        let results = record::read(path)?
+            .into_iter()
+            .map(|entry| {
+                Ok(ResultSet {
+                    query: normalize(&entry.query),
+                    params: Some(vec![entry.values()?]),
+                    response: Canned::Frame(entry.body()?),
+                })
+            })
+            .collect::<AppResult<_>>()?;
+        Ok(Mock {
+            results,
+            prepared: Mutex::new(vec![]),
+        })
+    }
+
+    fn respond(&self, cql: &str, values: &[Value]) -> Result<Response> {
+        let query = normalize(cql);
+        let found = self.results.iter().find(|x| {
+            x.query == query
+                && x.params
+                    .as_ref()
+                    .is_none_or(|sets| sets.iter().any(|set| set.as_slice() == values))
+        });
+        match found {
+            Some(ResultSet {
+                response: Canned::Rows(rows),
+                ..
+            }) => Ok(Response::Rows(BodyResResultRows {
+                metadata: rows.metadata.clone(),
+                rows_count: rows.rows_count,
+                rows_content: rows.rows_content.clone(),
+            })),
+            Some(ResultSet {
+                response: Canned::Frame(body),
+                ..
+            }) => backend::decode(body),
+            // Lookups of the schema made along the way find nothing
+            None if query.contains(" from system") => Ok(Response::Void),
+            None => Err(Error::General(format!("No fixture for query: {}", cql))),
+        }
+    }
+}
+
+impl Backend for Mock {
+    fn prepare(&self, cql: &str) -> Result<Prepared> {
+        let mut prepared = self.prepared.lock().unwrap();
+        prepared.push(cql.to_string());
+        Ok(Prepared::new(
+            (prepared.len() as u32 - 1).to_be_bytes().to_vec(),
+        ))
+    }
+
+    fn query(&self, cql: &str, params: Params) -> Result<Response> {
+        self.respond(cql, &params.values)
+    }
+
+    fn execute(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        let unknown = || Error::General("Unknown prepared statement".into());
+        let id = prepared.clone().into_plain().unwrap_or_default();
+        let index = u32::from_be_bytes(id.try_into().map_err(|_| unknown())?);
+        let cql = self
+            .prepared
+            .lock()
+            .unwrap()
+            .get(index as usize)
+            .cloned()
+            .ok_or_else(unknown)?;
+        self.respond(&cql, &params.values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_respond() {
+        let path = env::temp_dir().join(format!("kass-fixture-{}.json", process::id()));
+        fs::write(
+            &path,
+            r#"[{"query": "SELECT * FROM ks.users WHERE id = ?", "params": ["1..3"],
+                 "columns": {"id": "int", "name": "text"},
+                 "rows": [{"id": 1, "name": "alice"}, {"id": 2}]}]"#,
+        )
+        .unwrap();
+        let mock = Mock::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let prepared = mock
+            .prepare("select *\n from ks.users where id = ?")
+            .unwrap();
+        let values = params::parse_args(vec!["2..3"].into_iter()).unwrap()[0]
+            .values
+            .clone();
+        match mock.execute(&prepared, Params::new(values, Default::default())) {
+            Ok(Response::Rows(rows)) => {
+                assert_eq!(2, rows.rows_count);
+                assert_eq!(None, rows.rows_content[1][1].as_slice());
+            }
+            x => panic!("unexpected response {:?}", x),
+        }
+        let values = params::parse_args(vec!["4..5"].into_iter()).unwrap()[0]
+            .values
+            .clone();
+        assert!(mock
+            .execute(&prepared, Params::new(values, Default::default()))
+            .is_err());
+        assert!(matches!(
+            mock.query("SELECT * FROM system.peers", Params::default()),
+            Ok(Response::Void)
+        ));
+    }
+}
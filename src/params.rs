@@ -2,6 +2,7 @@ use crate::date_range::{DateTimeRange, DATE_FORMAT, DATE_TIME_FORMAT};
 use crate::errors::{AppError, AppResult};
 
 use cdrs::types::value::Value;
+use chrono::format::{Item, StrftimeItems};
 use core::ops::Range;
 use itertools::Itertools;
 use regex::Regex;
@@ -24,11 +25,96 @@ lazy_static! {
     static ref DATE_TIME_RANGE: Regex = Regex::new(
         r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2})\.\.(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2})(?:/(\d+)([mdwHMS])(?:/([a-zA-Z%\-/:]+))?)?$"
     ).unwrap();
+    // Dates and times with unpadded fields or a space before the time
+    static ref LOOSE_DATE_TIME: Regex = Regex::new(
+        r"^(\d{4})-(\d{1,2})-(\d{1,2})(?:[T ](\d{1,2}):(\d{1,2})(?::(\d{1,2}))?)?$"
+    ).unwrap();
     //static ref COMMA_SEPARATED: Regex = Regex::new(r#"(?:^|,)(?=[^"]|(")?)"?((?(1)[^"]*|[^,"]*))"?(?=,|$)"#).unwrap();
 }
 
 pub type Values = Vec<Value>;
 
+/// One combination of query parameter values, along with their
+/// textual form for reporting.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParamSet {
+    pub values: Values,
+    pub labels: Vec<String>,
+}
+
+const REDACTED: &str = "***";
+
+/// Positions of the parameters whose values are masked in logs and
+/// reports, still being bound to the query as usual.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Redaction(Vec<usize>);
+
+impl Redaction {
+    /// Each spec is either the 1-based position of a parameter or the
+    /// name of a named bind marker (`:name`) in `cql`.
+    pub fn parse<'a>(specs: impl Iterator<Item = &'a str>, cql: &str) -> AppResult<Redaction> {
+        let markers = bind_markers(cql);
+        let positions = specs
+            .map(|spec| match spec.parse::<usize>() {
+                Ok(0) => Err(AppError::new("parameter positions start at 1")),
+                Ok(n) => Ok(n - 1),
+                Err(_) => markers
+                    .iter()
+                    .position(|m| m.as_deref() == Some(spec))
+                    .ok_or_else(|| {
+                        AppError::new(format!("No bind marker named '{}' in the query", spec))
+                    }),
+            })
+            .collect::<AppResult<_>>()?;
+        Ok(Redaction(positions))
+    }
+
+    pub fn labels(&self, labels: &[String]) -> Vec<String> {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                if self.0.contains(&i) {
+                    REDACTED.to_string()
+                } else {
+                    x.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+// Bind markers of a query in order, with the name of the named ones.
+// String literals, quoted identifiers and comments are skipped.
+pub fn bind_markers(cql: &str) -> Vec<Option<String>> {
+    let mut markers = vec![];
+    let mut chars = cql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                for x in chars.by_ref() {
+                    if x == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                while chars.next_if(|x| *x != '\n').is_some() {}
+            }
+            '?' => markers.push(None),
+            ':' if chars.peek().is_some_and(|x| x.is_alphabetic() || *x == '_') => {
+                let mut name = String::new();
+                while let Some(x) = chars.next_if(|x| x.is_alphanumeric() || *x == '_') {
+                    name.push(x);
+                }
+                markers.push(Some(name));
+            }
+            _ => {}
+        }
+    }
+    markers
+}
+
 fn parse_int_range<'a>(
     from: &'a str,
     to: &'a str,
@@ -45,15 +131,225 @@ fn parse_int_range<'a>(
         } else {
             1
         };
+        if step == 0 {
+            return Err(AppError::new("the step of a range must be at least 1"));
+        }
         Ok(QueryValues::IntRange { range, step })
     }
 }
 
-fn comma_separated<'a>(s: &'a str) -> Vec<&'a str> {
+fn comma_separated(s: &str) -> Vec<&str> {
     s.split(',').collect()
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RangeKind {
+    Int,
+    Date,
+    DateTime,
+}
+
+impl RangeKind {
+    fn example(self) -> &'static str {
+        match self {
+            RangeKind::Int => "an int range (e.g. 1..100/10)",
+            RangeKind::Date => "a date range (e.g. 2019-09-01..2019-12-01/1m)",
+            RangeKind::DateTime => {
+                "a date time range (e.g. 2019-09-01T00:00:00..2019-09-02T00:00:00/1H)"
+            }
+        }
+    }
+
+    // The shape of the ends of the range, `d` standing for a digit
+    fn template(self) -> &'static str {
+        match self {
+            RangeKind::Int => "",
+            RangeKind::Date => "dddd-dd-dd",
+            RangeKind::DateTime => "dddd-dd-ddTdd:dd:dd",
+        }
+    }
+
+    fn shape(self) -> &'static str {
+        match self {
+            RangeKind::Int => "an integer",
+            RangeKind::Date => "YYYY-MM-DD",
+            RangeKind::DateTime => "YYYY-MM-DDTHH:MM:SS",
+        }
+    }
+
+    fn units(self) -> &'static str {
+        match self {
+            RangeKind::Int => "",
+            RangeKind::Date => "m, d or w",
+            RangeKind::DateTime => "m, d, w, H, M or S",
+        }
+    }
+}
+
+// The character position in `end` where it stops looking like an end of
+// a range of `kind`, if it does
+fn end_mismatch(end: &str, kind: RangeKind) -> Option<usize> {
+    if kind == RangeKind::Int {
+        return match end.chars().position(|c| !c.is_ascii_digit()) {
+            None if end.is_empty() => Some(0),
+            x => x,
+        };
+    }
+    let template: Vec<char> = kind.template().chars().collect();
+    let chars: Vec<char> = end.chars().collect();
+    (0..template.len().max(chars.len())).find(|&i| match (template.get(i), chars.get(i)) {
+        (Some('d'), Some(c)) => !c.is_ascii_digit(),
+        (Some(t), Some(c)) => t != c,
+        _ => true,
+    })
+}
+
+// `end` in the shape of `kind`, e.g. with its month and day padded or
+// the time of a date added
+fn fix_end(end: &str, kind: RangeKind) -> Option<String> {
+    if kind == RangeKind::Int {
+        return Some(end.to_string()).filter(|x| end_mismatch(x, kind).is_none());
+    }
+    let x = LOOSE_DATE_TIME.captures(end)?;
+    let part = |i: usize| x.get(i).map_or(0, |x| x.as_str().parse::<u32>().unwrap_or(0));
+    let date = format!("{}-{:02}-{:02}", &x[1], part(2), part(3));
+    Some(match kind {
+        RangeKind::DateTime => format!("{}T{:02}:{:02}:{:02}", date, part(4), part(5), part(6)),
+        _ => date,
+    })
+}
+
+// The unit of a step, by the names it could be given
+fn fix_unit(unit: &str, kind: RangeKind) -> Option<&'static str> {
+    let time = kind == RangeKind::DateTime;
+    Some(match unit {
+        "d" | "D" | "day" | "days" => "d",
+        "w" | "W" | "week" | "weeks" => "w",
+        "m" | "mo" | "month" | "months" => "m",
+        "M" if !time => "m",
+        "H" | "h" | "hour" | "hours" if time => "H",
+        "M" | "min" | "minute" | "minutes" if time => "M",
+        "S" | "s" | "sec" | "second" | "seconds" if time => "S",
+        _ => return None,
+    })
+}
+
+/// A diagnostic for `s` when it starts like a range but doesn't parse as
+/// one, rather than taking it as a literal string: where it goes wrong,
+/// and a correction when one can be guessed.
+fn lint_range(s: &str) -> Option<String> {
+    let (from, rest) = s.split_once("..")?;
+    if !from.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut segments = rest.splitn(3, '/');
+    let to = segments.next().unwrap_or_default();
+    let (step, last) = (segments.next(), segments.next());
+    let kind = if [from, to].iter().any(|x| x.contains(':') || x.contains('T')) {
+        RangeKind::DateTime
+    } else if from.contains('-') {
+        RangeKind::Date
+    } else {
+        RangeKind::Int
+    };
+
+    // The first problem found, at a character position of `s`
+    let mut problem: Option<(usize, String)> = None;
+    let mut report = |at: usize, what: String| {
+        problem.get_or_insert((at, what));
+    };
+    let mut suggestion = vec![];
+    let mut offset = 0;
+    for (end, sep) in [(from, ".."), (to, "/")] {
+        if let Some(i) = end_mismatch(end, kind) {
+            report(offset + i, format!("expected {}", kind.shape()));
+        }
+        suggestion.push(fix_end(end, kind).map(|x| x + sep));
+        offset += end.chars().count() + sep.len();
+    }
+
+    if let Some(step) = step {
+        let digits = step.chars().take_while(char::is_ascii_digit).count();
+        let unit = &step[digits..];
+        if digits == 0 {
+            report(offset, "expected the number of the step".into());
+        }
+        let fix = match kind {
+            RangeKind::Int if !unit.is_empty() => {
+                report(offset + digits, "int ranges step by a plain number".into());
+                Some("")
+            }
+            RangeKind::Int => Some(""),
+            _ => {
+                let fix = fix_unit(unit, kind);
+                if fix != Some(unit) {
+                    report(
+                        offset + digits,
+                        format!("the unit of the step must be one of {}", kind.units()),
+                    );
+                }
+                fix
+            }
+        };
+        let number = if digits == 0 { "1" } else { &step[..digits] };
+        suggestion.push(fix.map(|x| format!("{}{}/", number, x)));
+        offset += step.chars().count() + 1;
+    }
+
+    if let Some(last) = last {
+        let invalid = match kind {
+            RangeKind::Int => ["int", "smallint", "tinyint", "bigint"]
+                .iter()
+                .all(|x| x != &last)
+                .then_some(0),
+            _ => last
+                .chars()
+                .position(|c| !(c.is_ascii_alphabetic() || "%-/:".contains(c))),
+        };
+        if let Some(i) = invalid {
+            let what = match kind {
+                RangeKind::Int => "expected int, smallint, tinyint or bigint",
+                _ => "unexpected character in the format",
+            };
+            report(offset + i, what.into());
+        }
+        suggestion.push(Some(format!("{}/", last)));
+    }
+
+    let (at, what) = problem?;
+    let mut message = format!(
+        "Invalid parameter {}, which looks like {}: {} at character {}",
+        s,
+        kind.example(),
+        what,
+        at + 1
+    );
+    let suggestion = suggestion
+        .into_iter()
+        .collect::<Option<String>>()
+        .map(|x| x.trim_end_matches(&['/', '.'][..]).to_string())
+        .filter(|x| x != s && parse_query_values(x).is_ok());
+    if let Some(x) = suggestion {
+        message.push_str(&format!(". Did you mean {}?", x));
+    }
+    Some(message)
+}
+
+// The format of the values of a date range, checked as chrono panics
+// when formatting with an invalid one
+fn date_format(fmt: &str) -> AppResult<&str> {
+    if StrftimeItems::new(fmt).any(|x| x == Item::Error) {
+        Err(AppError::new(format!("Invalid date format {}", fmt)))
+    } else {
+        Ok(fmt)
+    }
+}
+
 fn parse_query_values<'a>(s: &'a str) -> AppResult<QueryValues<'a>> {
+    parse_values(s, false)
+}
+
+fn parse_values(s: &str, lenient: bool) -> AppResult<QueryValues<'_>> {
     if let Some(matches) = INT_RANGE.captures(s) {
         Ok(parse_int_range(
             matches.get(1).unwrap().as_str(),
@@ -64,48 +360,105 @@ fn parse_query_values<'a>(s: &'a str) -> AppResult<QueryValues<'a>> {
         let range = DateTimeRange::parse_date_strs(
             matches.get(1).unwrap().as_str(),
             matches.get(2).unwrap().as_str(),
-            matches.get(3).unwrap().as_str(),
-            matches.get(4).unwrap().as_str(),
+            // One day at a time by default
+            matches.get(3).map_or("1", |x| x.as_str()),
+            matches.get(4).map_or("d", |x| x.as_str()),
         )?;
-        let fmt = matches.get(5).map_or(DATE_FORMAT, |x| x.as_str());
+        let fmt = date_format(matches.get(5).map_or(DATE_FORMAT, |x| x.as_str()))?;
         Ok(QueryValues::DateTimeRange { range, fmt })
     } else if let Some(matches) = DATE_TIME_RANGE.captures(s) {
         let range = DateTimeRange::parse_date_time_strs(
             matches.get(1).unwrap().as_str(),
             matches.get(2).unwrap().as_str(),
-            matches.get(3).unwrap().as_str(),
-            matches.get(4).unwrap().as_str(),
+            matches.get(3).map_or("1", |x| x.as_str()),
+            matches.get(4).map_or("d", |x| x.as_str()),
         )?;
-        let fmt = matches.get(5).map_or(DATE_TIME_FORMAT, |x| x.as_str());
+        let fmt = date_format(matches.get(5).map_or(DATE_TIME_FORMAT, |x| x.as_str()))?;
         Ok(QueryValues::DateTimeRange { range, fmt })
+    } else if let Some(err) = lint_range(s).filter(|_| !lenient) {
+        Err(AppError::new(err))
     } else {
         Ok(QueryValues::Strings(comma_separated(s)))
     }
 }
 
-fn to_cdrs_values(vals: QueryValues) -> Values {
+fn to_cdrs_values(vals: QueryValues) -> Vec<(String, Value)> {
     match vals {
-        QueryValues::IntRange { range, step } => range.step_by(step).map_into().collect(),
-        QueryValues::Strings(xs) => xs.into_iter().map_into().collect(),
+        QueryValues::IntRange { range, step } => range
+            .step_by(step)
+            .map(|x| (x.to_string(), x.into()))
+            .collect(),
+        QueryValues::Strings(xs) => xs.into_iter().map(|x| (x.to_string(), x.into())).collect(),
         QueryValues::DateTimeRange { range, fmt } => range
             .map(|x| x.format(fmt).to_string())
-            .map_into()
+            .map(|x| (x.clone(), x.into()))
             .collect(),
     }
 }
 
-pub fn parse_args<'a>(args: impl Iterator<Item = &'a str>) -> AppResult<Vec<Values>> {
-    let results: AppResult<Vec<Values>> = args
-        .map(|arg| parse_query_values(arg).map(to_cdrs_values))
+/// The values a single parameter expands to, in the textual form they
+/// are reported in, without running any query.
+pub fn expand(arg: &str) -> AppResult<Vec<String>> {
+    Ok(to_cdrs_values(parse_query_values(arg)?)
+        .into_iter()
+        .map(|(label, _)| label)
+        .collect())
+}
+
+pub fn parse_args<'a>(args: impl Iterator<Item = &'a str>) -> AppResult<Vec<ParamSet>> {
+    parse_args_with(args, false)
+}
+
+/// Parse the parameters, taking the ones that look like a range but
+/// don't parse as one as literal strings when `lenient`
+/// (`--lenient-params`) rather than failing.
+pub fn parse_args_with<'a>(
+    args: impl Iterator<Item = &'a str>,
+    lenient: bool,
+) -> AppResult<Vec<ParamSet>> {
+    let results: AppResult<Vec<Vec<(String, Value)>>> = args
+        .map(|arg| parse_values(arg, lenient).map(to_cdrs_values))
         .collect();
 
-    Ok(results?.into_iter().multi_cartesian_product().collect())
+    Ok(results?
+        .into_iter()
+        .multi_cartesian_product()
+        .map(|xs| {
+            let (labels, values) = xs.into_iter().unzip();
+            ParamSet { values, labels }
+        })
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use chrono::{Datelike, NaiveDate};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    const CASES: usize = 500;
+
+    #[test]
+    fn test_redaction() {
+        let cql = "select * from t where id = :id and email = ? and note = 'a?:b' and x = :account_id";
+        assert_eq!(
+            vec![Some("id".to_string()), None, Some("account_id".to_string())],
+            bind_markers(cql)
+        );
+
+        let params = ParamSet {
+            values: vec![],
+            labels: vec!["1".into(), "a@b.c".into(), "42".into()],
+        };
+        let redaction = Redaction::parse(vec!["2", "account_id"].into_iter(), cql).unwrap();
+        assert_eq!(vec!["1", "***", "***"], redaction.labels(&params.labels));
+
+        assert!(Redaction::parse(vec!["0"].into_iter(), cql).is_err());
+        assert!(Redaction::parse(vec!["email"].into_iter(), cql).is_err());
+    }
 
     #[test]
     fn test_parse_int_range_valid_ranges() {
@@ -125,6 +478,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_lint_range() {
+        assert_eq!(
+            Some("Invalid parameter 2019-09-01..2019-12-01/1mo, which looks like a date range (e.g. 2019-09-01..2019-12-01/1m): the unit of the step must be one of m, d or w at character 25. Did you mean 2019-09-01..2019-12-01/1m?".to_string()),
+            lint_range("2019-09-01..2019-12-01/1mo")
+        );
+        let suggestion = |s| {
+            lint_range(s).and_then(|x| Some(x.split_once("Did you mean ")?.1.to_string()))
+        };
+        assert_eq!(
+            Some("2019-09-01..2019-12-01/1m?".to_string()),
+            suggestion("2019-9-1..2019-12-01/1m")
+        );
+        assert_eq!(
+            Some("2019-09-01T10:00:00..2019-09-02T00:00:00/1H?".to_string()),
+            suggestion("2019-09-01 10:00..2019-09-02T00:00:00/1h")
+        );
+        assert_eq!(Some("1..10/2?".to_string()), suggestion("1..10/2x"));
+        assert!(lint_range("1..1O").unwrap().contains("at character 5"));
+        assert_eq!(None, suggestion("1..1O"));
+        assert_eq!(None, lint_range("a..b"));
+        assert_eq!(None, lint_range("1.5"));
+        assert!(parse_query_values("2019-09-01..2019-12-01/1y").is_err());
+        assert_eq!(
+            Ok(QueryValues::Strings(vec!["a..b"])),
+            parse_query_values("a..b").map_err(|x| x.to_string())
+        );
+        assert_eq!(
+            Ok(QueryValues::Strings(vec!["1..1O"])),
+            parse_values("1..1O", true).map_err(|x| x.to_string())
+        );
+    }
+
     fn capture_groups(re: &Regex, s: &'static str) -> Option<Vec<&'static str>> {
         re.captures(s).map(|x| {
             x.iter()
@@ -258,6 +644,117 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_expand_int_ranges() {
+        let mut rng = StdRng::seed_from_u64(272);
+        for _ in 0..CASES {
+            let from = rng.gen_range(0..1000);
+            let to = from + rng.gen_range(1..500);
+            let step = rng.gen_range(1..20);
+            let arg = match step {
+                1 if rng.gen() => format!("{}..{}", from, to),
+                _ => format!("{}..{}/{}", from, to, step),
+            };
+            let values: Vec<i32> = expand(&arg)
+                .unwrap()
+                .iter()
+                .map(|x| x.parse().unwrap())
+                .collect();
+            assert_eq!((to - from + step - 1) / step, values.len() as i32, "{}", arg);
+            assert_eq!(Some(&from), values.first(), "{}", arg);
+            assert!(values.windows(2).all(|x| x[1] - x[0] == step), "{}", arg);
+            assert!(values.iter().all(|x| *x < to), "{}", arg);
+        }
+    }
+
+    #[test]
+    fn test_expand_date_ranges() {
+        let mut rng = StdRng::seed_from_u64(272);
+        for _ in 0..CASES {
+            let from = NaiveDate::from_ymd(rng.gen_range(1990..2030), 1, 1)
+                + chrono::Duration::days(rng.gen_range(0..366));
+            let days = rng.gen_range(1..400);
+            let to = from + chrono::Duration::days(days);
+            let step = rng.gen_range(1..10);
+            let unit = *["d", "w", "m"].choose(&mut rng).unwrap();
+            let arg = format!("{}..{}/{}{}", from, to, step, unit);
+            let values: Vec<NaiveDate> = expand(&arg)
+                .unwrap()
+                .iter()
+                .map(|x| NaiveDate::parse_from_str(x, DATE_FORMAT).unwrap())
+                .collect();
+            assert_eq!(Some(&from), values.first(), "{}", arg);
+            assert!(values.windows(2).all(|x| x[0] < x[1]), "{}", arg);
+            assert!(values.iter().all(|x| *x < to), "{}", arg);
+            match unit {
+                "d" => assert_eq!((days + step - 1) / step, values.len() as i64, "{}", arg),
+                "w" => assert_eq!((days + 7 * step - 1) / (7 * step), values.len() as i64),
+                // Clamped to the end of shorter months
+                _ => assert!(values.iter().all(|x| x.day() <= from.day()), "{}", arg),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_values_never_panics() {
+        let ends = [
+            "0",
+            "1",
+            "10",
+            "99999999999",
+            "2019-09-01",
+            "2019-9-1",
+            "2019-13-01",
+            "2020-02-29",
+            "2019-10-01",
+            "2019-09-01T10:00:00",
+            "2019-09-01T12:00:00",
+            "2019-09-01 10:00",
+            "x",
+            "",
+        ];
+        let steps = [
+            "0", "1", "3", "0d", "1d", "2w", "1m", "1mo", "0m", "1H", "30S", "1y", "4000000000d",
+            "999999999m", "d",
+        ];
+        let lasts = [
+            "int", "tinyint", "x", "%Y%m%d", "%d/%m/%Y", "%H:%M", "%Q", "%", "%-",
+        ];
+        let mut rng = StdRng::seed_from_u64(272);
+        for _ in 0..CASES * 4 {
+            let mut arg = format!(
+                "{}..{}",
+                ends.choose(&mut rng).unwrap(),
+                ends.choose(&mut rng).unwrap()
+            );
+            if rng.gen_bool(0.7) {
+                arg = format!("{}/{}", arg, steps.choose(&mut rng).unwrap());
+                if rng.gen_bool(0.4) {
+                    arg = format!("{}/{}", arg, lasts.choose(&mut rng).unwrap());
+                }
+            }
+            // A typo at a random place
+            if rng.gen_bool(0.3) {
+                let mut chars: Vec<char> = arg.chars().collect();
+                let i = rng.gen_range(0..=chars.len());
+                match rng.gen_range(0..3) {
+                    0 => chars.insert(i, *['.', '/', '-', ':', 'T', '1'].choose(&mut rng).unwrap()),
+                    1 if i < chars.len() => drop(chars.remove(i)),
+                    _ => chars.truncate(i),
+                }
+                arg = chars.into_iter().collect();
+            }
+            for lenient in [false, true] {
+                if let Ok(values) = parse_values(&arg, lenient) {
+                    to_cdrs_values(values);
+                }
+            }
+        }
+        assert!(expand("1..10/0").is_err());
+        assert!(expand("2019-09-01..2019-10-01/0d").is_err());
+        assert!(expand("2019-09-01..2019-10-01/1d/%Q").is_err());
+    }
+
     // #[test]
     // fn test_comma_separated_values() {
     //     let test_list = r#"a,,b,c,123,"hello, world",foo:123"#;
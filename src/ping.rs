@@ -0,0 +1,83 @@
+// `kass ping`: check that a cluster can be reached with the connection
+// options, e.g. of a profile, before launching a large run. The time to
+// connect is printed, then the node answering each query on
+// `system.local` with its version, data center and rack, and the round
+// trip time of the query.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value as JsonValue;
+
+use crate::backend::Params;
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+
+const CQL: &str = "SELECT rpc_address, release_version, data_center, rack FROM system.local";
+
+fn millis(d: Duration) -> String {
+    format!("{:.1} ms", d.as_secs_f64() * 1000.0)
+}
+
+fn describe(node: &JsonValue) -> String {
+    let field = |name: &str| node[name].as_str().unwrap_or("?").to_string();
+    format!(
+        "{}: Cassandra {}, data center {}, rack {}",
+        field("rpc_address"),
+        field("release_version"),
+        field("data_center"),
+        field("rack")
+    )
+}
+
+/// Query `system.local` `count` times, a second apart.
+pub fn ping(config: Config, count: usize) -> AppResult<()> {
+    let started = Instant::now();
+    let session = config.connect()?;
+    println!("Connected in {}", millis(started.elapsed()));
+    let mut times = Vec::with_capacity(count);
+    for i in 0..count {
+        if i > 0 {
+            thread::sleep(Duration::from_secs(1));
+        }
+        let started = Instant::now();
+        let resp = session.query(CQL, Params::default())?;
+        let time = started.elapsed();
+        let rows = core::collect_rows(&resp, &config)?;
+        let node = rows
+            .first()
+            .ok_or_else(|| AppError::new("No rows in system.local"))?;
+        println!("{}: {}", describe(node), millis(time));
+        times.push(time);
+    }
+    if count > 1 {
+        let total: Duration = times.iter().sum();
+        println!(
+            "min/avg/max {}/{}/{}",
+            millis(*times.iter().min().unwrap()),
+            millis(total / count as u32),
+            millis(*times.iter().max().unwrap())
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_describe() {
+        let node = json!({
+            "rpc_address": "10.0.0.1",
+            "release_version": "4.1.3",
+            "data_center": "eu-west",
+            "rack": "rack1",
+        });
+        assert_eq!(
+            "10.0.0.1: Cassandra 4.1.3, data center eu-west, rack rack1",
+            describe(&node)
+        );
+        assert_eq!("1.5 ms", millis(Duration::from_micros(1500)));
+    }
+}
@@ -0,0 +1,250 @@
+// Sinks and transforms of rows provided by other programs, e.g. to
+// decrypt columns with an internal key service or to write the rows to
+// a proprietary store, without forking kass.
+//
+// `--plugin [NAME=]PATH` names a program, after its file name without a
+// `kass-` prefix by default. `--sink NAME:ARG` runs `PATH sink ARG` and
+// writes the output rows to its stdin, one per line, as they'd be
+// written to stdout. `--transform NAME:ARG` runs `PATH transform ARG`,
+// writes each row to its stdin as a line of JSON and reads the row to
+// output in its place from a line of its stdout, `null` dropping the
+// row. The plugins get the rows before --mask is applied.
+//
+// `--transform-wasm MODULE` runs a WebAssembly module the same way as a
+// transform, with a WASI runtime, `wasmtime` by default, sandboxing it.
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+
+use serde_json::Value as JsonValue;
+
+use crate::errors::{AppError, AppResult};
+use crate::sink::Sink;
+
+/// The programs of `--plugin`, by name.
+#[derive(Debug, Default)]
+pub struct Plugins(HashMap<String, String>);
+
+impl Plugins {
+    pub fn parse<'a>(specs: impl Iterator<Item = &'a str>) -> AppResult<Plugins> {
+        let mut plugins = Plugins::default();
+        for spec in specs {
+            let (name, path) = match spec.split_once('=') {
+                Some((name, path)) => (name.to_string(), path),
+                None => {
+                    let stem = Path::new(spec)
+                        .file_stem()
+                        .and_then(|x| x.to_str())
+                        .unwrap_or(spec);
+                    (stem.trim_start_matches("kass-").to_string(), spec)
+                }
+            };
+            if plugins.0.insert(name.clone(), path.to_string()).is_some() {
+                return Err(AppError::new(format!("Two plugins named {}", name)));
+            }
+        }
+        Ok(plugins)
+    }
+
+    // The command of the plugin of `spec`, `NAME:ARG`, as a `kind` of
+    // plugin
+    fn command(&self, kind: &str, spec: &str) -> AppResult<Command> {
+        let (name, arg) = spec.split_once(':').unwrap_or((spec, ""));
+        let path = self.0.get(name).ok_or_else(|| {
+            AppError::new(format!("No plugin named {}, given with --plugin", name))
+        })?;
+        let mut command = Command::new(path);
+        command.args([kind, arg]);
+        Ok(command)
+    }
+
+    pub fn sink(&self, spec: &str) -> AppResult<PluginSink> {
+        let mut child = spawn(self.command("sink", spec)?, Stdio::inherit())?;
+        let stdin = child.stdin.take().map(BufWriter::new);
+        Ok(PluginSink {
+            name: spec.to_string(),
+            stdin: Mutex::new(stdin),
+            child: Mutex::new(child),
+        })
+    }
+
+    pub fn transform(&self, spec: &str) -> AppResult<Transform> {
+        Transform::start(spec, self.command("transform", spec)?)
+    }
+}
+
+/// The command running the WebAssembly `module` of `--transform-wasm`
+/// with the WASI runtime of `--wasm-runtime`, which gives it neither
+/// files nor network access.
+fn wasm_command(module: &str, runtime: &str) -> Command {
+    let mut command = Command::new(runtime);
+    command.args(["run", module, "transform"]);
+    command
+}
+
+pub fn wasm_transform(module: &str, runtime: &str) -> AppResult<Transform> {
+    Transform::start(module, wasm_command(module, runtime))
+}
+
+fn spawn(mut command: Command, stdout: Stdio) -> AppResult<Child> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .spawn()
+        .map_err(|err| {
+            AppError::new(format!(
+                "Unable to run the plugin {}: {}",
+                command.get_program().to_string_lossy(),
+                err
+            ))
+        })
+}
+
+// Wait for a plugin to finish once its stdin is closed
+fn finish(name: &str, child: &mut Child) {
+    match child.wait() {
+        Ok(status) if status.success() => {}
+        Ok(status) => diag!("The plugin {} failed: {}", name, status),
+        Err(err) => diag!("The plugin {} failed: {}", name, err),
+    }
+}
+
+/// The rows written to the stdin of a plugin, which has written them
+/// out once the sink is dropped.
+pub struct PluginSink {
+    name: String,
+    stdin: Mutex<Option<BufWriter<ChildStdin>>>,
+    child: Mutex<Child>,
+}
+
+impl Sink for PluginSink {
+    fn write_row(&self, row: &str) -> AppResult<()> {
+        let mut stdin = self.stdin.lock().unwrap();
+        let stdin = stdin
+            .as_mut()
+            .ok_or_else(|| AppError::new(format!("The plugin {} is closed", self.name)))?;
+        writeln!(stdin, "{}", row)
+            .map_err(|err| AppError::new(format!("The plugin {} failed: {}", self.name, err)))
+    }
+}
+
+impl Drop for PluginSink {
+    fn drop(&mut self) {
+        if let Some(mut stdin) = self.stdin.get_mut().unwrap().take() {
+            if let Err(err) = stdin.flush() {
+                diag!("The plugin {} failed: {}", self.name, err);
+            }
+        }
+        finish(&self.name, self.child.get_mut().unwrap());
+    }
+}
+
+/// A plugin replacing each row with the one it answers.
+pub struct Transform {
+    name: String,
+    pipes: Mutex<Option<(ChildStdin, BufReader<ChildStdout>)>>,
+    child: Mutex<Child>,
+}
+
+impl Transform {
+    fn start(name: &str, command: Command) -> AppResult<Transform> {
+        let mut child = spawn(command, Stdio::piped())?;
+        let pipes = child
+            .stdin
+            .take()
+            .zip(child.stdout.take().map(BufReader::new));
+        Ok(Transform {
+            name: name.to_string(),
+            pipes: Mutex::new(pipes),
+            child: Mutex::new(child),
+        })
+    }
+
+    /// The row to output in place of `row`, if any.
+    pub fn apply(&self, row: JsonValue) -> AppResult<Option<JsonValue>> {
+        let failed =
+            |err: io::Error| AppError::new(format!("The plugin {} failed: {}", self.name, err));
+        let mut pipes = self.pipes.lock().unwrap();
+        let (stdin, stdout) = pipes
+            .as_mut()
+            .ok_or_else(|| AppError::new(format!("The plugin {} is closed", self.name)))?;
+        writeln!(stdin, "{}", row).map_err(failed)?;
+        stdin.flush().map_err(failed)?;
+        let mut line = String::new();
+        if stdout.read_line(&mut line).map_err(failed)? == 0 {
+            return Err(AppError::new(format!(
+                "The plugin {} exited before answering a row",
+                self.name
+            )));
+        }
+        match serde_json::from_str(&line)? {
+            JsonValue::Null => Ok(None),
+            x => Ok(Some(x)),
+        }
+    }
+}
+
+impl Drop for Transform {
+    fn drop(&mut self) {
+        // Closing stdin lets the plugin exit
+        self.pipes.get_mut().unwrap().take();
+        finish(&self.name, self.child.get_mut().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let plugins =
+            Plugins::parse(vec!["./bin/kass-decrypt", "blob=/opt/blob-sink"].into_iter()).unwrap();
+        assert_eq!(
+            Some("./bin/kass-decrypt"),
+            plugins.0.get("decrypt").map(String::as_str)
+        );
+        assert_eq!(
+            Some("/opt/blob-sink"),
+            plugins.0.get("blob").map(String::as_str)
+        );
+        assert!(Plugins::parse(vec!["a/kass-x", "b/x"].into_iter()).is_err());
+        assert!(plugins.sink("missing:arg").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_transform() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Drops the rows with id 2 and passes the others through
+        let script = "#!/bin/sh\n[ \"$1\" = transform ] || exit 1\n\
+                      while read -r row; do\n\
+                      case \"$row\" in *'\"id\":2'*) echo null;; *) echo \"$row\";; esac\n\
+                      done\n";
+        let path = std::env::temp_dir().join(format!("kass-test-{}", std::process::id()));
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let plugins = Plugins::parse(std::iter::once(path.to_str().unwrap())).unwrap();
+        let name = format!("test-{}:", std::process::id());
+        let transform = plugins.transform(&name).unwrap();
+        let row = serde_json::json!({"id": 1});
+        assert_eq!(Some(row.clone()), transform.apply(row).unwrap());
+        assert_eq!(None, transform.apply(serde_json::json!({"id": 2})).unwrap());
+        drop(transform);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wasm_command() {
+        let command = wasm_command("rows.wasm", "wasmtime");
+        assert_eq!("wasmtime", command.get_program());
+        assert_eq!(
+            vec!["run", "rows.wasm", "transform"],
+            command.get_args().collect::<Vec<_>>()
+        );
+        assert!(wasm_transform("rows.wasm", "/nonexistent/wasmtime").is_err());
+    }
+}
@@ -0,0 +1,644 @@
+// Local forwarders standing between the driver and the nodes, for what
+// the driver can't do on its own connections: asking for compression
+// in the `STARTUP` (`--compression`), authenticating with more than one
+// `AUTH_RESPONSE` (`--auth sigv4`), connecting through a SOCKS5 proxy
+// (`--proxy`, `--ssh-tunnel`). The driver connects to a forwarder
+// per node in plain text over the loopback interface, and the forwarder
+// connects to the node, over TLS with `--ssl`, and relays the traffic
+// once the handshake is done.
+//
+// As the forwarders authenticate with the credentials of the operator,
+// they only accept the connections of kass itself: on Linux the peer of
+// each connection must be a socket of the process, found in
+// /proc/net/tcp and /proc/self/fd, so that other users and processes of
+// a shared host can't borrow its sessions. Other systems have no such
+// check.
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cdrs::compression::Compression;
+use openssl::ssl::{SslConnector, SslStream};
+
+use crate::backend;
+use crate::errors::{AppError, AppResult};
+use crate::sigv4::SigV4;
+use crate::socks::Socks5;
+
+// How long a TLS forwarder waits for the rest of a record once its
+// node's socket is readable, before checking the driver's side again
+#[cfg(unix)]
+const RECORD_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How long a TLS forwarder waits on one side before checking the other,
+// without poll(2)
+#[cfg(not(unix))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+const OP_STARTUP: u8 = 0x01;
+const FLAG_COMPRESSION: u8 = 0x01;
+
+/// A frame of the native protocol, the header holding the version,
+/// flags, stream, opcode and length of the body.
+pub struct Frame {
+    header: [u8; 9],
+    body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn read<R: Read>(stream: &mut R) -> AppResult<Frame> {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        let mut body = vec![0; len as usize];
+        stream.read_exact(&mut body)?;
+        Ok(Frame { header, body })
+    }
+
+    /// A request of `opcode` on stream 0.
+    pub fn request(version: u8, opcode: u8, body: Vec<u8>) -> Frame {
+        let mut header = [version, 0, 0, 0, opcode, 0, 0, 0, 0];
+        header[5..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        Frame { header, body }
+    }
+
+    pub fn write<W: Write>(&self, stream: &mut W) -> AppResult<()> {
+        stream.write_all(&self.header)?;
+        stream.write_all(&self.body)?;
+        Ok(())
+    }
+
+    pub fn version(&self) -> u8 {
+        self.header[0]
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.header[4]
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The frame with its body decompressed, as the frames following the
+    /// `STARTUP` may be once compression is negotiated.
+    pub fn decompress(self, compression: Compression) -> AppResult<Frame> {
+        if self.header[1] & FLAG_COMPRESSION == 0 {
+            return Ok(self);
+        }
+        let body = compression
+            .decode(self.body)
+            .map_err(|err| AppError::new(format!("Unable to decompress a frame: {}", err)))?;
+        let mut header = self.header;
+        header[1] &= !FLAG_COMPRESSION;
+        header[5..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        Ok(Frame { header, body })
+    }
+
+    /// An uncompressed frame on the same stream as `self`.
+    pub fn reply(&self, version: u8, opcode: u8, body: Vec<u8>) -> Frame {
+        let mut header = self.header;
+        header[0] = version;
+        header[1] = 0;
+        header[4] = opcode;
+        header[5..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        Frame { header, body }
+    }
+}
+
+pub fn read_string(body: &[u8], pos: &mut usize) -> Option<String> {
+    let len = u16::from_be_bytes([*body.get(*pos)?, *body.get(*pos + 1)?]) as usize;
+    let s = body.get(*pos + 2..*pos + 2 + len)?;
+    *pos += 2 + len;
+    Some(String::from_utf8_lossy(s).into_owned())
+}
+
+pub fn write_string(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    body.extend_from_slice(s.as_bytes());
+}
+
+/// `startup` asking for `compression`.
+fn with_compression(startup: &Frame, compression: Compression) -> AppResult<Frame> {
+    let invalid = || AppError::new("Invalid STARTUP frame");
+    if startup.opcode() != OP_STARTUP {
+        return Err(invalid());
+    }
+    let body = startup.body();
+    let count = match body.get(..2) {
+        Some(x) => u16::from_be_bytes([x[0], x[1]]),
+        None => return Err(invalid()),
+    };
+    let mut pos = 2;
+    let mut options = vec![];
+    for _ in 0..count {
+        let key = read_string(body, &mut pos).ok_or_else(invalid)?;
+        let value = read_string(body, &mut pos).ok_or_else(invalid)?;
+        if key != "COMPRESSION" {
+            options.push((key, value));
+        }
+    }
+    if let Some(name) = compression.as_str() {
+        options.push(("COMPRESSION".to_string(), name.to_string()));
+    }
+    let mut body = (options.len() as u16).to_be_bytes().to_vec();
+    for (key, value) in options {
+        write_string(&mut body, &key);
+        write_string(&mut body, &value);
+    }
+    Ok(startup.reply(startup.version(), OP_STARTUP, body))
+}
+
+/// The connection of a forwarder to its node.
+pub enum Remote {
+    Tcp(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Read for Remote {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Remote::Tcp(x) => x.read(buf),
+            Remote::Tls(x) => x.read(buf),
+        }
+    }
+}
+
+impl Write for Remote {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Remote::Tcp(x) => x.write(buf),
+            Remote::Tls(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Remote::Tcp(x) => x.flush(),
+            Remote::Tls(x) => x.flush(),
+        }
+    }
+}
+
+fn would_block(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Follows the frames read from a node, recording their size for
+/// `--stats` as they are relayed.
+#[derive(Default)]
+struct WireSizes {
+    header: Vec<u8>,
+    // What's left of the body of the current frame
+    remaining: usize,
+}
+
+impl WireSizes {
+    fn read(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(bytes.len());
+                self.remaining -= n;
+                bytes = &bytes[n..];
+                continue;
+            }
+            let n = (9 - self.header.len()).min(bytes.len());
+            self.header.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if let [_, _, s0, s1, _, l0, l1, l2, l3] = self.header[..] {
+                self.remaining = u32::from_be_bytes([l0, l1, l2, l3]) as usize;
+                let stream = u16::from_be_bytes([s0, s1]);
+                backend::record_wire_size(stream, 9 + self.remaining as u64);
+                self.header.clear();
+            }
+        }
+    }
+}
+
+// Copy what `remote` sends to `local`, recording the sizes of its frames
+fn copy_frames<R: Read, W: Write>(remote: &mut R, local: &mut W) -> io::Result<()> {
+    let mut sizes = WireSizes::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = remote.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        sizes.read(&buf[..n]);
+        local.write_all(&buf[..n])?;
+    }
+}
+
+/// Relay the traffic both ways until either side closes, from a thread
+/// per way.
+pub fn relay(local: TcpStream, remote: TcpStream) {
+    let (mut local_reader, mut remote_writer) = match (local.try_clone(), remote.try_clone()) {
+        (Ok(x), Ok(y)) => (x, y),
+        _ => return,
+    };
+    let (mut local_writer, mut remote_reader) = (local, remote);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut local_reader, &mut remote_writer);
+        let _ = remote_writer.shutdown(Shutdown::Write);
+    });
+    let _ = copy_frames(&mut remote_reader, &mut local_writer);
+    let _ = local_writer.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+}
+
+/// Relay the traffic both ways until either side closes.
+pub fn forward(local: TcpStream, remote: Remote) -> io::Result<()> {
+    match remote {
+        Remote::Tcp(remote) => {
+            relay(local, remote);
+            Ok(())
+        }
+        Remote::Tls(remote) => forward_tls(local, remote),
+    }
+}
+
+// A TLS stream can't be read and written from two threads, so a single
+// one waits for either side to be readable. The records already
+// decrypted don't show on the socket, and a readable socket may hold no
+// application data, e.g. a session ticket, hence the timeout.
+#[cfg(unix)]
+fn forward_tls(mut local: TcpStream, mut remote: SslStream<TcpStream>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    remote.get_ref().set_read_timeout(Some(RECORD_TIMEOUT))?;
+    let mut sizes = WireSizes::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let (local_ready, remote_ready) = match remote.ssl().pending() {
+            0 => {
+                let fd = |fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                let mut fds = [fd(local.as_raw_fd()), fd(remote.get_ref().as_raw_fd())];
+                if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                    match io::Error::last_os_error() {
+                        err if err.kind() == io::ErrorKind::Interrupted => continue,
+                        err => return Err(err),
+                    }
+                }
+                (fds[0].revents != 0, fds[1].revents != 0)
+            }
+            _ => (false, true),
+        };
+        if local_ready {
+            match local.read(&mut buf)? {
+                0 => return Ok(()),
+                n => remote.write_all(&buf[..n])?,
+            }
+        }
+        if remote_ready {
+            match remote.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    sizes.read(&buf[..n]);
+                    local.write_all(&buf[..n])?
+                }
+                Err(err) if would_block(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+// Both sides are read in turn with a short timeout
+#[cfg(not(unix))]
+fn forward_tls(mut local: TcpStream, mut remote: SslStream<TcpStream>) -> io::Result<()> {
+    local.set_read_timeout(Some(POLL_INTERVAL))?;
+    remote.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut sizes = WireSizes::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match local.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => remote.write_all(&buf[..n])?,
+            Err(err) if would_block(&err) => {}
+            Err(err) => return Err(err),
+        }
+        match remote.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                sizes.read(&buf[..n]);
+                local.write_all(&buf[..n])?
+            }
+            Err(err) if would_block(&err) => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Relay the `STARTUP` of the driver read from `local` to `remote`,
+/// asking for `compression`.
+pub fn relay_startup(
+    local: &mut TcpStream,
+    remote: &mut Remote,
+    compression: Compression,
+) -> AppResult<Frame> {
+    let startup = with_compression(&Frame::read(local)?, compression)?;
+    startup.write(remote)?;
+    Ok(startup)
+}
+
+// The port of a `/proc/net/tcp` address, e.g. `0100007F:1F90`
+#[cfg(target_os = "linux")]
+fn proc_port(address: &str) -> Option<u16> {
+    let (_, port) = address.rsplit_once(':')?;
+    u16::from_str_radix(port, 16).ok()
+}
+
+// The inode of the socket from `port` to `peer_port` in the table of
+// `/proc/net/tcp`
+#[cfg(target_os = "linux")]
+fn socket_inode(table: &str, port: u16, peer_port: u16) -> Option<String> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local = proc_port(fields.get(1)?)?;
+        let remote = proc_port(fields.get(2)?)?;
+        match local == port && remote == peer_port {
+            true => fields.get(9).map(|x| x.to_string()),
+            false => None,
+        }
+    })
+}
+
+/// Whether the other end of `local`, accepted by a forwarder, is a
+/// socket of this process, i.e. of the driver.
+#[cfg(target_os = "linux")]
+fn is_own_connection(local: &TcpStream) -> io::Result<bool> {
+    let (port, peer_port) = (local.local_addr()?.port(), local.peer_addr()?.port());
+    let table = fs::read_to_string("/proc/net/tcp")?;
+    let socket = match socket_inode(&table, peer_port, port) {
+        Some(inode) => format!("socket:[{}]", inode),
+        None => return Ok(false),
+    };
+    for fd in fs::read_dir("/proc/self/fd")? {
+        if fs::read_link(fd?.path()).is_ok_and(|x| x.as_os_str() == socket.as_str()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_own_connection(_local: &TcpStream) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Accept the connections of this process on a local port, handing
+/// each over to `handle` on its own thread. Returns the address of the
+/// port.
+pub fn listen<F>(handle: F) -> AppResult<String>
+where
+    F: Fn(TcpStream) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?.to_string();
+    let handle = Arc::new(handle);
+    thread::spawn(move || {
+        for local in listener.incoming().flatten() {
+            match is_own_connection(&local) {
+                Ok(true) => {
+                    let handle = handle.clone();
+                    thread::spawn(move || handle(local));
+                }
+                Ok(false) => diag!(
+                    "Refused a connection to a forwarder from {}, which isn't kass",
+                    local.peer_addr().map_or("?".to_string(), |x| x.to_string())
+                ),
+                Err(err) => diag!("Unable to check a connection to a forwarder: {}", err),
+            }
+        }
+    });
+    Ok(address)
+}
+
+pub struct Forwarders {
+    connector: Option<SslConnector>,
+    compression: Compression,
+    sigv4: Option<SigV4>,
+    socks: Option<Socks5>,
+    // The addresses of the forwarders, by node
+    addresses: Mutex<HashMap<String, String>>,
+}
+
+impl Forwarders {
+    pub fn new(
+        connector: Option<SslConnector>,
+        compression: Compression,
+        sigv4: Option<SigV4>,
+        socks: Option<Socks5>,
+    ) -> Forwarders {
+        Forwarders {
+            connector,
+            compression,
+            sigv4,
+            socks,
+            addresses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The addresses of the forwarders to `hosts`, started on first
+    /// use.
+    pub fn nodes(self: &Arc<Self>, hosts: &[String]) -> AppResult<Vec<String>> {
+        let mut addresses = self.addresses.lock().unwrap();
+        hosts
+            .iter()
+            .map(|host| {
+                if let Some(address) = addresses.get(host) {
+                    return Ok(address.clone());
+                }
+                let (forwarders, node) = (self.clone(), host.clone());
+                let address =
+                    listen(
+                        move |mut local| match forwarders.connect(&mut local, &node) {
+                            Ok(remote) => {
+                                let _ = forward(local, remote);
+                            }
+                            Err(err) => diag!("Unable to connect to {}: {}", node, err),
+                        },
+                    )?;
+                addresses.insert(host.clone(), address.clone());
+                Ok(address)
+            })
+            .collect()
+    }
+
+    // Connect to `host` on behalf of the driver, up to its `STARTUP`
+    fn connect(&self, local: &mut TcpStream, host: &str) -> AppResult<Remote> {
+        let tcp = match &self.socks {
+            Some(socks) => socks.connect(host)?,
+            None => TcpStream::connect(host)?,
+        };
+        let mut remote = match &self.connector {
+            Some(connector) => {
+                let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+                Remote::Tls(
+                    connector
+                        .connect(name, tcp)
+                        .map_err(|err| AppError::new(format!("TLS error: {}", err)))?,
+                )
+            }
+            None => Remote::Tcp(tcp),
+        };
+        let startup = relay_startup(local, &mut remote, self.compression)?;
+        if let Some(sigv4) = &self.sigv4 {
+            sigv4.authenticate(local, &mut remote, &startup, self.compression)?;
+        }
+        Ok(remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+    use openssl::x509::X509;
+    use std::sync::mpsc;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_socket_inode() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:8AE2 0100007F:1F90 01 00000000:00000000 00:00000000 00000000  1000        0 51234 1 0000000000000000 20 4 30 10 -1
+   1: 0100007F:1F90 0100007F:8AE2 01 00000000:00000000 00:00000000 00000000  1000        0 51235 1 0000000000000000 20 4 30 10 -1";
+        let inode = |port, peer_port| socket_inode(table, port, peer_port);
+        assert_eq!(Some("51234".into()), inode(0x8AE2, 0x1F90));
+        assert_eq!(Some("51235".into()), inode(0x1F90, 0x8AE2));
+        assert_eq!(None, inode(0x8AE2, 0x1F91));
+    }
+
+    #[test]
+    fn test_wire_sizes() {
+        let frame = |stream: u16, body: &[u8]| {
+            let mut frame = vec![0x84, 0];
+            frame.extend_from_slice(&stream.to_be_bytes());
+            frame.push(0x08);
+            frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            frame.extend_from_slice(body);
+            frame
+        };
+        let mut bytes = frame(48_001, b"ab");
+        bytes.extend(frame(48_002, &[0; 300]));
+        let mut sizes = WireSizes::default();
+        for chunk in bytes.chunks(4) {
+            sizes.read(chunk);
+        }
+        assert_eq!(Some(11), backend::take_wire_size(48_001));
+        assert_eq!(Some(309), backend::take_wire_size(48_002));
+        assert_eq!(None, backend::take_wire_size(48_003));
+    }
+
+    // A TLS server answering every line with itself
+    fn tls_echo() -> String {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert.build()).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let tcp = listener.incoming().next().unwrap().unwrap();
+            let mut tls = acceptor.accept(tcp).unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = tls.read(&mut buf) {
+                tls.write_all(&buf[..n]).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_forward_tls() {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let tcp = TcpStream::connect(tls_echo()).unwrap();
+        let remote = connector.build().connect("localhost", tcp).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut driver = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let local = listener.accept().unwrap().0;
+        thread::spawn(move || forward(local, Remote::Tls(remote)));
+
+        driver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for message in ["hello", "world"] {
+            driver.write_all(message.as_bytes()).unwrap();
+            let mut buf = [0; 5];
+            driver.read_exact(&mut buf).unwrap();
+            assert_eq!(message.as_bytes(), buf);
+        }
+    }
+
+    #[test]
+    fn test_listen() {
+        let (accepted, handled) = mpsc::channel();
+        let address = listen(move |_| accepted.send(()).unwrap()).unwrap();
+        let _local = TcpStream::connect(&address).unwrap();
+        handled.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Another process is refused
+        #[cfg(target_os = "linux")]
+        {
+            let (host, port) = address.split_once(':').unwrap();
+            let status = std::process::Command::new("bash")
+                .arg("-c")
+                .arg(format!("exec 3<>/dev/tcp/{}/{} && sleep 0.5", host, port))
+                .status();
+            if status.is_ok_and(|x| x.success()) {
+                assert!(handled.recv_timeout(Duration::from_millis(200)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_compression() {
+        let mut body = vec![0, 1];
+        write_string(&mut body, "CQL_VERSION");
+        write_string(&mut body, "3.0.0");
+        let mut header = [0x04, 0, 0, 0, OP_STARTUP, 0, 0, 0, 0];
+        header[5..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        let startup = Frame { header, body };
+
+        let lz4 = with_compression(&startup, Compression::Lz4).unwrap();
+        let mut expected = vec![0, 2];
+        for s in ["CQL_VERSION", "3.0.0", "COMPRESSION", "lz4"] {
+            write_string(&mut expected, s);
+        }
+        assert_eq!(expected, lz4.body);
+        assert_eq!(expected.len() as u8, lz4.header[8]);
+
+        let none = with_compression(&lz4, Compression::None).unwrap();
+        assert_eq!(startup.body, none.body);
+        assert!(with_compression(&lz4.reply(0x04, 0x07, vec![]), Compression::Lz4).is_err());
+        let truncated = startup.reply(0x04, OP_STARTUP, vec![0]);
+        assert!(with_compression(&truncated, Compression::Lz4).is_err());
+    }
+}
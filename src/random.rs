@@ -0,0 +1,156 @@
+// `--random N`: N rows picked uniformly at random among all those of
+// the run, however many there are, without holding more than N of them
+// per worker. Each row gets a random key and the N rows of lowest keys
+// are kept, so that the samples of the workers, kept apart as they run,
+// merge into a sample of the whole run by keeping the N lowest keys of
+// all of them. The rows are written once the run is done.
+use std::cmp::Ordering;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::BinaryHeap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::thread;
+
+use serde_json::Value as JsonValue;
+
+#[derive(Debug)]
+struct Candidate {
+    key: u64,
+    // The row formatted for the output, along with its values
+    out: String,
+    json: JsonValue,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+#[derive(Debug)]
+pub struct RandomRows {
+    rows: usize,
+    // A sample per worker, the candidate of highest key on top
+    samples: Vec<Mutex<BinaryHeap<Candidate>>>,
+}
+
+fn random_key() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Keep `candidate` if its key is among the `rows` lowest of `sample`
+fn keep(sample: &mut BinaryHeap<Candidate>, rows: usize, candidate: Candidate) {
+    if sample.len() < rows {
+        sample.push(candidate);
+    } else if sample.peek().is_some_and(|x| candidate.key < x.key) {
+        sample.pop();
+        sample.push(candidate);
+    }
+}
+
+impl RandomRows {
+    pub fn new(rows: usize, workers: usize) -> Self {
+        RandomRows {
+            rows,
+            samples: (0..workers.max(1))
+                .map(|_| Mutex::new(BinaryHeap::new()))
+                .collect(),
+        }
+    }
+
+    /// An empty sample of as many rows, for a run of its own.
+    pub fn fresh(&self) -> Self {
+        RandomRows::new(self.rows, self.samples.len())
+    }
+
+    /// Add a row to the sample of the current worker, `out` being the
+    /// row formatted for the output.
+    pub fn offer(&self, out: String, json: JsonValue) {
+        let mut hasher = DefaultHasher::new();
+        thread::current().id().hash(&mut hasher);
+        let worker = hasher.finish() as usize % self.samples.len();
+        let candidate = Candidate {
+            key: random_key(),
+            out,
+            json,
+        };
+        let mut sample = self.samples[worker].lock().unwrap();
+        keep(&mut sample, self.rows, candidate);
+    }
+
+    /// The rows of the sample of the whole run, emptying it.
+    pub fn take(&self) -> Vec<(String, JsonValue)> {
+        let mut merged = BinaryHeap::new();
+        for sample in &self.samples {
+            for candidate in sample.lock().unwrap().drain() {
+                keep(&mut merged, self.rows, candidate);
+            }
+        }
+        merged
+            .into_sorted_vec()
+            .into_iter()
+            .map(|x| (x.out, x.json))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sample() {
+        let random = Arc::new(RandomRows::new(10, 4));
+        let workers: Vec<_> = (0..4)
+            .map(|w| {
+                let random = random.clone();
+                thread::spawn(move || {
+                    for i in 0..250 {
+                        random.offer(format!("{}-{}", w, i), json!(i));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let rows = random.take();
+        assert_eq!(10, rows.len());
+        let mut outs: Vec<_> = rows.iter().map(|x| x.0.clone()).collect();
+        outs.sort();
+        outs.dedup();
+        assert_eq!(10, outs.len());
+        assert!(random.take().is_empty());
+    }
+
+    #[test]
+    fn test_keep() {
+        let candidate = |key| Candidate {
+            key,
+            out: key.to_string(),
+            json: JsonValue::Null,
+        };
+        let mut sample = BinaryHeap::new();
+        for key in [5, 3, 9, 1, 7] {
+            keep(&mut sample, 3, candidate(key));
+        }
+        let keys: Vec<u64> = sample.into_sorted_vec().iter().map(|x| x.key).collect();
+        assert_eq!(vec![1, 3, 5], keys);
+    }
+}
@@ -0,0 +1,172 @@
+// `--rate-schedule '00:00-07:00=2000,07:00-20:00=200'`: limit the
+// number of queries per second by the local time of day, e.g. to slow
+// a backfill down during business hours. Queries are not limited
+// outside of the windows of the schedule. The active rate is looked up
+// again every minute, so a long run follows the schedule.
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, Timelike};
+
+use crate::errors::{AppError, AppResult};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    // Minutes of the day, `start > end` for windows spanning midnight
+    start: u32,
+    end: u32,
+    rate: u32,
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    match (h, m) {
+        (24, 0) => Some(MINUTES_PER_DAY),
+        (0..=23, 0..=59) => Some(h * 60 + m),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateSchedule {
+    windows: Vec<Window>,
+}
+
+impl RateSchedule {
+    pub fn parse(s: &str) -> AppResult<RateSchedule> {
+        let windows = s
+            .split(',')
+            .map(|spec| {
+                let invalid = || {
+                    AppError::new(format!(
+                        "Invalid rate window {}, expected e.g. 07:00-20:00=200",
+                        spec
+                    ))
+                };
+                let (times, rate) = spec.split_once('=').ok_or_else(invalid)?;
+                let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+                let window = Window {
+                    start: parse_time(start).ok_or_else(invalid)?,
+                    end: parse_time(end).ok_or_else(invalid)?,
+                    rate: rate.trim().parse().map_err(|_| invalid())?,
+                };
+                if window.rate == 0 || window.start == window.end {
+                    return Err(invalid());
+                }
+                Ok(window)
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+        Ok(RateSchedule { windows })
+    }
+
+    /// The queries per second allowed at `minute` of the day, `None`
+    /// when unlimited. The first matching window wins.
+    fn rate_at(&self, minute: u32) -> Option<u32> {
+        self.windows
+            .iter()
+            .find(|x| x.contains(minute))
+            .map(|x| x.rate)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    rate: Option<u32>,
+    checked: Instant,
+    // The time at which the next query may start
+    next: Instant,
+}
+
+/// Spaces the queries of all the workers of a run evenly according to
+/// the rate of the schedule.
+#[derive(Debug)]
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    state: Mutex<Option<State>>,
+}
+
+fn current_minute() -> u32 {
+    let now = Local::now();
+    now.hour() * 60 + now.minute()
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule) -> RateLimiter {
+        RateLimiter {
+            schedule,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Block until the next query may start.
+    pub fn acquire(&self) {
+        let now = Instant::now();
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let refresh = state
+                .as_ref()
+                .is_none_or(|x| now.duration_since(x.checked) >= REFRESH_INTERVAL);
+            if refresh {
+                let rate = self.schedule.rate_at(current_minute());
+                if state.as_ref().is_none_or(|x| x.rate != rate) {
+                    match rate {
+                        Some(rate) => diag!("Rate limited to {} queries per second", rate),
+                        None => diag!("Rate no longer limited"),
+                    }
+                }
+                let next = state.as_ref().map_or(now, |x| x.next);
+                *state = Some(State {
+                    rate,
+                    checked: now,
+                    next,
+                });
+            }
+            let state = state.as_mut().unwrap();
+            match state.rate {
+                Some(rate) => {
+                    let start = state.next.max(now);
+                    state.next = start + Duration::from_secs(1) / rate;
+                    start - now
+                }
+                None => Duration::from_secs(0),
+            }
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let schedule =
+            RateSchedule::parse("00:00-07:00=2000,07:00-20:00=200,22:00-01:00=500").unwrap();
+        assert_eq!(Some(2000), schedule.rate_at(0));
+        assert_eq!(Some(200), schedule.rate_at(7 * 60));
+        assert_eq!(Some(200), schedule.rate_at(20 * 60 - 1));
+        assert_eq!(None, schedule.rate_at(21 * 60));
+        assert_eq!(Some(500), schedule.rate_at(23 * 60 + 30));
+        assert!(RateSchedule::parse("07:00-20:00").is_err());
+        assert!(RateSchedule::parse("07:00-25:00=10").is_err());
+        assert!(RateSchedule::parse("07:00-20:00=0").is_err());
+    }
+}
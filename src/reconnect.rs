@@ -0,0 +1,153 @@
+// A node restarting or a network blip would otherwise fail every query
+// left in a run. The statements that can't reach the cluster are retried
+// with an exponential backoff, and jitter so that the workers don't all
+// retry at once, until `--reconnect-timeout` has elapsed. The pools of
+// the driver replace the broken connections in the meantime.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cdrs::consistency::Consistency;
+use cdrs::error::{Error, Result};
+
+use crate::backend::{Backend, Params, Prepared, Response};
+
+const INITIAL_DELAY: Duration = Duration::from_millis(100);
+const MAX_DELAY: Duration = Duration::from_secs(10);
+
+pub struct Reconnecting<B> {
+    inner: B,
+    timeout: Duration,
+}
+
+// Errors of the connection rather than of the statement
+pub fn is_connection_error(err: &Error) -> bool {
+    match err {
+        Error::Io(_) => true,
+        Error::General(msg) => msg == "Unable to get transport",
+        _ => false,
+    }
+}
+
+/// The delay before the retry `attempt` (from 0), doubling up to
+/// `MAX_DELAY`, between half and all of it by `jitter` (0 to 1).
+pub fn delay(attempt: u32, jitter: f64) -> Duration {
+    let max = INITIAL_DELAY
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_DELAY, |x| x.min(MAX_DELAY));
+    max.mul_f64(0.5 + jitter / 2.0)
+}
+
+pub fn jitter() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
+impl<B: Backend> Reconnecting<B> {
+    pub fn new(inner: B, timeout: Duration) -> Reconnecting<B> {
+        Reconnecting { inner, timeout }
+    }
+
+    fn retry<T>(&self, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if is_connection_error(&err) && start.elapsed() < self.timeout => {
+                    if attempt == 0 {
+                        diag!("{}, reconnecting", err);
+                    }
+                    let remaining = self.timeout.saturating_sub(start.elapsed());
+                    thread::sleep(delay(attempt, jitter()).min(remaining));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<B: Backend> Backend for Reconnecting<B> {
+    fn prepare(&self, cql: &str) -> Result<Prepared> {
+        self.retry(|| self.inner.prepare(cql))
+    }
+
+    fn query(&self, cql: &str, params: Params) -> Result<Response> {
+        self.retry(|| self.inner.query(cql, params.clone()))
+    }
+
+    fn execute(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        self.retry(|| self.inner.execute(prepared, params.clone()))
+    }
+
+    fn speculate(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        self.retry(|| self.inner.speculate(prepared, params.clone()))
+    }
+
+    fn metadata(&self, keyspace: &str, table: &str, consistency: Consistency) -> Result<Response> {
+        self.retry(|| self.inner.metadata(keyspace, table, consistency))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Fails the first `failures` statements with `err`
+    struct Flaky {
+        failures: usize,
+        err: fn() -> Error,
+        calls: AtomicUsize,
+    }
+
+    impl Backend for Flaky {
+        fn prepare(&self, _cql: &str) -> Result<Prepared> {
+            unimplemented!()
+        }
+
+        fn query(&self, _cql: &str, _params: Params) -> Result<Response> {
+            match self.calls.fetch_add(1, Ordering::Relaxed) {
+                n if n < self.failures => Err((self.err)()),
+                _ => Ok(Response::Void),
+            }
+        }
+
+        fn execute(&self, _prepared: &Prepared, _params: Params) -> Result<Response> {
+            unimplemented!()
+        }
+    }
+
+    fn flaky(failures: usize, err: fn() -> Error, timeout: u64) -> Reconnecting<Flaky> {
+        let inner = Flaky {
+            failures,
+            err,
+            calls: AtomicUsize::new(0),
+        };
+        Reconnecting::new(inner, Duration::from_millis(timeout))
+    }
+
+    #[test]
+    fn test_retry() {
+        let reset = || Error::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+        let session = flaky(2, reset, 1000);
+        assert!(session.query("", Params::default()).is_ok());
+        assert_eq!(3, session.inner.calls.load(Ordering::Relaxed));
+
+        let session = flaky(100, reset, 50);
+        assert!(session.query("", Params::default()).is_err());
+
+        let invalid = || Error::General("Invalid query".into());
+        let session = flaky(1, invalid, 1000);
+        assert!(session.query("", Params::default()).is_err());
+        assert_eq!(1, session.inner.calls.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(Duration::from_millis(100), delay(0, 1.0));
+        assert_eq!(Duration::from_millis(200), delay(2, 0.0));
+        assert_eq!(MAX_DELAY, delay(40, 1.0));
+    }
+}
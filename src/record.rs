@@ -0,0 +1,137 @@
+// `--record`: append the raw result frames received from the cluster to
+// a file, keyed by query and parameters, for `--replay` to answer the
+// same queries offline. Every line of the file is a JSON object:
+//
+//     {"query": "...", "params": ["0000002a", null], "body": "..."}
+//
+// where the parameters and the frame body are hex encoded.
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+use cdrs::types::value::{Value, ValueType};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::Prepared;
+use crate::errors::{AppError, AppResult};
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub query: String,
+    params: Vec<Option<String>>,
+    body: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("{:02x}", bytes.iter().format(""))
+}
+
+fn from_hex(s: &str) -> AppResult<Vec<u8>> {
+    let invalid = || AppError::new(format!("Invalid hex string in recording: {}", s));
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(s.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+impl Entry {
+    pub fn new(query: &str, values: &[Value], body: &[u8]) -> Entry {
+        Entry {
+            query: query.to_string(),
+            params: values
+                .iter()
+                .map(|x| match x.value_type {
+                    ValueType::Normal(_) => Some(to_hex(&x.body)),
+                    _ => None,
+                })
+                .collect(),
+            body: to_hex(body),
+        }
+    }
+
+    pub fn values(&self) -> AppResult<Vec<Value>> {
+        self.params
+            .iter()
+            .map(|x| match x {
+                Some(x) => {
+                    let body = from_hex(x)?;
+                    Ok(Value {
+                        value_type: ValueType::Normal(body.len() as i32),
+                        body,
+                    })
+                }
+                None => Ok(Value::new_null()),
+            })
+            .collect()
+    }
+
+    pub fn body(&self) -> AppResult<Vec<u8>> {
+        from_hex(&self.body)
+    }
+}
+
+pub struct Recorder {
+    file: Mutex<File>,
+    // The queries of the prepared statements, by id
+    prepared: Mutex<HashMap<Vec<u8>, String>>,
+}
+
+impl Recorder {
+    /// Record to `path`, appending to the entries already there.
+    pub fn open(path: &str) -> AppResult<Recorder> {
+        Ok(Recorder {
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?),
+            prepared: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn prepared(&self, id: &Prepared, cql: &str) {
+        if let Some(id) = id.clone().into_plain() {
+            self.prepared.lock().unwrap().insert(id, cql.to_string());
+        }
+    }
+
+    pub fn query_of(&self, id: &Prepared) -> Option<String> {
+        let id = id.clone().into_plain()?;
+        self.prepared.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn record(&self, cql: &str, values: &[Value], body: &[u8]) -> std::io::Result<()> {
+        let line = serde_json::to_string(&Entry::new(cql, values, body))?;
+        writeln!(self.file.lock().unwrap(), "{}", line)
+    }
+}
+
+/// The entries of a recording.
+pub fn read(path: &str) -> AppResult<Vec<Entry>> {
+    let mut entries = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let values = vec![Value::from(42), Value::new_null()];
+        let entry = Entry::new("SELECT 1", &values, &[0, 1, 255]);
+        let entry: Entry = serde_json::from_str(&serde_json::to_string(&entry).unwrap()).unwrap();
+        assert_eq!(values, entry.values().unwrap());
+        assert_eq!(vec![0, 1, 255], entry.body().unwrap());
+        assert!(from_hex("0g").is_err());
+    }
+}
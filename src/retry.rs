@@ -0,0 +1,215 @@
+// `--retries N`: the statements failing with an error the cluster may
+// not return on another attempt, a timeout or a coordinator that is
+// overloaded or can't reach enough replicas, are retried up to N times
+// rather than failing the run. The retries back off exponentially with
+// jitter, as when reconnecting, so that the workers don't hammer the
+// cluster all at once. Counter updates aren't retried unless
+// `--force-counter-retry`.
+//
+// `--retry-budget 5%` aborts the run instead once more than 5% of the
+// queries needed retries, the cluster being unhealthy rather than
+// having a blip, on top of a few queries that can always be retried so
+// that the first ones of a run don't abort it.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use cdrs::error::{Error, Result};
+use cdrs::frame::frame_error::AdditionalErrorInfo;
+
+use crate::errors::{AppError, AppResult};
+use crate::reconnect;
+
+// The queries retried whatever the budget
+const MIN_RETRIED: usize = 10;
+
+/// The fraction of the queries of a run that may need retries.
+#[derive(Debug)]
+pub struct RetryBudget {
+    fraction: f64,
+    queries: AtomicUsize,
+    retried: AtomicUsize,
+}
+
+impl RetryBudget {
+    /// Parse a percentage, e.g. `5%`, or a fraction, e.g. `0.05`.
+    pub fn parse(s: &str) -> AppResult<RetryBudget> {
+        let fraction = match s.strip_suffix('%') {
+            Some(x) => x.trim().parse::<f64>().map(|x| x / 100.0),
+            None => s.parse::<f64>(),
+        };
+        match fraction {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(RetryBudget {
+                fraction,
+                queries: AtomicUsize::new(0),
+                retried: AtomicUsize::new(0),
+            }),
+            _ => Err(AppError::new(format!(
+                "Invalid retry budget {}, expected e.g. 5% or 0.05",
+                s
+            ))),
+        }
+    }
+
+    fn record_query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Account for a query about to be retried for the first time,
+    // failing if that exceeds the budget
+    fn record_retried(&self) -> Result<()> {
+        let retried = self.retried.fetch_add(1, Ordering::Relaxed) + 1;
+        let queries = self.queries.load(Ordering::Relaxed);
+        if retried > MIN_RETRIED + (self.fraction * queries as f64) as usize {
+            return Err(Error::General(format!(
+                "{} of {} queries needed retries, more than the --retry-budget of {}%, aborting",
+                retried,
+                queries,
+                self.fraction * 100.0
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Errors the statement may not get when sent again
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::Server(err) => matches!(
+            err.additional_info,
+            AdditionalErrorInfo::ReadTimeout(_)
+                | AdditionalErrorInfo::WriteTimeout(_)
+                | AdditionalErrorInfo::Unavailable(_)
+                | AdditionalErrorInfo::Overloaded(_)
+                | AdditionalErrorInfo::IsBootstrapping(_)
+        ),
+        _ => false,
+    }
+}
+
+/// Whether `err` is that of a statement whose replicas can't meet its
+/// consistency level, being down or too slow to answer.
+pub fn is_unavailable(err: &Error) -> bool {
+    match err {
+        Error::Server(err) => matches!(
+            err.additional_info,
+            AdditionalErrorInfo::Unavailable(_) | AdditionalErrorInfo::ReadTimeout(_)
+        ),
+        _ => false,
+    }
+}
+
+/// Run `f` until it succeeds, fails with an error that isn't transient
+/// or has been retried `retries` times, or its retry exceeds `budget`.
+pub fn with_retries<T>(
+    retries: u32,
+    budget: Option<&RetryBudget>,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    if let Some(budget) = budget {
+        budget.record_query();
+    }
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                if let (Some(budget), 0) = (budget, attempt) {
+                    budget.record_retried()?;
+                }
+                diag!("{}, retrying ({} of {})", err, attempt + 1, retries);
+                thread::sleep(reconnect::delay(attempt, reconnect::jitter()));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdrs::frame::frame_error::{CDRSError, SimpleError};
+    use cdrs::types::CString;
+
+    fn overloaded() -> Error {
+        Error::Server(CDRSError {
+            error_code: 0x1001,
+            message: CString::new("Overloaded".to_string()),
+            additional_info: AdditionalErrorInfo::Overloaded(SimpleError {}),
+        })
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let mut calls = 0;
+        let result = with_retries(2, None, || {
+            calls += 1;
+            match calls {
+                1 => Err(overloaded()),
+                _ => Ok(calls),
+            }
+        });
+        assert_eq!(2, result.unwrap());
+
+        let mut calls = 0;
+        assert!(with_retries(2, None, || -> Result<()> {
+            calls += 1;
+            Err(overloaded())
+        })
+        .is_err());
+        assert_eq!(3, calls);
+
+        let mut calls = 0;
+        assert!(with_retries(2, None, || -> Result<()> {
+            calls += 1;
+            Err(Error::General("Invalid query".into()))
+        })
+        .is_err());
+        assert_eq!(1, calls);
+    }
+
+    #[test]
+    fn test_is_unavailable() {
+        use cdrs::consistency::Consistency;
+        use cdrs::frame::frame_error::UnavailableError;
+
+        let unavailable = Error::Server(CDRSError {
+            error_code: 0x1000,
+            message: CString::new("Cannot achieve consistency level QUORUM".to_string()),
+            additional_info: AdditionalErrorInfo::Unavailable(UnavailableError {
+                cl: Consistency::Quorum,
+                required: 2,
+                alive: 1,
+            }),
+        });
+        assert!(is_unavailable(&unavailable));
+        assert!(!is_unavailable(&overloaded()));
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        assert_eq!(0.05, RetryBudget::parse("5%").unwrap().fraction);
+        assert_eq!(0.05, RetryBudget::parse("0.05").unwrap().fraction);
+        assert!(RetryBudget::parse("150%").is_err());
+        assert!(RetryBudget::parse("five").is_err());
+
+        let budget = RetryBudget::parse("10%").unwrap();
+        let query = |fails: bool| {
+            let mut calls = 0;
+            with_retries(1, Some(&budget), || {
+                calls += 1;
+                match (fails, calls) {
+                    (true, 1) => Err(overloaded()),
+                    _ => Ok(()),
+                }
+            })
+        };
+        for _ in 0..10 {
+            assert!(query(false).is_ok());
+        }
+        // The 10 queries always retried and 10% of the 22 queries
+        for _ in 0..12 {
+            assert!(query(true).is_ok());
+        }
+        assert!(query(true).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// `--sample 0.01:seed=42`: keep a fraction of the rows, chosen by a
+// hash of their primary key and the seed rather than at random, so that
+// sampling the same table twice returns the same rows.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use cdrs::frame::frame_result::RowsMetadata;
+use serde_json::Value as JsonValue;
+
+use crate::canonical;
+use crate::core::{Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+use crate::schema;
+
+// FNV-1a, whose output doesn't depend on the platform or Rust version
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Debug)]
+pub struct Sample {
+    fraction: f64,
+    seed: u64,
+    // The primary keys of the queried tables, looked up once
+    keys: Mutex<HashMap<(String, String), Vec<String>>>,
+}
+
+impl Sample {
+    pub fn parse(s: &str) -> AppResult<Sample> {
+        let invalid = || {
+            AppError::new(format!(
+                "Invalid sample {}, expected e.g. 0.01 or 0.01:seed=42",
+                s
+            ))
+        };
+        let (fraction, seed) = match s.split_once(':') {
+            Some((fraction, seed)) => (
+                fraction,
+                seed.strip_prefix("seed=")
+                    .ok_or_else(invalid)?
+                    .parse()
+                    .map_err(|_| invalid())?,
+            ),
+            None => (s, 0),
+        };
+        let fraction: f64 = fraction.parse().map_err(|_| invalid())?;
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(invalid());
+        }
+        Ok(Sample {
+            fraction,
+            seed,
+            keys: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The primary key columns of the table of the results.
+    pub fn key_columns(
+        &self,
+        session: &CurrentSession,
+        meta: &RowsMetadata,
+        config: &Config,
+    ) -> AppResult<Vec<String>> {
+        let (keyspace, table) = schema::result_table(meta)
+            .ok_or_else(|| AppError::new("--sample requires the results of a table"))?;
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(key) = keys.get(&(keyspace.clone(), table.clone())) {
+            return Ok(key.clone());
+        }
+        let columns: Vec<String> = schema::table_columns(session, &keyspace, &table, config)?
+            .into_iter()
+            .filter(|c| c.kind == "partition_key" || c.kind == "clustering")
+            .map(|c| c.name)
+            .collect();
+        keys.insert((keyspace, table), columns.clone());
+        Ok(columns)
+    }
+
+    /// Whether `row` is part of the sample.
+    pub fn keep(&self, key: &[String], row: &JsonValue) -> AppResult<bool> {
+        if self.fraction >= 1.0 {
+            return Ok(true);
+        }
+        let mut input = self.seed.to_string();
+        for col in key {
+            let value = row.get(col).ok_or_else(|| {
+                AppError::new(format!(
+                    "--sample requires the primary key column {} in the results",
+                    col
+                ))
+            })?;
+            input.push('\0');
+            input.push_str(&canonical::to_canonical_string(value));
+        }
+        Ok((fnv1a(input.as_bytes()) as f64 / u64::MAX as f64) < self.fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_keep() {
+        let key = vec!["id".to_string()];
+        let kept = |s: &str| {
+            let sample = Sample::parse(s).unwrap();
+            (0..1000)
+                .filter(|i| sample.keep(&key, &json!({ "id": i, "x": 1 })).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let a = kept("0.1:seed=42");
+        assert_eq!(a, kept("0.1:seed=42"));
+        assert_ne!(a, kept("0.1:seed=43"));
+        assert!(a.len() > 50 && a.len() < 150);
+        assert_eq!(1000, kept("1").len());
+        assert!(Sample::parse("0").is_err());
+        assert!(Sample::parse("0.1:42").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Schema changes are propagated asynchronously: until all the nodes
+// agree on the schema version, a statement using a table or column that
+// was just created may fail on the nodes that haven't seen it yet.
+use std::collections::BTreeSet;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cdrs::frame::frame_result::RowsMetadata;
+
+use crate::backend::Params;
+use crate::core::{self, Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn schema_versions(session: &CurrentSession, config: &Config) -> AppResult<BTreeSet<String>> {
+    let mut versions = BTreeSet::new();
+    for query in &[
+        "SELECT schema_version FROM system.local",
+        "SELECT schema_version FROM system.peers",
+    ] {
+        for row in core::collect_rows(&session.query(query, Params::default())?, config)? {
+            // Peers that are down have no schema version
+            if let Some(version) = row.get("schema_version").and_then(|x| x.as_str()) {
+                versions.insert(version.to_string());
+            }
+        }
+    }
+    Ok(versions)
+}
+
+pub struct Column {
+    pub name: String,
+    pub kind: String,
+    pub col_type: String,
+    pub position: i64,
+    // Only single cell values have a write time and a TTL
+    pub has_writetime: bool,
+    // Whether a clustering column is in descending order
+    pub descending: bool,
+}
+
+/// The columns of a table, in the order of `SELECT *`.
+pub fn table_columns(
+    session: &CurrentSession,
+    keyspace: &str,
+    table: &str,
+    config: &Config,
+) -> AppResult<Vec<Column>> {
+    let resp = session.metadata(keyspace, table, config.consistency())?;
+    let mut columns: Vec<Column> = core::collect_rows(&resp, config)?
+        .into_iter()
+        .filter_map(|row| {
+            let kind = row.get("kind")?.as_str()?.to_string();
+            let col_type = row.get("type")?.as_str()?;
+            Some(Column {
+                name: row.get("column_name")?.as_str()?.to_string(),
+                has_writetime: (kind == "regular" || kind == "static")
+                    && !["list<", "set<", "map<"]
+                        .iter()
+                        .any(|x| col_type.starts_with(x)),
+                position: row.get("position")?.as_i64()?,
+                descending: row.get("clustering_order").and_then(|x| x.as_str()) == Some("desc"),
+                col_type: col_type.to_string(),
+                kind,
+            })
+        })
+        .collect();
+    if columns.is_empty() {
+        return Err(AppError::new(format!(
+            "Table {}.{} not found",
+            keyspace, table
+        )));
+    }
+    // The order of `SELECT *`
+    let rank = |kind: &str| match kind {
+        "partition_key" => 0,
+        "clustering" => 1,
+        _ => 2,
+    };
+    columns.sort_by(|a, b| {
+        (rank(&a.kind), a.position, &a.name).cmp(&(rank(&b.kind), b.position, &b.name))
+    });
+    Ok(columns)
+}
+
+/// The keyspace and table of the results of `meta`, if they are those of
+/// a table.
+pub fn result_table(meta: &RowsMetadata) -> Option<(String, String)> {
+    match (&meta.global_table_space, meta.col_specs.first()) {
+        (Some(spec), _) if spec.len() == 2 => Some((spec[0].as_plain(), spec[1].as_plain())),
+        (_, Some(col)) => match (&col.ksname, &col.tablename) {
+            (Some(ks), Some(table)) => Some((ks.as_plain(), table.as_plain())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The name of an identifier, which is case insensitive unless quoted.
+pub fn unquote(identifier: &str) -> String {
+    if identifier.starts_with('"') {
+        identifier[1..identifier.len() - 1].replace("\"\"", "\"")
+    } else {
+        identifier.to_lowercase()
+    }
+}
+
+/// Quote an identifier, e.g. to keep its case.
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Block until all the nodes report the same schema version.
+pub fn wait_for_agreement(
+    session: &CurrentSession,
+    config: &Config,
+    timeout: Duration,
+) -> AppResult<()> {
+    let started = Instant::now();
+    loop {
+        let versions = schema_versions(session, config)?;
+        if versions.len() <= 1 {
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            return Err(AppError::new(format!(
+                "No schema agreement after {:?}, nodes report {} versions",
+                timeout,
+                versions.len()
+            )));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Split a CQL script into statements, ignoring the `;` in string
+/// literals, quoted identifiers and comments.
+pub fn split_statements(script: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut current = String::new();
+    let mut chars = script.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                current.push(c);
+                for x in chars.by_ref() {
+                    current.push(x);
+                    if x == c {
+                        break;
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                current.push_str("$$");
+                chars.next();
+                while let Some(x) = chars.next() {
+                    current.push(x);
+                    if x == '$' && chars.peek() == Some(&'$') {
+                        current.push('$');
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            '-' | '/' if chars.peek() == Some(&c) => {
+                while chars.next_if(|x| *x != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                for x in chars.by_ref() {
+                    if prev == '*' && x == '/' {
+                        break;
+                    }
+                    prev = x;
+                }
+            }
+            ';' => statements.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    statements.push(current);
+
+    statements
+        .into_iter()
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements() {
+        let script = "
+            -- users; and more
+            CREATE TABLE ks.users (id int PRIMARY KEY, name text);
+            /* a;b */ INSERT INTO ks.users (id, name) VALUES (1, 'a;b');
+            CREATE FUNCTION ks.f(x int) RETURNS NULL ON NULL INPUT RETURNS int
+                LANGUAGE java AS $$ return x; $$;
+            // trailing
+        ";
+        assert_eq!(
+            vec![
+                "CREATE TABLE ks.users (id int PRIMARY KEY, name text)",
+                "INSERT INTO ks.users (id, name) VALUES (1, 'a;b')",
+                "CREATE FUNCTION ks.f(x int) RETURNS NULL ON NULL INPUT RETURNS int\n                LANGUAGE java AS $$ return x; $$",
+            ],
+            split_statements(script)
+        );
+    }
+}
@@ -0,0 +1,604 @@
+// ScyllaDB runs a shard per core, each owning a slice of the tokens of
+// its node: a statement sent to a connection of another shard is handed
+// over between cores, which limits the throughput of large runs.
+//
+// Scylla advertises its sharding in the `SUPPORTED` answer to `OPTIONS`,
+// along with a shard-aware port where a connection goes to the shard of
+// its source port modulo the number of shards. When it does, each shard
+// of each node gets its own session, through a local forwarder
+// connecting from such a port, and the executions of a prepared
+// `SELECT` restricting its whole partition key with `= ?` go to the
+// shard owning the token of that key on its primary replica. The other
+// statements go to the shards in turn.
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use cdrs::consistency::Consistency;
+use cdrs::types::value::{Value, ValueType};
+use regex::Regex;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::backend::{Backend, Cdrs, Params, Prepared, Response};
+use crate::core::{self, Config, CurrentSession};
+use crate::dc;
+use crate::errors::{AppError, AppResult};
+use crate::params;
+use crate::proxy::{self, Frame};
+use crate::schema::{self, unquote};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const OP_OPTIONS: u8 = 0x05;
+const OP_SUPPORTED: u8 = 0x06;
+
+// The source ports picked for the shards, above those the OS hands out
+// by default on Linux
+const FIRST_PORT: usize = 49152;
+const LAST_PORT: usize = 65535;
+
+lazy_static! {
+    static ref SELECT: Regex = Regex::new(
+        r"(?is)^(\s*select\s+.+?\s+from\s+(\S+)\s+where\s+)(.+?)(?:\s+(?:order\s+by|group\s+by|per\s+partition\s+limit|limit|allow\s+filtering)\b.*)?$"
+    )
+    .unwrap();
+    static ref AND: Regex = Regex::new(r"(?i)\s+and\s+").unwrap();
+    static ref EQ_MARKER: Regex = Regex::new(
+        r#"^([A-Za-z_][A-Za-z0-9_]*|"(?:[^"]|"")+")\s*=\s*(?:\?|:[A-Za-z_][A-Za-z0-9_]*)$"#
+    )
+    .unwrap();
+}
+
+/// How a node splits its tokens over its shards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sharding {
+    pub shards: usize,
+    ignore_msb: u32,
+    // The shard-aware port
+    port: u16,
+}
+
+impl Sharding {
+    fn parse(options: &HashMap<String, Vec<String>>) -> Option<Sharding> {
+        let option = |name: &str| options.get(name)?.first().map(String::as_str);
+        if option("SCYLLA_PARTITIONER")? != "org.apache.cassandra.dht.Murmur3Partitioner"
+            || option("SCYLLA_SHARDING_ALGORITHM")? != "biased-token-round-robin"
+        {
+            return None;
+        }
+        Some(Sharding {
+            shards: option("SCYLLA_NR_SHARDS")?.parse().ok().filter(|x| *x > 0)?,
+            ignore_msb: option("SCYLLA_SHARDING_IGNORE_MSB")?.parse().ok()?,
+            port: option("SCYLLA_SHARD_AWARE_PORT")?.parse().ok()?,
+        })
+    }
+
+    /// The shard owning `token`.
+    pub fn shard_of(&self, token: i64) -> usize {
+        let biased = (token as u64 ^ (1 << 63))
+            .checked_shl(self.ignore_msb)
+            .unwrap_or(0);
+        ((biased as u128 * self.shards as u128) >> 64) as usize
+    }
+}
+
+fn read_string_multimap(body: &[u8]) -> Option<HashMap<String, Vec<String>>> {
+    let mut pos = 0;
+    let short = |pos: &mut usize| -> Option<usize> {
+        let x = u16::from_be_bytes([*body.get(*pos)?, *body.get(*pos + 1)?]);
+        *pos += 2;
+        Some(x as usize)
+    };
+    let mut options = HashMap::new();
+    for _ in 0..short(&mut pos)? {
+        let key = proxy::read_string(body, &mut pos)?;
+        let values = (0..short(&mut pos)?)
+            .map(|_| proxy::read_string(body, &mut pos))
+            .collect::<Option<_>>()?;
+        options.insert(key, values);
+    }
+    Some(options)
+}
+
+/// The sharding of the node at `host`, `None` unless it is a Scylla
+/// node with a shard-aware port.
+pub fn probe(host: &str) -> AppResult<Option<Sharding>> {
+    let address = resolve(host)?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)
+        .map_err(|err| AppError::new(format!("Unable to connect to {}: {}", host, err)))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    Frame::request(0x04, OP_OPTIONS, vec![]).write(&mut stream)?;
+    let supported = Frame::read(&mut stream)?;
+    if supported.opcode() != OP_SUPPORTED {
+        return Ok(None);
+    }
+    Ok(read_string_multimap(supported.body()).and_then(|x| Sharding::parse(&x)))
+}
+
+fn resolve(host: &str) -> AppResult<SocketAddr> {
+    host.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| AppError::new(format!("Unable to resolve {}", host)))
+}
+
+/// The token of a partition key, as the `Murmur3Partitioner` tells,
+/// from the variant of MurmurHash3 x64 128 in Cassandra, which reads
+/// the last bytes as signed.
+pub fn token(key: &[u8]) -> i64 {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+    let fmix = |mut k: u64| {
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        k ^ (k >> 33)
+    };
+    let mix1 = |k: u64| k.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix2 = |k: u64| k.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let mut blocks = key.chunks_exact(16);
+    for block in blocks.by_ref() {
+        let k1 = u64::from_le_bytes(block[..8].try_into().unwrap());
+        let k2 = u64::from_le_bytes(block[8..].try_into().unwrap());
+        h1 ^= mix1(k1);
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dc_e729);
+        h2 ^= mix2(k2);
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x3849_5ab5);
+    }
+    let tail = blocks.remainder();
+    let signed = |i: usize| tail[i] as i8 as i64 as u64;
+    if tail.len() > 8 {
+        h2 ^= mix2((8..tail.len()).fold(0, |k, i| k ^ (signed(i) << ((i - 8) * 8))));
+    }
+    if !tail.is_empty() {
+        h1 ^= mix1((0..tail.len().min(8)).fold(0, |k, i| k ^ (signed(i) << (i * 8))));
+    }
+
+    h1 ^= key.len() as u64;
+    h2 ^= key.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix(h1);
+    h2 = fmix(h2);
+    match h1.wrapping_add(h2) as i64 {
+        i64::MIN => i64::MAX,
+        x => x,
+    }
+}
+
+// The serialized partition key of a statement from the values at
+// `positions`, each component of a composite key being prefixed with its
+// length and followed by a zero byte
+fn routing_key(values: &[Value], positions: &[usize]) -> Option<Vec<u8>> {
+    let mut parts = positions.iter().map(|i| {
+        let value = values.get(*i)?;
+        Some(&value.body).filter(|_| matches!(value.value_type, ValueType::Normal(_)))
+    });
+    if positions.len() == 1 {
+        return parts.next()?.cloned();
+    }
+    let mut key = vec![];
+    for part in parts {
+        let part = part?;
+        key.extend_from_slice(&(part.len() as u16).to_be_bytes());
+        key.extend_from_slice(part);
+        key.push(0);
+    }
+    Some(key)
+}
+
+/// The positions of the bind markers giving the values of the
+/// `partition_key` columns in `cql`, when it restricts all of them with
+/// `= ?`.
+fn partition_key_markers(cql: &str, partition_key: &[String]) -> Option<Vec<usize>> {
+    let caps = SELECT.captures(cql)?;
+    let mut position = params::bind_markers(&caps[1]).len();
+    let mut columns = HashMap::new();
+    for restriction in AND.split(&caps[3]) {
+        if let Some(x) = EQ_MARKER.captures(restriction.trim()) {
+            columns.insert(unquote(&x[1]), position);
+        }
+        position += params::bind_markers(restriction).len();
+    }
+    partition_key
+        .iter()
+        .map(|x| columns.get(x).copied())
+        .collect()
+}
+
+/// The positions of the values making up the partition key of the
+/// prepared statement `cql`, if it gives the whole partition key.
+pub fn routing(
+    session: &CurrentSession,
+    cql: &str,
+    config: &Config,
+) -> AppResult<Option<Vec<usize>>> {
+    let table = match SELECT.captures(cql) {
+        Some(caps) => caps[2].to_string(),
+        None => return Ok(None),
+    };
+    let (keyspace, table) = match table.split_once('.') {
+        Some((keyspace, table)) => (unquote(keyspace), unquote(table)),
+        None => return Ok(None),
+    };
+    let partition_key: Vec<String> = schema::table_columns(session, &keyspace, &table, config)?
+        .into_iter()
+        .filter(|c| c.kind == "partition_key")
+        .map(|c| c.name)
+        .collect();
+    Ok(partition_key_markers(cql, &partition_key))
+}
+
+/// The sessions to the shards of the nodes of a cluster, by node.
+pub struct Ring<S> {
+    nodes: Vec<(Sharding, Vec<S>)>,
+    // The tokens of the nodes in order, with the index of their node
+    tokens: Vec<(i64, usize)>,
+    // The positions of the partition key values of the prepared
+    // statements, by id
+    routes: RwLock<HashMap<Vec<u8>, Vec<usize>>>,
+    next: AtomicUsize,
+}
+
+impl<S> Ring<S> {
+    fn new(nodes: Vec<(Sharding, Vec<S>, Vec<i64>)>) -> Ring<S> {
+        let mut tokens = vec![];
+        let nodes = nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, (sharding, shards, node_tokens))| {
+                tokens.extend(node_tokens.into_iter().map(|x| (x, i)));
+                (sharding, shards)
+            })
+            .collect();
+        tokens.sort_unstable();
+        Ring {
+            nodes,
+            tokens,
+            routes: RwLock::new(HashMap::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The ring with sessions made with `f` from those of `self`.
+    pub fn try_map<T, E>(self, mut f: impl FnMut(S) -> Result<T, E>) -> Result<Ring<T>, E> {
+        let nodes = self
+            .nodes
+            .into_iter()
+            .map(|(sharding, shards)| {
+                Ok((sharding, shards.into_iter().map(&mut f).collect::<Result<_, E>>()?))
+            })
+            .collect::<Result<_, E>>()?;
+        Ok(Ring {
+            nodes,
+            tokens: self.tokens,
+            routes: self.routes,
+            next: self.next,
+        })
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &S> {
+        self.nodes.iter().flat_map(|(_, shards)| shards)
+    }
+
+    /// The sessions in turn.
+    pub fn any(&self) -> &S {
+        let count: usize = self.nodes.iter().map(|(_, x)| x.len()).sum();
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        self.sessions().nth(i).unwrap()
+    }
+
+    /// Route the executions of the prepared statement `id` by the values
+    /// at `positions`.
+    pub fn route(&self, id: Vec<u8>, positions: Vec<usize>) {
+        self.routes.write().unwrap().insert(id, positions);
+    }
+
+    /// The session to the shard of the primary replica of the partition
+    /// an execution of the prepared statement `id` with `values` reads.
+    pub fn pick(&self, id: &[u8], values: &[Value]) -> &S {
+        let positions = self.routes.read().unwrap().get(id).cloned();
+        let key = match positions.and_then(|x| routing_key(values, &x)) {
+            Some(x) if !self.tokens.is_empty() => x,
+            _ => return self.any(),
+        };
+        let token = token(&key);
+        // The first node with a token from that of the key on, wrapping
+        // around the ring
+        let i = self.tokens.partition_point(|(x, _)| *x < token) % self.tokens.len();
+        let (sharding, shards) = &self.nodes[self.tokens[i].1];
+        &shards[sharding.shard_of(token)]
+    }
+}
+
+/// A backend sending the executions of the prepared statements to the
+/// shards owning the partitions they read.
+pub struct Sharded {
+    ring: Ring<Cdrs>,
+    // The session to the contact host, looking up the partition keys
+    contact: CurrentSession,
+    config: Config,
+}
+
+impl Sharded {
+    pub fn new(ring: Ring<Cdrs>, contact: CurrentSession, config: Config) -> Sharded {
+        Sharded {
+            ring,
+            contact,
+            config,
+        }
+    }
+}
+
+impl Backend for Sharded {
+    fn prepare(&self, cql: &str) -> cdrs::error::Result<Prepared> {
+        // Each shard has its own cache of prepared statements
+        let mut sessions = self.ring.sessions();
+        let prepared = sessions.next().unwrap().prepare(cql)?;
+        for session in sessions {
+            session.prepare(cql)?;
+        }
+        // Sent to the shards in turn when the partition key is unknown
+        if let Ok(Some(positions)) = routing(&self.contact, cql, &self.config) {
+            self.ring
+                .route(prepared.clone().into_plain().unwrap_or_default(), positions);
+        }
+        Ok(prepared)
+    }
+
+    fn query(&self, cql: &str, params: Params) -> cdrs::error::Result<Response> {
+        self.ring.any().query(cql, params)
+    }
+
+    fn execute(&self, prepared: &Prepared, params: Params) -> cdrs::error::Result<Response> {
+        let id = prepared.clone().into_plain().unwrap_or_default();
+        self.ring
+            .pick(&id, &params.values)
+            .execute(prepared, params)
+    }
+
+    fn speculate(&self, prepared: &Prepared, params: Params) -> cdrs::error::Result<Response> {
+        // Another shard, or most likely another node
+        self.ring.any().execute(prepared, params)
+    }
+
+    fn metadata(
+        &self,
+        keyspace: &str,
+        table: &str,
+        consistency: Consistency,
+    ) -> cdrs::error::Result<Response> {
+        self.contact.metadata(keyspace, table, consistency)
+    }
+}
+
+// The tokens of the nodes of the cluster, by address
+fn node_tokens(
+    session: &dyn Backend,
+    contact: &str,
+    config: &Config,
+) -> AppResult<HashMap<String, Vec<i64>>> {
+    let port = contact.rsplit(':').next().unwrap_or("9042");
+    let mut tokens = HashMap::new();
+    for table in ["local", "peers"] {
+        let cql = format!("SELECT rpc_address, tokens FROM system.{}", table);
+        let resp = session.query(&cql, Params::new(vec![], Consistency::One))?;
+        for row in core::collect_rows(&resp, config)? {
+            let address = match (table, row["rpc_address"].as_str()) {
+                ("local", _) => contact.to_string(),
+                (_, Some(address)) => dc::node_address(address, port),
+                _ => continue,
+            };
+            let node_tokens = row["tokens"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|x| x.as_str()?.parse().ok())
+                .collect();
+            tokens.insert(address, node_tokens);
+        }
+    }
+    Ok(tokens)
+}
+
+/// The addresses of local forwarders to each shard of `nodes`, `None`
+/// unless `contact` is a Scylla node with a shard-aware port.
+pub fn shards(
+    session: &dyn Backend,
+    contact: &str,
+    nodes: &[String],
+    config: &Config,
+) -> AppResult<Option<Ring<String>>> {
+    if probe(contact)?.is_none() {
+        return Ok(None);
+    }
+    let mut tokens = node_tokens(session, contact, config)?;
+    let nodes = nodes
+        .iter()
+        .map(|node| {
+            let sharding = probe(node)?.ok_or_else(|| {
+                AppError::new(format!("{} has no shard-aware port", node))
+            })?;
+            let address = SocketAddr::new(resolve(node)?.ip(), sharding.port);
+            let shards = (0..sharding.shards)
+                .map(|shard| {
+                    proxy::listen(move |local| {
+                        match connect_to_shard(address, shard, sharding.shards) {
+                            Ok(remote) => proxy::relay(local, remote),
+                            Err(err) => diag!(
+                                "Unable to connect to shard {} of {}: {}",
+                                shard, address, err
+                            ),
+                        }
+                    })
+                })
+                .collect::<AppResult<_>>()?;
+            Ok((sharding, shards, tokens.remove(node).unwrap_or_default()))
+        })
+        .collect::<AppResult<_>>()?;
+    Ok(Some(Ring::new(nodes)))
+}
+
+// The source ports tried in turn, so that the connections of the
+// shards don't keep trying the same ones
+static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// Connect to the shard-aware port at `address` from a local port
+/// picking `shard` out of `shards`.
+fn connect_to_shard(address: SocketAddr, shard: usize, shards: usize) -> io::Result<TcpStream> {
+    let first = FIRST_PORT + (shard + shards - FIRST_PORT % shards) % shards;
+    let count = (LAST_PORT - first) / shards + 1;
+    let local = match address {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let mut last_error = None;
+    for _ in 0..count {
+        let port = first + NEXT_PORT.fetch_add(1, Ordering::Relaxed) % count * shards;
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        let mut local = local;
+        local.set_port(port as u16);
+        match socket
+            .bind(&SockAddr::from(local))
+            .and_then(|_| socket.connect_timeout(&SockAddr::from(address), TIMEOUT))
+        {
+            Ok(()) => return Ok(socket.into()),
+            // Taken by another connection
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                ) =>
+            {
+                last_error = Some(err)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "No free local port")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        // SELECT token(1) of an int partition key
+        assert_eq!(-4069959284402364209, token(&1i32.to_be_bytes()));
+        // Through all the branches of the tail and a block
+        let key: Vec<u8> = (0..31u8).map(|x| x.wrapping_mul(37)).collect();
+        for n in 0..key.len() {
+            token(&key[..n]);
+        }
+
+        let sharding = Sharding {
+            shards: 4,
+            ignore_msb: 12,
+            port: 19042,
+        };
+        assert_eq!(0, sharding.shard_of(i64::MIN));
+        let shards: Vec<usize> = (0..64)
+            .map(|i| sharding.shard_of(i64::MIN + (i << 46)))
+            .collect();
+        assert!((0..4).all(|x| shards.contains(&x)));
+        assert!(shards.iter().all(|x| *x < 4));
+    }
+
+    #[test]
+    fn test_partition_key_markers() {
+        let key = vec!["tenant".to_string(), "id".to_string()];
+        assert_eq!(
+            Some(vec![2, 1]),
+            partition_key_markers(
+                "SELECT * FROM ks.t WHERE ts > ? AND id = ? and \"Tenant\" = :t",
+                &["Tenant".to_string(), "id".to_string()]
+            )
+        );
+        assert_eq!(
+            None,
+            partition_key_markers("SELECT * FROM ks.t WHERE tenant = ? AND id IN ?", &key)
+        );
+        assert_eq!(
+            Some(vec![1]),
+            partition_key_markers(
+                "SELECT writetime(x), ? FROM ks.t WHERE id = ? LIMIT 10",
+                &key[1..]
+            )
+        );
+
+        let values = vec![Value::from("a"), Value::from(7i32)];
+        assert_eq!(Some(b"a".to_vec()), routing_key(&values, &[0]));
+        assert_eq!(
+            Some(vec![0, 1, b'a', 0, 0, 4, 0, 0, 0, 7, 0]),
+            routing_key(&values, &[0, 1])
+        );
+        assert_eq!(None, routing_key(&values, &[2]));
+    }
+
+    #[test]
+    fn test_pick() {
+        let sharding = Sharding {
+            shards: 2,
+            ignore_msb: 0,
+            port: 19042,
+        };
+        let ring = Ring::new(vec![
+            (sharding, vec!["a0", "a1"], vec![0]),
+            (sharding, vec!["b0", "b1"], vec![i64::MAX]),
+        ]);
+        ring.route(vec![1], vec![0]);
+        let key = 1i32.to_be_bytes();
+        // The token of 1 is negative, owned by the node of token 0 and
+        // the first half of the tokens making up its shard 0
+        assert_eq!(&"a0", ring.pick(&[1], &[Value::new_normal(key.to_vec())]));
+        let other: Vec<&str> = (0..4).map(|_| *ring.pick(&[2], &[])).collect();
+        assert_eq!(vec!["a0", "a1", "b0", "b1"], other);
+        assert_eq!(
+            Sharding {
+                shards: 8,
+                ignore_msb: 12,
+                port: 19042
+            },
+            read_string_multimap(&supported())
+                .and_then(|x| Sharding::parse(&x))
+                .unwrap()
+        );
+    }
+
+    fn supported() -> Vec<u8> {
+        let options = [
+            ("SCYLLA_NR_SHARDS", "8"),
+            ("SCYLLA_SHARDING_IGNORE_MSB", "12"),
+            (
+                "SCYLLA_PARTITIONER",
+                "org.apache.cassandra.dht.Murmur3Partitioner",
+            ),
+            ("SCYLLA_SHARDING_ALGORITHM", "biased-token-round-robin"),
+            ("SCYLLA_SHARD_AWARE_PORT", "19042"),
+            ("COMPRESSION", "lz4"),
+        ];
+        let mut body = (options.len() as u16).to_be_bytes().to_vec();
+        for (key, value) in options {
+            proxy::write_string(&mut body, key);
+            body.extend_from_slice(&1u16.to_be_bytes());
+            proxy::write_string(&mut body, value);
+        }
+        body
+    }
+}
@@ -0,0 +1,224 @@
+// `kass self-update`: replace the running binary with the one of the
+// latest GitHub release, for hosts where kass was installed as a single
+// binary rather than with a package manager.
+//
+// The releases hold a `kass-<target>` binary per target along with its
+// SHA-256 checksum in `kass-<target>.sha256`, checked before replacing
+// the binary. The stable channel is the latest release and the nightly
+// channel the `nightly` pre-release, rebuilt every night.
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use openssl::ssl::{SslConnector, SslMethod};
+use serde_json::Value as JsonValue;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{AppError, AppResult};
+
+const RELEASES: &str = "https://api.github.com/repos/jerrypnz/kass/releases";
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Redirects followed, assets being served from another host
+const MAX_REDIRECTS: usize = 5;
+
+fn asset_name() -> String {
+    let name = format!("kass-{}", env!("KASS_TARGET"));
+    if cfg!(windows) {
+        name + ".exe"
+    } else {
+        name
+    }
+}
+
+struct Response<'a> {
+    status: u16,
+    // With lowercase names
+    headers: Vec<(String, String)>,
+    body: &'a [u8],
+}
+
+fn parse_response(response: &[u8]) -> AppResult<Response<'_>> {
+    let invalid = || AppError::new("Invalid HTTP response");
+    let end = response
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|x| x.split_whitespace().nth(1))
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(invalid)?;
+    let headers = lines
+        .filter_map(|x| x.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok(Response {
+        status,
+        headers,
+        body: &response[end + 4..],
+    })
+}
+
+fn get(url: &str) -> AppResult<Vec<u8>> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let rest = url
+            .strip_prefix("https://")
+            .ok_or_else(|| AppError::new(format!("Not an https URL: {}", url)))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let stream = TcpStream::connect((host, 443))
+            .map_err(|err| AppError::new(format!("Unable to connect to {}: {}", host, err)))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut stream = SslConnector::builder(SslMethod::tls())?
+            .build()
+            .connect(host, stream)
+            .map_err(|err| AppError::new(format!("TLS handshake with {} failed: {}", host, err)))?;
+        // HTTP/1.0 for a response without chunked encoding
+        write!(
+            stream,
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: kass/{}\r\nAccept: */*\r\n\r\n",
+            path,
+            host,
+            env!("CARGO_PKG_VERSION")
+        )?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        let response = parse_response(&response)?;
+        match response.status {
+            200 => return Ok(response.body.to_vec()),
+            301 | 302 | 303 | 307 | 308 => {
+                url = response
+                    .headers
+                    .into_iter()
+                    .find(|(name, _)| name == "location")
+                    .map(|(_, value)| value)
+                    .ok_or_else(|| AppError::new(format!("{} redirected nowhere", url)))?;
+            }
+            status => return Err(AppError::new(format!("{} returned {}", url, status))),
+        }
+    }
+    Err(AppError::new(format!("Too many redirects from {}", url)))
+}
+
+// The checksum in a `sha256sum` line, `<hex>  <file name>`
+fn parse_checksum(text: &str) -> Option<String> {
+    let hex = text.split_whitespace().next()?;
+    Some(hex.to_lowercase()).filter(|x| x.len() == 64 && x.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn download_url(release: &JsonValue, name: &str) -> AppResult<String> {
+    release["assets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|x| x["name"] == name)
+        .and_then(|x| x["browser_download_url"].as_str())
+        .map(String::from)
+        .ok_or_else(|| {
+            AppError::new(format!(
+                "The release {} has no {}",
+                release["tag_name"].as_str().unwrap_or("?"),
+                name
+            ))
+        })
+}
+
+pub fn self_update(channel: &str) -> AppResult<()> {
+    let url = match channel {
+        "nightly" => format!("{}/tags/nightly", RELEASES),
+        _ => format!("{}/latest", RELEASES),
+    };
+    let release: JsonValue = serde_json::from_slice(&get(&url)?)?;
+    let tag = release["tag_name"].as_str().unwrap_or("?");
+    let name = asset_name();
+    let checksum = get(&download_url(&release, &format!("{}.sha256", name))?)?;
+    let checksum = parse_checksum(&String::from_utf8_lossy(&checksum))
+        .ok_or_else(|| AppError::new(format!("Invalid checksum file of {}", name)))?;
+
+    let exe = env::current_exe()?;
+    if sha256_hex(&fs::read(&exe)?) == checksum {
+        diag!("kass is up to date with the {} release {}", channel, tag);
+        return Ok(());
+    }
+    diag!("Downloading {} of the {} release {}", name, channel, tag);
+    let binary = get(&download_url(&release, &name)?)?;
+    if sha256_hex(&binary) != checksum {
+        return Err(AppError::new(format!(
+            "The checksum of the downloaded {} doesn't match {}.sha256, leaving kass as it is",
+            name, name
+        )));
+    }
+
+    // Written next to the binary, for the rename to replace it at once
+    let tmp = exe.with_extension("new");
+    fs::write(&tmp, &binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755))?;
+    }
+    // Windows doesn't replace a running binary, which can be moved aside
+    #[cfg(windows)]
+    fs::rename(&exe, exe.with_extension("old"))?;
+    fs::rename(&tmp, &exe).map_err(|err| {
+        let _ = fs::remove_file(&tmp);
+        // Put the running binary back rather than leave none
+        #[cfg(windows)]
+        let _ = fs::rename(exe.with_extension("old"), &exe);
+        AppError::new(format!("Unable to replace {}: {}", exe.display(), err))
+    })?;
+    diag!(
+        "Updated {} to the {} release {}",
+        exe.display(),
+        channel,
+        tag
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let response = b"HTTP/1.1 302 Found\r\nLocation: https://objects.example.com/kass\r\n\r\n";
+        let response = parse_response(response).unwrap();
+        assert_eq!(302, response.status);
+        assert_eq!(
+            vec![(
+                "location".to_string(),
+                "https://objects.example.com/kass".to_string()
+            )],
+            response.headers
+        );
+        assert!(response.body.is_empty());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+
+        let hex = sha256_hex(b"kass");
+        assert_eq!(
+            Some(hex.clone()),
+            parse_checksum(&format!(
+                "{}  kass-x86_64-unknown-linux-gnu\n",
+                hex.to_uppercase()
+            ))
+        );
+        assert_eq!(None, parse_checksum("not a checksum"));
+    }
+}
@@ -0,0 +1,637 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use tiny_http::{Header, Method, Request, Response, Server as HttpServer};
+
+use crate::control::PauseControl;
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+use crate::sink::Sink;
+
+/// Body of `POST /query`.
+#[derive(Debug, Deserialize)]
+struct QueryRequest {
+    query: String,
+    #[serde(default)]
+    params: Vec<String>,
+    parallelism: Option<usize>,
+    /// Stream the rows back in the response, otherwise they have to be
+    /// fetched page by page with `GET /runs/:id/rows`
+    #[serde(default = "default_stream")]
+    stream: bool,
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+// The rows of a run kept for its clients, the oldest being dropped past
+// them
+const MAX_BUFFERED_ROWS: usize = 100_000;
+
+// The finished runs listed, the oldest being forgotten past them
+const MAX_FINISHED_RUNS: usize = 100;
+
+/// A query submitted to the server. Its rows are kept around so that
+/// clients can page through them and resume after a disconnect, until
+/// a client asks for the rows after them or there are too many.
+struct Run {
+    id: u64,
+    query: String,
+    control: Arc<PauseControl>,
+    state: Mutex<RunState>,
+    updated: Condvar,
+}
+
+#[derive(Default)]
+struct RunState {
+    started: bool,
+    rows: VecDeque<String>,
+    // The offset of the first row kept
+    first: usize,
+    outcome: Option<Result<(), String>>,
+}
+
+impl RunState {
+    // The offset of the next row
+    fn end(&self) -> usize {
+        self.first + self.rows.len()
+    }
+
+    // Drop the rows before `offset`
+    fn drop_rows(&mut self, offset: usize) {
+        while self.first < offset && self.rows.pop_front().is_some() {
+            self.first += 1;
+        }
+    }
+}
+
+// The rows at an offset were dropped
+#[derive(Debug)]
+struct Expired(usize);
+
+impl From<Expired> for AppError {
+    fn from(err: Expired) -> Self {
+        AppError::new(format!(
+            "The rows from offset {} are no longer kept, they were acknowledged or too many rows followed",
+            err.0
+        ))
+    }
+}
+
+impl Run {
+    fn new(id: u64, query: String) -> Self {
+        Run {
+            id,
+            query,
+            control: Arc::new(PauseControl::default()),
+            state: Mutex::new(RunState::default()),
+            updated: Condvar::new(),
+        }
+    }
+
+    fn start(&self) {
+        self.state.lock().unwrap().started = true;
+    }
+
+    fn push_row(&self, row: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.rows.push_back(row.to_string());
+        if state.rows.len() > MAX_BUFFERED_ROWS {
+            let first = state.first + 1;
+            state.drop_rows(first);
+        }
+        drop(state);
+        self.updated.notify_all();
+    }
+
+    /// Drop the rows before `offset`, which a client has received.
+    fn acknowledge(&self, offset: usize) {
+        self.state.lock().unwrap().drop_rows(offset);
+    }
+
+    fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().outcome.is_some()
+    }
+
+    // The error of a failed or cancelled run
+    fn error(&self) -> Option<String> {
+        match &self.state.lock().unwrap().outcome {
+            Some(Err(err)) => Some(err.clone()),
+            _ => None,
+        }
+    }
+
+    fn finish(&self, outcome: Result<(), String>) {
+        self.state.lock().unwrap().outcome = Some(outcome);
+        self.updated.notify_all();
+    }
+
+    /// Up to `max` rows starting at `offset`, waiting for at least one
+    /// unless the run is over. Also tells whether the end of the
+    /// results has been reached.
+    fn wait_rows(&self, offset: usize, max: usize) -> Result<(Vec<String>, bool), Expired> {
+        let mut state = self.state.lock().unwrap();
+        while state.end() <= offset && state.outcome.is_none() {
+            state = self.updated.wait(state).unwrap();
+        }
+        if offset < state.first {
+            return Err(Expired(offset));
+        }
+        let start = min(offset, state.end());
+        let end = min(start + max, state.end());
+        let rows = state
+            .rows
+            .range(start - state.first..end - state.first)
+            .cloned()
+            .collect();
+        let done = state.outcome.is_some() && end == state.end();
+        Ok((rows, done))
+    }
+
+    fn status(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+        let (status, error) = match &state.outcome {
+            None if !state.started => ("queued", None),
+            None => ("running", None),
+            Some(Ok(_)) => ("done", None),
+            Some(Err(_)) if self.control.is_cancelled() => ("cancelled", None),
+            Some(Err(err)) => ("failed", Some(err.clone())),
+        };
+        json!({
+            "id": self.id,
+            "query": self.query,
+            "status": status,
+            "rows": state.end(),
+            "error": error,
+        })
+    }
+}
+
+// Continuation tokens are opaque to clients, they only encode the run
+// and the offset of the next row to return.
+fn encode_token(run_id: u64, offset: usize) -> String {
+    format!("{:x}.{:x}", run_id, offset)
+}
+
+fn decode_token(token: &str, run_id: u64) -> Option<usize> {
+    let (id, offset) = token.split_once('.')?;
+    if u64::from_str_radix(id, 16).ok()? != run_id {
+        return None;
+    }
+    usize::from_str_radix(offset, 16).ok()
+}
+
+// Forget the oldest finished `runs` past `MAX_FINISHED_RUNS`
+fn evict_finished(runs: &mut BTreeMap<u64, Arc<Run>>) {
+    let finished: Vec<u64> = runs
+        .values()
+        .filter(|run| run.is_finished())
+        .map(|run| run.id)
+        .collect();
+    let evicted = finished.len().saturating_sub(MAX_FINISHED_RUNS);
+    for id in &finished[..evicted] {
+        runs.remove(id);
+    }
+}
+
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    url.split_once('?')?
+        .1
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+// Collects the rows of a run
+struct RunSink(Arc<Run>);
+
+impl Sink for RunSink {
+    fn write_row(&self, row: &str) -> AppResult<()> {
+        self.0.push_row(row);
+        Ok(())
+    }
+}
+
+// Turns the rows of a run into an NDJSON response body, ending with an
+// `{"error": ...}` record when the run fails, so that clients can tell
+// a partial export from a complete one. A client going away doesn't
+// affect the run, it can pick up the rest of the rows with
+// `GET /runs/:id/rows`.
+struct RowReader {
+    run: Arc<Run>,
+    offset: usize,
+    buf: Vec<u8>,
+    pos: usize,
+    // Whether the rows are all in `buf`, along with the error record
+    ended: bool,
+}
+
+impl Read for RowReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.buf.len() {
+            let (rows, _) = self
+                .run
+                .wait_rows(self.offset, 64)
+                .map_err(|err| io::Error::other(AppError::from(err).to_string()))?;
+            if self.ended {
+                return Ok(0);
+            }
+            self.offset += rows.len();
+            self.buf.clear();
+            if rows.is_empty() {
+                self.ended = true;
+                if let Some(err) = self.run.error() {
+                    self.buf = json!({ "error": err }).to_string().into_bytes();
+                    self.buf.push(b'\n');
+                }
+            }
+            for row in rows {
+                self.buf.extend_from_slice(row.as_bytes());
+                self.buf.push(b'\n');
+            }
+            self.pos = 0;
+        }
+        let n = min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Limits the number of runs executing at the same time, the others
+/// wait in line, whether they come through HTTP or gRPC.
+pub struct Slots {
+    max: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+pub struct Slot<'a>(&'a Slots);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.freed.notify_all();
+    }
+}
+
+impl Slots {
+    pub fn new(max: usize) -> Self {
+        Slots {
+            max,
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot, unless the run gets cancelled first.
+    pub fn acquire(&self, control: &PauseControl) -> Option<Slot<'_>> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.max && !control.is_cancelled() {
+            running = self.freed.wait(running).unwrap();
+        }
+        if control.is_cancelled() {
+            return None;
+        }
+        *running += 1;
+        Some(Slot(self))
+    }
+
+    // Wakes up queued runs so that cancelled ones can leave the queue
+    fn wake(&self) {
+        let _running = self.running.lock().unwrap();
+        self.freed.notify_all();
+    }
+}
+
+struct Server {
+    config: Config,
+    slots: Arc<Slots>,
+    runs: Mutex<BTreeMap<u64, Arc<Run>>>,
+    next_id: AtomicU64,
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn json_response(status: u16, json: JsonValue) -> Response<io::Cursor<Vec<u8>>> {
+    Response::from_string(json.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error_response(status: u16, msg: &str) -> Response<io::Cursor<Vec<u8>>> {
+    json_response(status, json!({ "error": msg }))
+}
+
+impl Server {
+    fn find_run(&self, id: &str) -> Option<Arc<Run>> {
+        let id = id.parse::<u64>().ok()?;
+        self.runs.lock().unwrap().get(&id).cloned()
+    }
+
+    fn handle(self: &Arc<Self>, mut request: Request) -> io::Result<()> {
+        let url = request.url().to_string();
+        let path = url.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+
+        match (request.method(), segments.as_slice()) {
+            (Method::Post, ["query"]) => {
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body)?;
+                match self.start_run(&body) {
+                    Ok((run, false)) => request.respond(json_response(
+                        202,
+                        json!({ "id": run.id, "next_token": encode_token(run.id, 0) }),
+                    )),
+                    Ok((run, true)) => {
+                        let reader = RowReader {
+                            run: run.clone(),
+                            offset: 0,
+                            buf: vec![],
+                            pos: 0,
+                            ended: false,
+                        };
+                        let response = Response::new(
+                            200.into(),
+                            vec![
+                                header("Content-Type", "application/x-ndjson"),
+                                header("X-Kass-Run-Id", &run.id.to_string()),
+                            ],
+                            reader,
+                            None,
+                            None,
+                        );
+                        request.respond(response)
+                    }
+                    Err(err) => request.respond(error_response(400, &err.to_string())),
+                }
+            }
+            (Method::Get, ["metrics"]) => {
+                let response = Response::from_string(self.config.metrics().render())
+                    .with_header(header("Content-Type", "text/plain; version=0.0.4"));
+                request.respond(response)
+            }
+            (Method::Get, ["runs"]) => {
+                let runs: Vec<JsonValue> = self
+                    .runs
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .map(|run| run.status())
+                    .collect();
+                request.respond(json_response(200, json!({ "runs": runs })))
+            }
+            (Method::Delete, ["runs", id]) => match self.find_run(id) {
+                Some(run) => {
+                    run.control.cancel();
+                    self.slots.wake();
+                    request.respond(json_response(200, run.status()))
+                }
+                None => request.respond(error_response(404, "run not found")),
+            },
+            (Method::Get, ["runs", id, "status"]) => match self.find_run(id) {
+                Some(run) => request.respond(json_response(200, run.status())),
+                None => request.respond(error_response(404, "run not found")),
+            },
+            (Method::Get, ["runs", id, "rows"]) => match self.find_run(id) {
+                Some(run) => {
+                    let response = rows_page(&run, &url)
+                        .map(|page| json_response(200, page))
+                        .unwrap_or_else(|err| error_response(400, &err.to_string()));
+                    request.respond(response)
+                }
+                None => request.respond(error_response(404, "run not found")),
+            },
+            _ => request.respond(error_response(404, "not found")),
+        }
+    }
+
+    // Returns the run and whether its rows should be streamed back
+    fn start_run(self: &Arc<Self>, body: &str) -> AppResult<(Arc<Run>, bool)> {
+        let req: QueryRequest = serde_json::from_str(body)
+            .map_err(|err| AppError::new(format!("Invalid request: {}", err)))?;
+        let param_values = if req.params.is_empty() {
+            None
+        } else {
+            Some(self.config.parse_params(req.params.iter().map(String::as_str))?)
+        };
+
+        let run = Arc::new(Run::new(
+            self.next_id.fetch_add(1, Ordering::Relaxed),
+            req.query,
+        ));
+        let mut runs = self.runs.lock().unwrap();
+        evict_finished(&mut runs);
+        runs.insert(run.id, run.clone());
+        drop(runs);
+
+        let mut config = self.config.with_sink(Arc::new(RunSink(run.clone())));
+        config.set_control(run.control.clone());
+        // --parallelism of the server is the upper bound for every run
+        if let Some(n) = req.parallelism.filter(|n| *n > 0) {
+            config.set_parallelism(min(n, self.config.parallelism()));
+        }
+
+        let server = self.clone();
+        let worker = run.clone();
+        thread::spawn(move || {
+            let result = match server.slots.acquire(&worker.control) {
+                Some(_slot) => {
+                    worker.start();
+                    core::run_query(config, &worker.query, param_values)
+                }
+                None => Err(AppError::new("Run cancelled")),
+            };
+            worker.finish(result.map_err(|err| err.to_string()));
+        });
+        Ok((run, req.stream))
+    }
+}
+
+// A page of `GET /runs/:id/rows?token=...&page_size=...`
+fn rows_page(run: &Run, url: &str) -> AppResult<JsonValue> {
+    let offset = match query_param(url, "token") {
+        Some(token) => decode_token(token, run.id)
+            .ok_or_else(|| AppError::new(format!("Invalid continuation token: {}", token)))?,
+        None => 0,
+    };
+    let page_size = match query_param(url, "page_size") {
+        Some(x) => x
+            .parse::<usize>()
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| AppError::new(format!("Invalid page_size: {}", x)))?,
+        None => DEFAULT_PAGE_SIZE,
+    };
+
+    // The rows before those of the token were received
+    run.acknowledge(offset);
+    let (rows, done) = run.wait_rows(offset, page_size)?;
+    let next_token = if done {
+        None
+    } else {
+        Some(encode_token(run.id, offset + rows.len()))
+    };
+    let rows = rows
+        .iter()
+        .map(|row| serde_json::from_str(row))
+        .collect::<Result<Vec<JsonValue>, _>>()?;
+    Ok(json!({ "rows": rows, "next_token": next_token }))
+}
+
+/// `kass serve`: expose the query runner over HTTP.
+///
+/// - `POST /query` with `{"query": "...", "params": ["1..10", "a,b"]}`
+///   streams the result rows as NDJSON, the run id is returned in the
+///   `X-Kass-Run-Id` header
+/// - `GET /runs` lists all the runs, `GET /runs/:id/status` reports the
+///   progress of a single one
+/// - `DELETE /runs/:id` cancels a run, whether it's queued or running
+/// - `GET /metrics` exposes counters of all the runs for Prometheus
+/// - `GET /runs/:id/rows?token=...&page_size=...` returns a page of the
+///   rows of a run along with the continuation token of the next page,
+///   which is `null` once all rows have been returned
+///
+/// At most as many runs as `slots` are executed at the same time, the
+/// others are queued.
+pub fn serve(listen: &str, config: Config, slots: Arc<Slots>) -> AppResult<()> {
+    let http = HttpServer::http(listen)
+        .map_err(|err| AppError::new(format!("Unable to listen on {}: {}", listen, err)))?;
+    diag!("Listening on http://{}", listen);
+
+    let server = Arc::new(Server {
+        config,
+        slots,
+        runs: Mutex::new(BTreeMap::new()),
+        next_id: AtomicU64::new(1),
+    });
+
+    for request in http.incoming_requests() {
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(err) = server.handle(request) {
+                diag!("Error handling request: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finished_run(rows: &[&str]) -> Arc<Run> {
+        let run = Arc::new(Run::new(1, "select".into()));
+        for row in rows {
+            run.push_row(row);
+        }
+        run.finish(Ok(()));
+        run
+    }
+
+    #[test]
+    fn test_row_reader() {
+        let reader = |run| RowReader {
+            run,
+            offset: 0,
+            buf: vec![],
+            pos: 0,
+            ended: false,
+        };
+        let mut out = String::new();
+        let run = finished_run(&["{\"a\":1}", "{\"a\":2}"]);
+        reader(run).read_to_string(&mut out).unwrap();
+        assert_eq!("{\"a\":1}\n{\"a\":2}\n", out);
+
+        // A failed run ends with its error
+        let run = Arc::new(Run::new(2, "select".into()));
+        run.push_row("{\"a\":1}");
+        run.finish(Err("Query failed".into()));
+        out.clear();
+        reader(run).read_to_string(&mut out).unwrap();
+        assert_eq!("{\"a\":1}\n{\"error\":\"Query failed\"}\n", out);
+    }
+
+    #[test]
+    fn test_slots() {
+        let slots = Arc::new(Slots::new(1));
+        let control = PauseControl::default();
+        let slot = slots.acquire(&control).unwrap();
+
+        // A queued run leaves the queue when cancelled
+        let run = Arc::new(Run::new(2, "select".into()));
+        let (waiting, queued) = (slots.clone(), run.clone());
+        let handle = thread::spawn(move || waiting.acquire(&queued.control).is_some());
+        run.control.cancel();
+        slots.wake();
+        assert!(!handle.join().unwrap());
+
+        drop(slot);
+        assert!(slots.acquire(&control).is_some());
+    }
+
+    #[test]
+    fn test_rows_page() {
+        let run = finished_run(&["1", "2", "3"]);
+        let page = rows_page(&run, "/runs/1/rows?page_size=2").unwrap();
+        assert_eq!(json!([1, 2]), page["rows"]);
+
+        let url = format!(
+            "/runs/1/rows?token={}",
+            page["next_token"].as_str().unwrap()
+        );
+        let page = rows_page(&run, &url).unwrap();
+        assert_eq!(json!([3]), page["rows"]);
+        assert_eq!(JsonValue::Null, page["next_token"]);
+
+        assert!(rows_page(&run, "/runs/1/rows?token=2.0").is_err());
+        assert!(rows_page(&run, "/runs/1/rows?page_size=0").is_err());
+        // The rows before the token were dropped
+        assert_eq!(3, run.status()["rows"]);
+        assert!(rows_page(&run, "/runs/1/rows?token=1.0").is_err());
+    }
+
+    #[test]
+    fn test_evict_finished() {
+        let mut runs = BTreeMap::new();
+        for id in 0..MAX_FINISHED_RUNS as u64 + 5 {
+            let run = Arc::new(Run::new(id, "select".into()));
+            // The second one still running
+            if id != 1 {
+                run.finish(Ok(()));
+            }
+            runs.insert(id, run);
+        }
+        evict_finished(&mut runs);
+        assert_eq!(MAX_FINISHED_RUNS + 1, runs.len());
+        assert!(runs.contains_key(&1));
+        assert!(!runs.contains_key(&4));
+        assert!(runs.contains_key(&5));
+    }
+
+    #[test]
+    fn test_max_buffered_rows() {
+        let run = Run::new(1, "select".into());
+        for i in 0..MAX_BUFFERED_ROWS + 10 {
+            run.push_row(&i.to_string());
+        }
+        run.finish(Ok(()));
+        assert_eq!(MAX_BUFFERED_ROWS, run.state.lock().unwrap().rows.len());
+        assert!(run.wait_rows(0, 1).is_err());
+        let (rows, _) = run.wait_rows(10, 1).unwrap();
+        assert_eq!(vec!["10".to_string()], rows);
+    }
+}
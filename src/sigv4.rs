@@ -0,0 +1,320 @@
+// `--auth sigv4`: authenticate to Amazon Keyspaces with AWS SigV4, using
+// the credentials of the standard AWS environment variables or shared
+// credentials file.
+//
+// The handshake is a challenge/response: the server answers the initial
+// `SigV4` token with a nonce, which must be signed in a second
+// `AUTH_RESPONSE`. The driver only sends one, so the handshake is done
+// by the forwarders of `proxy` instead, answering the `STARTUP` of the
+// driver.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+use cdrs::compression::Compression;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{AppError, AppResult};
+use crate::proxy::{Frame, Remote};
+
+const SERVICE: &str = "cassandra";
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const INITIAL_RESPONSE: &[u8] = b"SigV4\0\0";
+
+// Opcodes of the native protocol
+const OP_ERROR: u8 = 0x00;
+const OP_READY: u8 = 0x02;
+const OP_AUTHENTICATE: u8 = 0x03;
+const OP_AUTH_CHALLENGE: u8 = 0x0E;
+const OP_AUTH_RESPONSE: u8 = 0x0F;
+const OP_AUTH_SUCCESS: u8 = 0x10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+// The sections of an INI file, e.g. of ~/.aws/credentials
+fn ini_sections(text: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut sections = HashMap::new();
+    let mut current = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            current = name.trim().to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            sections
+                .entry(current.clone())
+                .or_insert_with(HashMap::new)
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    sections
+}
+
+fn aws_file(var: &str, name: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".aws").join(name)))
+}
+
+fn profile() -> String {
+    env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string())
+}
+
+impl AwsCredentials {
+    /// The credentials of the environment variables, or else of the
+    /// profile of the shared credentials file.
+    pub fn load() -> AppResult<AwsCredentials> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+        let profile = profile();
+        let text = aws_file("AWS_SHARED_CREDENTIALS_FILE", "credentials")
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+        Self::from_profile(&ini_sections(&text), &profile).ok_or_else(|| {
+            AppError::new(format!(
+                "No AWS credentials found in the environment or for the profile {}",
+                profile
+            ))
+        })
+    }
+
+    fn from_profile(
+        sections: &HashMap<String, HashMap<String, String>>,
+        profile: &str,
+    ) -> Option<AwsCredentials> {
+        let section = sections.get(profile)?;
+        Some(AwsCredentials {
+            access_key_id: section.get("aws_access_key_id")?.clone(),
+            secret_access_key: section.get("aws_secret_access_key")?.clone(),
+            session_token: section.get("aws_session_token").cloned(),
+        })
+    }
+}
+
+/// The region of the environment variables or of the profile of
+/// ~/.aws/config, or else of the Keyspaces endpoint `host`, e.g.
+/// cassandra.eu-west-1.amazonaws.com.
+pub fn region(host: &str) -> AppResult<String> {
+    if let Ok(region) = env::var("AWS_REGION").or_else(|_| env::var("AWS_DEFAULT_REGION")) {
+        return Ok(region);
+    }
+    let profile = match profile().as_str() {
+        "default" => "default".to_string(),
+        name => format!("profile {}", name),
+    };
+    let text = aws_file("AWS_CONFIG_FILE", "config")
+        .and_then(|path| fs::read_to_string(path).ok())
+        .unwrap_or_default();
+    if let Some(region) = ini_sections(&text)
+        .get(&profile)
+        .and_then(|x| x.get("region"))
+    {
+        return Ok(region.clone());
+    }
+    match host.split('.').collect::<Vec<_>>().as_slice() {
+        [SERVICE, region, ..] => Ok(region.to_string()),
+        _ => Err(AppError::new(
+            "No AWS region found, set AWS_REGION to the region of the cluster",
+        )),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("{:02x}", bytes.iter().format(""))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The answer to the `nonce=...` challenge of the server, signed at
+/// `now`.
+fn challenge_response(
+    challenge: &[u8],
+    credentials: &AwsCredentials,
+    region: &str,
+    now: DateTime<Utc>,
+) -> AppResult<String> {
+    let challenge = String::from_utf8_lossy(challenge);
+    let nonce = challenge
+        .split(',')
+        .find_map(|x| x.strip_prefix("nonce="))
+        .ok_or_else(|| AppError::new(format!("Unexpected SigV4 challenge: {}", challenge)))?;
+
+    let date = now.format("%Y%m%d").to_string();
+    let timestamp = now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let scope = format!("{}/{}/{}/aws4_request", date, region, SERVICE);
+    let query = format!(
+        "X-Amz-Algorithm={}&X-Amz-Credential={}%2F{}&X-Amz-Date={}&X-Amz-Expires=900",
+        ALGORITHM,
+        credentials.access_key_id,
+        scope.replace('/', "%2F"),
+        timestamp.replace(':', "%3A")
+    );
+    let request = format!(
+        "PUT\n/authenticate\n{}\nhost:{}\n\nhost\n{}",
+        query,
+        SERVICE,
+        sha256_hex(nonce.as_bytes())
+    );
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        timestamp,
+        scope,
+        sha256_hex(request.as_bytes())
+    );
+    let key = [region, SERVICE, "aws4_request"].iter().fold(
+        hmac(
+            format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+            &date,
+        ),
+        |key, x| hmac(&key, x),
+    );
+
+    let mut response = format!(
+        "signature={},access_key={},amzdate={}",
+        to_hex(&hmac(&key, &string_to_sign)),
+        credentials.access_key_id,
+        timestamp
+    );
+    if let Some(token) = &credentials.session_token {
+        response.push_str(&format!(",session_token={}", token));
+    }
+    Ok(response)
+}
+
+// The `[bytes]` body of an `AUTH_CHALLENGE`
+fn challenge_token(challenge: &Frame) -> &[u8] {
+    challenge.body().get(4..).unwrap_or_default()
+}
+
+fn auth_response(startup: &Frame, token: &[u8]) -> Frame {
+    let mut body = (token.len() as i32).to_be_bytes().to_vec();
+    body.extend_from_slice(token);
+    startup.reply(startup.version(), OP_AUTH_RESPONSE, body)
+}
+
+pub struct SigV4 {
+    credentials: AwsCredentials,
+    region: String,
+}
+
+impl SigV4 {
+    pub fn new(host: &str) -> AppResult<SigV4> {
+        Ok(SigV4 {
+            credentials: AwsCredentials::load()?,
+            region: region(host)?,
+        })
+    }
+
+    /// Answer the `STARTUP` of the driver relayed to `remote` by doing the
+    /// handshake, up to the `READY` handed over to the driver.
+    pub fn authenticate(
+        &self,
+        local: &mut TcpStream,
+        remote: &mut Remote,
+        startup: &Frame,
+        compression: Compression,
+    ) -> AppResult<()> {
+        let read = |remote: &mut Remote| Frame::read(remote)?.decompress(compression);
+        let mut resp = read(remote)?;
+        if resp.opcode() == OP_AUTHENTICATE {
+            auth_response(startup, INITIAL_RESPONSE).write(remote)?;
+            resp = read(remote)?;
+            if resp.opcode() == OP_AUTH_CHALLENGE {
+                let token = challenge_response(
+                    challenge_token(&resp),
+                    &self.credentials,
+                    &self.region,
+                    Utc::now(),
+                )?;
+                auth_response(startup, token.as_bytes()).write(remote)?;
+                resp = read(remote)?;
+            }
+            if resp.opcode() == OP_AUTH_SUCCESS {
+                resp = resp.reply(resp.version(), OP_READY, vec![]);
+            }
+        }
+        // Errors are handed over to the driver, which reports them
+        resp.write(local)?;
+        match resp.opcode() {
+            OP_READY => Ok(()),
+            OP_ERROR => Err(AppError::new("the handshake was rejected")),
+            op => Err(AppError::new(format!(
+                "unexpected frame {:#04x} during the SigV4 handshake",
+                op
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_challenge_response() {
+        let credentials = AwsCredentials {
+            access_key_id: "UserID-1".into(),
+            secret_access_key: "UserSecretKey-1".into(),
+            session_token: None,
+        };
+        let now = Utc.ymd(2020, 6, 9).and_hms_milli(22, 41, 51, 0);
+        let response = challenge_response(
+            b"nonce=91703fdc2ef562e19fbdab0f58e42fe5",
+            &credentials,
+            "us-west-2",
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            "signature=7f3691c18a81b8ce7457699effbfae5b09b4e0714ab38c1292dbdf082c9ddd87,access_key=UserID-1,amzdate=2020-06-09T22:41:51.000Z",
+            response
+        );
+        assert!(challenge_response(b"oops", &credentials, "us-west-2", now).is_err());
+    }
+
+    #[test]
+    fn test_credentials() {
+        let sections = ini_sections(
+            "[default]\naws_access_key_id = AKID\naws_secret_access_key = secret\n\n[other]\n# comment\naws_access_key_id=AKID2\naws_secret_access_key=secret2\naws_session_token=token\n",
+        );
+        assert_eq!(
+            Some(AwsCredentials {
+                access_key_id: "AKID2".into(),
+                secret_access_key: "secret2".into(),
+                session_token: Some("token".into()),
+            }),
+            AwsCredentials::from_profile(&sections, "other")
+        );
+        assert_eq!(None, AwsCredentials::from_profile(&sections, "missing"));
+    }
+}
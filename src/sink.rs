@@ -0,0 +1,334 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+use serde_json::Value as JsonValue;
+
+use crate::errors::{AppError, AppResult};
+
+/// Destination of the formatted result rows.
+pub trait Sink: Send + Sync {
+    fn write_row(&self, row: &str) -> AppResult<()>;
+
+    /// Write a result row, `values` being the row as JSON, which some
+    /// sinks route on.
+    fn write_result(&self, row: &str, _values: &JsonValue) -> AppResult<()> {
+        self.write_row(row)
+    }
+}
+
+pub struct Stdout;
+
+impl Sink for Stdout {
+    fn write_row(&self, row: &str) -> AppResult<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        writeln!(out, "{}", row)?;
+        Ok(())
+    }
+}
+
+/// How `--encrypt-to` encrypts a file, depending on the recipient.
+#[derive(Debug, PartialEq)]
+enum Encryption<'a> {
+    Age(&'a str),
+    Gpg(&'a str),
+}
+
+impl<'a> Encryption<'a> {
+    fn for_recipient(recipient: &'a str) -> Self {
+        if recipient.starts_with("age1") {
+            Encryption::Age(recipient)
+        } else {
+            Encryption::Gpg(recipient)
+        }
+    }
+
+    fn command(&self, path: &str) -> Command {
+        let mut command = match self {
+            Encryption::Age(recipient) => {
+                let mut command = Command::new("age");
+                command.args(["--encrypt", "--recipient", recipient, "--output", path]);
+                command
+            }
+            Encryption::Gpg(key) => {
+                let mut command = Command::new("gpg");
+                command.args(["--batch", "--yes", "--encrypt", "--recipient", key]);
+                command.args(["--output", path]);
+                command
+            }
+        };
+        command.stdin(Stdio::piped());
+        command
+    }
+}
+
+/// Rows written to a file, encrypted by `age` or `gpg` with
+/// `--encrypt-to`, in which case the file is only complete once the
+/// sink is dropped.
+pub struct FileSink {
+    out: Mutex<Box<dyn Write + Send>>,
+    encryptor: Mutex<Option<Child>>,
+}
+
+impl FileSink {
+    pub fn create(path: &str, encrypt_to: Option<&str>) -> AppResult<FileSink> {
+        let (out, encryptor): (Box<dyn Write + Send>, _) = match encrypt_to {
+            Some(recipient) => {
+                let mut child = Encryption::for_recipient(recipient)
+                    .command(path)
+                    .spawn()
+                    .map_err(|err| {
+                        AppError::new(format!("Unable to run the encryption: {}", err))
+                    })?;
+                let stdin = child.stdin.take();
+                (Box::new(BufWriter::new(stdin.unwrap())), Some(child))
+            }
+            None => (Box::new(BufWriter::new(File::create(path)?)), None),
+        };
+        Ok(FileSink {
+            out: Mutex::new(out),
+            encryptor: Mutex::new(encryptor),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn write_row(&self, row: &str) -> AppResult<()> {
+        writeln!(self.out.lock().unwrap(), "{}", row)?;
+        Ok(())
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        let out = self.out.get_mut().unwrap();
+        if let Err(err) = out.flush() {
+            diag!("Error writing the output: {}", err);
+        }
+        // Closing stdin lets the encryption finish
+        *out = Box::new(io::sink());
+        if let Some(mut child) = self.encryptor.get_mut().unwrap().take() {
+            match child.wait() {
+                Ok(status) if status.success() => {}
+                Ok(status) => diag!("Encryption of the output failed: {}", status),
+                Err(err) => diag!("Encryption of the output failed: {}", err),
+            }
+        }
+    }
+}
+
+// The placeholder of the part number in --output-template
+const PART: &str = "n";
+
+// A file of PartitionedSink, `used` telling when it was last written to
+struct OpenFile {
+    out: BufWriter<File>,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Files {
+    open: HashMap<String, OpenFile>,
+    // The files created by this run, which are appended to when opened
+    // again
+    created: HashSet<String>,
+    // The number of each partition's part, bumped when its file is
+    // opened again
+    parts: HashMap<String, usize>,
+    writes: u64,
+}
+
+/// `--partition-output-by day --output-template 'out/day={day}/part-{n}.jsonl'`:
+/// rows written to the file named after the values of some of their
+/// columns, Hive style. Once `max_open` files are open, the least
+/// recently used one is closed, and a new part of its partition started
+/// when it gets rows again. Lines other than result rows, i.e. the CSV
+/// header, are written at the top of every file.
+pub struct PartitionedSink {
+    template: String,
+    columns: Vec<String>,
+    max_open: usize,
+    preamble: Mutex<Vec<String>>,
+    files: Mutex<Files>,
+}
+
+// A column value made safe to use in a path
+fn path_segment(value: &JsonValue) -> String {
+    let s = match value {
+        JsonValue::String(s) => s.clone(),
+        x => x.to_string(),
+    };
+    match s.as_str() {
+        "" | "." | ".." => "_".repeat(s.len().max(1)),
+        _ => s.replace(['/', '\\', '\0'], "_"),
+    }
+}
+
+impl PartitionedSink {
+    pub fn new(template: &str, columns: Vec<String>, max_open: usize) -> AppResult<Self> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| AppError::new(format!("Unclosed {{ in {}", template)))?;
+            let name = &rest[start + 1..start + end];
+            if name != PART && !columns.iter().any(|c| c == name) {
+                return Err(AppError::new(format!(
+                    "{{{}}} of the output template is not a column of --partition-output-by",
+                    name
+                )));
+            }
+            rest = &rest[start + end..];
+        }
+        for column in columns.iter() {
+            if !template.contains(&format!("{{{}}}", column)) {
+                return Err(AppError::new(format!(
+                    "The output template is missing {{{}}}",
+                    column
+                )));
+            }
+        }
+        Ok(PartitionedSink {
+            template: template.to_string(),
+            columns,
+            max_open: max_open.max(1),
+            preamble: Mutex::new(vec![]),
+            files: Mutex::new(Files::default()),
+        })
+    }
+
+    // The partition of a row, as the template with its columns filled in
+    fn partition(&self, values: &JsonValue) -> AppResult<String> {
+        self.columns
+            .iter()
+            .try_fold(self.template.clone(), |path, column| {
+                let value = values.get(column).ok_or_else(|| {
+                    AppError::new(format!("The partition column {} is not selected", column))
+                })?;
+                Ok(path.replace(&format!("{{{}}}", column), &path_segment(value)))
+            })
+    }
+
+    fn open(&self, files: &mut Files, partition: &str) -> AppResult<OpenFile> {
+        if files.open.len() >= self.max_open {
+            let lru = files
+                .open
+                .iter()
+                .min_by_key(|(_, x)| x.used)
+                .map(|(path, _)| path.clone());
+            if let Some(mut file) = lru.and_then(|x| files.open.remove(&x)) {
+                file.out.flush()?;
+            }
+        }
+        let part = files.parts.entry(partition.to_string()).or_insert(0);
+        *part += 1;
+        let path = partition.replace(&format!("{{{}}}", PART), &part.to_string());
+        if let Some(dir) = Path::new(&path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let appending = !files.created.insert(path.clone());
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(appending)
+            .truncate(!appending)
+            .open(&path)
+            .map_err(|err| AppError::new(format!("Unable to open {}: {}", path, err)))?;
+        let mut out = BufWriter::new(file);
+        if !appending {
+            for line in self.preamble.lock().unwrap().iter() {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        Ok(OpenFile { out, used: 0 })
+    }
+}
+
+impl Sink for PartitionedSink {
+    fn write_row(&self, row: &str) -> AppResult<()> {
+        self.preamble.lock().unwrap().push(row.to_string());
+        let mut files = self.files.lock().unwrap();
+        for file in files.open.values_mut() {
+            writeln!(file.out, "{}", row)?;
+        }
+        Ok(())
+    }
+
+    fn write_result(&self, row: &str, values: &JsonValue) -> AppResult<()> {
+        let partition = self.partition(values)?;
+        let mut files = self.files.lock().unwrap();
+        files.writes += 1;
+        let used = files.writes;
+        if !files.open.contains_key(&partition) {
+            let file = self.open(&mut files, &partition)?;
+            files.open.insert(partition.clone(), file);
+        }
+        let file = files.open.get_mut(&partition).unwrap();
+        file.used = used;
+        writeln!(file.out, "{}", row)?;
+        Ok(())
+    }
+}
+
+impl Drop for PartitionedSink {
+    fn drop(&mut self) {
+        for file in self.files.get_mut().unwrap().open.values_mut() {
+            if let Err(err) = file.out.flush() {
+                diag!("Error writing the output: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_encryption() {
+        assert_eq!(
+            Encryption::Age("age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"),
+            Encryption::for_recipient(
+                "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"
+            )
+        );
+        assert_eq!(
+            Encryption::Gpg("ops@example.com"),
+            Encryption::for_recipient("ops@example.com")
+        );
+    }
+
+    #[test]
+    fn test_partitioned_sink() {
+        let dir = env::temp_dir().join(format!("kass-partitions-{}", process::id()));
+        let template = format!("{}/day={{day}}/part-{{n}}.csv", dir.display());
+        let sink = PartitionedSink::new(&template, vec!["day".into()], 1).unwrap();
+        sink.write_row("day,id").unwrap();
+        for (day, id) in [("mon", 1), ("tue", 2), ("mon", 3)] {
+            let values = json!({ "day": day, "id": id });
+            sink.write_result(&format!("{},{}", day, id), &values)
+                .unwrap();
+        }
+        assert!(sink.write_result("", &json!({ "id": 4 })).is_err());
+        drop(sink);
+        let read = |path: &str| fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!("day,id\nmon,1\n", read("day=mon/part-1.csv"));
+        assert_eq!("day,id\ntue,2\n", read("day=tue/part-1.csv"));
+        assert_eq!("day,id\nmon,3\n", read("day=mon/part-2.csv"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(PartitionedSink::new("out/{month}.jsonl", vec!["day".into()], 1).is_err());
+        assert!(PartitionedSink::new("out/all.jsonl", vec!["day".into()], 1).is_err());
+        assert_eq!("a_b", path_segment(&json!("a/b")));
+        assert_eq!("__", path_segment(&json!("..")));
+        assert_eq!("null", path_segment(&json!(null)));
+    }
+}
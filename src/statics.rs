@@ -0,0 +1,168 @@
+// `--static-once`: the static columns of a table hold one value per
+// partition, which every row of the partition repeats, so exports of
+// wide partitions end up mostly made of copies. The static columns are
+// only kept on the first row of each partition, or with
+// `--static-record` written once to a record of their own before the
+// rows of the partition, along with the partition key:
+//
+//     {"_static": {"id": 1, "owner": "alice"}}
+//
+// The partitions are told apart by the values of their partition key,
+// so the rows of a result without these columns are taken for one
+// partition.
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use cdrs::frame::frame_result::RowsMetadata;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::core::{Config, CurrentSession};
+use crate::errors::{AppError, AppResult};
+use crate::schema;
+
+pub const STATIC_RECORD_KEY: &str = "_static";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Columns {
+    partition_key: Vec<String>,
+    statics: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Statics {
+    // Whether the static columns go to a record of their own
+    record: bool,
+    // Those of the queried tables, looked up once
+    columns: Mutex<HashMap<(String, String), Columns>>,
+}
+
+/// The partition of the last row of a result.
+pub struct Partitions {
+    record: bool,
+    columns: Columns,
+    last: Option<Vec<JsonValue>>,
+}
+
+impl Statics {
+    pub fn new(record: bool) -> Statics {
+        Statics {
+            record,
+            columns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The partitions of a result of `meta`.
+    pub fn partitions(
+        &self,
+        session: &CurrentSession,
+        meta: &RowsMetadata,
+        config: &Config,
+    ) -> AppResult<Partitions> {
+        let (keyspace, table) = schema::result_table(meta)
+            .ok_or_else(|| AppError::new("--static-once requires the results of a table"))?;
+        let mut columns = self.columns.lock().unwrap();
+        let key = (keyspace, table);
+        if !columns.contains_key(&key) {
+            let mut found = Columns::default();
+            for column in schema::table_columns(session, &key.0, &key.1, config)? {
+                match column.kind.as_str() {
+                    "partition_key" => found.partition_key.push(column.name),
+                    "static" => found.statics.push(column.name),
+                    _ => {}
+                }
+            }
+            columns.insert(key.clone(), found);
+        }
+        Ok(Partitions {
+            record: self.record,
+            columns: columns[&key].clone(),
+            last: None,
+        })
+    }
+}
+
+impl Partitions {
+    /// `row` without its static columns unless it is the first row of
+    /// its partition, along with the record of the static columns of
+    /// the partition with `--static-record`.
+    pub fn apply(&mut self, row: JsonValue) -> (JsonValue, Option<JsonValue>) {
+        let mut row = match row {
+            JsonValue::Object(x) => x,
+            x => return (x, None),
+        };
+        let key: Vec<JsonValue> = self
+            .columns
+            .partition_key
+            .iter()
+            .filter_map(|x| row.get(x).cloned())
+            .collect();
+        let first = self.last.as_ref() != Some(&key);
+        self.last = Some(key);
+        if first && !self.record {
+            return (JsonValue::Object(row), None);
+        }
+        let statics: Map<String, JsonValue> = self
+            .columns
+            .statics
+            .iter()
+            .filter_map(|x| Some((x.clone(), row.remove(x)?)))
+            .collect();
+        let record = match first && !statics.is_empty() {
+            true => {
+                let mut record: Map<String, JsonValue> = self
+                    .columns
+                    .partition_key
+                    .iter()
+                    .filter_map(|x| Some((x.clone(), row.get(x)?.clone())))
+                    .collect();
+                record.extend(statics);
+                Some(JsonValue::Object(record))
+            }
+            false => None,
+        };
+        (JsonValue::Object(row), record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn partitions(record: bool) -> Partitions {
+        Partitions {
+            record,
+            columns: Columns {
+                partition_key: vec!["id".to_string()],
+                statics: vec!["owner".to_string()],
+            },
+            last: None,
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let rows = [
+            json!({"id": 1, "owner": "alice", "ts": 1}),
+            json!({"id": 1, "owner": "alice", "ts": 2}),
+            json!({"id": 2, "owner": "bob", "ts": 1}),
+        ];
+        let mut first_row = partitions(false);
+        let out: Vec<_> = rows.iter().map(|x| first_row.apply(x.clone())).collect();
+        assert_eq!((rows[0].clone(), None), out[0]);
+        assert_eq!((json!({"id": 1, "ts": 2}), None), out[1]);
+        assert_eq!((rows[2].clone(), None), out[2]);
+
+        let mut record = partitions(true);
+        let out: Vec<_> = rows.iter().map(|x| record.apply(x.clone())).collect();
+        assert_eq!(
+            (
+                json!({"id": 1, "ts": 1}),
+                Some(json!({"id": 1, "owner": "alice"}))
+            ),
+            out[0]
+        );
+        assert_eq!((json!({"id": 1, "ts": 2}), None), out[1]);
+        assert_eq!(Some(json!({"id": 2, "owner": "bob"})), out[2].1);
+    }
+}
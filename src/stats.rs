@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use cdrs::consistency::Consistency;
+use itertools::Itertools;
+
+use crate::consistency;
+
+/// Counters collected by the workers over a whole run, reported on
+/// stderr once the run has finished.
+#[derive(Debug, Default)]
+pub struct RunStats {
+    served: Mutex<BTreeMap<&'static str, usize>>,
+}
+
+impl RunStats {
+    /// Record that one parameter set was served at `consistency`.
+    pub fn record_served(&self, consistency: Consistency) {
+        let mut served = self.served.lock().unwrap();
+        *served.entry(consistency::name(consistency)).or_insert(0) += 1;
+    }
+
+    /// Summary of the consistency levels parameter sets were served
+    /// at. Only returned when some of them were not served at the
+    /// requested level, so consumers know the trust level of the
+    /// results.
+    pub fn consistency_summary(&self, requested: Consistency) -> Option<String> {
+        let served = self.served.lock().unwrap();
+        let requested = consistency::name(requested);
+        if served.keys().all(|x| *x == requested) {
+            None
+        } else {
+            Some(format!(
+                "Parameter sets served by consistency level (requested {}): {}",
+                requested,
+                served
+                    .iter()
+                    .map(|(level, n)| format!("{}={}", level, n))
+                    .join(", ")
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consistency_summary() {
+        let stats = RunStats::default();
+        stats.record_served(Consistency::Quorum);
+        stats.record_served(Consistency::Quorum);
+        assert_eq!(None, stats.consistency_summary(Consistency::Quorum));
+
+        stats.record_served(Consistency::One);
+        assert_eq!(
+            Some(
+                "Parameter sets served by consistency level (requested QUORUM): ONE=1, QUORUM=2"
+                    .to_string()
+            ),
+            stats.consistency_summary(Consistency::Quorum)
+        );
+    }
+}
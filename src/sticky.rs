@@ -0,0 +1,223 @@
+// `--sticky-coordinator`: the statements of a parameter set, the pages
+// of its results and its retries, all go to the same node rather than
+// to the nodes in turn, so that paging through a big partition again
+// and again hits the caches of one node. The node is picked by the hash
+// of the values bound, each node having its own session.
+//
+// A node failing with a connection error is left aside for a while, the
+// statements it would have got going to the next node until then, and
+// the nodes that missed a statement being prepared prepare it again
+// when they don't know it.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use cdrs::consistency::Consistency;
+use cdrs::error::Result;
+use cdrs::types::value::{Value, ValueType};
+
+use crate::backend::{Backend, Params, Prepared, Response, Statements};
+use crate::reconnect;
+
+// How long a node failing with a connection error is left aside
+const DOWN_FOR: Duration = Duration::from_secs(10);
+
+pub struct Sticky<B> {
+    nodes: Vec<B>,
+    // Until when each node is left aside
+    down: Mutex<Vec<Option<Instant>>>,
+    statements: Statements,
+    next: AtomicUsize,
+}
+
+// The node of the parameter set `values`, out of `nodes`
+fn node_of(values: &[Value], nodes: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    for value in values {
+        value.body.hash(&mut hasher);
+        match value.value_type {
+            ValueType::Normal(_) => 0u8.hash(&mut hasher),
+            _ => 1u8.hash(&mut hasher),
+        }
+    }
+    (hasher.finish() % nodes as u64) as usize
+}
+
+impl<B: Backend> Sticky<B> {
+    pub fn new(nodes: Vec<B>) -> Sticky<B> {
+        assert!(!nodes.is_empty());
+        Sticky {
+            down: Mutex::new(vec![None; nodes.len()]),
+            nodes,
+            statements: Statements::default(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // The nodes from `first` on, wrapping around, those left aside last
+    fn order(&self, first: usize) -> Vec<usize> {
+        let now = Instant::now();
+        let down = self.down.lock().unwrap();
+        let (up, down): (Vec<usize>, Vec<usize>) = (0..self.nodes.len())
+            .map(|i| (first + i) % self.nodes.len())
+            .partition(|i| down[*i].is_none_or(|until| until <= now));
+        up.into_iter().chain(down).collect()
+    }
+
+    // Run `f` on the node `first`, or the next ones while they fail
+    // with a connection error
+    fn run<T>(&self, first: usize, mut f: impl FnMut(&B) -> Result<T>) -> Result<T> {
+        let mut result = None;
+        for i in self.order(first) {
+            match f(&self.nodes[i]) {
+                Err(err) if reconnect::is_connection_error(&err) => {
+                    self.down.lock().unwrap()[i] = Some(Instant::now() + DOWN_FOR);
+                    result = Some(Err(err));
+                }
+                x => {
+                    self.down.lock().unwrap()[i] = None;
+                    return x;
+                }
+            }
+        }
+        result.unwrap()
+    }
+
+    // Execute `prepared` on the node `first` or the next ones, preparing
+    // it again on those that don't know it
+    fn execute_from(&self, first: usize, prepared: &Prepared, params: Params) -> Result<Response> {
+        self.run(first, |node| {
+            self.statements.execute(
+                prepared,
+                1,
+                || node.execute(prepared, params.clone()),
+                |cql| node.prepare(cql).map(drop),
+            )
+        })
+    }
+}
+
+impl<B: Backend> Backend for Sticky<B> {
+    fn prepare(&self, cql: &str) -> Result<Prepared> {
+        // Each node has its own cache of prepared statements, those that
+        // can't be reached preparing it once they are used
+        let mut prepared = None;
+        let mut result = None;
+        for node in &self.nodes {
+            match node.prepare(cql) {
+                Ok(x) => prepared = Some(x),
+                Err(err) if reconnect::is_connection_error(&err) => result = Some(err),
+                Err(err) => return Err(err),
+            }
+        }
+        let prepared = match (prepared, result) {
+            (Some(x), _) => x,
+            (None, err) => return Err(err.unwrap()),
+        };
+        self.statements.insert(&prepared, cql);
+        Ok(prepared)
+    }
+
+    fn query(&self, cql: &str, params: Params) -> Result<Response> {
+        let first = match params.values.is_empty() {
+            true => self.next.fetch_add(1, Ordering::Relaxed) % self.nodes.len(),
+            false => node_of(&params.values, self.nodes.len()),
+        };
+        self.run(first, |node| node.query(cql, params.clone()))
+    }
+
+    fn execute(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        let first = node_of(&params.values, self.nodes.len());
+        self.execute_from(first, prepared, params)
+    }
+
+    fn speculate(&self, prepared: &Prepared, params: Params) -> Result<Response> {
+        // The node after that of the parameter set
+        let first = node_of(&params.values, self.nodes.len()) + 1;
+        self.execute_from(first % self.nodes.len(), prepared, params)
+    }
+
+    fn metadata(&self, keyspace: &str, table: &str, consistency: Consistency) -> Result<Response> {
+        let first = self.next.fetch_add(1, Ordering::Relaxed) % self.nodes.len();
+        self.run(first, |node| node.metadata(keyspace, table, consistency))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdrs::error::Error;
+    use std::io;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    // Answers with its name until it is stopped
+    struct Node {
+        calls: Arc<Mutex<Vec<usize>>>,
+        name: usize,
+        stopped: AtomicBool,
+    }
+
+    impl Backend for Node {
+        fn prepare(&self, _cql: &str) -> Result<Prepared> {
+            unimplemented!()
+        }
+
+        fn query(&self, _cql: &str, _params: Params) -> Result<Response> {
+            if self.stopped.load(Ordering::Relaxed) {
+                return Err(Error::Io(io::Error::from(io::ErrorKind::ConnectionRefused)));
+            }
+            self.calls.lock().unwrap().push(self.name);
+            Ok(Response::Void)
+        }
+
+        fn execute(&self, _prepared: &Prepared, _params: Params) -> Result<Response> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn test_sticky() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let nodes = (0..3)
+            .map(|name| Node {
+                calls: calls.clone(),
+                name,
+                stopped: AtomicBool::new(false),
+            })
+            .collect();
+        let sticky = Sticky::new(nodes);
+        let params = |x: i32| Params::new(vec![x.into()], Default::default());
+        for x in 0..10 {
+            for _page in 0..3 {
+                sticky.query("SELECT", params(x)).unwrap();
+            }
+        }
+        let calls_of = |calls: &[usize]| -> Vec<usize> { calls.chunks(3).map(|x| x[0]).collect() };
+        let first = calls_of(&calls.lock().unwrap());
+        for pages in calls.lock().unwrap().chunks(3) {
+            assert!(pages.iter().all(|x| *x == pages[0]));
+        }
+
+        // The parameter sets of a stopped node go to the next one
+        calls.lock().unwrap().clear();
+        sticky.nodes[first[0]]
+            .stopped
+            .store(true, Ordering::Relaxed);
+        for x in 0..10 {
+            for _page in 0..3 {
+                sticky.query("SELECT", params(x)).unwrap();
+            }
+        }
+        let stopped: Vec<usize> = calls_of(&calls.lock().unwrap());
+        for (before, after) in first.iter().zip(&stopped) {
+            match *before == first[0] {
+                true => assert_eq!((first[0] + 1) % 3, *after),
+                false => assert_eq!(before, after),
+            }
+        }
+        assert!(sticky.down.lock().unwrap()[first[0]].is_some());
+    }
+}
@@ -0,0 +1,69 @@
+// `--ssl`: connect to the cluster over TLS, verifying its certificate
+// against the system roots or `--ca-cert`. `--no-verify-hostname` still
+// checks the certificate chain but accepts certificates issued for
+// another host, e.g. when connecting to nodes by IP address.
+//
+// `--client-cert` and `--client-key` authenticate kass to clusters
+// requiring client certificates.
+
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+
+use crate::errors::AppResult;
+
+// X509_V_ERR_HOSTNAME_MISMATCH and X509_V_ERR_IP_ADDRESS_MISMATCH
+const HOST_MISMATCH: &[i32] = &[62, 64];
+
+#[derive(Debug, Clone)]
+pub struct Tls {
+    pub ca_cert: Option<String>,
+    pub verify_hostname: bool,
+    // PEM files of the client certificate (chain) and its private key
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl Tls {
+    pub fn connector(&self) -> AppResult<SslConnector> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(path) = &self.ca_cert {
+            builder.set_ca_file(path)?;
+        }
+        if let Some(path) = &self.client_cert {
+            builder.set_certificate_chain_file(path)?;
+            builder.set_private_key_file(
+                self.client_key.as_deref().unwrap_or(path),
+                SslFiletype::PEM,
+            )?;
+            builder.check_private_key()?;
+        }
+        if !self.verify_hostname {
+            builder.set_verify_callback(SslVerifyMode::PEER, |ok, ctx| {
+                ok || HOST_MISMATCH.contains(&ctx.error().as_raw())
+            });
+        }
+        Ok(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connector() {
+        let tls = Tls {
+            ca_cert: None,
+            verify_hostname: false,
+            client_cert: None,
+            client_key: None,
+        };
+        assert!(tls.connector().is_ok());
+        let tls = Tls {
+            ca_cert: Some("/nonexistent/ca.pem".into()),
+            verify_hostname: true,
+            client_cert: None,
+            client_key: None,
+        };
+        assert!(tls.connector().is_err());
+    }
+}
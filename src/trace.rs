@@ -0,0 +1,28 @@
+// `--trace-out trace.json`: the spans of the stages of a run (parameter
+// expansion, prepare, execute, the fetch of each page after the first,
+// decode, serialize and sink write) written in the Chrome trace event
+// format, for chrome://tracing or Perfetto to show where the time goes,
+// each worker on its own track. Without it the spans are disabled and
+// cost next to nothing.
+use std::fs::File;
+use std::io::BufWriter;
+
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::prelude::*;
+
+use crate::errors::{AppError, AppResult};
+
+/// Write the spans to `path` until the guard is dropped.
+pub fn start(path: &str) -> AppResult<FlushGuard> {
+    let file = File::create(path)
+        .map_err(|err| AppError::new(format!("Unable to create {}: {}", path, err)))?;
+    let (layer, guard) = ChromeLayerBuilder::new()
+        .writer(BufWriter::new(file))
+        .include_args(true)
+        .build();
+    tracing_subscriber::registry()
+        .with(layer)
+        .try_init()
+        .map_err(|err| AppError::new(format!("Unable to trace the run: {}", err)))?;
+    Ok(guard)
+}
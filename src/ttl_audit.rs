@@ -0,0 +1,149 @@
+// `kass audit-ttl`: sample the rows of some partitions and report the
+// TTL remaining on them, to find the partitions whose rows never expire
+// and keep growing. The TTL of a row is the greatest TTL of its cells,
+// and a row never expires if any of its cells has no TTL.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde_json::{json, Value as JsonValue};
+
+use crate::core::{self, Config};
+use crate::errors::{AppError, AppResult};
+use crate::iterator_consumer::IteratorConsumer;
+use crate::params::ParamSet;
+use crate::schema::{self, quote, unquote};
+
+#[derive(Debug, Default, PartialEq)]
+struct Summary {
+    rows: usize,
+    no_ttl: usize,
+    min_ttl: Option<i64>,
+    max_ttl: Option<i64>,
+}
+
+impl Summary {
+    /// Add a row given the write time and TTL of each of its cells.
+    fn add(&mut self, cells: &[(Option<i64>, Option<i64>)]) {
+        self.rows += 1;
+        // Cells without a write time are null
+        let live: Vec<Option<i64>> = cells
+            .iter()
+            .filter(|(writetime, _)| writetime.is_some())
+            .map(|(_, ttl)| *ttl)
+            .collect();
+        if live.iter().any(|x| x.is_none()) {
+            self.no_ttl += 1;
+        } else if let Some(ttl) = live.into_iter().flatten().max() {
+            self.min_ttl = Some(self.min_ttl.map_or(ttl, |x| x.min(ttl)));
+            self.max_ttl = Some(self.max_ttl.map_or(ttl, |x| x.max(ttl)));
+        }
+    }
+
+    fn to_json(&self, labels: &[String]) -> JsonValue {
+        json!({
+            "partition": labels,
+            "rows": self.rows,
+            "no_ttl": self.no_ttl,
+            "min_ttl": self.min_ttl,
+            "max_ttl": self.max_ttl,
+        })
+    }
+}
+
+/// Report the TTL remaining on up to `sample` rows of each partition of
+/// `table` (`keyspace.table`), whose keys are given by `partitions`.
+pub fn audit(
+    config: Config,
+    table: &str,
+    partitions: Vec<ParamSet>,
+    sample: usize,
+) -> AppResult<()> {
+    let session = config.connect()?;
+    let (keyspace, name) = table
+        .split_once('.')
+        .ok_or_else(|| AppError::new("The table must be given as keyspace.table"))?;
+    let columns = schema::table_columns(&session, &unquote(keyspace), &unquote(name), &config)?;
+    let key: Vec<String> = columns
+        .iter()
+        .filter(|c| c.kind == "partition_key")
+        .map(|c| format!("{} = ?", quote(&c.name)))
+        .collect();
+    let cells: Vec<String> = columns
+        .iter()
+        .filter(|c| c.has_writetime)
+        .enumerate()
+        .map(|(i, c)| {
+            format!(
+                "WRITETIME({0}) AS w{1}, TTL({0}) AS t{1}",
+                quote(&c.name),
+                i
+            )
+        })
+        .collect();
+    if cells.is_empty() {
+        return Err(AppError::new(format!(
+            "Table {} has no columns with a TTL",
+            table
+        )));
+    }
+    let prepared = session.prepare(&format!(
+        "SELECT {} FROM {} WHERE {} LIMIT {}",
+        cells.join(", "),
+        table,
+        key.join(" AND "),
+        sample
+    ))?;
+
+    let total = partitions.len();
+    let never_expiring = Arc::new(AtomicUsize::new(0));
+    let counter = never_expiring.clone();
+    let n = cells.len();
+    partitions
+        .into_iter()
+        .consume(config.parallelism(), move |vs| -> AppResult<()> {
+            let params = config.params(vs.values.clone());
+            let resp = session.execute(&prepared, params)?;
+            let mut summary = Summary::default();
+            for row in core::collect_rows(&resp, &config)? {
+                let cell = |prefix: &str, i: usize| {
+                    row.get(format!("{}{}", prefix, i)).and_then(|x| x.as_i64())
+                };
+                let cells: Vec<_> = (0..n).map(|i| (cell("w", i), cell("t", i))).collect();
+                summary.add(&cells);
+            }
+            if summary.no_ttl > 0 {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            core::write_report(summary.to_json(&vs.labels), &config)
+        })?;
+
+    diag!(
+        "{} of {} partitions have rows without a TTL",
+        never_expiring.load(Ordering::Relaxed),
+        total
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut summary = Summary::default();
+        summary.add(&[(Some(1), Some(100)), (Some(1), Some(50))]);
+        summary.add(&[(Some(1), Some(10)), (None, None)]);
+        summary.add(&[(Some(1), None), (Some(1), Some(10))]);
+        summary.add(&[(None, None), (None, None)]);
+        assert_eq!(
+            Summary {
+                rows: 4,
+                no_ttl: 1,
+                min_ttl: Some(10),
+                max_ttl: Some(100),
+            },
+            summary
+        );
+    }
+}
@@ -1,17 +1,29 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::net::IpAddr;
 use std::result::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
 use cdrs::error::{Error as CDRSError, Result as CDRSResult};
 use cdrs::frame::frame_result::{ColType, ColTypeOption, ColTypeOptionValue};
 use cdrs::types::data_serialization_types::*;
 use cdrs::types::decimal::Decimal as CDRSDecimal;
 use cdrs::types::CBytes;
-use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
 use itertools::Itertools;
+use num_bigint::BigInt;
 use serde::{Serialize, Serializer};
 
+const MARSHAL_PACKAGE: &str = "org.apache.cassandra.db.marshal.";
+
+lazy_static! {
+    // The custom types already warned about
+    static ref UNKNOWN_CUSTOM_TYPES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+static WARNED_OUT_OF_RANGE: AtomicBool = AtomicBool::new(false);
+
 // Uuid wrapper struct for implementing `Serialize` trait
 pub struct Uuid(uuid::Uuid);
 
@@ -37,13 +49,30 @@ pub struct Decimal(bigdecimal::BigDecimal);
 
 impl From<CDRSDecimal> for Decimal {
     fn from(n: CDRSDecimal) -> Self {
+        // The scale is signed in the protocol
         Decimal(bigdecimal::BigDecimal::new(
             n.unscaled.into(),
-            n.scale.into(),
+            (n.scale as i32).into(),
         ))
     }
 }
 
+impl Decimal {
+    // A 4 bytes scale followed by the unscaled value as a varint of any
+    // length, which the decoder of cdrs limits to 8 bytes
+    fn decode(bytes: &[u8]) -> CDRSResult<Decimal> {
+        if bytes.len() < 4 {
+            return Err(CDRSError::General("Invalid decimal value".into()));
+        }
+        let (scale, unscaled) = bytes.split_at(4);
+        let scale = i32::from_be_bytes(scale.try_into().unwrap());
+        Ok(Decimal(bigdecimal::BigDecimal::new(
+            BigInt::from_signed_bytes_be(unscaled),
+            scale.into(),
+        )))
+    }
+}
+
 impl Serialize for Decimal {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         // BigDecimal is serialized as string to avoid f64 overflow
@@ -51,6 +80,50 @@ impl Serialize for Decimal {
     }
 }
 
+/// The number of fractional digits of times, all the significant ones
+/// when not set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimePrecision {
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl TimePrecision {
+    pub fn parse(s: &str) -> Option<TimePrecision> {
+        match s {
+            "ms" => Some(TimePrecision::Millis),
+            "us" => Some(TimePrecision::Micros),
+            "ns" => Some(TimePrecision::Nanos),
+            _ => None,
+        }
+    }
+}
+
+// Time wrapper struct formatting times with a fixed precision
+pub struct Time {
+    time: NaiveTime,
+    precision: Option<TimePrecision>,
+}
+
+impl Time {
+    pub fn to_time_string(&self) -> String {
+        let format = match self.precision {
+            None => return self.time.to_string(),
+            Some(TimePrecision::Millis) => "%H:%M:%S%.3f",
+            Some(TimePrecision::Micros) => "%H:%M:%S%.6f",
+            Some(TimePrecision::Nanos) => "%H:%M:%S%.9f",
+        };
+        self.time.format(format).to_string()
+    }
+}
+
+impl Serialize for Time {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_time_string().as_str())
+    }
+}
+
 // Define our own Blob type
 pub struct Blob {
     bytes: Vec<u8>,
@@ -82,7 +155,8 @@ impl Serialize for Blob {
 // - integers of different length -> i64
 // - float and double -> f64
 // - list/set/tuple -> Vec<ColValue>
-// - map/udt -> HashMap<String, ColValue>
+// - map/udt -> Vec<(String, ColValue)>, serialized as a JSON object with
+//   keys in the order they are stored by Cassandra
 #[derive(Serialize)]
 #[serde(untagged)]
 pub enum ColValue {
@@ -93,25 +167,88 @@ pub enum ColValue {
     String(String),
     Blob(Blob),
     Date(NaiveDate),
-    Time(NaiveTime),
+    Time(Time),
     Timestamp(DateTime<Utc>),
     Inet(IpAddr),
     Uuid(Uuid),
     Boolean(bool),
     Seq(Vec<ColValue>),
-    Map(HashMap<String, ColValue>),
+    #[serde(serialize_with = "serialize_entries")]
+    Map(Vec<(String, ColValue)>),
+}
+
+/// How to serialize CQL maps. Maps with non-string keys lose the key
+/// types in `Object` mode as JSON object keys are always strings.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MapMode {
+    /// `{"key": value, ...}`
+    #[default]
+    Object,
+    /// `[[key, value], ...]`
+    Entries,
+}
+
+/// What to do with the dates, times and timestamps out of the range of
+/// the output, e.g. in a corrupt cell.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InvalidDates {
+    /// Write the number of days (dates), milliseconds (timestamps) or
+    /// nanoseconds (times) stored, with a warning
+    #[default]
+    Raw,
+    Error,
+}
+
+/// What to do with the columns that can't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OnDecodeError {
+    /// Reject the whole row
+    #[default]
+    Fail,
+    Null,
+    /// Write the bytes stored, as a blob
+    RawHex,
+}
+
+/// Options controlling how column values are decoded.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub map_mode: MapMode,
+    pub invalid_dates: InvalidDates,
+    pub time_precision: Option<TimePrecision>,
+    pub on_decode_error: OnDecodeError,
+    // With --sparse, the empty values of the types that have none, the
+    // only cells told apart from those never written
+    pub empty_as_null: bool,
 }
 
 impl ColValue {
-    pub fn decode(col_type: &ColTypeOption, data: &CBytes) -> CDRSResult<Self> {
+    pub fn decode(
+        col_type: &ColTypeOption,
+        data: &CBytes,
+        opts: &DecodeOptions,
+    ) -> CDRSResult<Self> {
         if let Some(ref bytes) = data.as_plain() {
+            if bytes.is_empty() && !accepts_empty(&col_type.id) {
+                // Empty values can only be written explicitly (e.g. an
+                // empty buffer bound to an int column), they are
+                // treated as null with --sparse and are undecodable
+                // otherwise, as with the driver
+                return match opts.empty_as_null {
+                    true => Ok(ColValue::Null),
+                    false => Err(CDRSError::General(format!(
+                        "Empty value of a {:?} column",
+                        col_type.id
+                    ))),
+                };
+            }
             let value = match &col_type.id {
                 // null
                 ColType::Null => ColValue::Null,
                 // strings
                 ColType::Varchar => ColValue::String(decode_varchar(bytes)?),
                 ColType::Ascii => ColValue::String(decode_ascii(bytes)?),
-                ColType::Custom => ColValue::String(decode_custom(bytes)?),
+                ColType::Custom => decode_custom_value(col_type, data, bytes, opts)?,
                 // integers
                 ColType::Tinyint => ColValue::Int(decode_tinyint(bytes)? as i64),
                 ColType::Smallint => ColValue::Int(decode_smallint(bytes)? as i64),
@@ -123,26 +260,49 @@ impl ColValue {
                 ColType::Float => ColValue::Double(decode_float(bytes)? as f64),
                 ColType::Double => ColValue::Double(decode_double(bytes)?),
                 // Decimal
-                ColType::Decimal => ColValue::Decimal(decode_decimal(bytes)?.into()),
+                ColType::Decimal => ColValue::Decimal(Decimal::decode(bytes)?),
                 // bool
                 ColType::Boolean => ColValue::Boolean(decode_boolean(bytes)?),
                 // date time
-                ColType::Date => ColValue::Date(to_date(decode_date(bytes)?)),
-                ColType::Time => ColValue::Time(to_time(decode_time(bytes)?)),
-                ColType::Timestamp => ColValue::Timestamp(to_datetime(decode_timestamp(bytes)?)),
+                ColType::Date => {
+                    let days = date_days(decode_date(bytes)?);
+                    match to_date(days) {
+                        Some(x) => ColValue::Date(x),
+                        None => out_of_range("date", days, opts)?,
+                    }
+                }
+                ColType::Time => {
+                    let t = decode_time(bytes)?;
+                    match to_time(t) {
+                        Some(time) => ColValue::Time(Time {
+                            time,
+                            precision: opts.time_precision,
+                        }),
+                        None => out_of_range("time", t, opts)?,
+                    }
+                }
+                ColType::Timestamp => {
+                    let t = decode_timestamp(bytes)?;
+                    match to_datetime(t) {
+                        Some(x) => ColValue::Timestamp(x),
+                        None => out_of_range("timestamp", t, opts)?,
+                    }
+                }
                 // IP
                 ColType::Inet => ColValue::Inet(decode_inet(bytes)?),
                 // UUID
                 ColType::Uuid | ColType::Timeuuid => ColValue::Uuid(decode_timeuuid(bytes)?.into()),
                 // List / Set
-                ColType::List => ColValue::Seq(to_seq(&col_type.value, &decode_list(bytes)?)?),
-                ColType::Set => ColValue::Seq(to_seq(&col_type.value, &decode_set(bytes)?)?),
+                ColType::List => {
+                    ColValue::Seq(to_seq(&col_type.value, &decode_list(bytes)?, opts)?)
+                }
+                ColType::Set => ColValue::Seq(to_seq(&col_type.value, &decode_set(bytes)?, opts)?),
                 // Map
-                ColType::Map => ColValue::Map(to_map(&col_type.value, &decode_map(bytes)?)?),
+                ColType::Map => to_map(&col_type.value, &decode_map(bytes)?, opts)?,
                 // Tuple
-                ColType::Tuple => ColValue::Seq(to_tuple(&col_type.value, bytes)?),
+                ColType::Tuple => ColValue::Seq(to_tuple(&col_type.value, bytes, opts)?),
                 // UDT
-                ColType::Udt => ColValue::Map(to_udt(&col_type.value, bytes)?),
+                ColType::Udt => ColValue::Map(to_udt(&col_type.value, bytes, opts)?),
                 // Blob
                 ColType::Blob => ColValue::Blob(bytes.into()),
             };
@@ -159,7 +319,7 @@ impl ColValue {
             ColValue::Boolean(x) => Ok(x.to_string()),
             ColValue::Double(x) => Ok(x.to_string()),
             ColValue::Date(x) => Ok(x.to_string()),
-            ColValue::Time(x) => Ok(x.to_string()),
+            ColValue::Time(x) => Ok(x.to_time_string()),
             ColValue::Timestamp(x) => Ok(x.to_string()),
             ColValue::Inet(x) => Ok(x.to_string()),
             ColValue::Uuid(x) => Ok(x.to_hyphenated_string()),
@@ -169,26 +329,146 @@ impl ColValue {
     }
 }
 
-fn to_time(t: i64) -> NaiveTime {
-    let secs: u32 = (t / 1_000_000_000).try_into().unwrap_or(0);
-    let nano: u32 = (t % 1_000_000_000).try_into().unwrap_or(0);
-    NaiveTime::from_num_seconds_from_midnight(secs, nano)
+fn serialize_entries<S: Serializer>(
+    entries: &[(String, ColValue)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(entries.iter().map(|(k, v)| (k, v)))
+}
+
+/// The native type of a custom type given by its marshal class, e.g.
+/// `org.apache.cassandra.db.marshal.LongType` for `bigint`. Aggregates
+/// and UDFs sometimes return their values with such types.
+fn custom_base_type(class: &str) -> Option<ColType> {
+    let mut name = class.trim();
+    loop {
+        name = name.strip_prefix(MARSHAL_PACKAGE).unwrap_or(name);
+        let inner = ["ReversedType(", "FrozenType("]
+            .iter()
+            .find_map(|w| name.strip_prefix(w)?.strip_suffix(')'));
+        match inner {
+            Some(inner) => name = inner.trim(),
+            None => break,
+        }
+    }
+    Some(match name {
+        "AsciiType" => ColType::Ascii,
+        "LongType" => ColType::Bigint,
+        "BytesType" => ColType::Blob,
+        "BooleanType" => ColType::Boolean,
+        "CounterColumnType" => ColType::Counter,
+        "DecimalType" => ColType::Decimal,
+        "DoubleType" => ColType::Double,
+        "FloatType" => ColType::Float,
+        "Int32Type" => ColType::Int,
+        "TimestampType" | "DateType" => ColType::Timestamp,
+        "UUIDType" => ColType::Uuid,
+        "UTF8Type" => ColType::Varchar,
+        "IntegerType" => ColType::Varint,
+        "TimeUUIDType" => ColType::Timeuuid,
+        "InetAddressType" => ColType::Inet,
+        "SimpleDateType" => ColType::Date,
+        "TimeType" => ColType::Time,
+        "ShortType" => ColType::Smallint,
+        "ByteType" => ColType::Tinyint,
+        _ => return None,
+    })
+}
+
+// Decode the values of custom types by their native type when known,
+// or as text (or a blob when not valid UTF-8) with a warning otherwise
+fn decode_custom_value(
+    col_type: &ColTypeOption,
+    data: &CBytes,
+    bytes: &[u8],
+    opts: &DecodeOptions,
+) -> CDRSResult<ColValue> {
+    let class = match &col_type.value {
+        Some(ColTypeOptionValue::CString(class)) => class.as_str(),
+        _ => "",
+    };
+    if let Some(id) = custom_base_type(class) {
+        return ColValue::decode(&ColTypeOption { id, value: None }, data, opts);
+    }
+    let value = match std::str::from_utf8(bytes) {
+        Ok(_) => ColValue::String(decode_custom(bytes)?),
+        Err(_) => ColValue::Blob(Blob {
+            bytes: bytes.to_vec(),
+        }),
+    };
+    if UNKNOWN_CUSTOM_TYPES
+        .lock()
+        .unwrap()
+        .insert(class.to_string())
+    {
+        diag!(
+            "Warning: decoding the values of the unknown type {} as {}",
+            cql_type_name(col_type),
+            if matches!(value, ColValue::String(_)) {
+                "text"
+            } else {
+                "blob"
+            }
+        );
+    }
+    Ok(value)
+}
+
+fn accepts_empty(col_type: &ColType) -> bool {
+    matches!(
+        col_type,
+        ColType::Varchar | ColType::Ascii | ColType::Custom | ColType::Blob
+    )
+}
+
+fn out_of_range(type_name: &str, raw: i64, opts: &DecodeOptions) -> CDRSResult<ColValue> {
+    match opts.invalid_dates {
+        InvalidDates::Error => Err(CDRSError::General(format!(
+            "The {} {} is out of range",
+            type_name, raw
+        ))),
+        InvalidDates::Raw => {
+            if !WARNED_OUT_OF_RANGE.swap(true, Ordering::Relaxed) {
+                diag!(
+                    "Warning: the {} {} is out of range, writing out of range values as stored (see --invalid-dates)",
+                    type_name, raw
+                );
+            }
+            Ok(ColValue::Int(raw))
+        }
+    }
+}
+
+// Nanoseconds since midnight
+fn to_time(t: i64) -> Option<NaiveTime> {
+    let secs: u32 = (t / 1_000_000_000).try_into().ok()?;
+    let nano: u32 = (t % 1_000_000_000).try_into().ok()?;
+    NaiveTime::from_num_seconds_from_midnight_opt(secs, nano)
+}
+
+// Dates are stored as unsigned days with the epoch at 2^31, the decoder
+// of cdrs returns their bits as an i32
+fn date_days(d: i32) -> i64 {
+    i64::from(d as u32) - (1 << 31)
 }
 
-fn to_date(d: i32) -> NaiveDate {
-    let ts: i64 = i64::from(d) * 24 * 60 * 60 * 1000;
-    Utc.timestamp_millis(ts).naive_utc().date()
+fn to_date(days: i64) -> Option<NaiveDate> {
+    NaiveDate::from_ymd(1970, 1, 1).checked_add_signed(Duration::days(days))
 }
 
-fn to_datetime(t: i64) -> DateTime<Utc> {
-    Utc.timestamp_millis(t)
+fn to_datetime(t: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(t).single()
 }
 
-fn to_seq(meta: &Option<ColTypeOptionValue>, data: &[CBytes]) -> CDRSResult<Vec<ColValue>> {
+fn to_seq(
+    meta: &Option<ColTypeOptionValue>,
+    data: &[CBytes],
+    opts: &DecodeOptions,
+) -> CDRSResult<Vec<ColValue>> {
     match meta {
         Some(ColTypeOptionValue::CList(elem_type)) | Some(ColTypeOptionValue::CSet(elem_type)) => {
             data.iter()
-                .map(|x| ColValue::decode(elem_type, x))
+                .map(|x| ColValue::decode(elem_type, x, opts))
                 .collect()
         }
         _ => Err(CDRSError::General("Error converting list/set".into())),
@@ -198,28 +478,41 @@ fn to_seq(meta: &Option<ColTypeOptionValue>, data: &[CBytes]) -> CDRSResult<Vec<
 fn to_map(
     meta: &Option<ColTypeOptionValue>,
     data: &[(CBytes, CBytes)],
-) -> CDRSResult<HashMap<String, ColValue>> {
+    opts: &DecodeOptions,
+) -> CDRSResult<ColValue> {
     if let Some(ColTypeOptionValue::CMap((key_meta, value_meta))) = meta {
-        data.iter()
-            .map(|(k, v)| {
-                let key = ColValue::decode(key_meta, k)?.into_map_key()?;
-                let value = ColValue::decode(value_meta, v)?;
-                Ok((key, value))
-            })
-            .collect()
+        let entries = data.iter().map(|(k, v)| {
+            let key = ColValue::decode(key_meta, k, opts)?;
+            let value = ColValue::decode(value_meta, v, opts)?;
+            Ok((key, value))
+        });
+        match opts.map_mode {
+            MapMode::Object => entries
+                .map(|x| x.and_then(|(k, v)| Ok((k.into_map_key()?, v))))
+                .collect::<CDRSResult<_>>()
+                .map(ColValue::Map),
+            MapMode::Entries => entries
+                .map(|x| x.map(|(k, v)| ColValue::Seq(vec![k, v])))
+                .collect::<CDRSResult<_>>()
+                .map(ColValue::Seq),
+        }
     } else {
         Err(CDRSError::General("Error converting map".into()))
     }
 }
 
-fn to_tuple(meta: &Option<ColTypeOptionValue>, bytes: &[u8]) -> CDRSResult<Vec<ColValue>> {
+fn to_tuple(
+    meta: &Option<ColTypeOptionValue>,
+    bytes: &[u8],
+    opts: &DecodeOptions,
+) -> CDRSResult<Vec<ColValue>> {
     if let Some(ColTypeOptionValue::TupleType(tuple_meta)) = meta {
         let data = decode_tuple(bytes, tuple_meta.types.len())?;
         tuple_meta
             .types
             .iter()
             .zip(data.iter())
-            .map(|(t, x)| ColValue::decode(t, x))
+            .map(|(t, x)| ColValue::decode(t, x, opts))
             .collect()
     } else {
         Err(CDRSError::General("Error converting tuple".into()))
@@ -229,7 +522,8 @@ fn to_tuple(meta: &Option<ColTypeOptionValue>, bytes: &[u8]) -> CDRSResult<Vec<C
 fn to_udt(
     meta: &Option<ColTypeOptionValue>,
     bytes: &[u8],
-) -> CDRSResult<HashMap<String, ColValue>> {
+    opts: &DecodeOptions,
+) -> CDRSResult<Vec<(String, ColValue)>> {
     if let Some(ColTypeOptionValue::UdtType(udt_meta)) = meta {
         let data = decode_udt(bytes, udt_meta.descriptions.len())?;
         udt_meta
@@ -238,7 +532,7 @@ fn to_udt(
             .zip(data.iter())
             .map(|((name, t), x)| {
                 let key = name.as_plain();
-                let value = ColValue::decode(t, x)?;
+                let value = ColValue::decode(t, x, opts)?;
                 Ok((key, value))
             })
             .collect()
@@ -247,15 +541,64 @@ fn to_udt(
     }
 }
 
+/// The CQL name of a column type, e.g. `map<int, text>`.
+pub fn cql_type_name(col_type: &ColTypeOption) -> String {
+    let name = match col_type.id {
+        ColType::Custom => {
+            return match &col_type.value {
+                Some(ColTypeOptionValue::CString(class)) => format!("'{}'", class.as_str()),
+                _ => "custom".into(),
+            };
+        }
+        ColType::Ascii => "ascii",
+        ColType::Bigint => "bigint",
+        ColType::Blob => "blob",
+        ColType::Boolean => "boolean",
+        ColType::Counter => "counter",
+        ColType::Decimal => "decimal",
+        ColType::Double => "double",
+        ColType::Float => "float",
+        ColType::Int => "int",
+        ColType::Timestamp => "timestamp",
+        ColType::Uuid => "uuid",
+        ColType::Varchar => "text",
+        ColType::Varint => "varint",
+        ColType::Timeuuid => "timeuuid",
+        ColType::Inet => "inet",
+        ColType::Date => "date",
+        ColType::Time => "time",
+        ColType::Smallint => "smallint",
+        ColType::Tinyint => "tinyint",
+        ColType::Null => "null",
+        ColType::List | ColType::Set | ColType::Map | ColType::Udt | ColType::Tuple => {
+            return match &col_type.value {
+                Some(ColTypeOptionValue::CList(t)) => format!("list<{}>", cql_type_name(t)),
+                Some(ColTypeOptionValue::CSet(t)) => format!("set<{}>", cql_type_name(t)),
+                Some(ColTypeOptionValue::CMap((k, v))) => {
+                    format!("map<{}, {}>", cql_type_name(k), cql_type_name(v))
+                }
+                Some(ColTypeOptionValue::UdtType(udt)) => udt.udt_name.as_plain(),
+                Some(ColTypeOptionValue::TupleType(tuple)) => format!(
+                    "tuple<{}>",
+                    tuple.types.iter().map(cql_type_name).join(", ")
+                ),
+                _ => format!("{:?}", col_type.id).to_lowercase(),
+            };
+        }
+    };
+    name.to_string()
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use cdrs::types::CString;
     use std::mem;
 
     #[test]
     pub fn test_col_value_size() {
-        assert_eq!(64, mem::size_of::<ColValue>());
+        assert_eq!(40, mem::size_of::<ColValue>());
     }
 
     #[test]
@@ -264,4 +607,254 @@ mod tests {
         let n2: Decimal = n1.into();
         assert_eq!("1234.567893456789", n2.0.to_string().as_str());
     }
+
+    // The decimal string of `unscaled` * 10^-`scale`, computed from the
+    // digits of `unscaled`
+    fn decimal_string(unscaled: &BigInt, scale: i32) -> String {
+        let digits = unscaled.to_string();
+        let (sign, digits) = match digits.strip_prefix('-') {
+            Some(x) => ("-", x.to_string()),
+            None => ("", digits),
+        };
+        let scale = scale as i64;
+        let len = digits.len() as i64;
+        let abs = if scale <= 0 {
+            digits + &"0".repeat(-scale as usize)
+        } else if scale < len {
+            format!(
+                "{}.{}",
+                &digits[..(len - scale) as usize],
+                &digits[(len - scale) as usize..]
+            )
+        } else {
+            format!("0.{}{}", "0".repeat((scale - len) as usize), digits)
+        };
+        format!("{}{}", sign, abs)
+    }
+
+    #[test]
+    pub fn test_decode_decimals() {
+        let decimal = ColTypeOption {
+            id: ColType::Decimal,
+            value: None,
+        };
+        // xorshift, for reproducible cases
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..1000 {
+            let scale = (next() % 61) as i32 - 30;
+            let len = (next() % 24) as usize + 1;
+            let unscaled: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+
+            let mut bytes = scale.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&unscaled);
+            let value =
+                ColValue::decode(&decimal, &CBytes::new(bytes), &DecodeOptions::default()).unwrap();
+            let expected = decimal_string(&BigInt::from_signed_bytes_be(&unscaled), scale);
+            assert_eq!(
+                serde_json::to_string(&expected).unwrap(),
+                serde_json::to_string(&value).unwrap()
+            );
+        }
+        // 1.5E+3
+        let value = ColValue::decode(
+            &decimal,
+            &CBytes::new(vec![255, 255, 255, 254, 15]),
+            &DecodeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(r#""1500""#, serde_json::to_string(&value).unwrap());
+    }
+
+    fn int_text_map_type() -> ColTypeOption {
+        let key_type = ColTypeOption {
+            id: ColType::Int,
+            value: None,
+        };
+        let value_type = ColTypeOption {
+            id: ColType::Varchar,
+            value: None,
+        };
+        ColTypeOption {
+            id: ColType::Map,
+            value: Some(ColTypeOptionValue::CMap((
+                Box::new(key_type),
+                Box::new(value_type),
+            ))),
+        }
+    }
+
+    #[test]
+    pub fn test_cql_type_name() {
+        assert_eq!("map<int, text>", cql_type_name(&int_text_map_type()));
+        let list_type = ColTypeOption {
+            id: ColType::List,
+            value: Some(ColTypeOptionValue::CList(Box::new(ColTypeOption {
+                id: ColType::Timestamp,
+                value: None,
+            }))),
+        };
+        assert_eq!("list<timestamp>", cql_type_name(&list_type));
+    }
+
+    #[test]
+    pub fn test_decode_map_modes() {
+        // {1: "a"}
+        let data = CBytes::new(vec![0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 1, 97]);
+        let map_type = int_text_map_type();
+
+        let object = DecodeOptions {
+            map_mode: MapMode::Object,
+            ..Default::default()
+        };
+        let value = ColValue::decode(&map_type, &data, &object).unwrap();
+        assert_eq!(
+            r#"{"1":"a"}"#,
+            serde_json::to_string(&value).unwrap().as_str()
+        );
+
+        let entries = DecodeOptions {
+            map_mode: MapMode::Entries,
+            ..Default::default()
+        };
+        let value = ColValue::decode(&map_type, &data, &entries).unwrap();
+        assert_eq!(
+            r#"[[1,"a"]]"#,
+            serde_json::to_string(&value).unwrap().as_str()
+        );
+    }
+
+    #[test]
+    pub fn test_decode_custom_types() {
+        let custom = |class: &str| ColTypeOption {
+            id: ColType::Custom,
+            value: Some(ColTypeOptionValue::CString(CString::new(class.into()))),
+        };
+        let decode = |class: &str, bytes: Vec<u8>| {
+            let value = ColValue::decode(
+                &custom(class),
+                &CBytes::new(bytes),
+                &DecodeOptions::default(),
+            );
+            serde_json::to_string(&value.unwrap()).unwrap()
+        };
+        assert_eq!(
+            "42",
+            decode(
+                "org.apache.cassandra.db.marshal.LongType",
+                vec![0, 0, 0, 0, 0, 0, 0, 42]
+            )
+        );
+        assert_eq!(
+            "7",
+            decode(
+                "org.apache.cassandra.db.marshal.ReversedType(org.apache.cassandra.db.marshal.Int32Type)",
+                vec![0, 0, 0, 7]
+            )
+        );
+        assert_eq!(r#""abc""#, decode("com.example.MyType", b"abc".to_vec()));
+        assert_eq!(r#""FF00""#, decode("com.example.MyType", vec![255, 0]));
+    }
+
+    #[test]
+    pub fn test_decode_out_of_range_dates() {
+        let decode = |id: ColType, bytes: Vec<u8>, invalid_dates: InvalidDates| {
+            let opts = DecodeOptions {
+                invalid_dates,
+                ..Default::default()
+            };
+            ColValue::decode(
+                &ColTypeOption { id, value: None },
+                &CBytes::new(bytes),
+                &opts,
+            )
+            .map(|x| serde_json::to_string(&x).unwrap())
+        };
+        let raw = InvalidDates::Raw;
+        // The day before the epoch
+        assert_eq!(
+            r#""1969-12-31""#,
+            decode(ColType::Date, vec![127, 255, 255, 255], raw).unwrap()
+        );
+        assert_eq!(
+            "-2147483648",
+            decode(ColType::Date, vec![0, 0, 0, 0], raw).unwrap()
+        );
+        assert_eq!(
+            r#""1969-12-31T23:59:59.999Z""#,
+            decode(ColType::Timestamp, (-1i64).to_be_bytes().to_vec(), raw).unwrap()
+        );
+        assert_eq!(
+            i64::MAX.to_string(),
+            decode(ColType::Timestamp, i64::MAX.to_be_bytes().to_vec(), raw).unwrap()
+        );
+        assert_eq!(
+            "86400000000000",
+            decode(
+                ColType::Time,
+                86_400_000_000_000i64.to_be_bytes().to_vec(),
+                raw
+            )
+            .unwrap()
+        );
+        assert!(decode(
+            ColType::Timestamp,
+            i64::MIN.to_be_bytes().to_vec(),
+            InvalidDates::Error
+        )
+        .is_err());
+    }
+
+    #[test]
+    pub fn test_decode_time_precision() {
+        let time = ColTypeOption {
+            id: ColType::Time,
+            value: None,
+        };
+        // 01:02:03.123456789
+        let nanos: i64 = 3_723_123_456_789;
+        let decode = |nanos: i64, precision| {
+            let opts = DecodeOptions {
+                time_precision: TimePrecision::parse(precision),
+                ..Default::default()
+            };
+            let value = ColValue::decode(&time, &CBytes::new(nanos.to_be_bytes().to_vec()), &opts);
+            serde_json::to_string(&value.unwrap()).unwrap()
+        };
+        assert_eq!(r#""01:02:03.123456789""#, decode(nanos, ""));
+        assert_eq!(r#""01:02:03.123""#, decode(nanos, "ms"));
+        assert_eq!(r#""01:02:03.123456""#, decode(nanos, "us"));
+        assert_eq!(r#""01:02:03.000000000""#, decode(3_723_000_000_000, "ns"));
+    }
+
+    #[test]
+    pub fn test_decode_empty_values() {
+        let int_type = ColTypeOption {
+            id: ColType::Int,
+            value: None,
+        };
+        let text_type = ColTypeOption {
+            id: ColType::Varchar,
+            value: None,
+        };
+        let empty = CBytes::new(vec![]);
+        let sparse = DecodeOptions {
+            empty_as_null: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            ColValue::decode(&int_type, &empty, &sparse).unwrap(),
+            ColValue::Null
+        ));
+        assert!(ColValue::decode(&int_type, &empty, &DecodeOptions::default()).is_err());
+        assert!(matches!(
+            ColValue::decode(&text_type, &empty, &sparse).unwrap(),
+            ColValue::String(ref x) if x.is_empty()
+        ));
+    }
 }
@@ -0,0 +1,216 @@
+// The resources used by a run, reported on stderr with `--stats` once
+// done and every `--stats-interval` seconds along the way, to see
+// whether a run is bound by CPU, memory or the network when tuning
+// `--parallelism`.
+//
+// The CPU time and peak RSS are those of the whole process, from
+// getrusage(2). The bytes read are those of the frames received by each
+// worker as they came from the network, compressed with `--compression`,
+// the forwarders relaying them telling their size. The pre-flight query
+// runs before the workers start and is counted on its own. The results
+// buffered are those received but not written out yet, within
+// `--max-memory-mb`.
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+
+use crate::backend;
+use crate::memory::MemoryBudget;
+
+#[derive(Debug)]
+pub struct Usage {
+    started: Instant,
+    memory: Arc<MemoryBudget>,
+    // The bytes read by each worker, in the order they started
+    read: Mutex<Vec<(ThreadId, u64)>>,
+    // The thread that ran the pre-flight query, not a worker
+    preflight: Mutex<Option<ThreadId>>,
+}
+
+/// Stops printing the reports of `Usage::tick` once dropped.
+pub struct Ticker {
+    _stop: Sender<()>,
+}
+
+struct Rusage {
+    cpu: Duration,
+    peak_rss: u64,
+}
+
+#[cfg(unix)]
+fn rusage() -> Option<Rusage> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let time = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    // In kilobytes on Linux but in bytes on macOS
+    let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Some(Rusage {
+        cpu: time(usage.ru_utime) + time(usage.ru_stime),
+        peak_rss: usage.ru_maxrss as u64 * unit,
+    })
+}
+
+#[cfg(not(unix))]
+fn rusage() -> Option<Rusage> {
+    None
+}
+
+fn bytes(n: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = n as f64;
+    let mut unit = "B";
+    for x in units.iter() {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = x;
+    }
+    match unit {
+        "B" => format!("{} B", n),
+        _ => format!("{:.1} {}", size, unit),
+    }
+}
+
+impl Usage {
+    pub fn new(memory: Arc<MemoryBudget>) -> Usage {
+        Usage {
+            started: Instant::now(),
+            memory,
+            read: Mutex::new(vec![]),
+            preflight: Mutex::new(None),
+        }
+    }
+
+    /// Count what the current thread reads apart from the workers, as
+    /// that of the pre-flight query.
+    pub fn mark_preflight(&self) {
+        *self.preflight.lock().unwrap() = Some(thread::current().id());
+    }
+
+    /// Add the bytes read by the current thread since the last call
+    /// to those of its worker.
+    pub fn record_read(&self) {
+        let bytes = backend::take_bytes_read();
+        let id = thread::current().id();
+        let mut read = self.read.lock().unwrap();
+        match read.iter_mut().find(|(x, _)| *x == id) {
+            Some((_, total)) => *total += bytes,
+            None => read.push((id, bytes)),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let preflight = *self.preflight.lock().unwrap();
+        let read = self.read.lock().unwrap();
+        let (preflight, workers): (Vec<_>, Vec<_>) =
+            read.iter().partition(|(id, _)| Some(*id) == preflight);
+        format_report(
+            self.started.elapsed(),
+            rusage(),
+            preflight.first().map(|(_, x)| *x),
+            &workers.iter().map(|(_, x)| *x).collect::<Vec<_>>(),
+            self.memory.usage(),
+        )
+    }
+
+    /// Print the report every `interval` until the `Ticker` is
+    /// dropped.
+    pub fn tick(self: &Arc<Self>, interval: Duration) -> Ticker {
+        let (tx, rx) = mpsc::channel();
+        let usage = self.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                diag!("{}", usage.report());
+            }
+        });
+        Ticker { _stop: tx }
+    }
+}
+
+fn format_report(
+    elapsed: Duration,
+    usage: Option<Rusage>,
+    preflight: Option<u64>,
+    read: &[u64],
+    (buffered, limit): (usize, Option<usize>),
+) -> String {
+    let mut report = format!("Resource usage after {:.1} s:", elapsed.as_secs_f64());
+    if let Some(usage) = usage {
+        let cpu = usage.cpu.as_secs_f64();
+        report += &format!(" CPU {:.1} s", cpu);
+        // Too noisy to tell anything in the first second
+        if elapsed >= Duration::from_secs(1) {
+            report += &format!(" ({:.0}%)", cpu * 100.0 / elapsed.as_secs_f64());
+        }
+        report += &format!(", peak RSS {},", bytes(usage.peak_rss));
+    }
+    let total = preflight.unwrap_or(0) + read.iter().sum::<u64>();
+    report += &format!(" read {} from the network", bytes(total));
+    if preflight.is_some() || read.len() > 1 {
+        let workers = read
+            .iter()
+            .enumerate()
+            .map(|(i, x)| format!("worker {}: {}", i + 1, bytes(*x)));
+        let mut parts = preflight
+            .map(|x| format!("pre-flight: {}", bytes(x)))
+            .into_iter()
+            .chain(workers);
+        report += &format!(" ({})", parts.join(", "));
+    }
+    report += &format!(", {} of results buffered", bytes(buffered as u64));
+    if let Some(limit) = limit {
+        report += &format!(" (max {})", bytes(limit as u64));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_report() {
+        let usage = Rusage {
+            cpu: Duration::from_millis(1500),
+            peak_rss: 48 * 1024 * 1024,
+        };
+        assert_eq!(
+            "Resource usage after 3.0 s: CPU 1.5 s (50%), peak RSS 48.0 MiB, read 3.0 MiB from the network (worker 1: 1.0 MiB, worker 2: 2.0 MiB), 1.5 KiB of results buffered (max 64.0 MiB)",
+            format_report(
+                Duration::from_secs(3),
+                Some(usage),
+                None,
+                &[1024 * 1024, 2 * 1024 * 1024],
+                (1536, Some(64 * 1024 * 1024))
+            )
+        );
+        assert_eq!(
+            "Resource usage after 1.0 s: read 512.0 KiB from the network, 0 B of results buffered",
+            format_report(Duration::from_secs(1), None, None, &[512 * 1024], (0, None))
+        );
+        assert_eq!(
+            "Resource usage after 0.2 s: CPU 0.1 s, peak RSS 1023 B, read 0 B from the network, 0 B of results buffered",
+            format_report(
+                Duration::from_millis(200),
+                Some(Rusage {
+                    cpu: Duration::from_millis(100),
+                    peak_rss: 1023
+                }),
+                None,
+                &[],
+                (0, None)
+            )
+        );
+        assert_eq!(
+            "Resource usage after 1.0 s: read 3.0 KiB from the network (pre-flight: 1.0 KiB, worker 1: 2.0 KiB), 0 B of results buffered",
+            format_report(Duration::from_secs(1), None, Some(1024), &[2048], (0, None))
+        );
+        assert!(rusage().is_none_or(|x| x.peak_rss > 0));
+    }
+}
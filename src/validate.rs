@@ -0,0 +1,360 @@
+// `--validate-schema schema.json`: check every row written against a
+// JSON Schema. The rows that fail are written with their errors to
+// `--validation-errors` (stderr by default) instead of the output, and
+// the run fails once done.
+//
+// Only the keywords about the values of rows are supported: type, enum,
+// const, properties, required, additionalProperties, items, minItems,
+// maxItems, minimum, maximum, exclusiveMinimum, exclusiveMaximum,
+// minLength, maxLength, pattern, allOf, anyOf, oneOf and not. Schemas
+// using other keywords, e.g. $ref, are rejected rather than partially
+// applied.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use regex::Regex;
+use serde_json::{json, Value as JsonValue};
+
+use crate::errors::{AppError, AppResult};
+
+const KEYWORDS: &[&str] = &[
+    "type",
+    "enum",
+    "const",
+    "properties",
+    "required",
+    "additionalProperties",
+    "items",
+    "minItems",
+    "maxItems",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+];
+
+// Keywords without effect on validation
+const ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "format",
+];
+
+pub struct Validator {
+    schema: JsonValue,
+    patterns: HashMap<String, Regex>,
+    errors: Mutex<Box<dyn Write + Send>>,
+    checked: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+// Check that `schema` only uses supported keywords, compiling its
+// patterns along the way
+fn check_schema(
+    schema: &JsonValue,
+    path: &str,
+    patterns: &mut HashMap<String, Regex>,
+) -> AppResult<()> {
+    let obj = match schema {
+        JsonValue::Bool(_) => return Ok(()),
+        JsonValue::Object(obj) => obj,
+        _ => return Err(AppError::new(format!("Invalid schema at {}", path))),
+    };
+    for (key, value) in obj {
+        let path = format!("{}/{}", path, key);
+        if !KEYWORDS.contains(&key.as_str()) && !ANNOTATIONS.contains(&key.as_str()) {
+            return Err(AppError::new(format!(
+                "Unsupported schema keyword {}",
+                path
+            )));
+        }
+        match (key.as_str(), value) {
+            ("pattern", JsonValue::String(pattern)) => {
+                let regex = Regex::new(pattern).map_err(|err| {
+                    AppError::new(format!("Invalid pattern at {}: {}", path, err))
+                })?;
+                patterns.insert(pattern.clone(), regex);
+            }
+            ("properties", JsonValue::Object(props)) => {
+                for (name, schema) in props {
+                    check_schema(schema, &format!("{}/{}", path, name), patterns)?;
+                }
+            }
+            ("additionalProperties", schema) | ("items", schema) | ("not", schema) => {
+                check_schema(schema, &path, patterns)?
+            }
+            ("allOf", JsonValue::Array(schemas))
+            | ("anyOf", JsonValue::Array(schemas))
+            | ("oneOf", JsonValue::Array(schemas)) => {
+                for (i, schema) in schemas.iter().enumerate() {
+                    check_schema(schema, &format!("{}/{}", path, i), patterns)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn type_name(value: &JsonValue) -> &'static str {
+    match value {
+        JsonValue::Null => "null",
+        JsonValue::Bool(_) => "boolean",
+        JsonValue::Number(_) => "number",
+        JsonValue::String(_) => "string",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &JsonValue, t: &str) -> bool {
+    match t {
+        "integer" => value.as_f64().is_some_and(|x| x.fract() == 0.0),
+        t => type_name(value) == t,
+    }
+}
+
+impl Validator {
+    pub fn open(schema_path: &str, errors_path: Option<&str>) -> AppResult<Validator> {
+        let schema: JsonValue = serde_json::from_str(&fs::read_to_string(schema_path)?)?;
+        let mut patterns = HashMap::new();
+        check_schema(&schema, "#", &mut patterns)?;
+        let errors: Box<dyn Write + Send> = match errors_path {
+            Some(path) => Box::new(File::create(path)?),
+            None => Box::new(io::stderr()),
+        };
+        Ok(Validator {
+            schema,
+            patterns,
+            errors: Mutex::new(errors),
+            checked: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        })
+    }
+
+    // The errors of `value` against `schema`, as `path: message`
+    fn errors(&self, schema: &JsonValue, value: &JsonValue, path: &str, errors: &mut Vec<String>) {
+        let obj = match schema {
+            JsonValue::Bool(true) => return,
+            JsonValue::Bool(false) => return errors.push(format!("{}: not allowed", path)),
+            JsonValue::Object(obj) => obj,
+            _ => return,
+        };
+        let mut fail = |msg: String| errors.push(format!("{}: {}", path, msg));
+        let number = |key: &str| obj.get(key).and_then(JsonValue::as_f64);
+        let count = |key: &str| obj.get(key).and_then(JsonValue::as_u64);
+
+        match obj.get("type") {
+            Some(JsonValue::String(t)) if !has_type(value, t) => {
+                fail(format!("expected {}, found {}", t, type_name(value)))
+            }
+            Some(JsonValue::Array(ts))
+                if !ts
+                    .iter()
+                    .any(|t| t.as_str().is_some_and(|t| has_type(value, t))) =>
+            {
+                fail(format!("unexpected type {}", type_name(value)))
+            }
+            _ => {}
+        }
+        if let Some(JsonValue::Array(values)) = obj.get("enum") {
+            if !values.contains(value) {
+                fail(format!("{} is not one of the allowed values", value));
+            }
+        }
+        if let Some(expected) = obj.get("const") {
+            if expected != value {
+                fail(format!("expected {}", expected));
+            }
+        }
+        if let Some(x) = value.as_f64() {
+            if number("minimum").is_some_and(|min| x < min) {
+                fail(format!("{} is less than the minimum", x));
+            }
+            if number("maximum").is_some_and(|max| x > max) {
+                fail(format!("{} is more than the maximum", x));
+            }
+            if number("exclusiveMinimum").is_some_and(|min| x <= min) {
+                fail(format!("{} is not more than the exclusive minimum", x));
+            }
+            if number("exclusiveMaximum").is_some_and(|max| x >= max) {
+                fail(format!("{} is not less than the exclusive maximum", x));
+            }
+        }
+        if let JsonValue::String(s) = value {
+            let len = s.chars().count() as u64;
+            if count("minLength").is_some_and(|min| len < min) {
+                fail(format!(
+                    "shorter than {} characters",
+                    count("minLength").unwrap()
+                ));
+            }
+            if count("maxLength").is_some_and(|max| len > max) {
+                fail(format!(
+                    "longer than {} characters",
+                    count("maxLength").unwrap()
+                ));
+            }
+            if let Some(JsonValue::String(pattern)) = obj.get("pattern") {
+                if !self.patterns[pattern].is_match(s) {
+                    fail(format!("doesn't match {}", pattern));
+                }
+            }
+        }
+        if let JsonValue::Array(items) = value {
+            let len = items.len() as u64;
+            if count("minItems").is_some_and(|min| len < min) {
+                fail(format!("fewer than {} items", count("minItems").unwrap()));
+            }
+            if count("maxItems").is_some_and(|max| len > max) {
+                fail(format!("more than {} items", count("maxItems").unwrap()));
+            }
+        }
+        let mut nested = vec![];
+        if let JsonValue::Object(fields) = value {
+            if let Some(JsonValue::Array(required)) = obj.get("required") {
+                for name in required.iter().filter_map(JsonValue::as_str) {
+                    if !fields.contains_key(name) {
+                        fail(format!("missing required property {}", name));
+                    }
+                }
+            }
+            let props = obj.get("properties").and_then(JsonValue::as_object);
+            for (name, field) in fields {
+                match (
+                    props.and_then(|x| x.get(name)),
+                    obj.get("additionalProperties"),
+                ) {
+                    (Some(schema), _) | (None, Some(schema)) => {
+                        nested.push((schema, field, format!("{}/{}", path, name)))
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        if let (JsonValue::Array(items), Some(schema)) = (value, obj.get("items")) {
+            for (i, item) in items.iter().enumerate() {
+                nested.push((schema, item, format!("{}/{}", path, i)));
+            }
+        }
+        let subschemas = |key: &str| {
+            obj.get(key)
+                .and_then(JsonValue::as_array)
+                .map(|xs| xs.iter().collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        let passes = |schema: &JsonValue| {
+            let mut errors = vec![];
+            self.errors(schema, value, path, &mut errors);
+            errors.is_empty()
+        };
+        let any_of = subschemas("anyOf");
+        if !any_of.is_empty() && !any_of.iter().any(|x| passes(x)) {
+            fail("doesn't match any of the anyOf schemas".into());
+        }
+        let one_of = subschemas("oneOf");
+        if !one_of.is_empty() && one_of.iter().filter(|x| passes(x)).count() != 1 {
+            fail("doesn't match exactly one of the oneOf schemas".into());
+        }
+        if obj.get("not").is_some_and(passes) {
+            fail("matches the not schema".into());
+        }
+        for schema in subschemas("allOf") {
+            self.errors(schema, value, path, errors);
+        }
+        for (schema, value, path) in nested {
+            self.errors(schema, value, &path, errors);
+        }
+    }
+
+    /// Whether `row` is valid, writing its errors out when not.
+    pub fn check(&self, row: &JsonValue) -> AppResult<bool> {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        let mut errors = vec![];
+        self.errors(&self.schema, row, "", &mut errors);
+        if errors.is_empty() {
+            return Ok(true);
+        }
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        let report = json!({ "row": row, "errors": errors });
+        writeln!(self.errors.lock().unwrap(), "{}", report)?;
+        Ok(false)
+    }
+
+    /// Fails when rows didn't pass validation.
+    pub fn finish(&self) -> AppResult<()> {
+        self.errors.lock().unwrap().flush()?;
+        let failed = self.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            return Err(AppError::new(format!(
+                "{} of {} rows failed schema validation",
+                failed,
+                self.checked.load(Ordering::Relaxed)
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id", "email"],
+            "properties": {
+                "id": { "type": "integer", "minimum": 1 },
+                "email": { "type": "string", "pattern": "^[^@]+@[^@]+$" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } },
+                "score": { "anyOf": [{ "type": "null" }, { "type": "number", "maximum": 10 }] }
+            },
+            "additionalProperties": false
+        });
+        let mut patterns = HashMap::new();
+        check_schema(&schema, "#", &mut patterns).unwrap();
+        let validator = Validator {
+            schema,
+            patterns,
+            errors: Mutex::new(Box::new(io::sink())),
+            checked: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        };
+        let errors = |row: JsonValue| {
+            let mut errors = vec![];
+            validator.errors(&validator.schema, &row, "", &mut errors);
+            errors
+        };
+        assert!(
+            errors(json!({ "id": 1, "email": "a@b", "tags": ["a"], "score": null })).is_empty()
+        );
+        assert_eq!(
+            vec![
+                ": missing required property email",
+                "/id: 0 is less than the minimum",
+                "/tags/1: \"c\" is not one of the allowed values",
+                "/score: doesn't match any of the anyOf schemas",
+                "/other: not allowed",
+            ],
+            errors(json!({ "id": 0, "tags": ["a", "c"], "score": 11, "other": 1 }))
+        );
+        assert!(check_schema(&json!({ "$ref": "#/x" }), "#", &mut HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+// `kass --version --verbose`: the version of kass along with what it was
+// built with, as JSON, for bug reports and for scripts checking what the
+// installed binary supports.
+use chrono::NaiveDateTime;
+use serde_json::{json, Value as JsonValue};
+
+// The cargo features of Cargo.toml
+const FEATURES: &[(&str, bool)] = &[
+    ("grpc", cfg!(feature = "grpc")),
+    ("integration", cfg!(feature = "integration")),
+];
+
+/// Whether the command line, without the program, asks for the verbose
+/// version, which clap doesn't know of.
+pub fn is_verbose<T: AsRef<str>>(args: &[T]) -> bool {
+    args.len() == 2
+        && args.iter().any(|x| x.as_ref() == "--verbose")
+        && args
+            .iter()
+            .any(|x| ["--version", "-V"].contains(&x.as_ref()))
+}
+
+pub fn info() -> JsonValue {
+    let build_date = env!("KASS_BUILD_TIMESTAMP").parse().ok().map(|x| {
+        NaiveDateTime::from_timestamp(x, 0)
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string()
+    });
+    let git_commit = Some(env!("KASS_GIT_COMMIT")).filter(|x| !x.is_empty());
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": git_commit,
+        "build_date": build_date,
+        "features": FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>(),
+        // The native protocol versions of cdrs
+        "protocol_versions": [4],
+        "compression": ["lz4", "snappy"],
+        "tls": true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_info() {
+        assert!(is_verbose(&["--version", "--verbose"]));
+        assert!(is_verbose(&["--verbose", "-V"]));
+        assert!(!is_verbose(&["--verbose", "select 1"]));
+        assert!(!is_verbose(&["--version"]));
+        let info = info();
+        assert_eq!("0.1.0", info["version"]);
+        assert!(info["build_date"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(json!([4]), info["protocol_versions"]);
+    }
+}
@@ -0,0 +1,86 @@
+// The warnings the nodes send along with their responses, e.g. about
+// the tombstones scanned or the size of a batch. They are written to
+// stderr and counted, and `--max-warnings` aborts a run once the cluster
+// has sent more, as a scan that keeps causing them is likely harming
+// the cluster.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::errors::{AppError, AppResult};
+
+// The warnings written out, the others are only counted
+const SHOWN: usize = 10;
+
+#[derive(Debug)]
+pub struct Warnings {
+    max: Option<usize>,
+    count: AtomicUsize,
+}
+
+impl Warnings {
+    pub fn new(max: Option<usize>) -> Warnings {
+        Warnings {
+            max,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Warnings of the same `max`, for a run of its own.
+    pub fn fresh(&self) -> Warnings {
+        Warnings::new(self.max)
+    }
+
+    /// Count the warnings of a response.
+    pub fn record(&self, warnings: &[String]) {
+        for warning in warnings {
+            match self.count.fetch_add(1, Ordering::Relaxed) {
+                n if n < SHOWN => diag!("Warning from the cluster: {}", warning),
+                SHOWN => diag!(
+                    "Warning from the cluster: {} (further warnings are only counted)",
+                    warning
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    /// Fail once the cluster has sent more than `--max-warnings`.
+    pub fn check(&self) -> AppResult<()> {
+        match (self.count.load(Ordering::Relaxed), self.max) {
+            (n, Some(max)) if n > max => Err(AppError::new(format!(
+                "The cluster sent {} warnings, more than --max-warnings {}, aborting",
+                n, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of warnings received, if any.
+    pub fn summary(&self) -> Option<String> {
+        match self.count.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(format!("{} warnings from the cluster", n)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let warnings = Warnings::new(Some(2));
+        assert_eq!(None, warnings.summary());
+        warnings.record(&["Read 1001 live rows and 5000 tombstone cells".into()]);
+        warnings.record(&["a".into(), "b".into()]);
+        assert_eq!(
+            Some("3 warnings from the cluster".to_string()),
+            warnings.summary()
+        );
+        assert_eq!(
+            "The cluster sent 3 warnings, more than --max-warnings 2, aborting",
+            warnings.check().unwrap_err().to_string()
+        );
+        assert!(Warnings::new(None).check().is_ok());
+    }
+}
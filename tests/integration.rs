@@ -0,0 +1,227 @@
+// End-to-end tests running the kass binary against Cassandra in Docker,
+// with `cargo test --features integration`. The image is
+// `cassandra:4.1` unless `KASS_TEST_IMAGE` names another one, and
+// `KASS_TEST_HOST` skips Docker for a cluster already running.
+//
+// A single test starts the container, creates a table with a column of
+// every CQL type along with a counter table, and checks the output of
+// the queries in each format, so that the container is started once
+// and removed when done.
+#![cfg(feature = "integration")]
+
+use std::env;
+use std::process::{Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value as JsonValue};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(180);
+
+const SCHEMA: &[&str] = &[
+    "DROP KEYSPACE IF EXISTS kass_it",
+    "CREATE KEYSPACE kass_it WITH replication = {'class': 'SimpleStrategy', 'replication_factor': 1}",
+    "CREATE TYPE kass_it.address (street text, zip int)",
+    "CREATE TABLE kass_it.all_types (
+        id int PRIMARY KEY,
+        ascii_col ascii,
+        bigint_col bigint,
+        blob_col blob,
+        boolean_col boolean,
+        date_col date,
+        decimal_col decimal,
+        double_col double,
+        float_col float,
+        inet_col inet,
+        smallint_col smallint,
+        text_col text,
+        time_col time,
+        timestamp_col timestamp,
+        timeuuid_col timeuuid,
+        tinyint_col tinyint,
+        uuid_col uuid,
+        varint_col varint,
+        list_col list<int>,
+        set_col set<text>,
+        map_col map<text, int>,
+        tuple_col tuple<int, text>,
+        udt_col frozen<address>
+    )",
+    "CREATE TABLE kass_it.counters (id int PRIMARY KEY, hits counter)",
+];
+
+const DATA: &[&str] = &[
+    "INSERT INTO kass_it.all_types (id, ascii_col, bigint_col, blob_col, boolean_col, date_col,
+        decimal_col, double_col, float_col, inet_col, smallint_col, text_col, time_col,
+        timestamp_col, timeuuid_col, tinyint_col, uuid_col, varint_col, list_col, set_col,
+        map_col, tuple_col, udt_col)
+     VALUES (1, 'abc', 9000000000, 0xcafe, true, '2020-02-29', 12.345, 1.5, 2.5, '10.0.0.1',
+        -3, 'line 1\nline 2', '12:34:56.789', '2020-01-01 12:34:56.789+0000',
+        50554d6e-29bb-11e5-b345-feff819cdc9f, 7, 7d2f6b9c-1a2c-4c8e-9b0e-1f2d3c4b5a69,
+        123456789012, [1, 2, 3], {'a', 'b'}, {'x': 1, 'y': 2}, (1, 'one'),
+        {street: 'Queen St', zip: 1010})",
+    "INSERT INTO kass_it.all_types (id) VALUES (2)",
+    "UPDATE kass_it.counters SET hits = hits + 42 WHERE id = 1",
+];
+
+// The container, removed when dropped
+struct Cassandra {
+    id: Option<String>,
+    host: String,
+}
+
+fn docker(args: &[&str]) -> String {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .expect("Unable to run docker");
+    assert!(
+        output.status.success(),
+        "docker {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+impl Cassandra {
+    fn start() -> Cassandra {
+        if let Ok(host) = env::var("KASS_TEST_HOST") {
+            return Cassandra { id: None, host };
+        }
+        let image = env::var("KASS_TEST_IMAGE").unwrap_or_else(|_| "cassandra:4.1".into());
+        let id = docker(&["run", "-d", "--rm", "-p", "127.0.0.1::9042", &image]);
+        let port = docker(&["port", &id, "9042/tcp"]);
+        let host = port.lines().next().unwrap_or_default().to_string();
+        let cassandra = Cassandra { id: Some(id), host };
+        cassandra.wait_until_ready();
+        cassandra
+    }
+
+    fn wait_until_ready(&self) {
+        let started = Instant::now();
+        loop {
+            let output = self.kass(&["SELECT release_version FROM system.local"]);
+            if output.status.success() {
+                return;
+            }
+            assert!(
+                started.elapsed() < STARTUP_TIMEOUT,
+                "Cassandra didn't start: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            thread::sleep(Duration::from_secs(2));
+        }
+    }
+
+    fn kass(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_kass"))
+            .args(["-h", &self.host, "--reconnect-timeout", "0"])
+            .args(args)
+            .output()
+            .expect("Unable to run kass")
+    }
+
+    // The output of a successful run
+    fn run(&self, args: &[&str]) -> String {
+        let output = self.kass(args);
+        assert!(
+            output.status.success(),
+            "kass {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    fn rows(&self, args: &[&str]) -> Vec<JsonValue> {
+        self.run(args)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+impl Drop for Cassandra {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            let _ = Command::new("docker").args(["rm", "-f", id]).output();
+        }
+    }
+}
+
+fn check_json(cassandra: &Cassandra) {
+    let rows = cassandra.rows(&["SELECT * FROM kass_it.all_types WHERE id = ?", "1..2"]);
+    assert_eq!(2, rows.len());
+    let row = rows.iter().find(|x| x["id"] == 1).unwrap();
+    let expected = json!({
+        "ascii_col": "abc",
+        "bigint_col": 9000000000i64,
+        "blob_col": "CAFE",
+        "boolean_col": true,
+        "date_col": "2020-02-29",
+        "decimal_col": "12.345",
+        "double_col": 1.5,
+        "float_col": 2.5,
+        "inet_col": "10.0.0.1",
+        "smallint_col": -3,
+        "text_col": "line 1\nline 2",
+        "time_col": "12:34:56.789",
+        "timestamp_col": "2020-01-01T12:34:56.789Z",
+        "timeuuid_col": "50554d6e-29bb-11e5-b345-feff819cdc9f",
+        "tinyint_col": 7,
+        "uuid_col": "7d2f6b9c-1a2c-4c8e-9b0e-1f2d3c4b5a69",
+        "varint_col": 123456789012i64,
+        "list_col": [1, 2, 3],
+        "set_col": ["a", "b"],
+        "map_col": { "x": 1, "y": 2 },
+        "tuple_col": [1, "one"],
+        "udt_col": { "street": "Queen St", "zip": 1010 },
+    });
+    for (column, value) in expected.as_object().unwrap() {
+        assert_eq!(value, &row[column], "column {}", column);
+    }
+    let empty = rows.iter().find(|x| x["id"] == 2).unwrap();
+    assert_eq!(JsonValue::Null, empty["list_col"]);
+
+    let counters = cassandra.rows(&["SELECT * FROM kass_it.counters WHERE id = ?", "1"]);
+    assert_eq!(vec![json!({ "id": 1, "hits": 42 })], counters);
+}
+
+fn check_csv(cassandra: &Cassandra) {
+    let out = cassandra.run(&[
+        "--format",
+        "csv",
+        "--typed-header",
+        "--escape-control-chars",
+        "SELECT id, text_col, list_col, blob_col FROM kass_it.all_types WHERE id = ?",
+        "1",
+    ]);
+    assert_eq!(
+        "id,text_col,list_col,blob_col\n\
+         int,text,list<int>,blob\n\
+         1,line 1\\nline 2,\"[1,2,3]\",CAFE\n",
+        out
+    );
+}
+
+fn check_map_entries(cassandra: &Cassandra) {
+    let rows = cassandra.rows(&[
+        "--map-mode",
+        "entries",
+        "SELECT map_col FROM kass_it.all_types WHERE id = ?",
+        "1",
+    ]);
+    assert_eq!(vec![json!({ "map_col": [["x", 1], ["y", 2]] })], rows);
+}
+
+#[test]
+fn test_end_to_end() {
+    let cassandra = Cassandra::start();
+    for cql in SCHEMA.iter().chain(DATA) {
+        cassandra.run(&[cql]);
+    }
+    check_json(&cassandra);
+    check_csv(&cassandra);
+    check_map_entries(&cassandra);
+}